@@ -1,4 +1,4 @@
-use rinf::{DartSignal, RustSignal};
+use rinf::{DartSignal, RustSignal, SignalPiece};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, DartSignal)]
@@ -37,6 +37,8 @@ pub struct SshExecRequest {
     pub private_key_passphrase: Option<String>,
     pub connect_timeout_ms: i32,
     pub command_timeout_ms: i32,
+    /// Gzip stdout on the wire when the remote has `gzip`.
+    pub compress: bool,
 }
 
 #[derive(Serialize, RustSignal)]
@@ -46,9 +48,19 @@ pub struct SshExecResponse {
     pub stdout: String,
     pub stderr: String,
     pub exit_status: i32,
+    /// Set when the request asked for `compress`.
+    pub compression: Option<CompressionStats>,
     pub error: Option<String>,
 }
 
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug)]
+pub struct CompressionStats {
+    /// False when the remote had no `gzip` and output came back as-is.
+    pub compressed: bool,
+    pub wire_bytes: u64,
+    pub raw_bytes: u64,
+}
+
 #[derive(Deserialize, DartSignal)]
 pub struct SshStartCommandRequest {
     pub request_id: u64,
@@ -59,6 +71,12 @@ pub struct SshStartCommandRequest {
     pub private_key_pem: Option<String>,
     pub private_key_passphrase: Option<String>,
     pub connect_timeout_ms: i32,
+    /// Stop the command once it has been running this long.
+    pub max_duration_ms: Option<i32>,
+    /// Stop the command if it writes nothing to stdout or stderr for this long.
+    pub idle_output_timeout_ms: Option<i32>,
+    /// Lines longer than this are delivered as `SshStreamLinePart` chunks.
+    pub max_line_bytes: Option<i32>,
 }
 
 #[derive(Serialize, RustSignal)]
@@ -76,10 +94,38 @@ pub struct SshStreamLine {
     pub line: String,
 }
 
+/// A piece of a line longer than the stream's `max_line_bytes`. Parts of one
+/// line arrive in order with increasing `index`; `is_final` ends the line.
+#[derive(Serialize, RustSignal)]
+pub struct SshStreamLinePart {
+    pub stream_id: u64,
+    pub is_stderr: bool,
+    pub index: u32,
+    pub is_final: bool,
+    pub chunk: String,
+}
+
+/// Why a stream ended. `exit_status` is only meaningful for `Exited`.
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamTermination {
+    Exited,
+    Signaled,
+    TransportError,
+    Cancelled,
+    TimedOut,
+}
+
 #[derive(Serialize, RustSignal)]
 pub struct SshStreamExit {
     pub stream_id: u64,
     pub exit_status: i32,
+    /// Signal name without the `SIG` prefix (e.g. `TERM`) when `termination`
+    /// is `Signaled`.
+    pub exit_signal: Option<String>,
+    pub core_dumped: bool,
+    /// Error message the server attached to the signal report, if any.
+    pub signal_message: Option<String>,
+    pub termination: StreamTermination,
     pub error: Option<String>,
 }
 
@@ -110,7 +156,14 @@ pub struct SshWriteFileRequest {
     pub port: i32,
     pub username: String,
     pub remote_path: String,
-    pub contents: String,
+    pub contents: Vec<u8>,
+    /// Permission bits for the file. Without one, an existing file keeps its
+    /// mode and a new file gets the remote umask default.
+    pub mode: Option<u32>,
+    /// Fail if the file already exists.
+    pub create_only: bool,
+    /// Fail unless the existing file has this SHA-256 (hex).
+    pub if_matches_sha256: Option<String>,
     pub private_key_pem: Option<String>,
     pub private_key_passphrase: Option<String>,
     pub connect_timeout_ms: i32,
@@ -121,6 +174,294 @@ pub struct SshWriteFileRequest {
 pub struct SshWriteFileResponse {
     pub request_id: u64,
     pub ok: bool,
+    /// SHA-256 of the file now in place, as computed by the remote.
+    pub sha256: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Reads part of a file under `root`. Relative paths start at `root`; paths
+/// that resolve outside it (including through symlinks) are refused.
+#[derive(Deserialize, DartSignal)]
+pub struct FsReadRequest {
+    pub request_id: u64,
+    pub host: String,
+    pub port: i32,
+    pub username: String,
+    pub root: String,
+    pub path: String,
+    pub offset: u64,
+    /// Bytes wanted from `offset`; reads to the end of the file if unset.
+    pub length: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub private_key_pem: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    pub connect_timeout_ms: i32,
+    pub command_timeout_ms: i32,
+    pub compress: bool,
+}
+
+#[derive(Serialize, RustSignal)]
+pub struct FsReadResponse {
+    pub request_id: u64,
+    pub ok: bool,
+    pub data: Vec<u8>,
+    pub offset: u64,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+    pub mode: u32,
+    /// Fewer bytes than requested came back because of `max_bytes`.
+    pub truncated: bool,
+    pub compression: Option<CompressionStats>,
+    pub error: Option<String>,
+}
+
+/// Lists a remote directory for the project picker. An empty `path` lists
+/// the login directory.
+#[derive(Deserialize, DartSignal)]
+pub struct FsListRequest {
+    pub request_id: u64,
+    pub host: String,
+    pub port: i32,
+    pub username: String,
+    pub path: String,
+    pub show_hidden: bool,
+    /// Directory levels to descend; defaults to 1.
+    pub depth: Option<u32>,
+    pub private_key_pem: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    pub connect_timeout_ms: i32,
+    pub command_timeout_ms: i32,
+}
+
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsEntryKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub struct FsEntry {
+    /// Relative to the listed directory.
+    pub path: String,
+    pub kind: FsEntryKind,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+    pub is_git_repo: bool,
+    /// The directory contains `.field_exec/`.
+    pub has_field_exec: bool,
+}
+
+#[derive(Serialize, RustSignal)]
+pub struct FsListResponse {
+    pub request_id: u64,
+    pub ok: bool,
+    /// Physical path of the listed directory.
+    pub path: String,
+    pub entries: Vec<FsEntry>,
+    /// The listing hit the entry limit.
+    pub truncated: bool,
+    /// False when the host has no sftp subsystem and a shell fallback ran.
+    pub via_sftp: bool,
+    pub error: Option<String>,
+}
+
+/// Fetches bytes appended to a log since `from_offset`. Pass back the
+/// `inode` of the previous response so a rotated file is noticed.
+#[derive(Deserialize, DartSignal)]
+pub struct LogFetchRequest {
+    pub request_id: u64,
+    pub host: String,
+    pub port: i32,
+    pub username: String,
+    pub path: String,
+    pub from_offset: u64,
+    pub inode: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub private_key_pem: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    pub connect_timeout_ms: i32,
+    pub command_timeout_ms: i32,
+    pub compress: bool,
+}
+
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogReset {
+    /// The file got shorter than `from_offset`.
+    Truncated,
+    /// A different file now lives at the path.
+    Rotated,
+}
+
+#[derive(Serialize, RustSignal)]
+pub struct LogFetchResponse {
+    pub request_id: u64,
+    pub ok: bool,
+    pub data: Vec<u8>,
+    /// Offset of the first byte in `data`; 0 after a reset.
+    pub offset: u64,
+    pub next_offset: u64,
+    pub inode: u64,
+    pub size: u64,
+    /// Set when the fetch started over from 0; drop what was read before.
+    pub reset: Option<LogReset>,
+    /// More bytes are waiting past `next_offset`.
+    pub has_more: bool,
+    pub compression: Option<CompressionStats>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobSandbox {
+    ReadOnly,
+    WorkspaceWrite,
+    DangerFullAccess,
+}
+
+#[derive(Deserialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobApprovalPolicy {
+    Untrusted,
+    OnFailure,
+    OnRequest,
+    Never,
+}
+
+/// Starts a Codex run for a tab, in tmux when the host has it and under
+/// `nohup` otherwise. The output schema is composed from the project's
+/// `.field_exec/schema.d` first.
+#[derive(Deserialize, DartSignal)]
+pub struct JobStartRequest {
+    pub request_id: u64,
+    pub host: String,
+    pub port: i32,
+    pub username: String,
+    pub project_dir: String,
+    pub tab_id: String,
+    pub prompt: String,
+    /// Host identity the tmux window name is derived from.
+    pub target_key: String,
+    pub resume_thread_id: Option<String>,
+    pub model: Option<String>,
+    pub profile: Option<String>,
+    pub sandbox: Option<JobSandbox>,
+    pub approval_policy: Option<JobApprovalPolicy>,
+    pub images: Vec<String>,
+    pub use_tmux: bool,
+    /// JSON line appended to the tab log before the job starts.
+    pub log_preamble: Option<String>,
+    pub private_key_pem: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    pub connect_timeout_ms: i32,
+    pub command_timeout_ms: i32,
+}
+
+#[derive(Serialize, RustSignal)]
+pub struct JobStartResponse {
+    pub request_id: u64,
+    pub ok: bool,
+    /// `tmux:<session>:<window>` or `pid:<pid>`.
+    pub job_id: String,
+    pub log_path: String,
+    pub stderr_path: String,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, DartSignal)]
+pub struct JobStopRequest {
+    pub request_id: u64,
+    pub host: String,
+    pub port: i32,
+    pub username: String,
+    pub job_id: String,
+    /// Project and tab the job was started for.
+    pub project_dir: String,
+    pub tab_id: String,
+    pub private_key_pem: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    pub connect_timeout_ms: i32,
+    pub command_timeout_ms: i32,
+}
+
+#[derive(Serialize, RustSignal)]
+pub struct JobStopResponse {
+    pub request_id: u64,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, DartSignal)]
+pub struct JobStatusRequest {
+    pub request_id: u64,
+    pub host: String,
+    pub port: i32,
+    pub username: String,
+    pub job_id: String,
+    /// Project and tab the job was started for.
+    pub project_dir: String,
+    pub tab_id: String,
+    /// Seconds without output before a running job counts as hung.
+    pub hang_after_secs: Option<u64>,
+    pub private_key_pem: Option<String>,
+    pub private_key_passphrase: Option<String>,
+    pub connect_timeout_ms: i32,
+    pub command_timeout_ms: i32,
+}
+
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Exited,
+    /// The host has no tmux to ask about a tmux job.
+    Unknown,
+}
+
+/// How a job's latest turn went.
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobOutcome {
+    Running,
+    Completed,
+    /// Codex reported `turn.failed`; see `outcome_message`.
+    Failed,
+    /// Still running but silent for `idle_secs`.
+    Hung,
+    Cancelled,
+    /// The job ended without finishing its turn; `outcome_message` holds
+    /// the last error, if any.
+    Crashed,
+    Unknown,
+}
+
+/// What a job's heartbeat file shows beyond the process table.
+#[derive(Serialize, SignalPiece, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobAlarm {
+    /// The host booted since the job started.
+    Rebooted,
+    /// The job's pid now belongs to another process.
+    PidReused,
+    /// The job's log has not grown for `idle_secs`.
+    Hung,
+}
+
+#[derive(Serialize, RustSignal)]
+pub struct JobStatusResponse {
+    pub request_id: u64,
+    pub ok: bool,
+    pub state: Option<JobState>,
+    pub outcome: Option<JobOutcome>,
+    pub outcome_message: Option<String>,
+    pub alarm: Option<JobAlarm>,
+    /// Seconds since the log last grew, from the heartbeat.
+    pub idle_secs: Option<u64>,
+    pub thread_id: Option<String>,
+    /// The agent's final message of a completed turn.
+    pub final_message: Option<String>,
+    /// `final_message` as JSON after the output schema fallbacks.
+    pub response_json: Option<String>,
+    /// What is still wrong with the response after the fallbacks.
+    pub response_violations: Vec<String>,
     pub error: Option<String>,
 }
 
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use async_ssh2_tokio::Error;
+use async_ssh2_tokio::client::Client;
+use field_exec_core::remote_fs::{DirEntry, DirList, DirListing, EntryKind, MAX_LIST_ENTRIES};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::FileType;
+use tokio::time::timeout;
+
+/// How a listing was produced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListVia {
+    Sftp,
+    /// The server has no sftp subsystem; `find`/`stat` ran in a shell.
+    Shell,
+}
+
+/// Lists a remote directory over SFTP, falling back to the shell script from
+/// [`DirList::script`] when the sftp subsystem is unavailable.
+pub async fn list_dir(
+    client: &Client,
+    list: &DirList,
+    command_timeout: Duration,
+) -> Result<(DirListing, ListVia), String> {
+    let sftp = match client.open_sftp(None).await {
+        Ok(sftp) => sftp,
+        Err(Error::SubsystemRefused(_)) => {
+            let res = timeout(command_timeout, client.execute(&list.script()))
+                .await
+                .map_err(|_| "SSH command timeout".to_owned())?
+                .map_err(|e| e.to_string())?;
+            let listing = list
+                .parse_output(res.exit_status, &res.stdout, &res.stderr)
+                .map_err(|e| e.to_string())?;
+            return Ok((listing, ListVia::Shell));
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    let listing = timeout(command_timeout, sftp_walk(&sftp, list))
+        .await
+        .map_err(|_| "SFTP listing timeout".to_owned())?;
+    let _ = sftp.close().await;
+    listing.map(|l| (l, ListVia::Sftp))
+}
+
+async fn sftp_walk(sftp: &SftpSession, list: &DirList) -> Result<DirListing, String> {
+    let root = sftp
+        .canonicalize(list.path())
+        .await
+        .map_err(|e| format!("{}: {e}", list.path()))?;
+    let mut entries = Vec::new();
+    let mut truncated = false;
+    // (path relative to root, depth of its children)
+    let mut pending = vec![(String::new(), 1u32)];
+
+    'walk: while let Some((rel, depth)) = pending.pop() {
+        let dir = join(&root, &rel);
+        let Ok(read_dir) = sftp.read_dir(dir.clone()).await else {
+            // Unreadable subdirectories are skipped like `find` does; only
+            // the top level is an error.
+            if rel.is_empty() {
+                return Err(format!("{dir}: cannot read directory"));
+            }
+            continue;
+        };
+        for entry in read_dir {
+            let name = entry.file_name();
+            if !list.includes_hidden() && name.starts_with('.') {
+                continue;
+            }
+            if entries.len() >= MAX_LIST_ENTRIES {
+                truncated = true;
+                break 'walk;
+            }
+            let meta = entry.metadata();
+            let path = join(&rel, &name);
+            let kind = match entry.file_type() {
+                FileType::Dir => EntryKind::Dir,
+                FileType::File => EntryKind::File,
+                FileType::Symlink => EntryKind::Symlink,
+                FileType::Other => EntryKind::Other,
+            };
+            let (mut is_git_repo, mut has_field_exec) = (false, false);
+            if kind == EntryKind::Dir {
+                let abs = join(&root, &path);
+                is_git_repo = sftp
+                    .try_exists(format!("{abs}/.git"))
+                    .await
+                    .unwrap_or(false);
+                has_field_exec = sftp
+                    .metadata(format!("{abs}/.field_exec"))
+                    .await
+                    .is_ok_and(|m| m.is_dir());
+                if depth < list.max_depth() {
+                    pending.push((path.clone(), depth + 1));
+                }
+            }
+            entries.push(DirEntry {
+                path,
+                kind,
+                size: meta.size.unwrap_or(0),
+                mtime: meta.mtime.map(i64::from).unwrap_or(0),
+                is_git_repo,
+                has_field_exec,
+            });
+        }
+    }
+
+    Ok(DirListing {
+        path: root,
+        entries,
+        truncated,
+    }
+    .finish())
+}
+
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() {
+        name.to_owned()
+    } else if base.ends_with('/') {
+        format!("{base}{name}")
+    } else {
+        format!("{base}/{name}")
+    }
+}
@@ -1,6 +1,9 @@
+use std::fmt;
 use std::time::Duration;
 
 use async_ssh2_tokio::client::{AuthMethod, Client, ServerCheckMethod};
+use field_exec_core::compress::{CompressionStats, gunzip_output, gzip_command};
+use tokio::sync::mpsc;
 use tokio::time::timeout;
 
 pub struct SshCommandResult {
@@ -71,3 +74,117 @@ pub async fn run_command(
         exit_status: i32::try_from(result.exit_status).unwrap_or(-1),
     })
 }
+
+/// Size of the pieces stdin is sent in.
+const STDIN_PIECE_BYTES: usize = 32 * 1024;
+
+pub struct ExecOutput {
+    pub exit_status: u32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub compression: Option<CompressionStats>,
+}
+
+#[derive(Debug)]
+pub enum ExecError {
+    Timeout,
+    Ssh(async_ssh2_tokio::Error),
+    /// The compressed stdout could not be inflated.
+    Decompress(String),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => f.write_str("SSH command timeout"),
+            Self::Ssh(e) => e.fmt(f),
+            Self::Decompress(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Runs `command` and returns its exit status with stdout and stderr as raw
+/// bytes, for callers that must not lose binary output to UTF-8 decoding.
+/// With `compress`, stdout travels gzip-compressed and is inflated here.
+pub async fn exec_bytes(
+    client: &Client,
+    command: &str,
+    command_timeout: Duration,
+    compress: bool,
+) -> Result<ExecOutput, ExecError> {
+    exec(client, command, None, command_timeout, compress).await
+}
+
+/// Like [`exec_bytes`], with `input` as the command's stdin.
+pub async fn exec_bytes_with_input(
+    client: &Client,
+    command: &str,
+    input: &[u8],
+    command_timeout: Duration,
+) -> Result<ExecOutput, ExecError> {
+    exec(client, command, Some(input), command_timeout, false).await
+}
+
+async fn exec(
+    client: &Client,
+    command: &str,
+    input: Option<&[u8]>,
+    command_timeout: Duration,
+    compress: bool,
+) -> Result<ExecOutput, ExecError> {
+    let command = if compress {
+        gzip_command(command)
+    } else {
+        command.to_owned()
+    };
+    let stdin_rx = input.map(|input| {
+        let pieces = input.chunks(STDIN_PIECE_BYTES);
+        // Room for every piece plus EOF, so all of stdin is queued up front.
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>(pieces.len() + 1);
+        for piece in pieces {
+            let _ = stdin_tx.try_send(piece.to_vec());
+        }
+        let _ = stdin_tx.try_send(Vec::new());
+        stdin_rx
+    });
+    let (stdout_tx, mut stdout_rx) = mpsc::channel::<Vec<u8>>(8);
+    let (stderr_tx, mut stderr_rx) = mpsc::channel::<Vec<u8>>(8);
+    let exec_future =
+        client.execute_io(&command, stdout_tx, Some(stderr_tx), stdin_rx, false, None);
+    tokio::pin!(exec_future);
+
+    let mut out = Vec::new();
+    let mut err = Vec::new();
+    let exit_status = timeout(command_timeout, async {
+        loop {
+            tokio::select! {
+                result = &mut exec_future => break result,
+                Some(bytes) = stdout_rx.recv() => out.extend_from_slice(&bytes),
+                Some(bytes) = stderr_rx.recv() => err.extend_from_slice(&bytes),
+            }
+        }
+    })
+    .await
+    .map_err(|_| ExecError::Timeout)?
+    .map_err(ExecError::Ssh)?;
+    while let Ok(bytes) = stdout_rx.try_recv() {
+        out.extend_from_slice(&bytes);
+    }
+    while let Ok(bytes) = stderr_rx.try_recv() {
+        err.extend_from_slice(&bytes);
+    }
+    let (stdout, compression) = if compress {
+        let (raw, stats) = gunzip_output(&out).map_err(ExecError::Decompress)?;
+        (raw, Some(stats))
+    } else {
+        (out, None)
+    };
+    Ok(ExecOutput {
+        exit_status,
+        stdout,
+        stderr: err,
+        compression,
+    })
+}
@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+use async_ssh2_tokio::Error;
+use async_ssh2_tokio::client::CommandExit;
+use field_exec_core::stream::{StreamEnd, StreamSignal, StreamTermination};
+use tokio::time::sleep_until;
+
+/// How a streamed command ended, from what `execute_io_with_exit` returned.
+pub fn stream_end(result: &Result<CommandExit, Error>) -> StreamEnd {
+    match result {
+        Ok(CommandExit {
+            exit_status: Some(code),
+            ..
+        }) => StreamEnd::exited(*code),
+        Ok(CommandExit {
+            exit_signal: Some(signal),
+            core_dumped,
+            error_message,
+            ..
+        }) => StreamEnd::signaled(signal.clone(), *core_dumped, error_message.clone()),
+        Ok(_) => StreamEnd::failed(
+            StreamTermination::TransportError,
+            Error::CommandDidntExit.to_string(),
+        ),
+        Err(e) => StreamEnd::failed(termination_for_error(e), e.to_string()),
+    }
+}
+
+fn termination_for_error(err: &Error) -> StreamTermination {
+    match err {
+        Error::IoError(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            StreamTermination::TimedOut
+        }
+        _ => StreamTermination::TransportError,
+    }
+}
+
+pub fn stream_signal(signal: StreamSignal) -> russh::Sig {
+    match signal {
+        StreamSignal::Term => russh::Sig::TERM,
+        StreamSignal::Kill => russh::Sig::KILL,
+    }
+}
+
+/// Sleeps until a watchdog deadline, or forever when there is none.
+pub async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(d) => sleep_until(d.into()).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_ssh2_tokio::client::{AuthMethod, Client, ServerCheckMethod};
+use field_exec_adapters::fs::{ListVia, list_dir};
+use field_exec_core::remote_fs::{DirList, EntryKind};
+use russh::keys::ssh_key::rand_core::OsRng;
+use russh::server::{Auth, Msg, Session};
+use russh::{Channel, ChannelId, CryptoVec};
+use russh_sftp::protocol::{Attrs, File, FileAttributes, Handle, Name, Status, StatusCode};
+use tokio::net::TcpListener;
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("field_exec_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(fs::create_dir_all(&dir).is_ok());
+        Self(dir)
+    }
+
+    fn path(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+
+    fn add(&self, rel: &str) {
+        let path = self.0.join(rel);
+        if let Some(dir) = rel.strip_suffix('/') {
+            assert!(fs::create_dir_all(self.0.join(dir)).is_ok());
+        } else {
+            assert!(path.parent().is_some_and(|p| fs::create_dir_all(p).is_ok()));
+            assert!(fs::write(path, rel).is_ok());
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Subsystem {
+    Local,
+    /// Accepts the subsystem but never answers the SFTP handshake.
+    Broken,
+    Refused,
+}
+
+/// Serves SFTP from the local filesystem and runs exec requests in `/bin/sh`.
+struct TestServer {
+    sftp: Subsystem,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+    execs: Arc<AtomicUsize>,
+    requested: Arc<Mutex<Vec<String>>>,
+}
+
+impl russh::server::Handler for TestServer {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, _: &str, _: &str) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let stream = self.channels.remove(&channel);
+        match (name, self.sftp, stream) {
+            ("sftp", Subsystem::Local, Some(stream)) => {
+                session.channel_success(channel)?;
+                let handler = LocalSftp {
+                    dirs: HashMap::new(),
+                    requested: self.requested.clone(),
+                };
+                russh_sftp::server::run(stream.into_stream(), handler).await;
+                Ok(())
+            }
+            ("sftp", Subsystem::Broken, _) => {
+                // The client gives up after its 10 second init timeout.
+                session.channel_success(channel)
+            }
+            _ => session.channel_failure(channel),
+        }
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        command: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.execs.fetch_add(1, Ordering::SeqCst);
+        let command = String::from_utf8_lossy(command).into_owned();
+        session.channel_success(channel)?;
+        let handle = session.handle();
+        tokio::spawn(async move {
+            let output = tokio::task::spawn_blocking(move || {
+                std::process::Command::new("/bin/sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(std::process::Stdio::null())
+                    .output()
+            })
+            .await;
+            let Ok(Ok(output)) = output else {
+                let _ = handle.exit_status_request(channel, 127).await;
+                let _ = handle.close(channel).await;
+                return;
+            };
+            let code = output.status.code().unwrap_or(-1) as u32;
+            let _ = handle.data(channel, CryptoVec::from(output.stdout)).await;
+            let _ = handle
+                .extended_data(channel, 1, CryptoVec::from(output.stderr))
+                .await;
+            let _ = handle.exit_status_request(channel, code).await;
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        });
+        Ok(())
+    }
+}
+
+/// The read-only part of an SFTP server, enough for `list_dir`.
+struct LocalSftp {
+    dirs: HashMap<String, Vec<File>>,
+    /// Every path the client asked about, in order.
+    requested: Arc<Mutex<Vec<String>>>,
+}
+
+impl LocalSftp {
+    fn record(&self, path: &str) {
+        if let Ok(mut requested) = self.requested.lock() {
+            requested.push(path.to_owned());
+        }
+    }
+}
+
+impl russh_sftp::server::Handler for LocalSftp {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        self.record(&path);
+        let real = fs::canonicalize(&path).map_err(|_| StatusCode::NoSuchFile)?;
+        Ok(Name {
+            id,
+            files: vec![File::dummy(real.to_string_lossy())],
+        })
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        self.record(&path);
+        let read_dir = fs::read_dir(&path).map_err(|_| StatusCode::NoSuchFile)?;
+        let files = read_dir
+            .flatten()
+            .filter_map(|entry| {
+                let meta = entry.path().symlink_metadata().ok()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                Some(File::new(name, FileAttributes::from(&meta)))
+            })
+            .collect();
+        self.dirs.insert(path.clone(), files);
+        Ok(Handle { id, handle: path })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        match self.dirs.remove(&handle) {
+            Some(files) => Ok(Name { id, files }),
+            None => Err(StatusCode::Eof),
+        }
+    }
+
+    async fn close(&mut self, id: u32, _: String) -> Result<Status, Self::Error> {
+        Ok(Status {
+            id,
+            status_code: StatusCode::Ok,
+            error_message: "Ok".to_owned(),
+            language_tag: "en-US".to_owned(),
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.record(&path);
+        let meta = fs::metadata(&path).map_err(|_| StatusCode::NoSuchFile)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&meta),
+        })
+    }
+}
+
+struct Connected {
+    client: Client,
+    execs: Arc<AtomicUsize>,
+    requested: Arc<Mutex<Vec<String>>>,
+}
+
+async fn connect(sftp: Subsystem) -> Connected {
+    let Ok(listener) = TcpListener::bind(("127.0.0.1", 0)).await else {
+        panic!("bind test server");
+    };
+    let Ok(addr) = listener.local_addr() else {
+        panic!("test server address");
+    };
+    let Ok(key) = russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)
+    else {
+        panic!("host key");
+    };
+    let config = Arc::new(russh::server::Config {
+        keys: vec![key],
+        ..Default::default()
+    });
+    let execs = Arc::new(AtomicUsize::new(0));
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let handler = TestServer {
+        sftp,
+        channels: HashMap::new(),
+        execs: execs.clone(),
+        requested: requested.clone(),
+    };
+    tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.ok()?;
+        let session = russh::server::run_stream(config, tcp, handler).await.ok()?;
+        session.await.ok()
+    });
+    let client = Client::connect(
+        ("127.0.0.1", addr.port()),
+        "u",
+        AuthMethod::with_password("x"),
+        ServerCheckMethod::NoCheck,
+    )
+    .await;
+    let Ok(client) = client else {
+        panic!("connect to test server");
+    };
+    Connected {
+        client,
+        execs,
+        requested,
+    }
+}
+
+fn tree(dir: &TempDir) {
+    for rel in [
+        "README.md",
+        ".env",
+        "src/main.rs",
+        "src/deep/mod.rs",
+        "repo/.git/",
+        "repo/Cargo.toml",
+        "tool/.field_exec/env.sh",
+    ] {
+        dir.add(rel);
+    }
+}
+
+fn paths(listing: &field_exec_core::remote_fs::DirListing) -> Vec<&str> {
+    listing.entries.iter().map(|e| e.path.as_str()).collect()
+}
+
+#[tokio::test]
+async fn sftp_walk_lists_relative_paths_down_to_the_depth() {
+    let dir = TempDir::new("sftp_walk");
+    tree(&dir);
+    let server = connect(Subsystem::Local).await;
+    let list = DirList::new(dir.path()).depth(Some(2));
+    let result = list_dir(&server.client, &list, Duration::from_secs(10)).await;
+    let Ok((listing, via)) = result else {
+        panic!("list_dir: {result:?}");
+    };
+    assert_eq!(via, ListVia::Sftp);
+    assert_eq!(
+        Path::new(&listing.path).canonicalize().ok(),
+        dir.0.canonicalize().ok()
+    );
+    assert_eq!(
+        paths(&listing),
+        [
+            "README.md",
+            "repo",
+            "repo/Cargo.toml",
+            "src",
+            "src/deep",
+            "src/main.rs",
+            "tool",
+        ]
+    );
+    let flags: Vec<_> = listing
+        .entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::Dir)
+        .map(|e| (e.path.as_str(), e.is_git_repo, e.has_field_exec))
+        .collect();
+    assert_eq!(
+        flags,
+        [
+            ("repo", true, false),
+            ("src", false, false),
+            ("src/deep", false, false),
+            ("tool", false, true),
+        ]
+    );
+    let readme = listing.entries.iter().find(|e| e.path == "README.md");
+    assert_eq!(readme.map(|e| (e.kind, e.size)), Some((EntryKind::File, 9)));
+    assert_eq!(server.execs.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn sftp_walk_includes_hidden_entries_on_request() {
+    let dir = TempDir::new("sftp_walk_hidden");
+    tree(&dir);
+    let server = connect(Subsystem::Local).await;
+    let list = DirList::new(dir.path()).show_hidden(true).depth(Some(1));
+    let result = list_dir(&server.client, &list, Duration::from_secs(10)).await;
+    let Ok((listing, _)) = result else {
+        panic!("list_dir: {result:?}");
+    };
+    assert_eq!(
+        paths(&listing),
+        [".env", "README.md", "repo", "src", "tool"]
+    );
+}
+
+#[tokio::test]
+async fn sftp_walk_joins_paths_under_the_filesystem_root() {
+    let server = connect(Subsystem::Local).await;
+    let list = DirList::new("/").depth(Some(1));
+    let result = list_dir(&server.client, &list, Duration::from_secs(10)).await;
+    let Ok((listing, _)) = result else {
+        panic!("list_dir: {result:?}");
+    };
+    assert_eq!(listing.path, "/");
+    assert!(paths(&listing).contains(&"tmp"));
+    assert!(paths(&listing).iter().all(|p| !p.contains('/')));
+    let requested = server
+        .requested
+        .lock()
+        .map(|r| r.clone())
+        .unwrap_or_default();
+    assert!(requested.contains(&"/tmp/.git".to_owned()), "{requested:?}");
+    assert!(requested.iter().all(|p| !p.contains("//")), "{requested:?}");
+}
+
+#[tokio::test]
+async fn sftp_errors_are_reported_instead_of_listing_in_a_shell() {
+    let dir = TempDir::new("sftp_missing");
+    let missing = format!("{}/missing", dir.path());
+    let server = connect(Subsystem::Local).await;
+    let result = list_dir(
+        &server.client,
+        &DirList::new(&missing),
+        Duration::from_secs(10),
+    )
+    .await;
+    let Err(err) = result else {
+        panic!("listed a missing directory: {result:?}");
+    };
+    assert!(err.starts_with(&missing), "{err}");
+
+    let server = connect(Subsystem::Broken).await;
+    let result = list_dir(
+        &server.client,
+        &DirList::new(dir.path()),
+        Duration::from_secs(10),
+    )
+    .await;
+    assert!(result.is_err(), "{result:?}");
+    assert_eq!(server.execs.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn refused_sftp_falls_back_to_the_shell() {
+    let dir = TempDir::new("sftp_refused");
+    tree(&dir);
+    let server = connect(Subsystem::Refused).await;
+    let list = DirList::new(dir.path()).depth(Some(1));
+    let result = list_dir(&server.client, &list, Duration::from_secs(10)).await;
+    let Ok((listing, via)) = result else {
+        panic!("list_dir: {result:?}");
+    };
+    assert_eq!(via, ListVia::Shell);
+    assert_eq!(paths(&listing), ["README.md", "repo", "src", "tool"]);
+    assert_eq!(server.execs.load(Ordering::SeqCst), 1);
+}
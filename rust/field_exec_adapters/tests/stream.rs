@@ -0,0 +1,54 @@
+use async_ssh2_tokio::Error;
+use async_ssh2_tokio::client::CommandExit;
+use field_exec_adapters::stream::stream_end;
+use field_exec_core::stream::{StreamEnd, StreamTermination};
+
+#[test]
+fn signaled_exits_keep_the_signal_and_core_dump() {
+    let exit = CommandExit {
+        exit_status: None,
+        exit_signal: Some("SEGV".to_owned()),
+        core_dumped: true,
+        error_message: Some("segfault".to_owned()),
+    };
+    assert_eq!(
+        stream_end(&Ok(exit)),
+        StreamEnd {
+            exit_status: -1,
+            exit_signal: Some("SEGV".to_owned()),
+            core_dumped: true,
+            signal_message: Some("segfault".to_owned()),
+            termination: StreamTermination::Signaled,
+            error: Some("killed by signal SEGV".to_owned()),
+        }
+    );
+
+    let exit = CommandExit {
+        exit_signal: Some("TERM".to_owned()),
+        ..CommandExit::default()
+    };
+    let end = stream_end(&Ok(exit));
+    assert_eq!(
+        (end.termination, end.core_dumped, end.signal_message),
+        (StreamTermination::Signaled, false, None)
+    );
+}
+
+#[test]
+fn exits_and_failures_map_to_their_termination() {
+    let exit = CommandExit {
+        exit_status: Some(3),
+        ..CommandExit::default()
+    };
+    assert_eq!(stream_end(&Ok(exit)), StreamEnd::exited(3));
+
+    let end = stream_end(&Ok(CommandExit::default()));
+    assert_eq!(end.termination, StreamTermination::TransportError);
+
+    let timed_out = std::io::Error::from(std::io::ErrorKind::TimedOut);
+    let end = stream_end(&Err(Error::IoError(timed_out)));
+    assert_eq!(
+        (end.termination, end.exit_status),
+        (StreamTermination::TimedOut, -1)
+    );
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::shell::{SHA256_FN, sh_quote};
+
+/// Exit status the write script uses when a precondition does not hold.
+const EXIT_PRECONDITION: u32 = 3;
+/// Exit status when the remote has no usable SHA-256 tool.
+const EXIT_NO_CHECKSUM: u32 = 4;
+/// Exit status when the bytes that arrived do not hash to what was sent.
+const EXIT_CHECKSUM_MISMATCH: u32 = 5;
+
+/// Condition the existing remote file must meet before it is replaced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum WritePrecondition {
+    #[default]
+    None,
+    /// Fail if anything already exists at the path.
+    CreateOnly,
+    /// Fail unless the current file's SHA-256 (lowercase hex) matches.
+    IfMatchesSha256(String),
+}
+
+impl WritePrecondition {
+    /// Builds the precondition from the `create_only` / `if_matches_sha256`
+    /// request fields, which cannot both be set.
+    pub fn from_flags(
+        create_only: bool,
+        if_matches_sha256: Option<String>,
+    ) -> Result<Self, AtomicWriteError> {
+        match (create_only, if_matches_sha256) {
+            (false, None) => Ok(Self::None),
+            (true, None) => Ok(Self::CreateOnly),
+            (false, Some(hash)) => Ok(Self::IfMatchesSha256(hash)),
+            (true, Some(_)) => Err(AtomicWriteError::ConflictingPreconditions),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AtomicWriteError {
+    ConflictingPreconditions,
+    PreconditionFailed(String),
+    ChecksumUnavailable,
+    ChecksumMismatch { expected: String, actual: String },
+    Failed { exit_status: u32, message: String },
+}
+
+impl fmt::Display for AtomicWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ConflictingPreconditions => {
+                f.write_str("create_only and if_matches_sha256 are mutually exclusive")
+            }
+            Self::PreconditionFailed(msg) => write!(f, "precondition failed: {msg}"),
+            Self::ChecksumUnavailable => {
+                f.write_str("remote has no sha256sum, shasum or openssl to verify the write")
+            }
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "checksum mismatch (sent {expected}, remote has {actual})"
+                )
+            }
+            Self::Failed {
+                exit_status,
+                message,
+            } if message.is_empty() => write!(f, "write failed (exit={exit_status})"),
+            Self::Failed {
+                exit_status,
+                message,
+            } => write!(f, "write failed (exit={exit_status}): {message}"),
+        }
+    }
+}
+
+impl std::error::Error for AtomicWriteError {}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// A remote file write that never leaves a half-written target behind.
+///
+/// The script built by [`AtomicWrite::script`] reads the new contents from
+/// stdin into a temp file next to the target, checks the precondition and the
+/// SHA-256 of what arrived, and only then renames the temp file into place.
+/// Without an explicit mode, an existing target keeps its permissions and a
+/// new file gets the usual `0666 & ~umask`.
+#[derive(Clone, Debug)]
+pub struct AtomicWrite {
+    remote_path: String,
+    sha256: String,
+    mode: Option<u32>,
+    precondition: WritePrecondition,
+    private_dir: bool,
+}
+
+impl AtomicWrite {
+    pub fn new(remote_path: impl Into<String>, contents: &[u8]) -> Self {
+        Self {
+            remote_path: remote_path.into(),
+            sha256: sha256_hex(contents),
+            mode: None,
+            precondition: WritePrecondition::None,
+            private_dir: false,
+        }
+    }
+
+    pub fn mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode.map(|m| m & 0o7777);
+        self
+    }
+
+    pub fn precondition(mut self, precondition: WritePrecondition) -> Self {
+        self.precondition = precondition;
+        self
+    }
+
+    /// Restricts the target's directory to its owner (`chmod 700`), best
+    /// effort.
+    pub fn private_dir(mut self, private_dir: bool) -> Self {
+        self.private_dir = private_dir;
+        self
+    }
+
+    /// SHA-256 of the contents this write was built for.
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn script(&self) -> String {
+        let mut lines = vec![
+            format!("dst={}", sh_quote(&self.remote_path)),
+            format!("expected={}", self.sha256),
+            r#"dir=$(dirname "$dst")"#.to_owned(),
+            r#"mkdir -p "$dir" || exit 1"#.to_owned(),
+        ];
+        if self.private_dir {
+            lines.push(r#"chmod 700 "$dir" >/dev/null 2>&1 || true"#.to_owned());
+        }
+        lines.extend([
+            r#"tmp=$(mktemp "$dir/.$(basename "$dst").XXXXXX") || exit 1"#.to_owned(),
+            r#"trap 'rm -f "$tmp"' EXIT"#.to_owned(),
+            r#"cat > "$tmp" || exit 1"#.to_owned(),
+            SHA256_FN.to_owned(),
+            r#"sum=$(sha256_of "$tmp")"#.to_owned(),
+            format!(r#"[ -n "$sum" ] || exit {EXIT_NO_CHECKSUM}"#),
+            format!(
+                r#"[ "$sum" = "$expected" ] || {{ echo "$sum" >&2; exit {EXIT_CHECKSUM_MISMATCH}; }}"#
+            ),
+        ]);
+
+        match &self.precondition {
+            WritePrecondition::None | WritePrecondition::CreateOnly => {}
+            WritePrecondition::IfMatchesSha256(hash) => {
+                lines.push(format!(
+                    r#"[ -f "$dst" ] || {{ echo "target does not exist" >&2; exit {EXIT_PRECONDITION}; }}"#
+                ));
+                lines.push(r#"cur=$(sha256_of "$dst")"#.to_owned());
+                lines.push(format!(
+                    r#"[ "$cur" = {} ] || {{ echo "target hash is $cur" >&2; exit {EXIT_PRECONDITION}; }}"#,
+                    sh_quote(&hash.to_ascii_lowercase())
+                ));
+            }
+        }
+
+        match self.mode {
+            Some(mode) => lines.push(format!(r#"chmod {mode:o} "$tmp" || exit 1"#)),
+            None => lines.push(
+                concat!(
+                    r#"perm=$(stat -c %a "$dst" 2>/dev/null || stat -f %Lp "$dst" 2>/dev/null"#,
+                    r#" || printf '%o' $((0666 & ~0$(umask)))); chmod "$perm" "$tmp" || exit 1"#
+                )
+                .to_owned(),
+            ),
+        }
+
+        // `ln` refuses to replace an existing file, which makes create-only
+        // atomic; everything else is a plain rename.
+        match self.precondition {
+            WritePrecondition::CreateOnly => lines.push(format!(
+                r#"ln "$tmp" "$dst" 2>/dev/null || {{ echo "target already exists" >&2; exit {EXIT_PRECONDITION}; }}"#
+            )),
+            _ => lines.push(r#"mv -f "$tmp" "$dst" || exit 1"#.to_owned()),
+        }
+        lines.push(r#"echo "sha256=$sum""#.to_owned());
+        lines.join("\n")
+    }
+
+    /// Interprets how the script ended; on success returns the SHA-256 the
+    /// remote computed for the file now in place.
+    pub fn parse_result(
+        &self,
+        exit_status: u32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<String, AtomicWriteError> {
+        let stderr = stderr.trim();
+        match exit_status {
+            0 => {
+                let actual = stdout
+                    .lines()
+                    .find_map(|l| l.trim().strip_prefix("sha256="))
+                    .unwrap_or_default()
+                    .to_owned();
+                if actual == self.sha256 {
+                    Ok(actual)
+                } else {
+                    Err(AtomicWriteError::ChecksumMismatch {
+                        expected: self.sha256.clone(),
+                        actual,
+                    })
+                }
+            }
+            EXIT_PRECONDITION => Err(AtomicWriteError::PreconditionFailed(stderr.to_owned())),
+            EXIT_NO_CHECKSUM => Err(AtomicWriteError::ChecksumUnavailable),
+            EXIT_CHECKSUM_MISMATCH => Err(AtomicWriteError::ChecksumMismatch {
+                expected: self.sha256.clone(),
+                actual: stderr.to_owned(),
+            }),
+            exit_status => Err(AtomicWriteError::Failed {
+                exit_status,
+                message: stderr.to_owned(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use crate::job::check_exit;
+use crate::job_status::{JobOutcome, JobStatus};
+use crate::remote_fs::RemoteFsError;
+use crate::shell::sh_quote;
+
+/// Pattern kept out of every auto-commit, both through the repository's
+/// `info/exclude` and as an exclude pathspec for files already tracked.
+pub const FIELD_EXEC_EXCLUDE: &str = "**/.field_exec/";
+/// Exit status when `git add` or `git commit` fails.
+const EXIT_GIT_FAILED: u32 = 3;
+
+/// Why no commit was attempted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The job still runs, or its latest turn did not complete.
+    JobNotCompleted,
+    /// The final response had no usable `commit_message`.
+    EmptyMessage,
+    GitNotFound,
+    NotARepository,
+    /// `HEAD` is not on a branch.
+    DetachedHead,
+    NoChanges,
+}
+
+impl SkipReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::JobNotCompleted => "job_not_completed",
+            Self::EmptyMessage => "empty_commit_message",
+            Self::GitNotFound => "git_not_found",
+            Self::NotARepository => "not_a_git_repo",
+            Self::DetachedHead => "detached_head",
+            Self::NoChanges => "no_changes",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommitResult {
+    Committed {
+        sha: String,
+    },
+    /// A dry run found changes; nothing was staged or committed.
+    DryRun,
+    /// The branch matched `pattern` from the protected list.
+    Protected {
+        pattern: String,
+    },
+    Skipped(SkipReason),
+    /// `git add` or `git commit` failed, e.g. in a hook.
+    Failed {
+        message: String,
+    },
+}
+
+/// What an auto-commit did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitReport {
+    /// The checked-out branch, once the script got that far.
+    pub branch: Option<String>,
+    pub result: CommitResult,
+    /// Paths the commit contains, or would contain on a dry run, relative
+    /// to the repository root.
+    pub files: Vec<String>,
+}
+
+impl CommitReport {
+    pub fn skipped(reason: SkipReason) -> Self {
+        Self {
+            branch: None,
+            result: CommitResult::Skipped(reason),
+            files: Vec::new(),
+        }
+    }
+}
+
+/// Puts a commit message on one line, the way the app shows it.
+pub fn normalize_commit_message(message: &str) -> String {
+    message.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The `commit_message` of a job's latest turn, from its
+/// [`JobStatusCheck`](crate::job_status::JobStatusCheck). Only a job that
+/// exited after completing its turn has one, so a run that is still going
+/// or crashed never commits under the message of an earlier run.
+pub fn commit_message_from_status(status: &JobStatus) -> Result<String, SkipReason> {
+    if status.outcome != JobOutcome::Completed {
+        return Err(SkipReason::JobNotCompleted);
+    }
+    status
+        .response
+        .as_ref()
+        .and_then(|check| check.response.as_ref())
+        .map(|response| normalize_commit_message(&response.commit_message))
+        .filter(|message| !message.is_empty())
+        .ok_or(SkipReason::EmptyMessage)
+}
+
+/// Turns a branch glob into a `case` pattern: `*` and `?` stay wildcards,
+/// everything else is quoted.
+fn case_pattern(pattern: &str) -> String {
+    let mut out = String::new();
+    let mut literal = String::new();
+    for c in pattern.chars() {
+        if c == '*' || c == '?' {
+            if !literal.is_empty() {
+                out.push_str(&sh_quote(&literal));
+                literal.clear();
+            }
+            out.push(c);
+        } else {
+            literal.push(c);
+        }
+    }
+    if !literal.is_empty() {
+        out.push_str(&sh_quote(&literal));
+    }
+    out
+}
+
+/// Stages the project's changes and commits them with the turn's message.
+///
+/// `.field_exec/` never goes in: it is added to the repository's
+/// `info/exclude` and left out of `git add`. The commit is refused on a
+/// detached `HEAD` or when the branch matches a protected glob (`*` and
+/// `?` wildcards). A dry run stages into a throwaway index, so the
+/// repository is left exactly as it was.
+///
+/// The script prints `@branch <name>`, then one of `@skipped <reason>`,
+/// `@protected <pattern>`, `@dry_run` or `@committed <sha>`; the last two
+/// are followed by `@staged` and the NUL-separated paths.
+#[derive(Clone, Debug)]
+pub struct AutoCommit {
+    project_dir: String,
+    message: String,
+    protected_branches: Vec<String>,
+    dry_run: bool,
+}
+
+impl AutoCommit {
+    /// `message` is normalized to one line; callers skip the commit with
+    /// [`SkipReason::EmptyMessage`] when it is blank.
+    pub fn new(project_dir: impl Into<String>, message: &str) -> Self {
+        Self {
+            project_dir: project_dir.into(),
+            message: normalize_commit_message(message),
+            protected_branches: Vec::new(),
+            dry_run: false,
+        }
+    }
+
+    /// Branch globs to never commit on, such as `master` or `release/*`;
+    /// none by default.
+    pub fn protected_branches(mut self, patterns: Vec<String>) -> Self {
+        self.protected_branches = patterns;
+        self.protected_branches.retain(|p| !p.trim().is_empty());
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The message as it will be committed.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn script(&self) -> String {
+        let skip =
+            |reason: SkipReason| format!("{{ echo '@skipped {}'; exit 0; }}", reason.as_str());
+        let mut lines = vec![
+            format!("cd {} || exit 2", sh_quote(&self.project_dir)),
+            format!(
+                "command -v git >/dev/null 2>&1 || {}",
+                skip(SkipReason::GitNotFound)
+            ),
+            format!(
+                "git rev-parse --is-inside-work-tree >/dev/null 2>&1 || {}",
+                skip(SkipReason::NotARepository)
+            ),
+            r#"branch=$(git symbolic-ref --short -q HEAD) || branch="""#.to_owned(),
+            r#"printf '@branch %s\n' "$branch""#.to_owned(),
+            format!(r#"[ -n "$branch" ] || {}"#, skip(SkipReason::DetachedHead)),
+        ];
+        if !self.protected_branches.is_empty() {
+            lines.push(r#"case "$branch" in"#.to_owned());
+            for pattern in &self.protected_branches {
+                lines.push(format!(
+                    "  {}) printf '@protected %s\\n' {}; exit 0 ;;",
+                    case_pattern(pattern),
+                    sh_quote(pattern)
+                ));
+            }
+            lines.push("esac".to_owned());
+        }
+        let pattern = sh_quote(FIELD_EXEC_EXCLUDE);
+        if self.dry_run {
+            lines.extend([
+                r#"index=$(git rev-parse --git-path index) || exit 1"#.to_owned(),
+                "GIT_INDEX_FILE=$(mktemp) || exit 1".to_owned(),
+                "export GIT_INDEX_FILE".to_owned(),
+                r#"trap 'rm -f "$GIT_INDEX_FILE"' EXIT"#.to_owned(),
+                // Git refuses an empty index file, so an unborn repository
+                // starts without one.
+                r#"if [ -f "$index" ]; then cp "$index" "$GIT_INDEX_FILE" || exit 1; else rm -f "$GIT_INDEX_FILE"; fi"#
+                    .to_owned(),
+            ]);
+        } else {
+            lines.extend([
+                r#"exclude=$(git rev-parse --git-path info/exclude) || exit 1"#.to_owned(),
+                r#"mkdir -p "$(dirname "$exclude")" || exit 1"#.to_owned(),
+                format!(r#"if ! grep -qxF {pattern} "$exclude" 2>/dev/null; then"#),
+                r#"  if [ -s "$exclude" ] && [ -n "$(tail -c 1 "$exclude")" ]; then echo >> "$exclude"; fi"#
+                    .to_owned(),
+                format!(r#"  printf '%s\n' {pattern} >> "$exclude" || exit 1"#),
+                "fi".to_owned(),
+            ]);
+        }
+        lines.extend([
+            format!(
+                "git add -A -- . {} >&2 || exit {EXIT_GIT_FAILED}",
+                sh_quote(":(exclude,glob)**/.field_exec/**")
+            ),
+            format!(
+                "git diff --cached --quiet && {}",
+                skip(SkipReason::NoChanges)
+            ),
+        ]);
+        if self.dry_run {
+            lines.extend([
+                "echo '@dry_run'".to_owned(),
+                "echo '@staged'".to_owned(),
+                "git diff --cached --name-only -z".to_owned(),
+            ]);
+        } else {
+            lines.extend([
+                format!(
+                    "git commit -q -m {} >&2 || exit {EXIT_GIT_FAILED}",
+                    sh_quote(&self.message)
+                ),
+                r#"printf '@committed %s\n' "$(git rev-parse HEAD)""#.to_owned(),
+                "echo '@staged'".to_owned(),
+                "git diff-tree -r --root --no-commit-id --name-only -z HEAD".to_owned(),
+            ]);
+        }
+        lines.join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &[u8],
+        stderr: &str,
+    ) -> Result<CommitReport, RemoteFsError> {
+        // Paths may hold newlines, so they come last and NUL-separated.
+        let (head, staged) = match find(stdout, b"\n@staged\n") {
+            Some(at) => (&stdout[..at], Some(&stdout[at + b"\n@staged\n".len()..])),
+            None => (stdout, None),
+        };
+        let head = String::from_utf8_lossy(head);
+        let value = |key: &str| {
+            head.lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+        };
+        let branch = value("@branch")
+            .filter(|b| !b.is_empty())
+            .map(str::to_owned);
+        if exit_status == EXIT_GIT_FAILED {
+            return Ok(CommitReport {
+                branch,
+                result: CommitResult::Failed {
+                    message: stderr.trim().to_owned(),
+                },
+                files: Vec::new(),
+            });
+        }
+        check_exit(exit_status, stderr)?;
+
+        let result = if let Some(sha) = value("@committed") {
+            CommitResult::Committed {
+                sha: sha.trim().to_owned(),
+            }
+        } else if head.lines().any(|line| line == "@dry_run") {
+            CommitResult::DryRun
+        } else if let Some(pattern) = value("@protected") {
+            CommitResult::Protected {
+                pattern: pattern.to_owned(),
+            }
+        } else if let Some(reason) = value("@skipped") {
+            let reason = [
+                SkipReason::GitNotFound,
+                SkipReason::NotARepository,
+                SkipReason::DetachedHead,
+                SkipReason::NoChanges,
+            ]
+            .into_iter()
+            .find(|r| r.as_str() == reason)
+            .ok_or_else(|| RemoteFsError::Malformed(format!("skip reason {reason}")))?;
+            CommitResult::Skipped(reason)
+        } else {
+            return Err(RemoteFsError::Malformed(head.trim().to_owned()));
+        };
+        let files = staged
+            .unwrap_or_default()
+            .split(|&b| b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| String::from_utf8_lossy(path).into_owned())
+            .collect();
+        Ok(CommitReport {
+            branch,
+            result,
+            files,
+        })
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
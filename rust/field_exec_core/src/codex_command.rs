@@ -0,0 +1,382 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::shell::sh_quote;
+
+/// Developer instructions every run gets; a project's own
+/// `.field_exec/developer_instructions.txt` is appended to them.
+pub const DEFAULT_DEVELOPER_INSTRUCTIONS: &str = "\
+You are running as Codex CLI in non-interactive mode.
+
+You MUST produce a final response that is valid JSON matching the schema passed via `--output-schema`.
+- Put all user-visible content in `message` (markdown allowed).
+- Always include `commit_message` as a concise, single-line git commit message (imperative mood).
+  - The client may run `git add -A && git commit -m \"<commit_message>\"` only if there are changes.
+- If you produced images the user should see (e.g. golden test diffs, screenshots), include them in `images`:
+  - Each entry must include `path` as an absolute filesystem path inside the workspace (project directory).
+  - Optionally include a short `caption`.
+  - The client may fetch these images and display them in the chat.
+- You may use a .gitignored path for image storage such as `.field_exec/images` to ensure they are not committed and only visible to the user.
+- If you did not produce any images, return `images` as an empty array (`[]`).
+- If the user asks you to send images already in the project, this means putting them in the images array.
+- If you need a user decision, return `actions` as button options:
+  - Each action has `id`, `label`, and `value`.
+  - When tapped, the client sends `value` as the next user message.
+  - Even if the only option is \"Continue\", provide that option for the user.
+
+Do NOT add extra wrapper text outside the JSON object.
+";
+
+/// A value for `codex exec -c key=value`, which Codex parses as TOML.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TomlValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<TomlValue>),
+    /// An inline table; keys are kept in the given order.
+    Table(Vec<(String, TomlValue)>),
+}
+
+impl TomlValue {
+    pub fn render(&self) -> String {
+        match self {
+            Self::String(s) => toml_string(s),
+            Self::Integer(i) => i.to_string(),
+            Self::Float(f) if f.is_nan() => "nan".to_owned(),
+            Self::Float(f) if f.is_infinite() => if *f > 0.0 { "inf" } else { "-inf" }.to_owned(),
+            // `{:?}` keeps the `.0` TOML needs to read the value as a float.
+            Self::Float(f) => format!("{f:?}"),
+            Self::Boolean(b) => b.to_string(),
+            Self::Array(items) => {
+                let items: Vec<String> = items.iter().map(Self::render).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Self::Table(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{} = {}", toml_key(k), v.render()))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+        }
+    }
+}
+
+impl From<&str> for TomlValue {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_owned())
+    }
+}
+
+impl From<String> for TomlValue {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl From<bool> for TomlValue {
+    fn from(b: bool) -> Self {
+        Self::Boolean(b)
+    }
+}
+
+impl From<i64> for TomlValue {
+    fn from(i: i64) -> Self {
+        Self::Integer(i)
+    }
+}
+
+/// Encodes `s` as a TOML basic string, quotes included.
+pub fn toml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn toml_key(key: &str) -> String {
+    if is_bare_key(key) {
+        key.to_owned()
+    } else {
+        toml_string(key)
+    }
+}
+
+/// `--sandbox` policy for commands the agent runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SandboxMode {
+    ReadOnly,
+    WorkspaceWrite,
+    DangerFullAccess,
+}
+
+impl SandboxMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read-only",
+            Self::WorkspaceWrite => "workspace-write",
+            Self::DangerFullAccess => "danger-full-access",
+        }
+    }
+}
+
+/// When Codex stops to ask before running a command. `codex exec` has no
+/// flag for it, so it is passed as the `approval_policy` config override.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApprovalPolicy {
+    Untrusted,
+    OnFailure,
+    OnRequest,
+    Never,
+}
+
+impl ApprovalPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Untrusted => "untrusted",
+            Self::OnFailure => "on-failure",
+            Self::OnRequest => "on-request",
+            Self::Never => "never",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CodexCommandError {
+    /// Config keys are dotted paths of bare TOML keys.
+    InvalidConfigKey(String),
+    /// Codex splits `--image` values on commas.
+    ImagePathHasComma(String),
+    /// A thread id that is empty or would be read as a flag.
+    InvalidThreadId(String),
+}
+
+impl fmt::Display for CodexCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidConfigKey(key) => write!(f, "invalid config key: {key:?}"),
+            Self::ImagePathHasComma(path) => write!(f, "image path contains a comma: {path}"),
+            Self::InvalidThreadId(id) => write!(f, "invalid thread id: {id:?}"),
+        }
+    }
+}
+
+impl std::error::Error for CodexCommandError {}
+
+/// Builds a `codex exec` invocation that reads its prompt from stdin.
+///
+/// Option values are attached with `=` so a value starting with `-` can
+/// never be read as a flag.
+#[derive(Clone, Debug)]
+pub struct CodexCommand {
+    program: String,
+    config: Vec<(String, TomlValue)>,
+    cd: Option<String>,
+    skip_git_repo_check: bool,
+    json: bool,
+    output_schema: Option<String>,
+    model: Option<String>,
+    profile: Option<String>,
+    sandbox: Option<SandboxMode>,
+    approval: Option<ApprovalPolicy>,
+    images: Vec<String>,
+    resume: Option<String>,
+}
+
+impl Default for CodexCommand {
+    fn default() -> Self {
+        Self {
+            program: "codex".to_owned(),
+            config: Vec::new(),
+            cd: None,
+            skip_git_repo_check: false,
+            json: true,
+            output_schema: None,
+            model: None,
+            profile: None,
+            sandbox: None,
+            approval: None,
+            images: Vec::new(),
+            resume: None,
+        }
+    }
+}
+
+impl CodexCommand {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The executable to run, `codex` by default.
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Adds `-c key=value`. A later override of the same key wins, as in
+    /// Codex itself.
+    pub fn config(mut self, key: impl Into<String>, value: impl Into<TomlValue>) -> Self {
+        self.config.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn cd(mut self, cd: Option<String>) -> Self {
+        self.cd = cd.filter(|d| !d.is_empty());
+        self
+    }
+
+    pub fn skip_git_repo_check(mut self, skip: bool) -> Self {
+        self.skip_git_repo_check = skip;
+        self
+    }
+
+    /// Emit JSONL events (`--json`); on by default.
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    pub fn output_schema(mut self, path: Option<String>) -> Self {
+        self.output_schema = path.filter(|p| !p.is_empty());
+        self
+    }
+
+    pub fn model(mut self, model: Option<String>) -> Self {
+        self.model = model.filter(|m| !m.is_empty());
+        self
+    }
+
+    pub fn profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile.filter(|p| !p.is_empty());
+        self
+    }
+
+    pub fn sandbox(mut self, sandbox: Option<SandboxMode>) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    pub fn approval(mut self, approval: Option<ApprovalPolicy>) -> Self {
+        self.approval = approval;
+        self
+    }
+
+    pub fn image(mut self, path: impl Into<String>) -> Self {
+        self.images.push(path.into());
+        self
+    }
+
+    /// Continue an earlier thread instead of starting a new one.
+    pub fn resume(mut self, thread_id: Option<String>) -> Self {
+        self.resume = thread_id.filter(|t| !t.is_empty());
+        self
+    }
+
+    /// Arguments after the program name.
+    pub fn args(&self) -> Result<Vec<String>, CodexCommandError> {
+        let mut args = vec!["exec".to_owned()];
+        let approval = self
+            .approval
+            .map(|a| ("approval_policy".to_owned(), TomlValue::from(a.as_str())));
+        for (key, value) in self.config.iter().chain(approval.iter()) {
+            if !key.split('.').all(is_bare_key) {
+                return Err(CodexCommandError::InvalidConfigKey(key.clone()));
+            }
+            args.push("-c".to_owned());
+            args.push(format!("{key}={}", value.render()));
+        }
+        if let Some(cd) = &self.cd {
+            args.push(format!("--cd={cd}"));
+        }
+        if self.skip_git_repo_check {
+            args.push("--skip-git-repo-check".to_owned());
+        }
+        if self.json {
+            args.push("--json".to_owned());
+        }
+        if let Some(schema) = &self.output_schema {
+            args.push(format!("--output-schema={schema}"));
+        }
+        if let Some(model) = &self.model {
+            args.push(format!("--model={model}"));
+        }
+        if let Some(profile) = &self.profile {
+            args.push(format!("--profile={profile}"));
+        }
+        if let Some(sandbox) = self.sandbox {
+            args.push(format!("--sandbox={}", sandbox.as_str()));
+        }
+        for image in &self.images {
+            if image.contains(',') {
+                return Err(CodexCommandError::ImagePathHasComma(image.clone()));
+            }
+            args.push(format!("--image={image}"));
+        }
+        if let Some(thread_id) = &self.resume {
+            if thread_id.starts_with('-') {
+                return Err(CodexCommandError::InvalidThreadId(thread_id.clone()));
+            }
+            args.push("resume".to_owned());
+            args.push(thread_id.clone());
+        }
+        // The prompt comes from stdin.
+        args.push("-".to_owned());
+        Ok(args)
+    }
+
+    /// The program followed by [`CodexCommand::args`].
+    pub fn argv(&self) -> Result<Vec<String>, CodexCommandError> {
+        let mut argv = vec![self.program.clone()];
+        argv.extend(self.args()?);
+        Ok(argv)
+    }
+
+    /// The command as one POSIX shell string, for running over SSH.
+    pub fn shell_string(&self) -> Result<String, CodexCommandError> {
+        Ok(shell_join(&self.argv()?))
+    }
+}
+
+/// Joins words into a shell command line that splits back into exactly
+/// those words.
+pub fn shell_join<S: AsRef<str>>(words: &[S]) -> String {
+    words
+        .iter()
+        .map(|w| sh_quote(w.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The prompt as Codex should read it from stdin: always newline-terminated.
+pub fn prompt_stdin(prompt: &str) -> String {
+    if prompt.ends_with('\n') {
+        prompt.to_owned()
+    } else {
+        format!("{prompt}\n")
+    }
+}
@@ -0,0 +1,332 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lines::LineBuffer;
+
+/// Token counts reported with `turn.completed`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub cached_input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// One event of `codex exec --json` output.
+///
+/// Codex adds event and item types between releases, so anything this model
+/// does not know is kept as raw JSON instead of failing the stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CodexEvent {
+    ThreadStarted {
+        thread_id: String,
+    },
+    TurnStarted,
+    TurnCompleted {
+        usage: Option<Usage>,
+    },
+    TurnFailed {
+        message: String,
+    },
+    ItemStarted(CodexItem),
+    ItemUpdated(CodexItem),
+    ItemCompleted(CodexItem),
+    /// A stream-level error; Codex may still recover and finish the turn.
+    Error {
+        message: String,
+    },
+    Unknown {
+        event_type: String,
+        raw: Value,
+    },
+}
+
+impl CodexEvent {
+    /// Parses one decoded JSONL object. Returns `None` for objects without a
+    /// `type`, which are not events at all.
+    pub fn from_value(value: Value) -> Option<Self> {
+        let event_type = value.get("type")?.as_str()?.to_owned();
+        let event = match event_type.as_str() {
+            "thread.started" => match str_field(&value, "thread_id") {
+                Some(thread_id) => Self::ThreadStarted { thread_id },
+                None => Self::Unknown {
+                    event_type,
+                    raw: value,
+                },
+            },
+            "turn.started" => Self::TurnStarted,
+            "turn.completed" => Self::TurnCompleted {
+                usage: value.get("usage").and_then(|u| Usage::deserialize(u).ok()),
+            },
+            "turn.failed" => Self::TurnFailed {
+                message: error_message(value.get("error")).unwrap_or_default(),
+            },
+            "item.started" | "item.updated" | "item.completed" => {
+                let Some(item) = value.get("item").and_then(CodexItem::from_value) else {
+                    return Some(Self::Unknown {
+                        event_type,
+                        raw: value,
+                    });
+                };
+                match event_type.as_str() {
+                    "item.started" => Self::ItemStarted(item),
+                    "item.updated" => Self::ItemUpdated(item),
+                    _ => Self::ItemCompleted(item),
+                }
+            }
+            "error" => Self::Error {
+                message: str_field(&value, "message").unwrap_or_default(),
+            },
+            _ => Self::Unknown {
+                event_type,
+                raw: value,
+            },
+        };
+        Some(event)
+    }
+
+    /// The item carried by `item.*` events.
+    pub fn item(&self) -> Option<&CodexItem> {
+        match self {
+            Self::ItemStarted(item) | Self::ItemUpdated(item) | Self::ItemCompleted(item) => {
+                Some(item)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodexItem {
+    pub id: String,
+    pub details: ItemDetails,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ItemDetails {
+    AgentMessage { text: String },
+    Reasoning { text: String },
+    CommandExecution(CommandExecution),
+    FileChange(FileChange),
+    McpToolCall(McpToolCall),
+    WebSearch { query: String },
+    TodoList { items: Vec<TodoItem> },
+    Error { message: String },
+    Unknown { item_type: String, raw: Value },
+}
+
+impl CodexItem {
+    fn from_value(value: &Value) -> Option<Self> {
+        // Older releases called the field `item_type` and the final
+        // reply an `assistant_message`.
+        let item_type = value
+            .get("type")
+            .or_else(|| value.get("item_type"))?
+            .as_str()?
+            .to_owned();
+        let id = match value.get("id") {
+            Some(Value::String(id)) => id.clone(),
+            Some(Value::Number(id)) => id.to_string(),
+            _ => String::new(),
+        };
+        let known = match item_type.as_str() {
+            "agent_message" | "assistant_message" => {
+                str_field(value, "text").map(|text| ItemDetails::AgentMessage { text })
+            }
+            "reasoning" => str_field(value, "text").map(|text| ItemDetails::Reasoning { text }),
+            "command_execution" => CommandExecution::deserialize(value)
+                .ok()
+                .map(ItemDetails::CommandExecution),
+            "file_change" => FileChange::deserialize(value)
+                .ok()
+                .map(ItemDetails::FileChange),
+            "mcp_tool_call" => McpToolCall::deserialize(value)
+                .ok()
+                .map(ItemDetails::McpToolCall),
+            "web_search" => Some(ItemDetails::WebSearch {
+                query: str_field(value, "query").unwrap_or_default(),
+            }),
+            "todo_list" => TodoList::deserialize(value)
+                .ok()
+                .map(|list| ItemDetails::TodoList { items: list.items }),
+            "error" => Some(ItemDetails::Error {
+                message: str_field(value, "message").unwrap_or_default(),
+            }),
+            _ => None,
+        };
+        let details = known.unwrap_or_else(|| ItemDetails::Unknown {
+            item_type,
+            raw: value.clone(),
+        });
+        Some(Self { id, details })
+    }
+
+    /// Text of an agent message; with `--output-schema` this is the JSON
+    /// structured response.
+    pub fn agent_text(&self) -> Option<&str> {
+        match &self.details {
+            ItemDetails::AgentMessage { text } => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Progress of a command, patch or tool call. Missing or unrecognised
+/// statuses read as `Unknown`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemStatus {
+    InProgress,
+    Completed,
+    Failed,
+    Declined,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct CommandExecution {
+    #[serde(default)]
+    pub command: String,
+    #[serde(default)]
+    pub aggregated_output: String,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub status: ItemStatus,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Add,
+    Delete,
+    Update,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct FileUpdate {
+    pub path: String,
+    #[serde(default)]
+    pub kind: ChangeKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct FileChange {
+    #[serde(default)]
+    pub changes: Vec<FileUpdate>,
+    #[serde(default)]
+    pub status: ItemStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct McpToolCall {
+    #[serde(default)]
+    pub server: String,
+    #[serde(default)]
+    pub tool: String,
+    #[serde(default)]
+    pub arguments: Value,
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<Value>,
+    #[serde(default)]
+    pub status: ItemStatus,
+}
+
+impl McpToolCall {
+    /// The failure message, whether sent as `{"message": ...}` or a string.
+    pub fn error_message(&self) -> Option<String> {
+        error_message(self.error.as_ref())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct TodoItem {
+    pub text: String,
+    #[serde(default)]
+    pub completed: bool,
+}
+
+#[derive(Deserialize)]
+struct TodoList {
+    #[serde(default)]
+    items: Vec<TodoItem>,
+}
+
+fn str_field(value: &Value, key: &str) -> Option<String> {
+    value.get(key)?.as_str().map(str::to_owned)
+}
+
+fn error_message(error: Option<&Value>) -> Option<String> {
+    match error? {
+        Value::String(message) => Some(message.clone()),
+        error => str_field(error, "message"),
+    }
+}
+
+/// One line of a Codex JSONL log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CodexLine {
+    Event(CodexEvent),
+    /// Anything that is not a JSON event, such as warnings Codex prints
+    /// before the stream starts or stderr captured into the same log.
+    Text(String),
+}
+
+impl CodexLine {
+    /// Classifies one line; blank lines yield `None`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        let event = serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(CodexEvent::from_value);
+        Some(match event {
+            Some(event) => Self::Event(event),
+            None => Self::Text(line.to_owned()),
+        })
+    }
+}
+
+/// Turns raw `codex exec --json` output into typed lines as bytes arrive.
+#[derive(Debug, Default)]
+pub struct CodexEventStream {
+    lines: LineBuffer,
+}
+
+impl CodexEventStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<CodexLine> {
+        self.lines
+            .push(bytes)
+            .into_iter()
+            .filter_map(|chunk| CodexLine::parse(&chunk.text))
+            .collect()
+    }
+
+    /// Parses a last line that had no trailing newline.
+    pub fn finish(&mut self) -> Option<CodexLine> {
+        self.lines
+            .finish()
+            .and_then(|chunk| CodexLine::parse(&chunk.text))
+    }
+}
+
+/// Parses a complete log.
+pub fn parse_log(bytes: &[u8]) -> Vec<CodexLine> {
+    let mut stream = CodexEventStream::new();
+    let mut lines = stream.push(bytes);
+    lines.extend(stream.finish());
+    lines
+}
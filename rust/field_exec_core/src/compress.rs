@@ -0,0 +1,63 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+/// First stdout line of a wrapped command when the remote had `gzip`.
+const GZIP_MARKER: &[u8] = b"field_exec:gzip\n";
+/// First stdout line when it did not and the output is uncompressed.
+const RAW_MARKER: &[u8] = b"field_exec:raw\n";
+
+/// What compression did for one command's stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// The remote had `gzip`; false means the output came back as-is.
+    pub compressed: bool,
+    /// Bytes that crossed the connection.
+    pub wire_bytes: u64,
+    /// Bytes after decompression.
+    pub raw_bytes: u64,
+}
+
+/// Wraps `command` so its stdout is gzip-compressed when the remote has
+/// `gzip`, keeping the command's own exit status. Pass the output through
+/// [`gunzip_output`] to undo it.
+pub fn gzip_command(command: &str) -> String {
+    // The exit status is carried out of the pipeline through a temp file;
+    // the subshell keeps an `exit` in `command` from skipping that step.
+    [
+        "if command -v gzip >/dev/null 2>&1; then".to_owned(),
+        "  rcf=$(mktemp) || exit 1".to_owned(),
+        r"  printf 'field_exec:gzip\n'".to_owned(),
+        format!("  {{ (\n{command}\n); echo $? > \"$rcf\"; }} | gzip -c"),
+        r#"  rc=$(cat "$rcf"); rm -f "$rcf"; exit "${rc:-1}""#.to_owned(),
+        "fi".to_owned(),
+        r"printf 'field_exec:raw\n'".to_owned(),
+        format!("(\n{command}\n)"),
+    ]
+    .join("\n")
+}
+
+/// Strips the marker added by [`gzip_command`] and decompresses stdout.
+pub fn gunzip_output(stdout: &[u8]) -> Result<(Vec<u8>, CompressionStats), String> {
+    if let Some(raw) = stdout.strip_prefix(RAW_MARKER) {
+        let stats = CompressionStats {
+            compressed: false,
+            wire_bytes: raw.len() as u64,
+            raw_bytes: raw.len() as u64,
+        };
+        return Ok((raw.to_vec(), stats));
+    }
+    let Some(gz) = stdout.strip_prefix(GZIP_MARKER) else {
+        return Err("compressed output is missing its marker".to_owned());
+    };
+    let mut raw = Vec::new();
+    GzDecoder::new(gz)
+        .read_to_end(&mut raw)
+        .map_err(|e| format!("gzip decode failed: {e}"))?;
+    let stats = CompressionStats {
+        compressed: true,
+        wire_bytes: gz.len() as u64,
+        raw_bytes: raw.len() as u64,
+    };
+    Ok((raw, stats))
+}
@@ -0,0 +1,649 @@
+use std::fmt;
+
+use crate::codex_command::{
+    CodexCommand, CodexCommandError, DEFAULT_DEVELOPER_INSTRUCTIONS, prompt_stdin, shell_join,
+};
+use crate::output_schema::SCHEMA_PATH;
+use crate::remote_fs::RemoteFsError;
+use crate::shell::sh_quote;
+
+/// The one tmux session every job on a host runs in, one window per tab.
+pub const TMUX_SESSION: &str = "__field_exec__";
+/// Per-tab logs, job ids and prompts, relative to the project directory.
+pub const SESSIONS_DIR: &str = ".field_exec/sessions";
+/// Sourced before Codex starts, if present. Aliases do not expand in a
+/// non-interactive shell, so it should define functions or variables.
+pub const ENV_SH: &str = ".field_exec/env.sh";
+/// Appended to [`DEFAULT_DEVELOPER_INSTRUCTIONS`] for every run.
+pub const DEVELOPER_INSTRUCTIONS_PATH: &str = ".field_exec/developer_instructions.txt";
+/// Log line type reporting that `.gitignore` was updated for `.field_exec/`.
+pub const GITIGNORE_BOOTSTRAP_TYPE: &str = "server.gitignore_bootstrap";
+/// Log line type the launcher appends before every run, so each run starts
+/// a new turn even without a `log_preamble`.
+pub const JOB_STARTED_TYPE: &str = "server.job_started";
+
+/// Seconds between two writes of a job's heartbeat file.
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 15;
+
+/// Exit status when the project directory does not exist.
+const EXIT_NO_PROJECT: u32 = 2;
+/// Exit status when tmux is needed but not installed.
+const EXIT_NO_TMUX: u32 = 127;
+
+/// Where tools are commonly installed outside a login shell's `PATH`.
+const PATH_LINE: &str =
+    r#"PATH="/opt/homebrew/bin:/usr/local/bin:$HOME/.local/bin:$PATH"; export PATH"#;
+
+/// How a launched job can be found again: a tmux window or a plain process.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RemoteJobId {
+    Tmux { session: String, window: String },
+    Pid(u32),
+}
+
+impl RemoteJobId {
+    /// Parses `tmux:<session>:<window>` or `pid:<pid>`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if let Some(rest) = s.strip_prefix("tmux:") {
+            let (session, window) = rest.split_once(':')?;
+            let (session, window) = (session.trim(), window.trim());
+            if session.is_empty() || window.is_empty() || window.contains(':') {
+                return None;
+            }
+            return Some(Self::Tmux {
+                session: session.to_owned(),
+                window: window.to_owned(),
+            });
+        }
+        let pid = s.strip_prefix("pid:")?.trim().parse().ok()?;
+        (pid > 1).then_some(Self::Pid(pid))
+    }
+}
+
+impl fmt::Display for RemoteJobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tmux { session, window } => write!(f, "tmux:{session}:{window}"),
+            Self::Pid(pid) => write!(f, "pid:{pid}"),
+        }
+    }
+}
+
+fn fnv1a64_hex(s: &str) -> String {
+    let hash = s.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// The tmux window for a tab: stable across restarts and namespaced by
+/// target and project, so several projects can share [`TMUX_SESSION`].
+pub fn tmux_window_name(target_key: &str, project_dir: &str, tab_id: &str) -> String {
+    let project_key = fnv1a64_hex(&format!("{target_key}|{project_dir}"));
+    let tab: String = tab_id.chars().filter(|&c| c != '-').take(10).collect();
+    format!("cr_{}_{tab}", &project_key[..6])
+}
+
+/// Absolute paths of one tab's files under [`SESSIONS_DIR`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionFiles {
+    /// Codex's JSONL output.
+    pub log: String,
+    pub stderr_log: String,
+    /// The current [`RemoteJobId`].
+    pub job: String,
+    /// Only written for `pid:` jobs.
+    pub pid: String,
+    pub prompt: String,
+    /// The [`JobLaunch::run_script`] the job runs.
+    pub run: String,
+    /// The job's [`Heartbeat`].
+    pub heartbeat: String,
+}
+
+impl SessionFiles {
+    pub fn new(project_dir: &str, tab_id: &str) -> Self {
+        let dir = format!("{}/{SESSIONS_DIR}", project_dir.trim_end_matches('/'));
+        let file = |ext: &str| format!("{dir}/{tab_id}.{ext}");
+        Self {
+            log: file("log"),
+            stderr_log: file("stderr.log"),
+            job: file("job"),
+            pid: file("pid"),
+            prompt: file("prompt"),
+            run: file("sh"),
+            heartbeat: file("hb"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobError {
+    /// Tab ids name files, so they are limited to letters, digits, `-` and `_`.
+    InvalidTabId(String),
+    Command(CodexCommandError),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTabId(id) => write!(f, "invalid tab id: {id:?}"),
+            Self::Command(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+impl From<CodexCommandError> for JobError {
+    fn from(e: CodexCommandError) -> Self {
+        Self::Command(e)
+    }
+}
+
+/// Starts `codex exec` for one tab so that it outlives the SSH connection.
+///
+/// The launch script saves the prompt from its stdin (see
+/// [`JobLaunch::input`]), writes the output schema, if given, and the
+/// [`JobLaunch::run_script`] under `.field_exec/`, then runs the job in the
+/// tab's tmux window, or under `nohup` when the host has no tmux, and prints
+/// `FIELD_EXEC_JOB=<id>`. The job itself sources [`ENV_SH`], merges the
+/// project's developer instructions into the defaults, makes sure git
+/// ignores `.field_exec/` and appends Codex's output to the tab's log.
+#[derive(Clone, Debug)]
+pub struct JobLaunch {
+    project_dir: String,
+    tab_id: String,
+    prompt: String,
+    window: String,
+    command: CodexCommand,
+    output_schema: Option<String>,
+    base_instructions: String,
+    bootstrap_gitignore: bool,
+    use_tmux: bool,
+    log_preamble: Option<String>,
+}
+
+impl JobLaunch {
+    pub fn new(
+        project_dir: impl Into<String>,
+        tab_id: impl Into<String>,
+        prompt: impl Into<String>,
+    ) -> Self {
+        let project_dir = project_dir.into();
+        let tab_id = tab_id.into();
+        Self {
+            window: tmux_window_name("", &project_dir, &tab_id),
+            project_dir,
+            tab_id,
+            prompt: prompt.into(),
+            command: CodexCommand::new(),
+            output_schema: None,
+            base_instructions: DEFAULT_DEVELOPER_INSTRUCTIONS.to_owned(),
+            bootstrap_gitignore: true,
+            use_tmux: true,
+            log_preamble: None,
+        }
+    }
+
+    /// Names the tmux window after [`tmux_window_name`] for `target_key`.
+    pub fn target_key(mut self, target_key: &str) -> Self {
+        self.window = tmux_window_name(target_key, &self.project_dir, &self.tab_id);
+        self
+    }
+
+    /// The Codex invocation; `--cd` and `--output-schema` are set by the
+    /// launcher.
+    pub fn command(mut self, command: CodexCommand) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Schema JSON to write to [`SCHEMA_PATH`] and pass to Codex.
+    pub fn output_schema(mut self, schema_json: Option<String>) -> Self {
+        self.output_schema = schema_json;
+        self
+    }
+
+    pub fn base_instructions(mut self, instructions: impl Into<String>) -> Self {
+        self.base_instructions = instructions.into();
+        self
+    }
+
+    /// Add `**/.field_exec/` to `.gitignore` before the run; on by default.
+    pub fn bootstrap_gitignore(mut self, bootstrap: bool) -> Self {
+        self.bootstrap_gitignore = bootstrap;
+        self
+    }
+
+    /// Prefer tmux when installed; on by default.
+    pub fn use_tmux(mut self, use_tmux: bool) -> Self {
+        self.use_tmux = use_tmux;
+        self
+    }
+
+    /// A line appended to the log before the job starts, such as the app's
+    /// record of the user message.
+    pub fn log_preamble(mut self, line: Option<String>) -> Self {
+        self.log_preamble = line.filter(|l| !l.is_empty());
+        self
+    }
+
+    pub fn files(&self) -> SessionFiles {
+        SessionFiles::new(&self.project_dir, &self.tab_id)
+    }
+
+    fn schema_path(&self) -> String {
+        format!("{}/{SCHEMA_PATH}", self.project_dir.trim_end_matches('/'))
+    }
+
+    /// The script the job runs, inside tmux or under `nohup`.
+    pub fn run_script(&self) -> Result<String, JobError> {
+        let files = self.files();
+        let mut command = self.command.clone().cd(Some(self.project_dir.clone()));
+        if self.output_schema.is_some() {
+            command = command.output_schema(Some(self.schema_path()));
+        }
+        // `args()` starts with `exec`; the developer instructions go right
+        // after it so later `-c` overrides still win.
+        let args = command.args()?;
+        let tail = shell_join(args.get(1..).unwrap_or_default());
+        let base = self
+            .base_instructions
+            .replace('\r', "")
+            .replace("'''", "''\u{2019}");
+
+        let mut lines = vec![
+            PATH_LINE.to_owned(),
+            format!("PROJECT={}", sh_quote(&self.project_dir)),
+            r#"cd "$PROJECT" 2>/dev/null || { echo "Failed to cd into $PROJECT" >&2; exit 2; }"#
+                .to_owned(),
+            format!("LOG={}", sh_quote(&files.log)),
+            format!("ERR={}", sh_quote(&files.stderr_log)),
+            r#"exec >> "$LOG" 2>> "$ERR""#.to_owned(),
+            format!("HB={}", sh_quote(&files.heartbeat)),
+            "HB_PID=$$".to_owned(),
+            "HB_STARTED=$(date +%s)".to_owned(),
+            format!("HB_BOOT=$({})", boot_id_command()),
+            // Output counts as any growth of the log, checked every beat.
+            "(".to_owned(),
+            "  size=; out=$HB_STARTED".to_owned(),
+            r#"  while kill -0 "$HB_PID" 2>/dev/null; do"#.to_owned(),
+            "    now=$(date +%s)".to_owned(),
+            r#"    new=$(wc -c < "$LOG" 2>/dev/null | tr -d ' ')"#.to_owned(),
+            r#"    if [ "$new" != "$size" ]; then size=$new; out=$now; fi"#.to_owned(),
+            r#"    printf 'pid=%s\nstarted=%s\nboot_id=%s\noutput=%s\nbeat=%s\n' "$HB_PID" "$HB_STARTED" "$HB_BOOT" "$out" "$now" > "$HB.tmp" && mv -f "$HB.tmp" "$HB""#
+                .to_owned(),
+            format!("    sleep {HEARTBEAT_INTERVAL_SECS}"),
+            "  done".to_owned(),
+            ") > /dev/null 2>&1 < /dev/null &".to_owned(),
+            "HB_LOOP=$!".to_owned(),
+            format!("if [ -f {ENV_SH} ]; then . ./{ENV_SH}; fi"),
+            r#"if [ -z "${CODEX_BIN:-}" ]; then CODEX_BIN="$(command -v codex 2>/dev/null || true)"; fi"#
+                .to_owned(),
+            r#"if [ -z "$CODEX_BIN" ]; then"#.to_owned(),
+            r#"  for p in /opt/homebrew/bin/codex /usr/local/bin/codex "$HOME/.local/bin/codex" /usr/bin/codex; do"#
+                .to_owned(),
+            r#"    if [ -x "$p" ]; then CODEX_BIN="$p"; break; fi"#.to_owned(),
+            "  done".to_owned(),
+            "fi".to_owned(),
+            r#"if [ -z "$CODEX_BIN" ]; then echo "codex not found" >&2; exit 127; fi"#.to_owned(),
+            // Both parts go into a TOML multi-line literal string, which
+            // cannot contain its own delimiter.
+            format!("BASE_DEV={}", sh_quote(&base)),
+            format!(r#"USER_DEV="$(cat {DEVELOPER_INSTRUCTIONS_PATH} 2>/dev/null || true)""#),
+            r#"APOS="'"; DELIM="$APOS$APOS$APOS""#.to_owned(),
+            r#"USER_DEV="$(printf %s "$USER_DEV" | tr -d '\r' | sed "s/$DELIM/$APOS$APOS’/g")""#
+                .to_owned(),
+            format!(
+                r#"DEV_COMBINED="$(printf '%s\n\n# Project developer instructions ({DEVELOPER_INSTRUCTIONS_PATH})\n%s\n' "$BASE_DEV" "$USER_DEV")""#
+            ),
+            r#"DEV_TOML="$(printf '%s\n%s\n%s\n' "$DELIM" "$DEV_COMBINED" "$DELIM")""#.to_owned(),
+        ];
+        if self.bootstrap_gitignore {
+            let pattern = sh_quote("**/.field_exec/");
+            let event = |status: &str| {
+                sh_quote(&format!(
+                    r#"{{"type":"{GITIGNORE_BOOTSTRAP_TYPE}","status":"{status}"}}"#
+                ))
+            };
+            lines.extend([
+                "if command -v git >/dev/null 2>&1 && git rev-parse --is-inside-work-tree >/dev/null 2>&1; then"
+                    .to_owned(),
+                // `.field_exec/` itself does not count as work in progress.
+                r#"  before_status="$(git status --porcelain 2>/dev/null | grep -v '\.field_exec/' || true)""#
+                    .to_owned(),
+                "  changed=0".to_owned(),
+                "  if [ ! -f .gitignore ]; then : > .gitignore; changed=1; fi".to_owned(),
+                format!("  if ! grep -qxF {pattern} .gitignore 2>/dev/null; then"),
+                r#"    if [ -s .gitignore ] && [ -n "$(tail -c 1 .gitignore)" ]; then echo >> .gitignore; fi"#
+                    .to_owned(),
+                format!("    printf '%s\\n' {pattern} >> .gitignore; changed=1"),
+                "  fi".to_owned(),
+                // Only commit the change into a clean tree, so it never
+                // sweeps up someone's work in progress.
+                r#"  if [ "$changed" -eq 1 ] && [ -z "$before_status" ] && git add .gitignore >/dev/null 2>&1 && git commit -m 'chore: ignore .field_exec' >/dev/null 2>&1; then"#
+                    .to_owned(),
+                format!("    printf '%s\\n' {}", event("committed")),
+                r#"  elif [ "$changed" -eq 1 ]; then"#.to_owned(),
+                format!("    printf '%s\\n' {}", event("updated")),
+                "  fi".to_owned(),
+                "fi".to_owned(),
+            ]);
+        }
+        lines.extend([
+            format!(
+                r#""$CODEX_BIN" exec -c "developer_instructions=$DEV_TOML" {tail} < {}"#,
+                sh_quote(&files.prompt)
+            ),
+            "status=$?".to_owned(),
+            r#"kill "$HB_LOOP" 2>/dev/null"#.to_owned(),
+            // Codex may end its last event without a newline, which would
+            // leave a line-based tail waiting for it.
+            r#"printf '\n'"#.to_owned(),
+            r#"exit "$status""#.to_owned(),
+        ]);
+        Ok(lines.join("\n"))
+    }
+
+    /// What [`JobLaunch::script`] expects on stdin: the prompt. Prompts can
+    /// be far larger than a single command-line argument may be.
+    pub fn input(&self) -> String {
+        prompt_stdin(&self.prompt)
+    }
+
+    /// The script to run over SSH with [`JobLaunch::input`] as its stdin;
+    /// prints `FIELD_EXEC_JOB=<id>`.
+    pub fn script(&self) -> Result<String, JobError> {
+        check_tab_id(&self.tab_id)?;
+        let files = self.files();
+        let run_path = sh_quote(&files.run);
+        let run = format!("sh {run_path}");
+        let mut lines = vec![
+            PATH_LINE.to_owned(),
+            format!(
+                "cd {} 2>/dev/null || {{ echo {} >&2; exit {EXIT_NO_PROJECT}; }}",
+                sh_quote(&self.project_dir),
+                sh_quote(&self.project_dir)
+            ),
+            format!("mkdir -p {SESSIONS_DIR} .field_exec/tmp || exit 1"),
+            format!("touch {DEVELOPER_INSTRUCTIONS_PATH} 2>/dev/null || true"),
+            "umask 077".to_owned(),
+            format!("cat > {} || exit 1", sh_quote(&files.prompt)),
+            // A new file each time: a job still running the previous script
+            // keeps reading the old one.
+            format!(
+                "printf '%s\\n' {} > {run_path}.tmp || exit 1",
+                sh_quote(&self.run_script()?)
+            ),
+            format!("mv -f {run_path}.tmp {run_path} || exit 1"),
+        ];
+        if let Some(schema) = &self.output_schema {
+            let path = sh_quote(&self.schema_path());
+            lines.extend([
+                format!("printf %s {} > {path}.tmp || exit 1", sh_quote(schema)),
+                format!("mv -f {path}.tmp {path} || exit 1"),
+            ]);
+        }
+        if let Some(line) = &self.log_preamble {
+            lines.push(format!(
+                "printf '%s\\n' {} >> {} || exit 1",
+                sh_quote(line),
+                sh_quote(&files.log)
+            ));
+        }
+        lines.push(format!(
+            "printf '%s\\n' {} >> {} || exit 1",
+            sh_quote(&format!(r#"{{"type":"{JOB_STARTED_TYPE}"}}"#)),
+            sh_quote(&files.log)
+        ));
+        // A heartbeat left by the previous run would describe another pid.
+        lines.push(format!(
+            "rm -f {} {} {}",
+            sh_quote(&files.job),
+            sh_quote(&files.pid),
+            sh_quote(&files.heartbeat)
+        ));
+        if self.use_tmux {
+            lines.extend([
+                tmux_lookup(),
+                r#"if [ -n "$TMUX_BIN" ]; then"#.to_owned(),
+                format!("  SESSION={}", sh_quote(TMUX_SESSION)),
+                format!("  WINDOW={}", sh_quote(&self.window)),
+                r#"  if ! "$TMUX_BIN" has-session -t "$SESSION" 2>/dev/null; then"#.to_owned(),
+                format!(r#"    "$TMUX_BIN" new-session -d -s "$SESSION" -n "$WINDOW" {run} || exit 1"#),
+                r#"  elif "$TMUX_BIN" list-windows -t "$SESSION" -F '#W' | grep -qxF "$WINDOW"; then"#
+                    .to_owned(),
+                format!(r#"    "$TMUX_BIN" respawn-window -k -t "$SESSION:$WINDOW" {run} || exit 1"#),
+                "  else".to_owned(),
+                format!(r#"    "$TMUX_BIN" new-window -d -t "$SESSION:" -n "$WINDOW" {run} || exit 1"#),
+                "  fi".to_owned(),
+                r#"  job="tmux:$SESSION:$WINDOW""#.to_owned(),
+                "else".to_owned(),
+            ]);
+        } else {
+            lines.push("if true; then".to_owned());
+        }
+        lines.extend([
+            // With setsid the job leads its own process group, so stopping
+            // it also stops Codex and anything Codex started.
+            "  if command -v setsid >/dev/null 2>&1; then".to_owned(),
+            format!("    setsid nohup {run} >/dev/null 2>&1 < /dev/null &"),
+            "  else".to_owned(),
+            format!("    nohup {run} >/dev/null 2>&1 < /dev/null &"),
+            "  fi".to_owned(),
+            "  pid=$!".to_owned(),
+            format!(r#"  echo "$pid" > {}"#, sh_quote(&files.pid)),
+            r#"  job="pid:$pid""#.to_owned(),
+            "fi".to_owned(),
+            format!(r#"echo "$job" > {}"#, sh_quote(&files.job)),
+            r#"echo "FIELD_EXEC_JOB=$job""#.to_owned(),
+        ]);
+        Ok(lines.join("\n"))
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<RemoteJobId, RemoteFsError> {
+        check_exit(exit_status, stderr)?;
+        stdout
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix("FIELD_EXEC_JOB="))
+            .and_then(RemoteJobId::parse)
+            .ok_or_else(|| RemoteFsError::Malformed("no job id in launch output".to_owned()))
+    }
+}
+
+/// Prints an id that changes on every boot: the kernel's boot id on Linux,
+/// the boot time on macOS and the BSDs.
+pub(crate) fn boot_id_command() -> String {
+    [
+        "{ cat /proc/sys/kernel/random/boot_id 2>/dev/null ||",
+        r#"sysctl -n kern.boottime 2>/dev/null | sed -n 's/^{ sec = \([0-9]*\),.*/\1/p'; }"#,
+        "| head -n 1",
+    ]
+    .join(" ")
+}
+
+/// What a running job's wrapper writes to [`SessionFiles::heartbeat`]
+/// every [`HEARTBEAT_INTERVAL_SECS`], as `key=value` lines. Times are Unix
+/// seconds on the host's clock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// The wrapper's pid; for `pid:` jobs the job's own.
+    pub pid: u32,
+    pub started_at: u64,
+    /// See [`boot_id_command`]; empty when the host offers neither.
+    pub boot_id: String,
+    /// When the log last grew.
+    pub last_output_at: u64,
+    /// When the file was written.
+    pub beat_at: u64,
+}
+
+impl Heartbeat {
+    pub fn parse(text: &str) -> Option<Self> {
+        let field = |key: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                .map(str::trim)
+        };
+        Some(Self {
+            pid: field("pid")?.parse().ok()?,
+            started_at: field("started")?.parse().ok()?,
+            boot_id: field("boot_id").unwrap_or_default().to_owned(),
+            last_output_at: field("output")?.parse().ok()?,
+            beat_at: field("beat")?.parse().ok()?,
+        })
+    }
+}
+
+fn tmux_lookup() -> String {
+    [
+        r#"TMUX_BIN="$(command -v tmux 2>/dev/null || true)""#,
+        r#"if [ -z "$TMUX_BIN" ]; then"#,
+        "  for p in /opt/homebrew/bin/tmux /usr/local/bin/tmux /usr/bin/tmux; do",
+        r#"    if [ -x "$p" ]; then TMUX_BIN="$p"; break; fi"#,
+        "  done",
+        "fi",
+    ]
+    .join("\n")
+}
+
+/// Tab ids become file names under [`SESSIONS_DIR`].
+pub fn check_tab_id(tab_id: &str) -> Result<(), JobError> {
+    if tab_id.is_empty()
+        || !tab_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(JobError::InvalidTabId(tab_id.to_owned()));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_exit(exit_status: u32, stderr: &str) -> Result<(), RemoteFsError> {
+    if exit_status == 0 {
+        return Ok(());
+    }
+    let message = stderr.trim().to_owned();
+    Err(match exit_status {
+        EXIT_NO_PROJECT => RemoteFsError::RootNotFound(message),
+        exit_status => RemoteFsError::Failed {
+            exit_status,
+            message,
+        },
+    })
+}
+
+/// Log line type `stop_script` appends before stopping a running job, so
+/// its status reads as cancelled rather than crashed.
+pub const JOB_CANCELLED_TYPE: &str = "server.job_cancelled";
+
+/// Script that stops a job; stopping one that already ended is not an error.
+///
+/// With the tab's `files`, a running job first gets a [`JOB_CANCELLED_TYPE`]
+/// line in its log, and a `pid:` job is left alone when its heartbeat shows
+/// the pid now belongs to another process.
+pub fn stop_script(job: &RemoteJobId, files: Option<&SessionFiles>) -> String {
+    let mut lines = liveness_lines(job);
+    if let Some(files) = files {
+        if let RemoteJobId::Pid(pid) = job {
+            let heartbeat = sh_quote(&files.heartbeat);
+            lines.extend([
+                format!(r#"if [ "$state" = running ] && [ -f {heartbeat} ]; then"#),
+                format!("  hb_pid=$(sed -n 's/^pid=//p' {heartbeat})"),
+                format!("  hb_boot=$(sed -n 's/^boot_id=//p' {heartbeat})"),
+                format!("  boot=$({})", boot_id_command()),
+                format!(r#"  if [ "$hb_pid" != {pid} ]; then state=exited; fi"#),
+                r#"  if [ -n "$hb_boot" ] && [ -n "$boot" ] && [ "$hb_boot" != "$boot" ]; then state=exited; fi"#
+                    .to_owned(),
+                "fi".to_owned(),
+            ]);
+        }
+        // The leading newline ends any line Codex left unfinished.
+        lines.push(format!(
+            r#"[ "$state" != running ] || printf '\n{{"type":"{JOB_CANCELLED_TYPE}"}}\n' >> {} 2>/dev/null || true"#,
+            sh_quote(&files.log)
+        ));
+    }
+    match job {
+        RemoteJobId::Tmux { session, window } => {
+            lines.push(format!(
+                r#"[ -n "$TMUX_BIN" ] || {{ echo "tmux not found" >&2; exit {EXIT_NO_TMUX}; }}"#
+            ));
+            lines.push(format!(
+                r#""$TMUX_BIN" kill-window -t {} 2>/dev/null || true"#,
+                sh_quote(&format!("{session}:{window}"))
+            ));
+        }
+        // The group exists when the job was started through setsid.
+        RemoteJobId::Pid(pid) => lines.push(format!(
+            r#"[ "$state" != running ] || kill -TERM -{pid} 2>/dev/null || kill -TERM {pid} 2>/dev/null || true"#
+        )),
+    }
+    lines.join("\n")
+}
+
+/// Whether a job's process is still there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobLiveness {
+    Running,
+    Exited,
+    /// The host has no tmux to ask about a `tmux:` job.
+    Unknown,
+}
+
+/// Shell lines that set `$state` to `running`, `exited` or `unknown`.
+pub(crate) fn liveness_lines(job: &RemoteJobId) -> Vec<String> {
+    match job {
+        RemoteJobId::Tmux { session, window } => vec![
+            PATH_LINE.to_owned(),
+            tmux_lookup(),
+            r#"if [ -z "$TMUX_BIN" ]; then"#.to_owned(),
+            "  state=unknown".to_owned(),
+            "else".to_owned(),
+            // A dead pane lingers when the user's tmux sets remain-on-exit.
+            format!(
+                r#"  dead=$("$TMUX_BIN" list-panes -t {} -F '#{{pane_dead}}' 2>/dev/null | head -n 1)"#,
+                sh_quote(&format!("{session}:{window}"))
+            ),
+            r#"  if [ "$dead" = 0 ]; then state=running; else state=exited; fi"#.to_owned(),
+            "fi".to_owned(),
+        ],
+        RemoteJobId::Pid(pid) => vec![format!(
+            "if kill -0 {pid} 2>/dev/null; then state=running; else state=exited; fi"
+        )],
+    }
+}
+
+/// Script that prints `@state running`, `@state exited` or `@state unknown`
+/// for a job.
+pub fn status_script(job: &RemoteJobId) -> String {
+    let mut lines = liveness_lines(job);
+    lines.push(r#"echo "@state $state""#.to_owned());
+    lines.join("\n")
+}
+
+pub fn parse_status(
+    exit_status: u32,
+    stdout: &str,
+    stderr: &str,
+) -> Result<JobLiveness, RemoteFsError> {
+    check_exit(exit_status, stderr)?;
+    let state = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("@state "))
+        .ok_or_else(|| RemoteFsError::Malformed("no job state".to_owned()))?;
+    match state.trim() {
+        "running" => Ok(JobLiveness::Running),
+        "exited" => Ok(JobLiveness::Exited),
+        "unknown" => Ok(JobLiveness::Unknown),
+        other => Err(RemoteFsError::Malformed(format!("job state {other}"))),
+    }
+}
@@ -0,0 +1,350 @@
+use serde_json::Value;
+
+use crate::codex_events::{CodexEvent, CodexLine, parse_log};
+use crate::job::{
+    Heartbeat, JOB_CANCELLED_TYPE, JOB_STARTED_TYPE, JobError, JobLiveness, RemoteJobId,
+    SessionFiles, boot_id_command, check_tab_id, liveness_lines, parse_status,
+};
+use crate::output_schema::{SCHEMA_PATH, base_schema, parse_file_frames};
+use crate::remote_fs::RemoteFsError;
+use crate::shell::sh_quote;
+use crate::structured_response::{ResponseCheck, check_response};
+
+/// Log line type the app appends before each run of a tab.
+pub const USER_MESSAGE_TYPE: &str = "client.user_message";
+/// How much of the end of a session log is read to classify a job.
+pub const LOG_TAIL_BYTES: u64 = 256 * 1024;
+/// How much of the end of the stderr log is read for a crash message.
+const STDERR_TAIL_BYTES: u64 = 4 * 1024;
+const SCHEMA_MAX_BYTES: u64 = 1024 * 1024;
+const HEARTBEAT_MAX_BYTES: u64 = 4 * 1024;
+/// A running job whose log has not grown for this long counts as hung.
+pub const DEFAULT_HANG_AFTER_SECS: u64 = 20 * 60;
+
+/// How a job's latest turn went, as far as the host can tell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobOutcome {
+    Running,
+    Completed,
+    /// Codex reported `turn.failed`.
+    Failed {
+        message: String,
+    },
+    /// The job still runs but its log has not grown for `idle_secs`.
+    Hung {
+        idle_secs: u64,
+    },
+    /// The job was stopped through [`stop_script`](crate::job::stop_script).
+    Cancelled,
+    /// The job is gone without ending its turn. `message` is the last error
+    /// Codex reported, or else the last line of its stderr.
+    Crashed {
+        message: Option<String>,
+    },
+    /// The host cannot say whether the job runs and the log has no ending.
+    Unknown,
+}
+
+/// How a turn ended in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TurnEnd {
+    Completed,
+    Failed { message: String },
+    Cancelled,
+}
+
+/// What a session log says about its latest turn.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TurnLog {
+    /// The last thread the log mentions, kept across turns.
+    pub thread_id: Option<String>,
+    pub end: Option<TurnEnd>,
+    /// Text of the turn's last completed agent message.
+    pub final_message: Option<String>,
+    /// The turn's last `error` event.
+    pub last_error: Option<String>,
+}
+
+impl TurnLog {
+    /// Follows the log in order. The app's user message, the launcher's run
+    /// marker, `thread.started` and `turn.started` each begin a new turn, so
+    /// endings of earlier runs in the same log are forgotten.
+    pub fn from_lines(lines: &[CodexLine]) -> Self {
+        let mut log = Self::default();
+        for line in lines {
+            let CodexLine::Event(event) = line else {
+                continue;
+            };
+            match event {
+                CodexEvent::ThreadStarted { thread_id } => {
+                    log.start_turn();
+                    log.thread_id = Some(thread_id.clone());
+                }
+                CodexEvent::TurnStarted => log.start_turn(),
+                CodexEvent::TurnCompleted { .. } => log.end = Some(TurnEnd::Completed),
+                CodexEvent::TurnFailed { message } => {
+                    log.end = Some(TurnEnd::Failed {
+                        message: message.clone(),
+                    });
+                }
+                CodexEvent::ItemCompleted(item) => {
+                    if let Some(text) = item.agent_text() {
+                        log.final_message = Some(text.to_owned());
+                    }
+                }
+                CodexEvent::Error { message } => log.last_error = Some(message.clone()),
+                CodexEvent::Unknown { event_type, .. }
+                    if event_type == USER_MESSAGE_TYPE || event_type == JOB_STARTED_TYPE =>
+                {
+                    log.start_turn();
+                }
+                // Stopping races with the turn ending; an ending wins.
+                CodexEvent::Unknown { event_type, .. } if event_type == JOB_CANCELLED_TYPE => {
+                    log.end.get_or_insert(TurnEnd::Cancelled);
+                }
+                _ => {}
+            }
+        }
+        log
+    }
+
+    fn start_turn(&mut self) {
+        self.end = None;
+        self.final_message = None;
+        self.last_error = None;
+    }
+
+    /// Combines the log with whether the job's process is still there.
+    /// `stderr_tail` supplies the crash message when Codex logged no error.
+    pub fn outcome(&self, liveness: JobLiveness, stderr_tail: &str) -> JobOutcome {
+        let ended = self.end.clone().map(|end| match end {
+            TurnEnd::Completed => JobOutcome::Completed,
+            TurnEnd::Failed { message } => JobOutcome::Failed { message },
+            TurnEnd::Cancelled => JobOutcome::Cancelled,
+        });
+        match liveness {
+            JobLiveness::Running => JobOutcome::Running,
+            JobLiveness::Exited => ended.unwrap_or_else(|| JobOutcome::Crashed {
+                message: self.last_error.clone().or_else(|| {
+                    stderr_tail
+                        .lines()
+                        .map(str::trim)
+                        .rfind(|line| !line.is_empty())
+                        .map(str::to_owned)
+                }),
+            }),
+            JobLiveness::Unknown => ended.unwrap_or(JobOutcome::Unknown),
+        }
+    }
+}
+
+/// Something the heartbeat shows that the process table does not.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeartbeatAlarm {
+    /// The host booted since the job started, so the job is gone.
+    Rebooted,
+    /// The job's pid now belongs to another process.
+    PidReused,
+    /// The job runs but its log has not grown for `idle_secs`.
+    Hung { idle_secs: u64 },
+}
+
+impl HeartbeatAlarm {
+    /// Compares a heartbeat with the host's current boot id and clock.
+    pub fn check(
+        heartbeat: &Heartbeat,
+        job: &RemoteJobId,
+        liveness: JobLiveness,
+        boot_id: &str,
+        now: u64,
+        hang_after_secs: u64,
+    ) -> Option<Self> {
+        if !heartbeat.boot_id.is_empty() && !boot_id.is_empty() && heartbeat.boot_id != boot_id {
+            return Some(Self::Rebooted);
+        }
+        if liveness != JobLiveness::Running {
+            return None;
+        }
+        if let RemoteJobId::Pid(pid) = job
+            && heartbeat.pid != *pid
+        {
+            return Some(Self::PidReused);
+        }
+        let idle_secs = now.saturating_sub(heartbeat.last_output_at);
+        (idle_secs >= hang_after_secs).then_some(Self::Hung { idle_secs })
+    }
+}
+
+/// A job's liveness and the outcome of its latest turn.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobStatus {
+    /// Whether the job runs, after the heartbeat had its say: a reused pid
+    /// or a reboot reads as exited.
+    pub liveness: JobLiveness,
+    pub outcome: JobOutcome,
+    pub heartbeat: Option<Heartbeat>,
+    pub alarm: Option<HeartbeatAlarm>,
+    /// Seconds since the log last grew, when the heartbeat tells.
+    pub idle_secs: Option<u64>,
+    pub thread_id: Option<String>,
+    /// The agent's final message; only set for a completed turn.
+    pub final_message: Option<String>,
+    /// `final_message` checked against the project's output schema, or the
+    /// base schema when none was written.
+    pub response: Option<ResponseCheck>,
+}
+
+/// Checks whether a job still runs and reads its tab's heartbeat and the
+/// tail of its log, stderr log and output schema to tell how its turn went.
+/// The thread id comes from the log's last `thread.started` line, which a
+/// long turn can push out of the tail.
+///
+/// The script prints `@state <liveness>`, `@now <unix time>` and
+/// `@boot <boot id>`, then each file that exists as `@file <bytes> <name>`
+/// followed by its raw contents.
+#[derive(Clone, Debug)]
+pub struct JobStatusCheck {
+    job: RemoteJobId,
+    tab_id: String,
+    files: SessionFiles,
+    schema_path: String,
+    hang_after_secs: u64,
+}
+
+impl JobStatusCheck {
+    pub fn new(job: RemoteJobId, project_dir: &str, tab_id: &str) -> Self {
+        Self {
+            job,
+            tab_id: tab_id.to_owned(),
+            files: SessionFiles::new(project_dir, tab_id),
+            schema_path: format!("{}/{SCHEMA_PATH}", project_dir.trim_end_matches('/')),
+            hang_after_secs: DEFAULT_HANG_AFTER_SECS,
+        }
+    }
+
+    /// How long a running job may go without output before it counts as
+    /// hung; [`DEFAULT_HANG_AFTER_SECS`] by default.
+    pub fn hang_after_secs(mut self, secs: u64) -> Self {
+        self.hang_after_secs = secs;
+        self
+    }
+
+    pub fn script(&self) -> Result<String, JobError> {
+        check_tab_id(&self.tab_id)?;
+        let mut lines = liveness_lines(&self.job);
+        lines.extend([
+            r#"echo "@state $state""#.to_owned(),
+            r#"echo "@now $(date +%s)""#.to_owned(),
+            format!(r#"echo "@boot $({})""#, boot_id_command()),
+            "tail_file() {".to_owned(),
+            r#"  [ -f "$1" ] || return 0"#.to_owned(),
+            r#"  size=$(wc -c < "$1" | tr -d ' ')"#.to_owned(),
+            r#"  [ "$size" -le "$2" ] || size=$2"#.to_owned(),
+            r#"  printf '@file %s %s\n' "$size" "$3""#.to_owned(),
+            r#"  tail -c "$size" "$1""#.to_owned(),
+            "}".to_owned(),
+            format!("tail_file {} {LOG_TAIL_BYTES} log", sh_quote(&self.files.log)),
+            format!(
+                r#"thread=$(grep -E '"type" *: *"thread\.started"' {} 2>/dev/null | tail -n 1)"#,
+                sh_quote(&self.files.log)
+            ),
+            r#"[ -z "$thread" ] || printf '@file %s thread\n%s\n' "$(printf '%s\n' "$thread" | wc -c | tr -d ' ')" "$thread""#
+                .to_owned(),
+            format!(
+                "tail_file {} {STDERR_TAIL_BYTES} stderr",
+                sh_quote(&self.files.stderr_log)
+            ),
+            format!("tail_file {} {SCHEMA_MAX_BYTES} schema", sh_quote(&self.schema_path)),
+            format!(
+                "tail_file {} {HEARTBEAT_MAX_BYTES} heartbeat",
+                sh_quote(&self.files.heartbeat)
+            ),
+        ]);
+        Ok(lines.join("\n"))
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &[u8],
+        stderr: &str,
+    ) -> Result<JobStatus, RemoteFsError> {
+        // The `@state`, `@now` and `@boot` lines come before the files.
+        let mut header = Vec::new();
+        let mut rest = stdout;
+        while rest.starts_with(b"@") && !rest.starts_with(b"@file ") {
+            let newline = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            header.push(String::from_utf8_lossy(&rest[..newline]).into_owned());
+            rest = rest.get(newline + 1..).unwrap_or_default();
+        }
+        let header_value = |key: &str| {
+            header
+                .iter()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+                .map(str::trim)
+        };
+        let liveness = parse_status(exit_status, &header.join("\n"), stderr)?;
+        let frames = parse_file_frames(rest)?;
+        let frame = |name: &str| {
+            frames
+                .iter()
+                .find(|(frame, _)| frame == name)
+                .map(|(_, body)| *body)
+        };
+
+        let heartbeat =
+            frame("heartbeat").and_then(|body| Heartbeat::parse(&String::from_utf8_lossy(body)));
+        let now = header_value("@now").and_then(|now| now.parse().ok());
+        let alarm = heartbeat.as_ref().zip(now).and_then(|(heartbeat, now)| {
+            HeartbeatAlarm::check(
+                heartbeat,
+                &self.job,
+                liveness,
+                header_value("@boot").unwrap_or_default(),
+                now,
+                self.hang_after_secs,
+            )
+        });
+        let liveness = match alarm {
+            Some(HeartbeatAlarm::Rebooted | HeartbeatAlarm::PidReused) => JobLiveness::Exited,
+            _ => liveness,
+        };
+
+        let turn = TurnLog::from_lines(&parse_log(frame("log").unwrap_or_default()));
+        let stderr_tail = String::from_utf8_lossy(frame("stderr").unwrap_or_default());
+        let outcome = match (turn.outcome(liveness, &stderr_tail), &alarm) {
+            (JobOutcome::Running, Some(HeartbeatAlarm::Hung { idle_secs })) => JobOutcome::Hung {
+                idle_secs: *idle_secs,
+            },
+            (JobOutcome::Crashed { .. }, Some(HeartbeatAlarm::Rebooted)) => JobOutcome::Crashed {
+                message: Some("the host rebooted while the job was running".to_owned()),
+            },
+            (outcome, _) => outcome,
+        };
+        let final_message = turn
+            .final_message
+            .filter(|_| outcome == JobOutcome::Completed);
+        let response = final_message.as_deref().map(|text| {
+            let schema = frame("schema")
+                .and_then(|body| serde_json::from_slice::<Value>(body).ok())
+                .unwrap_or_else(base_schema);
+            check_response(text, &schema)
+        });
+        Ok(JobStatus {
+            liveness,
+            outcome,
+            idle_secs: heartbeat
+                .as_ref()
+                .zip(now)
+                .map(|(heartbeat, now)| now.saturating_sub(heartbeat.last_output_at)),
+            heartbeat,
+            alarm,
+            // The tail may start after the turn that named the thread.
+            thread_id: frame("thread")
+                .and_then(|body| TurnLog::from_lines(&parse_log(body)).thread_id)
+                .or(turn.thread_id),
+            final_message,
+            response,
+        })
+    }
+}
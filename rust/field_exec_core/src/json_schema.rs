@@ -0,0 +1,305 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// One way an instance fails its schema.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// JSON Pointer to the offending value; empty for the root.
+    pub path: String,
+    /// The schema keyword that failed, e.g. `required`.
+    pub keyword: &'static str,
+    pub message: String,
+}
+
+/// Checks `instance` against `schema` and lists every violation found.
+///
+/// Covers the subset of JSON Schema that structured outputs accept: `type`,
+/// `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items`, `minItems`/`maxItems`, `minLength`/`maxLength`, `pattern`,
+/// `minimum`/`maximum` and their exclusive forms, `anyOf`, `allOf` and local
+/// `$ref`s into `$defs` or `definitions`. Annotations and unknown keywords
+/// are ignored.
+pub fn validate(schema: &Value, instance: &Value) -> Vec<Violation> {
+    let mut validator = Validator {
+        root: schema,
+        violations: Vec::new(),
+    };
+    validator.check(schema, instance, &mut String::new(), 0);
+    validator.violations
+}
+
+/// Escapes a property name for use in a JSON Pointer.
+pub fn pointer_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// `$ref` chains deeper than this are reported instead of followed.
+const MAX_REF_DEPTH: u32 = 32;
+
+struct Validator<'a> {
+    root: &'a Value,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Validator<'a> {
+    fn fail(&mut self, path: &str, keyword: &'static str, message: String) {
+        self.violations.push(Violation {
+            path: path.to_owned(),
+            keyword,
+            message,
+        });
+    }
+
+    fn check(&mut self, schema: &'a Value, instance: &Value, path: &mut String, depth: u32) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                self.fail(path, "false", "no value is allowed here".to_owned());
+                return;
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match self.resolve(reference) {
+                Some(target) if depth < MAX_REF_DEPTH => {
+                    self.check(target, instance, path, depth + 1);
+                }
+                Some(_) => self.fail(path, "$ref", format!("{reference} nests too deeply")),
+                None => self.fail(path, "$ref", format!("cannot resolve {reference}")),
+            }
+        }
+
+        if let Some(expected) = schema.get("type")
+            && !type_matches(expected, instance)
+        {
+            self.fail(
+                path,
+                "type",
+                format!(
+                    "expected {}, found {}",
+                    type_names(expected),
+                    type_of(instance)
+                ),
+            );
+            // Nothing below makes sense for a value of the wrong type.
+            return;
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum")
+            && !allowed.contains(instance)
+        {
+            let allowed = Value::Array(allowed.clone());
+            self.fail(path, "enum", format!("{instance} is not one of {allowed}"));
+        }
+        if let Some(constant) = schema.get("const")
+            && constant != instance
+        {
+            self.fail(path, "const", format!("expected {constant}"));
+        }
+
+        match instance {
+            Value::String(s) => self.check_string(schema, s, path),
+            Value::Number(_) => self.check_number(schema, instance, path),
+            Value::Array(items) => self.check_array(schema, items, path, depth),
+            Value::Object(object) => self.check_object(schema, object, path, depth),
+            Value::Null | Value::Bool(_) => {}
+        }
+
+        if let Some(Value::Array(subschemas)) = schema.get("allOf") {
+            for subschema in subschemas {
+                self.check(subschema, instance, path, depth + 1);
+            }
+        }
+        if let Some(Value::Array(subschemas)) = schema.get("anyOf") {
+            let matched = subschemas.iter().any(|subschema| {
+                let mut probe = Validator {
+                    root: self.root,
+                    violations: Vec::new(),
+                };
+                probe.check(subschema, instance, &mut path.clone(), depth + 1);
+                probe.violations.is_empty()
+            });
+            if !matched {
+                self.fail(
+                    path,
+                    "anyOf",
+                    "matches none of the allowed schemas".to_owned(),
+                );
+            }
+        }
+    }
+
+    fn check_string(&mut self, schema: &Map<String, Value>, s: &str, path: &str) {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+            && len < min
+        {
+            self.fail(path, "minLength", format!("length {len} is below {min}"));
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+            && len > max
+        {
+            self.fail(path, "maxLength", format!("length {len} is above {max}"));
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            match Regex::new(pattern) {
+                Ok(re) if re.is_match(s) => {}
+                Ok(_) => self.fail(path, "pattern", format!("does not match {pattern}")),
+                Err(e) => self.fail(path, "pattern", format!("invalid pattern {pattern}: {e}")),
+            }
+        }
+    }
+
+    fn check_number(&mut self, schema: &Map<String, Value>, instance: &Value, path: &str) {
+        let Some(n) = instance.as_f64() else {
+            return;
+        };
+        let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+        if let Some(min) = bound("minimum")
+            && n < min
+        {
+            self.fail(path, "minimum", format!("{n} is below {min}"));
+        }
+        if let Some(max) = bound("maximum")
+            && n > max
+        {
+            self.fail(path, "maximum", format!("{n} is above {max}"));
+        }
+        if let Some(min) = bound("exclusiveMinimum")
+            && n <= min
+        {
+            self.fail(path, "exclusiveMinimum", format!("{n} is not above {min}"));
+        }
+        if let Some(max) = bound("exclusiveMaximum")
+            && n >= max
+        {
+            self.fail(path, "exclusiveMaximum", format!("{n} is not below {max}"));
+        }
+    }
+
+    fn check_array(
+        &mut self,
+        schema: &'a Map<String, Value>,
+        items: &[Value],
+        path: &mut String,
+        depth: u32,
+    ) {
+        let len = items.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+            && len < min
+        {
+            self.fail(
+                path,
+                "minItems",
+                format!("{len} items, need at least {min}"),
+            );
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+            && len > max
+        {
+            self.fail(
+                path,
+                "maxItems",
+                format!("{len} items, allowed at most {max}"),
+            );
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                let len = path.len();
+                path.push_str(&format!("/{index}"));
+                self.check(item_schema, item, path, depth + 1);
+                path.truncate(len);
+            }
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        schema: &'a Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &mut String,
+        depth: u32,
+    ) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    self.fail(path, "required", format!("missing property {key:?}"));
+                }
+            }
+        }
+        let additional = schema.get("additionalProperties");
+        for (key, value) in object {
+            let len = path.len();
+            path.push('/');
+            path.push_str(&pointer_token(key));
+            match (properties.and_then(|p| p.get(key)), additional) {
+                (Some(property_schema), _) => self.check(property_schema, value, path, depth + 1),
+                (None, Some(Value::Bool(false))) => {
+                    self.fail(
+                        path,
+                        "additionalProperties",
+                        format!("unexpected property {key:?}"),
+                    );
+                }
+                (None, Some(extra_schema @ Value::Object(_))) => {
+                    self.check(extra_schema, value, path, depth + 1);
+                }
+                (None, _) => {}
+            }
+            path.truncate(len);
+        }
+    }
+
+    /// Resolves `#`, `#/$defs/name` and other local JSON Pointers.
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        let pointer = reference.strip_prefix('#')?;
+        if pointer.is_empty() {
+            return Some(self.root);
+        }
+        self.root.pointer(pointer)
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn type_matches(expected: &Value, instance: &Value) -> bool {
+    let one = |name: &str| match name {
+        "number" => instance.is_number(),
+        "integer" => match instance {
+            Value::Number(n) => {
+                n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0)
+            }
+            _ => false,
+        },
+        name => type_of(instance) == name,
+    };
+    match expected {
+        Value::String(name) => one(name),
+        Value::Array(names) => names.iter().filter_map(Value::as_str).any(one),
+        _ => true,
+    }
+}
+
+fn type_names(expected: &Value) -> String {
+    match expected {
+        Value::Array(names) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" or "),
+        Value::String(name) => name.clone(),
+        other => other.to_string(),
+    }
+}
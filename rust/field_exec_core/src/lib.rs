@@ -1,2 +1,19 @@
-// Placeholder for pure, testable domain logic.
+// Pure, testable domain logic shared by the daemon and the app runtime.
 
+pub mod atomic_write;
+pub mod auto_commit;
+pub mod codex_command;
+pub mod codex_events;
+pub mod compress;
+pub mod job;
+pub mod job_status;
+pub mod json_schema;
+pub mod lines;
+pub mod output_schema;
+pub mod ports;
+pub mod remote_fs;
+pub mod shell;
+pub mod stream;
+pub mod structured_response;
+pub mod sync;
+pub mod transfer;
@@ -0,0 +1,107 @@
+/// Position of a chunk within an over-long line that was split for delivery.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinePart {
+    pub index: u32,
+    pub is_final: bool,
+}
+
+/// One unit of stream output: either a whole line (`part` is `None`) or a
+/// piece of a line longer than the configured limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineChunk {
+    pub text: String,
+    pub part: Option<LinePart>,
+}
+
+/// Splits raw stream bytes into lines.
+///
+/// Bytes are buffered until a newline arrives, so multi-byte characters
+/// spanning two reads are decoded intact. With `max_line_bytes` set, a line
+/// that grows past the limit is emitted early as numbered parts instead of
+/// being held in memory until its newline shows up.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+    max_line_bytes: Option<usize>,
+    next_part: u32,
+}
+
+impl LineBuffer {
+    pub fn new(max_line_bytes: Option<usize>) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_line_bytes: max_line_bytes.filter(|v| *v > 0),
+            next_part: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<LineChunk> {
+        self.pending.extend_from_slice(bytes);
+        let mut out = Vec::new();
+        loop {
+            let newline = self.pending.iter().position(|b| *b == b'\n');
+            let line_len = newline.unwrap_or(self.pending.len());
+            if let Some(max) = self.max_line_bytes
+                && line_len > max
+            {
+                let cut = utf8_boundary(&self.pending, max);
+                let text = String::from_utf8_lossy(&self.pending[..cut]).into_owned();
+                self.pending.drain(..cut);
+                out.push(self.part(text, false));
+                continue;
+            }
+            let Some(idx) = newline else {
+                break;
+            };
+            let text = String::from_utf8_lossy(&self.pending[..idx])
+                .trim_end_matches('\r')
+                .to_owned();
+            self.pending.drain(..idx + 1);
+            if self.next_part > 0 {
+                out.push(self.part(text, true));
+            } else {
+                out.push(LineChunk { text, part: None });
+            }
+        }
+        out
+    }
+
+    /// Emits whatever is left once the stream has ended. A trailing partial
+    /// line is trimmed and dropped if blank, matching how streams have always
+    /// behaved; a split line always gets its final part.
+    pub fn finish(&mut self) -> Option<LineChunk> {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        if self.next_part > 0 {
+            let text = text.trim_end_matches(['\r', '\n']).to_owned();
+            return Some(self.part(text, true));
+        }
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+        Some(LineChunk {
+            text: text.to_owned(),
+            part: None,
+        })
+    }
+
+    fn part(&mut self, text: String, is_final: bool) -> LineChunk {
+        let index = self.next_part;
+        self.next_part = if is_final { 0 } else { index + 1 };
+        LineChunk {
+            text,
+            part: Some(LinePart { index, is_final }),
+        }
+    }
+}
+
+/// Largest cut point `<= max` that does not split a UTF-8 sequence. Falls back
+/// to `max` when no boundary exists (invalid input).
+fn utf8_boundary(bytes: &[u8], max: usize) -> usize {
+    let mut i = max;
+    while i > 0 && bytes.get(i).is_some_and(|b| b & 0xC0 == 0x80) {
+        i -= 1;
+    }
+    if i == 0 { max } else { i }
+}
@@ -0,0 +1,506 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde_json::{Value, json};
+
+use crate::remote_fs::RemoteFsError;
+use crate::shell::sh_quote;
+
+/// Where extension files live, relative to the project directory.
+pub const SCHEMA_DIR: &str = ".field_exec/schema.d";
+/// Where the composed schema is written for `--output-schema`.
+pub const SCHEMA_PATH: &str = ".field_exec/output-schema.json";
+/// Fields of the base schema, which extensions may not redefine.
+pub const BASE_FIELDS: [&str; 4] = ["message", "commit_message", "images", "actions"];
+
+/// Exit status when the project directory does not exist.
+const EXIT_NO_PROJECT: u32 = 2;
+
+/// The JSON Schema passed to `codex exec --output-schema`, matching the
+/// app's `CodexOutputSchema`.
+pub fn base_schema() -> Value {
+    json!({
+        "type": "object",
+        "description": concat!(
+            "Final structured response from Codex. This must be valid JSON and MUST match this ",
+            "schema exactly. The client uses `message` for user-visible output, `commit_message` ",
+            "to optionally auto-commit git changes, `images` to reference image files produced ",
+            "in the workspace, and `actions` to render interactive buttons (quick replies).",
+        ),
+        "properties": {
+            "message": {
+                "type": "string",
+                "description": concat!(
+                    "User-visible assistant message. Write the final answer here (markdown ",
+                    "allowed). This should be the main response the user reads in the chat. ",
+                    "Do not include JSON or extra wrapper text outside this field.",
+                ),
+            },
+            "commit_message": {
+                "type": "string",
+                "description": concat!(
+                    "A concise, single-line git commit message summarizing the work performed, ",
+                    "in imperative mood (e.g., \"Add project tabs and persist sessions\"). The ",
+                    "client will run `git add -A && git commit -m \"<commit_message>\"` only if ",
+                    "there are uncommitted changes. Provide this even if you made no changes ",
+                    "(use something like \"No changes\").",
+                ),
+                "minLength": 1,
+            },
+            "images": {
+                "type": "array",
+                "description": concat!(
+                    "Optional image references produced during this turn. Each entry must use ",
+                    "an absolute `path` to an image file inside the workspace (the current ",
+                    "project directory). The client may fetch and render these images on-demand.",
+                ),
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": concat!(
+                                "Absolute filesystem path to an image file within the ",
+                                "workspace (project directory).",
+                            ),
+                            "minLength": 1,
+                        },
+                        "caption": {
+                            "type": "string",
+                            "description": "Short caption shown under the image.",
+                        },
+                    },
+                    "required": ["path", "caption"],
+                    "additionalProperties": false,
+                },
+            },
+            "actions": {
+                "type": "array",
+                "description": concat!(
+                    "Optional interactive buttons the client should render under the final ",
+                    "message. Each action becomes a button; when the user taps it, the client ",
+                    "sends `value` as the next user message (no typing). Use this for structured ",
+                    "decisions (yes/no, choose option A/B, etc.). If no buttons are needed, ",
+                    "return an empty array.",
+                ),
+                "items": {
+                    "type": "object",
+                    "description": concat!(
+                        "A single UI action button. Keep labels short; values should be exactly ",
+                        "what you want the user to say next.",
+                    ),
+                    "properties": {
+                        "id": {
+                            "type": "string",
+                            "description": concat!(
+                                "Stable identifier for this action within the response ",
+                                "(e.g., \"yes\", \"no\", \"option_a\").",
+                            ),
+                            "minLength": 1,
+                        },
+                        "label": {
+                            "type": "string",
+                            "description": "Button text shown to the user (e.g., \"Yes\", \"No\").",
+                            "minLength": 1,
+                        },
+                        "value": {
+                            "type": "string",
+                            "description": concat!(
+                                "The exact user message to inject into the chat when this ",
+                                "button is tapped (e.g., \"yes\").",
+                            ),
+                            "minLength": 1,
+                        },
+                    },
+                    "required": ["id", "label", "value"],
+                    "additionalProperties": false,
+                },
+            },
+        },
+        "required": ["message", "commit_message", "images", "actions"],
+        "additionalProperties": false,
+        "strict": true,
+    })
+}
+
+/// A field added to the base schema by an extension file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtensionField {
+    pub name: String,
+    pub schema: Value,
+    /// File name under [`SCHEMA_DIR`] that defined the field.
+    pub source: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaError {
+    InvalidJson {
+        source: String,
+        message: String,
+    },
+    InvalidExtension {
+        source: String,
+        message: String,
+    },
+    /// The extension redefines one of [`BASE_FIELDS`].
+    ReservedField {
+        source: String,
+        field: String,
+    },
+    /// Two extensions define the same field differently.
+    Conflict {
+        field: String,
+        sources: Vec<String>,
+    },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidJson { source, message } => write!(f, "{source}: invalid JSON: {message}"),
+            Self::InvalidExtension { source, message } => write!(f, "{source}: {message}"),
+            Self::ReservedField { source, field } => {
+                write!(f, "{source}: {field:?} is a base schema field")
+            }
+            Self::Conflict { field, sources } => {
+                write!(
+                    f,
+                    "{field:?} is defined differently in {}",
+                    sources.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Why a project's output schema could not be composed from the remote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaLoadError {
+    Remote(RemoteFsError),
+    Invalid(Vec<SchemaError>),
+}
+
+impl fmt::Display for SchemaLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Remote(e) => e.fmt(f),
+            Self::Invalid(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                f.write_str(&messages.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchemaLoadError {}
+
+/// Parses one extension file: an object schema fragment whose `properties`
+/// are the fields to add, e.g. `{"properties": {"risk_level": {...}}}`.
+///
+/// Structured outputs require every property, so all extension fields are
+/// required; a field the agent may leave empty should allow `null`. The same
+/// strict-mode rules apply inside a field: every nested object must set
+/// `"additionalProperties": false` and list all of its properties in
+/// `required`. Only `properties` is merged into the composed schema, so
+/// `$ref` and `$defs` are rejected rather than left dangling.
+pub fn parse_extension(source: &str, text: &str) -> Result<Vec<ExtensionField>, SchemaError> {
+    let invalid = |message: &str| SchemaError::InvalidExtension {
+        source: source.to_owned(),
+        message: message.to_owned(),
+    };
+    let value: Value = serde_json::from_str(text).map_err(|e| SchemaError::InvalidJson {
+        source: source.to_owned(),
+        message: e.to_string(),
+    })?;
+    let properties = value
+        .get("properties")
+        .and_then(Value::as_object)
+        .ok_or_else(|| invalid("expected an object with \"properties\""))?;
+    if let Some(key) = DEFINITION_KEYS.into_iter().find(|k| value.get(k).is_some()) {
+        return Err(invalid(&format!(
+            "{key:?} is not supported; inline the schema"
+        )));
+    }
+    let mut fields = Vec::new();
+    for (name, schema) in properties {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(invalid(&format!("invalid field name {name:?}")));
+        }
+        if !schema.is_object() {
+            return Err(invalid(&format!("schema for {name:?} is not an object")));
+        }
+        if let Some(message) = strict_violation(schema, name) {
+            return Err(invalid(&message));
+        }
+        if BASE_FIELDS.contains(&name.as_str()) {
+            return Err(SchemaError::ReservedField {
+                source: source.to_owned(),
+                field: name.clone(),
+            });
+        }
+        fields.push(ExtensionField {
+            name: name.clone(),
+            schema: schema.clone(),
+            source: source.to_owned(),
+        });
+    }
+    Ok(fields)
+}
+
+/// Keywords for reusable definitions, which extensions cannot use.
+const DEFINITION_KEYS: [&str; 2] = ["$defs", "definitions"];
+
+/// The first place in `schema` that strict structured outputs would
+/// reject, or that refers to a definition, described with `path`, the
+/// dotted location of the subschema.
+fn strict_violation(schema: &Value, path: &str) -> Option<String> {
+    let schema = schema.as_object()?;
+    if let Some(key) = ["$ref"]
+        .into_iter()
+        .chain(DEFINITION_KEYS)
+        .find(|k| schema.contains_key(*k))
+    {
+        return Some(format!(
+            "{path:?} uses {key:?}, which is not supported; inline the schema"
+        ));
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let is_object = properties.is_some()
+        || match schema.get("type") {
+            Some(Value::String(t)) => t == "object",
+            Some(Value::Array(types)) => types.iter().any(|t| t == "object"),
+            _ => false,
+        };
+    if is_object {
+        if schema.get("additionalProperties") != Some(&Value::Bool(false)) {
+            return Some(format!("{path:?} must set \"additionalProperties\": false"));
+        }
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        for name in properties.into_iter().flat_map(|p| p.keys()) {
+            if !required.contains(&name.as_str()) {
+                return Some(format!(
+                    "{path:?} must list {name:?} in \"required\"; allow null instead"
+                ));
+            }
+        }
+    }
+    let nested = properties
+        .into_iter()
+        .flatten()
+        .map(|(name, sub)| (format!("{path}.{name}"), sub));
+    let items = match schema.get("items") {
+        Some(Value::Array(items)) => items.iter().collect(),
+        Some(items) => vec![items],
+        None => Vec::new(),
+    }
+    .into_iter()
+    .map(|sub| (format!("{path}[]"), sub));
+    let variants = ["anyOf", "oneOf", "allOf"]
+        .into_iter()
+        .filter_map(|k| schema.get(k).and_then(Value::as_array))
+        .flatten()
+        .map(|sub| (path.to_owned(), sub));
+    nested
+        .chain(items)
+        .chain(variants)
+        .find_map(|(path, sub)| strict_violation(sub, &path))
+}
+
+/// The base schema plus the fields of any extensions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutputSchema {
+    extensions: Vec<ExtensionField>,
+}
+
+impl OutputSchema {
+    pub fn base() -> Self {
+        Self::default()
+    }
+
+    /// Parses and merges extension files given as `(file name, contents)`,
+    /// in file name order. Every problem found is reported, not just the
+    /// first; the same field defined identically twice is not a conflict.
+    pub fn compose(files: &[(String, String)]) -> Result<Self, Vec<SchemaError>> {
+        let mut files: Vec<&(String, String)> = files.iter().collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut errors = Vec::new();
+        let mut by_name: BTreeMap<String, Vec<ExtensionField>> = BTreeMap::new();
+        let mut order = Vec::new();
+        for (source, text) in files {
+            match parse_extension(source, text) {
+                Ok(fields) => {
+                    for field in fields {
+                        let defs = by_name.entry(field.name.clone()).or_default();
+                        if defs.is_empty() {
+                            order.push(field.name.clone());
+                        }
+                        defs.push(field);
+                    }
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        let mut extensions = Vec::new();
+        for name in order {
+            let mut defs = by_name.remove(&name).unwrap_or_default().into_iter();
+            let Some(first) = defs.next() else {
+                continue;
+            };
+            let others: Vec<ExtensionField> = defs.collect();
+            if others.iter().all(|d| d.schema == first.schema) {
+                extensions.push(first);
+            } else {
+                errors.push(SchemaError::Conflict {
+                    field: name,
+                    sources: std::iter::once(first)
+                        .chain(others)
+                        .map(|d| d.source)
+                        .collect(),
+                });
+            }
+        }
+        if errors.is_empty() {
+            Ok(Self { extensions })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn extensions(&self) -> &[ExtensionField] {
+        &self.extensions
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut schema = base_schema();
+        if let Some(properties) = schema.get_mut("properties").and_then(Value::as_object_mut) {
+            for field in &self.extensions {
+                properties.insert(field.name.clone(), field.schema.clone());
+            }
+        }
+        if let Some(required) = schema.get_mut("required").and_then(Value::as_array_mut) {
+            required.extend(
+                self.extensions
+                    .iter()
+                    .map(|field| Value::String(field.name.clone())),
+            );
+        }
+        schema
+    }
+
+    /// The schema file contents, as written to [`SCHEMA_PATH`].
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::to_string_pretty(&self.to_value()).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+}
+
+/// Path of the composed schema file for a project.
+pub fn schema_path(project_dir: &str) -> String {
+    format!("{}/{SCHEMA_PATH}", project_dir.trim_end_matches('/'))
+}
+
+/// Reads a project's extension files from the remote.
+///
+/// The script prints `@file <bytes> <name>` followed by the raw contents for
+/// each `*.json` file in [`SCHEMA_DIR`]; a missing directory yields none.
+#[derive(Clone, Debug)]
+pub struct ExtensionLoad {
+    project_dir: String,
+}
+
+impl ExtensionLoad {
+    pub fn new(project_dir: impl Into<String>) -> Self {
+        Self {
+            project_dir: project_dir.into(),
+        }
+    }
+
+    pub fn script(&self) -> String {
+        [
+            format!(
+                "cd {} 2>/dev/null || {{ echo {} >&2; exit {EXIT_NO_PROJECT}; }}",
+                sh_quote(&self.project_dir),
+                sh_quote(&self.project_dir)
+            ),
+            format!("[ -d {SCHEMA_DIR} ] || exit 0"),
+            format!("for f in {SCHEMA_DIR}/*.json; do"),
+            r#"  [ -f "$f" ] || continue"#.to_owned(),
+            r#"  printf '@file %s %s\n' "$(wc -c < "$f" | tr -d ' ')" "${f##*/}""#.to_owned(),
+            r#"  cat "$f""#.to_owned(),
+            "done".to_owned(),
+        ]
+        .join("\n")
+    }
+
+    /// Returns `(file name, contents)` pairs for [`OutputSchema::compose`].
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &[u8],
+        stderr: &str,
+    ) -> Result<Vec<(String, String)>, RemoteFsError> {
+        if exit_status != 0 {
+            let message = stderr.trim().to_owned();
+            return Err(match exit_status {
+                EXIT_NO_PROJECT => RemoteFsError::RootNotFound(message),
+                exit_status => RemoteFsError::Failed {
+                    exit_status,
+                    message,
+                },
+            });
+        }
+        Ok(parse_file_frames(stdout)?
+            .into_iter()
+            .map(|(name, body)| (name, String::from_utf8_lossy(body).into_owned()))
+            .collect())
+    }
+
+    /// Composes the project's [`OutputSchema`] from what the script printed.
+    pub fn compose(
+        &self,
+        exit_status: u32,
+        stdout: &[u8],
+        stderr: &str,
+    ) -> Result<OutputSchema, SchemaLoadError> {
+        let files = self
+            .parse_output(exit_status, stdout, stderr)
+            .map_err(SchemaLoadError::Remote)?;
+        OutputSchema::compose(&files).map_err(SchemaLoadError::Invalid)
+    }
+}
+
+/// Splits output made of `@file <bytes> <name>` headers, each followed by
+/// exactly that many raw bytes.
+pub(crate) fn parse_file_frames(mut rest: &[u8]) -> Result<Vec<(String, &[u8])>, RemoteFsError> {
+    let mut files = Vec::new();
+    while !rest.is_empty() {
+        let malformed = || RemoteFsError::Malformed("truncated file listing".to_owned());
+        let newline = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(malformed)?;
+        let header = String::from_utf8_lossy(&rest[..newline]);
+        let (len, name) = header
+            .strip_prefix("@file ")
+            .and_then(|h| h.split_once(' '))
+            .and_then(|(len, name)| Some((len.parse::<usize>().ok()?, name.to_owned())))
+            .ok_or_else(|| RemoteFsError::Malformed(header.to_string()))?;
+        let body = rest
+            .get(newline + 1..newline + 1 + len)
+            .ok_or_else(malformed)?;
+        files.push((name, body));
+        rest = &rest[newline + 1 + len..];
+    }
+    Ok(files)
+}
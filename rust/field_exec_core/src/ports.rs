@@ -0,0 +1,276 @@
+use crate::remote_fs::RemoteFsError;
+use crate::shell::sh_quote;
+
+/// Exit status when none of `ss`, `lsof` or `netstat` is installed.
+const EXIT_NO_PROBE: u32 = 4;
+/// Exit status when the project directory does not exist.
+const EXIT_NO_PROJECT: u32 = 2;
+
+/// Which tool produced a port listing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeVia {
+    Ss,
+    Lsof,
+    Netstat,
+}
+
+/// One listening TCP socket on the remote host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListeningPort {
+    /// Local address as printed by the probe, without brackets or zone;
+    /// `*`, `0.0.0.0` and `::` mean every interface.
+    pub address: String,
+    pub port: u16,
+    /// Unknown when the socket belongs to another user and we are not root.
+    pub pid: Option<u32>,
+    pub process: Option<String>,
+    pub cwd: Option<String>,
+}
+
+impl ListeningPort {
+    /// Host to forward to from the server: `localhost` for wildcard
+    /// listeners, the address itself otherwise.
+    pub fn connect_host(&self) -> &str {
+        match self.address.as_str() {
+            "*" | "0.0.0.0" | "::" => "localhost",
+            address => address,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortListing {
+    pub via: ProbeVia,
+    pub ports: Vec<ListeningPort>,
+    /// The physical project directory, when filtering by it.
+    pub project_dir: Option<String>,
+}
+
+/// Lists listening TCP ports with `ss -ltnp`, falling back to `lsof` and
+/// then `netstat`.
+///
+/// The script prints `@via <tool>`, the probe's raw output, then `@cwd` and
+/// one `pid cwd` line per owning process. With a project directory it
+/// starts with `@project <physical path>` and only ports whose process runs
+/// inside it are kept.
+#[derive(Clone, Debug, Default)]
+pub struct PortProbe {
+    project_dir: Option<String>,
+}
+
+impl PortProbe {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn project_dir(mut self, project_dir: Option<String>) -> Self {
+        self.project_dir = project_dir;
+        self
+    }
+
+    pub fn script(&self) -> String {
+        let mut lines = Vec::new();
+        if let Some(dir) = &self.project_dir {
+            lines.push(format!("project={}", sh_quote(dir)));
+            lines.push(format!(
+                r#"project=$(cd "$project" 2>/dev/null && pwd -P) || {{ echo {} >&2; exit {EXIT_NO_PROJECT}; }}"#,
+                sh_quote(dir)
+            ));
+            lines.push(r#"echo "@project $project""#.to_owned());
+        }
+        lines.extend([
+            "if command -v ss >/dev/null 2>&1; then via=ss; out=$(ss -ltnp 2>/dev/null)".to_owned(),
+            // lsof exits 1 when nothing is listening.
+            "elif command -v lsof >/dev/null 2>&1; then via=lsof; out=$(lsof -nP -iTCP -sTCP:LISTEN 2>/dev/null)"
+                .to_owned(),
+            "elif command -v netstat >/dev/null 2>&1; then via=netstat; out=$(netstat -ltnp 2>/dev/null || netstat -an -p tcp 2>/dev/null)"
+                .to_owned(),
+            format!(r#"else echo "no ss, lsof or netstat" >&2; exit {EXIT_NO_PROBE}; fi"#),
+            r#"echo "@via $via""#.to_owned(),
+            r#"printf '%s\n' "$out""#.to_owned(),
+            "case $via in".to_owned(),
+            r#"  ss) pids=$(printf '%s\n' "$out" | grep -o 'pid=[0-9]*' | cut -d= -f2) ;;"#
+                .to_owned(),
+            r#"  lsof) pids=$(printf '%s\n' "$out" | awk 'NR > 1 { print $2 }') ;;"#.to_owned(),
+            r#"  netstat) pids=$(printf '%s\n' "$out" | awk '{ print $NF }' | grep -o '^[0-9][0-9]*/' | tr -d /) ;;"#
+                .to_owned(),
+            "esac".to_owned(),
+            "echo @cwd".to_owned(),
+            r#"for pid in $(printf '%s\n' $pids | sort -u); do"#.to_owned(),
+            r#"  cwd=$(readlink "/proc/$pid/cwd" 2>/dev/null || lsof -a -p "$pid" -d cwd -Fn 2>/dev/null | sed -n 's/^n//p')"#
+                .to_owned(),
+            r#"  [ -n "$cwd" ] && echo "$pid $cwd""#.to_owned(),
+            "done".to_owned(),
+            "exit 0".to_owned(),
+        ]);
+        lines.join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<PortListing, RemoteFsError> {
+        if exit_status != 0 {
+            let message = stderr.trim().to_owned();
+            return Err(match exit_status {
+                EXIT_NO_PROJECT => RemoteFsError::RootNotFound(message),
+                exit_status => RemoteFsError::Failed {
+                    exit_status,
+                    message,
+                },
+            });
+        }
+        let mut project_dir = None;
+        let mut via = None;
+        let mut ports = Vec::new();
+        let mut lines = stdout.lines();
+        for line in lines.by_ref() {
+            if let Some(dir) = line.strip_prefix("@project ") {
+                project_dir = Some(dir.to_owned());
+            } else if let Some(tool) = line.strip_prefix("@via ") {
+                via = Some(match tool {
+                    "ss" => ProbeVia::Ss,
+                    "lsof" => ProbeVia::Lsof,
+                    "netstat" => ProbeVia::Netstat,
+                    other => return Err(RemoteFsError::Malformed(format!("probe {other}"))),
+                });
+            } else if line == "@cwd" {
+                break;
+            } else if let Some(via) = via {
+                let parsed = match via {
+                    ProbeVia::Ss => parse_ss_line(line),
+                    ProbeVia::Lsof => parse_lsof_line(line),
+                    ProbeVia::Netstat => parse_netstat_line(line),
+                };
+                ports.extend(parsed);
+            }
+        }
+        let via = via.ok_or_else(|| RemoteFsError::Malformed("missing probe header".to_owned()))?;
+        let cwds: Vec<(u32, &str)> = lines
+            .filter_map(|line| {
+                let (pid, cwd) = line.split_once(' ')?;
+                Some((pid.parse().ok()?, cwd))
+            })
+            .collect();
+        for port in &mut ports {
+            port.cwd = port.pid.and_then(|pid| {
+                cwds.iter()
+                    .find(|(p, _)| *p == pid)
+                    .map(|(_, cwd)| (*cwd).to_owned())
+            });
+        }
+        if let Some(dir) = &project_dir {
+            ports.retain(|port| port.cwd.as_deref().is_some_and(|cwd| is_within(cwd, dir)));
+        }
+        ports.sort_by(|a, b| (a.port, &a.address, a.pid).cmp(&(b.port, &b.address, b.pid)));
+        ports.dedup();
+        Ok(PortListing {
+            via,
+            ports,
+            project_dir,
+        })
+    }
+}
+
+fn is_within(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Splits `addr:port`, `[v6]:port` or `v6%zone:port` on the last colon.
+fn split_host_port(local: &str) -> Option<(String, u16)> {
+    let (address, port) = local.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    let address = address.split('%').next().unwrap_or(address);
+    Some((address.to_owned(), port))
+}
+
+/// `LISTEN 0 128 127.0.0.1:3000 0.0.0.0:* users:(("node",pid=42,fd=20))`.
+/// Some `ss` builds leave out the state column when it is implied by `-l`.
+fn parse_ss_line(line: &str) -> Option<ListeningPort> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let local = match fields.first() {
+        Some(&"LISTEN") => fields.get(3)?,
+        Some(first) if first.parse::<u64>().is_ok() => fields.get(2)?,
+        _ => return None,
+    };
+    let (address, port) = split_host_port(local)?;
+    let (process, pid) = match line.find("users:((\"") {
+        Some(start) => {
+            let rest = &line[start + "users:((\"".len()..];
+            let name = rest.split('"').next().map(str::to_owned);
+            let pid = rest
+                .split_once("pid=")
+                .and_then(|(_, p)| p.split(|c: char| !c.is_ascii_digit()).next())
+                .and_then(|p| p.parse().ok());
+            (name, pid)
+        }
+        None => (None, None),
+    };
+    Some(ListeningPort {
+        address,
+        port,
+        pid,
+        process,
+        cwd: None,
+    })
+}
+
+/// `node 42 dev 20u IPv4 0x0 0t0 TCP 127.0.0.1:3000 (LISTEN)`.
+fn parse_lsof_line(line: &str) -> Option<ListeningPort> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.last() != Some(&"(LISTEN)") || fields.len() < 4 {
+        return None;
+    }
+    let (address, port) = split_host_port(fields[fields.len() - 2])?;
+    Some(ListeningPort {
+        address,
+        port,
+        pid: fields[1].parse().ok(),
+        // lsof escapes spaces in command names as `\x20`.
+        process: Some(fields[0].replace("\\x20", " ")),
+        cwd: None,
+    })
+}
+
+/// Linux: `tcp 0 0 0.0.0.0:22 0.0.0.0:* LISTEN 42/sshd: /usr/sbin`.
+/// BSD: `tcp4 0 0 127.0.0.1.3000 *.* LISTEN`, where the port follows a dot,
+/// also for IPv6 (`::1.3000`).
+fn parse_netstat_line(line: &str) -> Option<ListeningPort> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if !fields.first()?.starts_with("tcp") || fields.get(5) != Some(&"LISTEN") {
+        return None;
+    }
+    let local = fields[3];
+    // Linux addresses end in `:port`, so only BSD ones end in `.digits`.
+    let (address, port) = match local.rsplit_once('.') {
+        Some((address, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            let address = address.split('%').next().unwrap_or(address);
+            (address.to_owned(), port.parse().ok()?)
+        }
+        _ => split_host_port(local)?,
+    };
+    let (pid, process) = match fields.get(6).and_then(|owner| owner.split_once('/')) {
+        Some((pid, name)) => {
+            let name = std::iter::once(name)
+                .chain(fields[7..].iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            (pid.parse().ok(), Some(name))
+        }
+        None => (None, None),
+    };
+    Some(ListeningPort {
+        address,
+        port,
+        pid,
+        process,
+        cwd: None,
+    })
+}
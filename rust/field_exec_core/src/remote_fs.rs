@@ -0,0 +1,506 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::shell::sh_quote;
+
+/// Exit status the scripts use when the project root cannot be resolved.
+const EXIT_NO_ROOT: u32 = 2;
+/// Exit status when the resolved path escapes the project root.
+const EXIT_OUTSIDE_ROOT: u32 = 3;
+/// Exit status when the path does not name something readable.
+const EXIT_NOT_FOUND: u32 = 4;
+
+/// Bytes returned by one read when the caller sets no limit.
+pub const DEFAULT_MAX_READ_BYTES: u64 = 8 * 1024 * 1024;
+/// Upper bound on `max_bytes`; larger reads should use a transfer instead.
+pub const MAX_READ_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteFsError {
+    RootNotFound(String),
+    OutsideRoot,
+    NotFound(String),
+    Malformed(String),
+    Failed { exit_status: u32, message: String },
+}
+
+impl fmt::Display for RemoteFsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RootNotFound(root) => write!(f, "project root not found: {root}"),
+            Self::OutsideRoot => f.write_str("path is outside the project root"),
+            Self::NotFound(msg) => f.write_str(msg),
+            Self::Malformed(msg) => write!(f, "unexpected remote output: {msg}"),
+            Self::Failed {
+                exit_status,
+                message,
+            } if message.is_empty() => write!(f, "remote command failed (exit={exit_status})"),
+            Self::Failed {
+                exit_status,
+                message,
+            } => write!(f, "remote command failed (exit={exit_status}): {message}"),
+        }
+    }
+}
+
+impl std::error::Error for RemoteFsError {}
+
+/// Shell lines that set `$root` to the physical project root and `$real` to
+/// the physical location of `path`, exiting unless the latter is inside the
+/// former. Relative paths are taken relative to the root.
+fn resolve_in_root(root: &str, path: &str) -> Vec<String> {
+    vec![
+        format!("root={}", sh_quote(root)),
+        format!("path={}", sh_quote(path)),
+        format!(
+            r#"root=$(cd "$root" 2>/dev/null && pwd -P) || {{ echo {} >&2; exit {EXIT_NO_ROOT}; }}"#,
+            sh_quote(root)
+        ),
+        r#"case "$path" in /*) ;; *) path="$root/$path" ;; esac"#.to_owned(),
+        format!(
+            r#"dir=$(cd "$(dirname "$path")" 2>/dev/null && pwd -P) || {{ echo "no such file or directory" >&2; exit {EXIT_NOT_FOUND}; }}"#
+        ),
+        r#"real="${dir%/}/$(basename "$path")""#.to_owned(),
+        format!(
+            r#"if [ -L "$real" ]; then real=$(readlink -f "$real" 2>/dev/null || realpath "$real" 2>/dev/null) || exit {EXIT_NOT_FOUND}; fi"#
+        ),
+        format!(r#"case "$real/" in "${{root%/}}"/*) ;; *) exit {EXIT_OUTSIDE_ROOT} ;; esac"#),
+    ]
+}
+
+fn script_error(exit_status: u32, stderr: &str) -> RemoteFsError {
+    let stderr = stderr.trim();
+    match exit_status {
+        EXIT_NO_ROOT => RemoteFsError::RootNotFound(stderr.to_owned()),
+        EXIT_OUTSIDE_ROOT => RemoteFsError::OutsideRoot,
+        EXIT_NOT_FOUND => RemoteFsError::NotFound(stderr.to_owned()),
+        exit_status => RemoteFsError::Failed {
+            exit_status,
+            message: stderr.to_owned(),
+        },
+    }
+}
+
+/// A byte range of a file read through one shell command.
+///
+/// The script prints `size mtime mode` on the first line and then the raw
+/// bytes, so the caller must capture stdout as bytes, not text.
+#[derive(Clone, Debug)]
+pub struct FileRead {
+    root: String,
+    path: String,
+    offset: u64,
+    length: Option<u64>,
+    max_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileContents {
+    pub data: Vec<u8>,
+    pub offset: u64,
+    /// Size of the whole file.
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+    /// Permission bits.
+    pub mode: u32,
+    /// Fewer bytes than requested were returned because of `max_bytes`.
+    pub truncated: bool,
+}
+
+impl FileRead {
+    pub fn new(root: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            path: path.into(),
+            offset: 0,
+            length: None,
+            max_bytes: DEFAULT_MAX_READ_BYTES,
+        }
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Bytes wanted from `offset`; `None` reads to the end of the file.
+    pub fn length(mut self, length: Option<u64>) -> Self {
+        self.length = length;
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes
+            .unwrap_or(DEFAULT_MAX_READ_BYTES)
+            .clamp(1, MAX_READ_BYTES);
+        self
+    }
+
+    pub fn script(&self) -> String {
+        let count = self.length.unwrap_or(u64::MAX).min(self.max_bytes);
+        let mut lines = resolve_in_root(&self.root, &self.path);
+        lines.extend([
+            format!(
+                r#"[ -f "$real" ] || {{ echo "not a regular file" >&2; exit {EXIT_NOT_FOUND}; }}"#
+            ),
+            r#"meta=$(stat -c '%s %Y %a' "$real" 2>/dev/null || stat -f '%z %m %Lp' "$real") || exit 1"#
+                .to_owned(),
+            r#"echo "$meta""#.to_owned(),
+            format!(r#"tail -c +{} "$real" | head -c {count}"#, self.offset + 1),
+        ]);
+        lines.join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        mut stdout: Vec<u8>,
+        stderr: &str,
+    ) -> Result<FileContents, RemoteFsError> {
+        if exit_status != 0 {
+            return Err(script_error(exit_status, stderr));
+        }
+        let newline = stdout
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| RemoteFsError::Malformed("missing file metadata".to_owned()))?;
+        let meta = String::from_utf8_lossy(&stdout[..newline]).into_owned();
+        let mut fields = meta.split_whitespace();
+        let (Some(size), Some(mtime), Some(mode)) = (
+            fields.next().and_then(|v| v.parse::<u64>().ok()),
+            fields.next().and_then(|v| v.parse::<i64>().ok()),
+            fields.next().and_then(|v| u32::from_str_radix(v, 8).ok()),
+        ) else {
+            return Err(RemoteFsError::Malformed(meta));
+        };
+        let data = stdout.split_off(newline + 1);
+
+        let requested_end = match self.length {
+            Some(length) => self.offset.saturating_add(length).min(size),
+            None => size,
+        };
+        let truncated = self.offset.saturating_add(data.len() as u64) < requested_end;
+        Ok(FileContents {
+            data,
+            offset: self.offset,
+            size,
+            mtime,
+            mode,
+            truncated,
+        })
+    }
+}
+
+/// Directory levels listed when the caller does not ask for a depth.
+pub const DEFAULT_LIST_DEPTH: u32 = 1;
+pub const MAX_LIST_DEPTH: u32 = 8;
+/// Listings stop after this many entries and report `truncated`.
+pub const MAX_LIST_ENTRIES: usize = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DirEntry {
+    /// Path relative to the listed directory, `/`-separated.
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+    /// The entry is a directory containing `.git`.
+    pub is_git_repo: bool,
+    /// The entry is a directory containing `.field_exec/`.
+    pub has_field_exec: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirListing {
+    /// Physical path of the listed directory.
+    pub path: String,
+    pub entries: Vec<DirEntry>,
+    pub truncated: bool,
+}
+
+impl DirListing {
+    /// Sorts entries by path and applies [`MAX_LIST_ENTRIES`].
+    pub fn finish(mut self) -> Self {
+        if self.entries.len() > MAX_LIST_ENTRIES {
+            self.entries.truncate(MAX_LIST_ENTRIES);
+            self.truncated = true;
+        }
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        self
+    }
+}
+
+/// A recursive directory listing, either walked over SFTP by the caller or
+/// produced by the portable `find`/`stat` script from [`DirList::script`].
+#[derive(Clone, Debug)]
+pub struct DirList {
+    path: String,
+    show_hidden: bool,
+    depth: u32,
+}
+
+impl DirList {
+    /// An empty path lists the login directory.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            show_hidden: false,
+            depth: DEFAULT_LIST_DEPTH,
+        }
+    }
+
+    pub fn show_hidden(mut self, show_hidden: bool) -> Self {
+        self.show_hidden = show_hidden;
+        self
+    }
+
+    pub fn depth(mut self, depth: Option<u32>) -> Self {
+        self.depth = depth.unwrap_or(DEFAULT_LIST_DEPTH).clamp(1, MAX_LIST_DEPTH);
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        if self.path.trim().is_empty() {
+            "."
+        } else {
+            &self.path
+        }
+    }
+
+    pub fn includes_hidden(&self) -> bool {
+        self.show_hidden
+    }
+
+    pub fn max_depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// One line per entry: `kind size mtime git field_exec path`, tab
+    /// separated, after a first line holding the physical directory path.
+    pub fn script(&self) -> String {
+        let hidden = if self.show_hidden {
+            "-print"
+        } else {
+            r"\( -name '.*' -prune \) -o -print"
+        };
+        [
+            format!(
+                r#"cd {} 2>/dev/null || {{ echo "no such directory" >&2; exit {EXIT_NOT_FOUND}; }}"#,
+                sh_quote(self.path())
+            ),
+            "pwd -P".to_owned(),
+            format!(
+                "find . -mindepth 1 -maxdepth {} {hidden} | head -n {} | while IFS= read -r p; do",
+                self.depth,
+                MAX_LIST_ENTRIES + 1
+            ),
+            r#"  if [ -L "$p" ]; then t=l; elif [ -d "$p" ]; then t=d; elif [ -f "$p" ]; then t=f; else t=o; fi"#
+                .to_owned(),
+            r#"  meta=$(stat -c '%s %Y' "$p" 2>/dev/null || stat -f '%z %m' "$p" 2>/dev/null) || continue"#
+                .to_owned(),
+            r#"  g=0; x=0"#.to_owned(),
+            r#"  if [ "$t" = d ]; then [ -e "$p/.git" ] && g=1; [ -d "$p/.field_exec" ] && x=1; fi"#
+                .to_owned(),
+            r#"  set -- $meta; printf '%s\t%s\t%s\t%s\t%s\t%s\n' "$t" "$1" "$2" "$g" "$x" "${p#./}""#
+                .to_owned(),
+            "done".to_owned(),
+        ]
+        .join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<DirListing, RemoteFsError> {
+        if exit_status != 0 {
+            return Err(script_error(exit_status, stderr));
+        }
+        let mut lines = stdout.lines();
+        let path = lines
+            .next()
+            .map(str::to_owned)
+            .ok_or_else(|| RemoteFsError::Malformed("missing directory path".to_owned()))?;
+        let mut entries = Vec::new();
+        for line in lines {
+            let mut fields = line.splitn(6, '\t');
+            let (Some(kind), Some(size), Some(mtime), Some(git), Some(fe), Some(rel)) = (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) else {
+                // A name with a newline in it; nothing sensible to show.
+                continue;
+            };
+            let kind = match kind {
+                "d" => EntryKind::Dir,
+                "f" => EntryKind::File,
+                "l" => EntryKind::Symlink,
+                _ => EntryKind::Other,
+            };
+            entries.push(DirEntry {
+                path: rel.to_owned(),
+                kind,
+                size: size.parse().unwrap_or(0),
+                mtime: mtime.parse().unwrap_or(0),
+                is_git_repo: git == "1",
+                has_field_exec: fe == "1",
+            });
+        }
+        Ok(DirListing {
+            path,
+            entries,
+            truncated: false,
+        }
+        .finish())
+    }
+}
+
+/// Why a log fetch started over from offset 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogReset {
+    /// The file is shorter than the caller's offset.
+    Truncated,
+    /// A different file (by inode) now lives at the path.
+    Rotated,
+}
+
+/// Fetches bytes appended to a log since the caller's last offset.
+///
+/// The script prints `inode size start reset` on the first line and then the
+/// raw bytes from `start`, so stdout must be captured as bytes.
+#[derive(Clone, Debug)]
+pub struct LogFetch {
+    path: String,
+    from_offset: u64,
+    inode: Option<u64>,
+    max_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogChunk {
+    pub data: Vec<u8>,
+    /// Offset of `data[0]`; 0 after a reset.
+    pub offset: u64,
+    /// Offset to pass on the next fetch.
+    pub next_offset: u64,
+    pub inode: u64,
+    pub size: u64,
+    pub reset: Option<LogReset>,
+}
+
+impl LogChunk {
+    /// More bytes are already available past `next_offset`.
+    pub fn has_more(&self) -> bool {
+        self.next_offset < self.size
+    }
+}
+
+impl LogFetch {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            from_offset: 0,
+            inode: None,
+            max_bytes: DEFAULT_MAX_READ_BYTES,
+        }
+    }
+
+    pub fn from_offset(mut self, from_offset: u64) -> Self {
+        self.from_offset = from_offset;
+        self
+    }
+
+    /// Inode seen on the previous fetch; a different one means rotation.
+    pub fn inode(mut self, inode: Option<u64>) -> Self {
+        self.inode = inode;
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes
+            .unwrap_or(DEFAULT_MAX_READ_BYTES)
+            .clamp(1, MAX_READ_BYTES);
+        self
+    }
+
+    pub fn script(&self) -> String {
+        let rotated = match self.inode {
+            Some(inode) => format!(r#"[ "$ino" != {inode} ]"#),
+            None => "false".to_owned(),
+        };
+        [
+            format!("f={}", sh_quote(&self.path)),
+            format!(
+                r#"[ -f "$f" ] || {{ echo "no such log file" >&2; exit {EXIT_NOT_FOUND}; }}"#
+            ),
+            r#"meta=$(stat -L -c '%i %s' "$f" 2>/dev/null || stat -L -f '%i %z' "$f") || exit 1"#
+                .to_owned(),
+            r#"set -- $meta; ino=$1; size=$2"#.to_owned(),
+            format!("start={}; reset=none", self.from_offset),
+            format!(
+                r#"if {rotated}; then start=0; reset=rotated; elif [ "$size" -lt "$start" ]; then start=0; reset=truncated; fi"#
+            ),
+            r#"echo "$ino $size $start $reset""#.to_owned(),
+            format!(r#"tail -c +$((start + 1)) "$f" | head -c {}"#, self.max_bytes),
+        ]
+        .join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        mut stdout: Vec<u8>,
+        stderr: &str,
+    ) -> Result<LogChunk, RemoteFsError> {
+        if exit_status != 0 {
+            return Err(script_error(exit_status, stderr));
+        }
+        let newline = stdout
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| RemoteFsError::Malformed("missing log metadata".to_owned()))?;
+        let meta = String::from_utf8_lossy(&stdout[..newline]).into_owned();
+        let mut fields = meta.split_whitespace();
+        let (Some(inode), Some(size), Some(offset), Some(reset)) = (
+            fields.next().and_then(|v| v.parse::<u64>().ok()),
+            fields.next().and_then(|v| v.parse::<u64>().ok()),
+            fields.next().and_then(|v| v.parse::<u64>().ok()),
+            fields.next(),
+        ) else {
+            return Err(RemoteFsError::Malformed(meta));
+        };
+        let reset = match reset {
+            "rotated" => Some(LogReset::Rotated),
+            "truncated" => Some(LogReset::Truncated),
+            _ => None,
+        };
+        let data = stdout.split_off(newline + 1);
+        // The file may have grown between `stat` and `tail`.
+        let next_offset = offset + data.len() as u64;
+        Ok(LogChunk {
+            data,
+            offset,
+            next_offset,
+            inode,
+            size: size.max(next_offset),
+            reset,
+        })
+    }
+}
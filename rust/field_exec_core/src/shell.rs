@@ -0,0 +1,23 @@
+/// Quotes `s` as a single POSIX shell word.
+///
+/// Words made only of characters the shell never treats specially are left
+/// bare to keep logged commands readable; everything else is single-quoted.
+pub fn sh_quote(s: &str) -> String {
+    if s.is_empty() {
+        return "''".to_owned();
+    }
+    if s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '/' | ':' | '=' | '@' | '-'))
+    {
+        return s.to_owned();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Defines `sha256_of [file]`, which prints the lowercase hex SHA-256 of a
+/// file (or stdin) using whichever of sha256sum, shasum or openssl exists,
+/// and prints nothing if none does.
+pub const SHA256_FN: &str = concat!(
+    r#"sha256_of() { { sha256sum "$@" 2>/dev/null || shasum -a 256 "$@" 2>/dev/null"#,
+    r#" || openssl dgst -sha256 -r "$@" 2>/dev/null; } | cut -d' ' -f1 | tr -d '*'; }"#
+);
@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Grace period between escalation steps once a stream has timed out:
+/// TERM, then KILL, then give up on the channel.
+pub const STREAM_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// A signal the watchdog wants delivered to a timed-out command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamSignal {
+    Term,
+    Kill,
+}
+
+/// Tracks the duration and idle-output limits of a running stream.
+#[derive(Debug)]
+pub struct StreamWatchdog {
+    max_deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    idle_deadline: Option<Instant>,
+    timeout_reason: Option<&'static str>,
+    /// Next escalation deadline, and whether KILL has already been sent.
+    escalation: Option<(Instant, bool)>,
+}
+
+impl StreamWatchdog {
+    pub fn new(max_duration: Option<Duration>, idle_timeout: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            max_deadline: max_duration.map(|d| now + d),
+            idle_timeout,
+            idle_deadline: idle_timeout.map(|d| now + d),
+            timeout_reason: None,
+            escalation: None,
+        }
+    }
+
+    pub fn on_output(&mut self) {
+        if let Some(idle) = self.idle_timeout {
+            self.idle_deadline = Some(Instant::now() + idle);
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        if let Some((at, _)) = self.escalation {
+            return Some(at);
+        }
+        match (self.max_deadline, self.idle_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Called when `deadline()` passes. Returns the signal to send to the
+    /// remote process, or `None` once it is time to abandon the channel.
+    pub fn expire(&mut self) -> Option<StreamSignal> {
+        let now = Instant::now();
+        match self.escalation {
+            None => {
+                let max_hit = self.max_deadline.is_some_and(|d| d <= now);
+                self.timeout_reason = Some(if max_hit {
+                    "max duration exceeded"
+                } else {
+                    "idle output timeout"
+                });
+                self.escalation = Some((now + STREAM_TIMEOUT_GRACE, false));
+                Some(StreamSignal::Term)
+            }
+            Some((_, false)) => {
+                self.escalation = Some((now + STREAM_TIMEOUT_GRACE, true));
+                Some(StreamSignal::Kill)
+            }
+            Some((_, true)) => None,
+        }
+    }
+
+    pub fn timeout_reason(&self) -> Option<&'static str> {
+        self.timeout_reason
+    }
+}
+
+/// Why a stream ended. `exit_status` is only meaningful for `Exited`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamTermination {
+    Exited,
+    Signaled,
+    TransportError,
+    Cancelled,
+    TimedOut,
+}
+
+/// How a streamed command ended, as reported to whoever watches it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamEnd {
+    pub exit_status: i32,
+    /// Signal name without the `SIG` prefix when `termination` is
+    /// `Signaled`.
+    pub exit_signal: Option<String>,
+    pub core_dumped: bool,
+    /// Error message the server attached to the signal report, if any.
+    pub signal_message: Option<String>,
+    pub termination: StreamTermination,
+    pub error: Option<String>,
+}
+
+impl StreamEnd {
+    pub fn exited(exit_status: u32) -> Self {
+        Self {
+            exit_status: i32::try_from(exit_status).unwrap_or(-1),
+            exit_signal: None,
+            core_dumped: false,
+            signal_message: None,
+            termination: StreamTermination::Exited,
+            error: None,
+        }
+    }
+
+    pub fn signaled(signal: String, core_dumped: bool, signal_message: Option<String>) -> Self {
+        Self {
+            exit_status: -1,
+            error: Some(format!("killed by signal {signal}")),
+            exit_signal: Some(signal),
+            core_dumped,
+            signal_message,
+            termination: StreamTermination::Signaled,
+        }
+    }
+
+    pub fn failed(termination: StreamTermination, error: impl Into<String>) -> Self {
+        Self {
+            exit_status: -1,
+            exit_signal: None,
+            core_dumped: false,
+            signal_message: None,
+            termination,
+            error: Some(error.into()),
+        }
+    }
+
+    /// Once the watchdog timed a stream out, it reads as timed out however
+    /// the command itself ended.
+    pub fn watched_by(mut self, watchdog: &StreamWatchdog) -> Self {
+        if let Some(reason) = watchdog.timeout_reason() {
+            self.termination = StreamTermination::TimedOut;
+            self.error = Some(reason.to_owned());
+        }
+        self
+    }
+}
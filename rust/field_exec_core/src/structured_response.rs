@@ -0,0 +1,474 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+
+use crate::json_schema::{Violation, validate};
+use crate::output_schema::BASE_FIELDS;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageRef {
+    pub path: String,
+    pub caption: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Action {
+    pub id: String,
+    pub label: String,
+    pub value: String,
+}
+
+/// The value of a field added by a schema extension.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtensionValue {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    List(Vec<ExtensionValue>),
+    Object(BTreeMap<String, ExtensionValue>),
+}
+
+impl ExtensionValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Integer(i) => Some(*i as f64),
+            Self::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Self::Null => Value::Null,
+            Self::Boolean(b) => Value::Bool(*b),
+            Self::Integer(i) => Value::from(*i),
+            Self::Number(n) => Number::from_f64(*n).map_or(Value::Null, Value::Number),
+            Self::String(s) => Value::String(s.clone()),
+            Self::List(items) => Value::Array(items.iter().map(Self::to_value).collect()),
+            Self::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<&Value> for ExtensionValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Bool(b) => Self::Boolean(*b),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => Self::Integer(i),
+                None => Self::Number(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(s) => Self::String(s.clone()),
+            Value::Array(items) => Self::List(items.iter().map(Self::from).collect()),
+            Value::Object(fields) => Self::Object(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Self::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The agent's final reply under the output schema.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StructuredResponse {
+    pub message: String,
+    pub commit_message: String,
+    pub images: Vec<ImageRef>,
+    pub actions: Vec<Action>,
+    /// Fields from schema extensions that the reply included, by name.
+    pub extensions: BTreeMap<String, ExtensionValue>,
+}
+
+impl StructuredResponse {
+    /// Reads an extension field into a project's own type.
+    pub fn extension<T: DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Option<Result<T, serde_json::Error>> {
+        self.extensions
+            .get(name)
+            .map(|value| serde_json::from_value(value.to_value()))
+    }
+}
+
+/// A tolerant reading applied because the reply drifted from the schema.
+/// Image and action indexes refer to entries as the agent sent them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    /// A string field was missing or not a string and was read as empty.
+    FieldDefaulted {
+        field: String,
+    },
+    /// A property the schema does not define was ignored.
+    PropertyIgnored {
+        field: String,
+    },
+    /// `images` was an object; its values were used as the list.
+    ImagesFromMap,
+    /// `images` was missing or not a list and was read as empty.
+    ImagesDefaulted,
+    /// The entry was wrapped as `{"image": {...}}` or `{"ref": {...}}`.
+    ImageUnwrapped {
+        index: usize,
+        wrapper: String,
+    },
+    /// The entry used an alias such as `file_path` for `path`.
+    ImageKeyAlias {
+        index: usize,
+        from: String,
+        to: String,
+    },
+    /// The entry was a bare path string.
+    ImageFromString {
+        index: usize,
+    },
+    /// The entry was a `[path, caption]` list.
+    ImageFromPair {
+        index: usize,
+    },
+    /// A path or caption that was not a string was converted to one.
+    ImageValueCoerced {
+        index: usize,
+        field: String,
+    },
+    ImageCaptionDefaulted {
+        index: usize,
+    },
+    /// The entry had no usable path and was skipped.
+    ImageDropped {
+        index: usize,
+    },
+    /// `actions` was missing or not a list and was read as empty.
+    ActionsDefaulted,
+    /// The action was not an object or lacked a non-empty id, label or
+    /// value, and was skipped.
+    ActionDropped {
+        index: usize,
+    },
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FieldDefaulted { field } => write!(f, "{field} missing or not a string"),
+            Self::PropertyIgnored { field } => write!(f, "ignored unknown property {field}"),
+            Self::ImagesFromMap => f.write_str("images given as an object"),
+            Self::ImagesDefaulted => f.write_str("images missing or not a list"),
+            Self::ImageUnwrapped { index, wrapper } => {
+                write!(f, "image {index} wrapped in {wrapper:?}")
+            }
+            Self::ImageKeyAlias { index, from, to } => {
+                write!(f, "image {index} used {from:?} for {to:?}")
+            }
+            Self::ImageFromString { index } => write!(f, "image {index} given as a string"),
+            Self::ImageFromPair { index } => write!(f, "image {index} given as a list"),
+            Self::ImageValueCoerced { index, field } => {
+                write!(f, "image {index} {field} was not a string")
+            }
+            Self::ImageCaptionDefaulted { index } => write!(f, "image {index} has no caption"),
+            Self::ImageDropped { index } => write!(f, "image {index} has no path"),
+            Self::ActionsDefaulted => f.write_str("actions missing or not a list"),
+            Self::ActionDropped { index } => write!(f, "action {index} is incomplete"),
+        }
+    }
+}
+
+/// The outcome of checking a final `agent_message` against the output
+/// schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseCheck {
+    /// The reply with fallbacks applied; `None` unless it was a JSON object.
+    pub response: Option<StructuredResponse>,
+    /// The normalized JSON object the response was read from.
+    pub normalized: Option<Map<String, Value>>,
+    /// How the reply as sent breaks the schema.
+    pub violations: Vec<Violation>,
+    /// What is still wrong after the fallbacks.
+    pub remaining: Vec<Violation>,
+    pub fallbacks: Vec<Fallback>,
+}
+
+impl ResponseCheck {
+    /// The reply matched the schema without any help.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Alternative names models use for image keys, in order of preference.
+const PATH_KEYS: [&str; 5] = ["path", "file_path", "filepath", "image_path", "file"];
+const CAPTION_KEYS: [&str; 4] = ["caption", "title", "label", "alt"];
+
+/// Validates an `agent_message` text against `schema`, then applies the
+/// app's tolerant readings and validates again.
+pub fn check_response(text: &str, schema: &Value) -> ResponseCheck {
+    let raw: Value = match serde_json::from_str(text.trim()) {
+        Ok(raw) => raw,
+        Err(e) => {
+            let violation = Violation {
+                path: String::new(),
+                keyword: "json",
+                message: format!("not valid JSON: {e}"),
+            };
+            return ResponseCheck {
+                response: None,
+                normalized: None,
+                violations: vec![violation.clone()],
+                remaining: vec![violation],
+                fallbacks: Vec::new(),
+            };
+        }
+    };
+    let violations = validate(schema, &raw);
+    let Value::Object(object) = raw else {
+        return ResponseCheck {
+            response: None,
+            normalized: None,
+            remaining: violations.clone(),
+            violations,
+            fallbacks: Vec::new(),
+        };
+    };
+    let (normalized, fallbacks) = normalize(object, schema);
+    let remaining = validate(schema, &Value::Object(normalized.clone()));
+    ResponseCheck {
+        response: Some(read_response(&normalized, schema)),
+        normalized: Some(normalized),
+        violations,
+        remaining,
+        fallbacks,
+    }
+}
+
+fn normalize(
+    mut object: Map<String, Value>,
+    schema: &Value,
+) -> (Map<String, Value>, Vec<Fallback>) {
+    let mut fallbacks = Vec::new();
+    if schema.get("additionalProperties") == Some(&Value::Bool(false))
+        && let Some(properties) = schema.get("properties").and_then(Value::as_object)
+    {
+        object.retain(|key, _| {
+            let known = properties.contains_key(key);
+            if !known {
+                fallbacks.push(Fallback::PropertyIgnored { field: key.clone() });
+            }
+            known
+        });
+    }
+    for field in ["message", "commit_message"] {
+        if !object.get(field).is_some_and(Value::is_string) {
+            fallbacks.push(Fallback::FieldDefaulted {
+                field: field.to_owned(),
+            });
+            object.insert(field.to_owned(), Value::String(String::new()));
+        }
+    }
+    let images = normalize_images(object.remove("images"), &mut fallbacks);
+    object.insert("images".to_owned(), images);
+    let actions = normalize_actions(object.remove("actions"), &mut fallbacks);
+    object.insert("actions".to_owned(), actions);
+    (object, fallbacks)
+}
+
+fn normalize_images(raw: Option<Value>, fallbacks: &mut Vec<Fallback>) -> Value {
+    let entries: Vec<Value> = match raw {
+        Some(Value::Array(entries)) => entries,
+        Some(Value::Object(map)) => {
+            fallbacks.push(Fallback::ImagesFromMap);
+            map.into_iter().map(|(_, v)| v).collect()
+        }
+        _ => {
+            fallbacks.push(Fallback::ImagesDefaulted);
+            Vec::new()
+        }
+    };
+    let mut images = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let image = match entry {
+            Value::Object(map) => normalize_image_object(index, map, fallbacks),
+            Value::String(path) => {
+                fallbacks.push(Fallback::ImageFromString { index });
+                Some((path.trim().to_owned(), String::new()))
+            }
+            Value::Array(pair) => {
+                fallbacks.push(Fallback::ImageFromPair { index });
+                let text = |v: Option<&Value>| v.map(coerce_string).unwrap_or_default();
+                let path = text(pair.first()).trim().to_owned();
+                Some((path, text(pair.get(1)).trim().to_owned()))
+            }
+            _ => None,
+        };
+        match image {
+            Some((path, caption)) if !path.trim().is_empty() => {
+                let mut image = Map::new();
+                image.insert("path".to_owned(), Value::String(path));
+                image.insert("caption".to_owned(), Value::String(caption));
+                images.push(Value::Object(image));
+            }
+            _ => fallbacks.push(Fallback::ImageDropped { index }),
+        }
+    }
+    Value::Array(images)
+}
+
+fn normalize_image_object(
+    index: usize,
+    mut map: Map<String, Value>,
+    fallbacks: &mut Vec<Fallback>,
+) -> Option<(String, String)> {
+    for wrapper in ["image", "ref"] {
+        if let Some(Value::Object(inner)) = map.get(wrapper) {
+            fallbacks.push(Fallback::ImageUnwrapped {
+                index,
+                wrapper: wrapper.to_owned(),
+            });
+            map = inner.clone();
+            break;
+        }
+    }
+    let mut field = |keys: &[&str], to: &str| {
+        let (key, value) = keys
+            .iter()
+            .find_map(|k| map.get(*k).filter(|v| !v.is_null()).map(|v| (*k, v)))?;
+        if key != to {
+            fallbacks.push(Fallback::ImageKeyAlias {
+                index,
+                from: key.to_owned(),
+                to: to.to_owned(),
+            });
+        }
+        if !value.is_string() {
+            fallbacks.push(Fallback::ImageValueCoerced {
+                index,
+                field: to.to_owned(),
+            });
+        }
+        Some(coerce_string(value))
+    };
+    let path = field(&PATH_KEYS, "path")?;
+    let caption = match field(&CAPTION_KEYS, "caption") {
+        Some(caption) => caption,
+        None => {
+            fallbacks.push(Fallback::ImageCaptionDefaulted { index });
+            String::new()
+        }
+    };
+    Some((path, caption))
+}
+
+fn normalize_actions(raw: Option<Value>, fallbacks: &mut Vec<Fallback>) -> Value {
+    let Some(Value::Array(entries)) = raw else {
+        fallbacks.push(Fallback::ActionsDefaulted);
+        return Value::Array(Vec::new());
+    };
+    let mut actions = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let complete = entry.as_object().is_some_and(|action| {
+            ["id", "label", "value"].iter().all(|k| {
+                action
+                    .get(*k)
+                    .and_then(Value::as_str)
+                    .is_some_and(|s| !s.is_empty())
+            })
+        });
+        if complete {
+            actions.push(entry);
+        } else {
+            fallbacks.push(Fallback::ActionDropped { index });
+        }
+    }
+    Value::Array(actions)
+}
+
+fn coerce_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn read_response(object: &Map<String, Value>, schema: &Value) -> StructuredResponse {
+    let text = |v: &Value, key: &str| {
+        v.get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let list = |key: &str| {
+        object
+            .get(key)
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default()
+    };
+    StructuredResponse {
+        message: object
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+        commit_message: object
+            .get("commit_message")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned(),
+        images: list("images")
+            .iter()
+            .map(|image| ImageRef {
+                path: text(image, "path"),
+                caption: text(image, "caption"),
+            })
+            .collect(),
+        actions: list("actions")
+            .iter()
+            .map(|action| Action {
+                id: text(action, "id"),
+                label: text(action, "label"),
+                value: text(action, "value"),
+            })
+            .collect(),
+        extensions: schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flat_map(|properties| properties.keys())
+            .filter(|name| !BASE_FIELDS.contains(&name.as_str()))
+            .filter_map(|name| Some((name.clone(), ExtensionValue::from(object.get(name)?))))
+            .collect(),
+    }
+}
@@ -0,0 +1,642 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use std::time::UNIX_EPOCH;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::remote_fs::RemoteFsError;
+use crate::shell::sh_quote;
+
+/// Exit status the scripts use when the sync root does not exist.
+const EXIT_NO_ROOT: u32 = 2;
+/// Exit status when the remote has no usable SHA-256 tool.
+const EXIT_NO_CHECKSUM: u32 = 4;
+
+/// Paths per command line in the batched scripts, to stay far below ARG_MAX.
+const PATHS_PER_COMMAND: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncDirection {
+    /// Local tree to the remote one.
+    Push,
+    /// Remote tree to the local one.
+    Pull,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileMeta {
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+    /// Lowercase hex SHA-256, when it has been computed.
+    pub sha256: Option<String>,
+}
+
+/// Regular files under a sync root, keyed by `/`-separated relative path.
+/// `.git` directories are never part of a tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileTree {
+    pub files: BTreeMap<String, FileMeta>,
+    /// `(directory, contents)` of every `.gitignore`, directory relative to
+    /// the root and empty for the root itself.
+    pub gitignores: Vec<(String, String)>,
+}
+
+impl FileTree {
+    pub fn apply_hashes(&mut self, hashes: BTreeMap<String, String>) {
+        for (path, sha) in hashes {
+            if let Some(meta) = self.files.get_mut(&path) {
+                meta.sha256 = Some(sha);
+            }
+        }
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern
+            .trim()
+            .trim_start_matches("./")
+            .trim_end_matches('/');
+        if pattern.is_empty() {
+            continue;
+        }
+        builder.add(Glob::new(pattern).map_err(|e| format!("bad glob {pattern:?}: {e}"))?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// `path` and each of its parent directories, deepest first.
+fn self_and_parents(path: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(path), |p| p.rfind('/').map(|i| &p[..i]))
+}
+
+/// Decides which relative paths take part in a sync. A path is synced when
+/// it matches an include glob (or there are none), matches no exclude glob
+/// and, if enabled, is not ignored by a `.gitignore`. Globs match the path or
+/// any parent directory, so `node_modules` excludes everything below it.
+#[derive(Clone, Debug)]
+pub struct SyncFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// Deepest directory first, so nested files override their parents.
+    gitignores: Vec<(String, Gitignore)>,
+}
+
+impl SyncFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        let include = glob_set(include)?;
+        Ok(Self {
+            include: (!include.is_empty()).then_some(include),
+            exclude: glob_set(exclude)?,
+            gitignores: Vec::new(),
+        })
+    }
+
+    /// Adds the rules of `.gitignore` files as listed in [`FileTree::gitignores`].
+    pub fn gitignores(mut self, files: &[(String, String)]) -> Self {
+        for (dir, contents) in files {
+            let root = if dir.is_empty() { "." } else { dir.as_str() };
+            let mut builder = GitignoreBuilder::new(root);
+            for line in contents.lines() {
+                // A bad line is skipped, as git itself does.
+                let _ = builder.add_line(None, line);
+            }
+            if let Ok(gitignore) = builder.build() {
+                self.gitignores.push((dir.clone(), gitignore));
+            }
+        }
+        // The root's own file comes last; `""` would otherwise count as one
+        // level deep, like a top-level directory.
+        self.gitignores.sort_by_key(|(dir, _)| {
+            std::cmp::Reverse(if dir.is_empty() {
+                0
+            } else {
+                self_and_parents(dir).count()
+            })
+        });
+        self
+    }
+
+    pub fn allows(&self, path: &str) -> bool {
+        let mut parents = self_and_parents(path);
+        if parents.any(|p| self.exclude.is_match(p)) {
+            return false;
+        }
+        if let Some(include) = &self.include
+            && !self_and_parents(path).any(|p| include.is_match(p))
+        {
+            return false;
+        }
+        !self.is_gitignored(path)
+    }
+
+    fn is_gitignored(&self, path: &str) -> bool {
+        for (dir, gitignore) in &self.gitignores {
+            let under = dir.is_empty()
+                || path
+                    .strip_prefix(dir.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'));
+            if !under {
+                continue;
+            }
+            let m = gitignore.matched_path_or_any_parents(path, false);
+            if m.is_ignore() {
+                return true;
+            }
+            if m.is_whitelist() {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// Why a file is copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyReason {
+    /// Missing on the destination.
+    New,
+    SizeChanged,
+    /// Same size, different SHA-256.
+    ContentChanged,
+    /// Same size and different mtime, with no hashes to compare.
+    MtimeChanged,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncAction {
+    Copy {
+        path: String,
+        size: u64,
+        /// Source mtime, applied to the copy so the next sync sees no change.
+        mtime: i64,
+        reason: CopyReason,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Copies first, then deletions, each sorted by path.
+    pub actions: Vec<SyncAction>,
+    pub unchanged: usize,
+    /// Files on either side left out by the filter.
+    pub filtered: usize,
+}
+
+impl SyncPlan {
+    pub fn copy_bytes(&self) -> u64 {
+        self.actions
+            .iter()
+            .map(|a| match a {
+                SyncAction::Copy { size, .. } => *size,
+                SyncAction::Delete { .. } => 0,
+            })
+            .sum()
+    }
+
+    /// Parent directories the copies need on the destination.
+    pub fn parent_dirs(&self) -> Vec<String> {
+        let dirs: BTreeSet<&str> = self
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                SyncAction::Copy { path, .. } => path.rfind('/').map(|i| &path[..i]),
+                SyncAction::Delete { .. } => None,
+            })
+            .collect();
+        dirs.into_iter().map(str::to_owned).collect()
+    }
+}
+
+/// Files that have the same size on both sides but different mtimes and
+/// are missing a hash, i.e. the ones worth hashing before [`plan`].
+pub fn hash_candidates(source: &FileTree, dest: &FileTree, filter: &SyncFilter) -> Vec<String> {
+    source
+        .files
+        .iter()
+        .filter(|(path, _)| filter.allows(path))
+        .filter_map(|(path, src)| {
+            let dst = dest.files.get(path)?;
+            let undecided = src.size == dst.size
+                && src.mtime != dst.mtime
+                && (src.sha256.is_none() || dst.sha256.is_none());
+            undecided.then(|| path.clone())
+        })
+        .collect()
+}
+
+/// Works out what makes `dest` match `source`. Files outside the filter are
+/// neither copied nor deleted, and nothing is deleted unless `delete` is set.
+pub fn plan(source: &FileTree, dest: &FileTree, filter: &SyncFilter, delete: bool) -> SyncPlan {
+    let mut plan = SyncPlan::default();
+    for (path, src) in &source.files {
+        if !filter.allows(path) {
+            plan.filtered += 1;
+            continue;
+        }
+        let reason = match dest.files.get(path) {
+            None => Some(CopyReason::New),
+            Some(dst) if dst.size != src.size => Some(CopyReason::SizeChanged),
+            Some(dst) => match (&src.sha256, &dst.sha256) {
+                (Some(a), Some(b)) if a != b => Some(CopyReason::ContentChanged),
+                (Some(_), Some(_)) => None,
+                _ if dst.mtime != src.mtime => Some(CopyReason::MtimeChanged),
+                _ => None,
+            },
+        };
+        match reason {
+            Some(reason) => plan.actions.push(SyncAction::Copy {
+                path: path.clone(),
+                size: src.size,
+                mtime: src.mtime,
+                reason,
+            }),
+            None => plan.unchanged += 1,
+        }
+    }
+    for path in dest.files.keys() {
+        if source.files.contains_key(path) {
+            continue;
+        }
+        if !filter.allows(path) {
+            plan.filtered += 1;
+        } else if delete {
+            plan.actions.push(SyncAction::Delete { path: path.clone() });
+        }
+    }
+    plan
+}
+
+fn cd_root(root: &str) -> String {
+    format!(
+        r#"cd {} 2>/dev/null || {{ echo {} >&2; exit {EXIT_NO_ROOT}; }}"#,
+        sh_quote(root),
+        sh_quote(root)
+    )
+}
+
+/// Picks the first SHA-256 tool present; each prints `<hex> <path>` lines.
+fn hash_tool() -> String {
+    [
+        "if command -v sha256sum >/dev/null 2>&1; then hash='sha256sum'",
+        "elif command -v shasum >/dev/null 2>&1; then hash='shasum -a 256'",
+        "elif command -v openssl >/dev/null 2>&1; then hash='openssl dgst -sha256 -r'",
+        &format!("else exit {EXIT_NO_CHECKSUM}; fi"),
+    ]
+    .join("\n")
+}
+
+fn sync_error(exit_status: u32, stderr: &str) -> RemoteFsError {
+    let stderr = stderr.trim();
+    match exit_status {
+        EXIT_NO_ROOT => RemoteFsError::RootNotFound(stderr.to_owned()),
+        EXIT_NO_CHECKSUM => RemoteFsError::Failed {
+            exit_status,
+            message: "remote has no sha256sum, shasum or openssl".to_owned(),
+        },
+        exit_status => RemoteFsError::Failed {
+            exit_status,
+            message: stderr.to_owned(),
+        },
+    }
+}
+
+/// Parses a `<hex> <path>` line of `sha256sum`, `shasum` or `openssl -r`.
+fn parse_hash_line(line: &str) -> Option<(String, String)> {
+    let (sha, rest) = line.split_at_checked(64)?;
+    if !sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let path = rest.strip_prefix(' ')?;
+    let path = path.strip_prefix([' ', '*']).unwrap_or(path);
+    Some((
+        path.trim_start_matches("./").to_owned(),
+        sha.to_ascii_lowercase(),
+    ))
+}
+
+/// A manifest path with its leading `./` dropped, as long as it stays below
+/// the root: only plain names, no `..`, no root and nothing empty.
+fn relative_path(path: &str) -> Option<&str> {
+    let path = path.trim_start_matches("./");
+    let plain = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    plain.then_some(path)
+}
+
+/// Lists a remote tree in one command: size and mtime of every regular
+/// file, optionally every file's SHA-256, and optionally the contents of
+/// every `.gitignore`.
+///
+/// Output records are `F size mtime path` and `H sha path` lines, and
+/// `I length path` lines each followed by that many bytes and a newline.
+/// A path that would leave the root makes the output malformed.
+#[derive(Clone, Debug)]
+pub struct RemoteManifest {
+    root: String,
+    checksums: bool,
+    gitignore: bool,
+}
+
+impl RemoteManifest {
+    pub fn new(root: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            checksums: false,
+            gitignore: false,
+        }
+    }
+
+    /// Hash every file up front instead of only the undecided ones.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    pub fn gitignore(mut self, gitignore: bool) -> Self {
+        self.gitignore = gitignore;
+        self
+    }
+
+    pub fn script(&self) -> String {
+        const FILES: &str = r"find . -name .git -prune -o -type f";
+        let mut lines = vec![
+            cd_root(&self.root),
+            "if stat -c %s . >/dev/null 2>&1; then".to_owned(),
+            format!("  {FILES} -exec stat -c '%s %Y %n' {{}} + | sed 's/^/F /'"),
+            "else".to_owned(),
+            format!("  {FILES} -exec stat -f '%z %m %N' {{}} + | sed 's/^/F /'"),
+            "fi".to_owned(),
+        ];
+        if self.checksums {
+            lines.push(hash_tool());
+            lines.push(format!(r"{FILES} -exec $hash {{}} + | sed 's/^/H /'"));
+        }
+        if self.gitignore {
+            lines.push(format!(
+                r#"{FILES} -name .gitignore -print | while IFS= read -r f; do printf 'I %s %s\n' "$(wc -c < "$f" | tr -d ' ')" "$f"; cat "$f"; echo; done"#
+            ));
+        }
+        lines.join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &[u8],
+        stderr: &str,
+    ) -> Result<FileTree, RemoteFsError> {
+        if exit_status != 0 {
+            return Err(sync_error(exit_status, stderr));
+        }
+        let mut tree = FileTree::default();
+        let mut hashes = BTreeMap::new();
+        let mut rest = stdout;
+        while !rest.is_empty() {
+            let end = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+            let line = String::from_utf8_lossy(&rest[..end]).into_owned();
+            rest = rest.get(end + 1..).unwrap_or_default();
+            let malformed = || RemoteFsError::Malformed(line.clone());
+            if let Some(record) = line.strip_prefix("F ") {
+                let mut fields = record.splitn(3, ' ');
+                let (Some(size), Some(mtime), Some(path)) = (
+                    fields.next().and_then(|v| v.parse::<u64>().ok()),
+                    fields.next().and_then(|v| v.parse::<i64>().ok()),
+                    fields.next().and_then(relative_path),
+                ) else {
+                    return Err(malformed());
+                };
+                tree.files.insert(
+                    path.to_owned(),
+                    FileMeta {
+                        size,
+                        mtime,
+                        sha256: None,
+                    },
+                );
+            } else if let Some(record) = line.strip_prefix("H ") {
+                let (path, sha) = parse_hash_line(record).ok_or_else(malformed)?;
+                relative_path(&path).ok_or_else(malformed)?;
+                hashes.insert(path, sha);
+            } else if let Some(record) = line.strip_prefix("I ") {
+                let (Some(len), Some(path)) =
+                    record.split_once(' ').map_or((None, None), |(len, path)| {
+                        (len.parse::<usize>().ok(), Some(path))
+                    })
+                else {
+                    return Err(malformed());
+                };
+                let contents = rest.get(..len).ok_or_else(malformed)?;
+                let path = relative_path(path).ok_or_else(malformed)?;
+                let dir = path.strip_suffix(".gitignore").unwrap_or_default();
+                tree.gitignores.push((
+                    dir.trim_end_matches('/').to_owned(),
+                    String::from_utf8_lossy(contents).into_owned(),
+                ));
+                rest = rest.get(len + 1..).unwrap_or_default();
+            } else if !line.is_empty() {
+                return Err(malformed());
+            }
+        }
+        tree.apply_hashes(hashes);
+        Ok(tree)
+    }
+}
+
+/// Hashes the given files of a remote tree in one command.
+#[derive(Clone, Debug)]
+pub struct RemoteHashes {
+    root: String,
+    paths: Vec<String>,
+}
+
+impl RemoteHashes {
+    pub fn new(root: impl Into<String>, paths: Vec<String>) -> Self {
+        Self {
+            root: root.into(),
+            paths,
+        }
+    }
+
+    pub fn script(&self) -> String {
+        let mut lines = vec![cd_root(&self.root), hash_tool()];
+        for batch in self.paths.chunks(PATHS_PER_COMMAND) {
+            let args: Vec<String> = batch.iter().map(|p| sh_quote(&format!("./{p}"))).collect();
+            lines.push(format!("$hash {}", args.join(" ")));
+        }
+        lines.join("\n")
+    }
+
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<BTreeMap<String, String>, RemoteFsError> {
+        // A file that vanished since the manifest fails the whole plan: the
+        // tree changed under it.
+        if exit_status != 0 {
+            return Err(sync_error(exit_status, stderr));
+        }
+        Ok(stdout.lines().filter_map(parse_hash_line).collect())
+    }
+}
+
+/// Creates the parent directories a sync's copies need.
+pub fn mkdirs_script(root: &str, dirs: &[String]) -> String {
+    let mut lines = vec![cd_root(root)];
+    for batch in dirs.chunks(PATHS_PER_COMMAND) {
+        let args: Vec<String> = batch.iter().map(|p| sh_quote(&format!("./{p}"))).collect();
+        lines.push(format!("mkdir -p {} || exit 1", args.join(" ")));
+    }
+    lines.join("\n")
+}
+
+/// Deletes files of a remote tree, then any directories that became empty.
+pub fn delete_script(root: &str, paths: &[String]) -> String {
+    let mut lines = vec![cd_root(root)];
+    for batch in paths.chunks(PATHS_PER_COMMAND) {
+        let args: Vec<String> = batch.iter().map(|p| sh_quote(&format!("./{p}"))).collect();
+        lines.push(format!("rm -f {} || exit 1", args.join(" ")));
+    }
+    let dirs: BTreeSet<&str> = paths
+        .iter()
+        .filter_map(|p| p.rfind('/').map(|i| &p[..i]))
+        .collect();
+    for dir in dirs.into_iter().rev() {
+        lines.push(format!(
+            "rmdir -p {} 2>/dev/null",
+            sh_quote(&format!("./{dir}"))
+        ));
+    }
+    lines.push("exit 0".to_owned());
+    lines.join("\n")
+}
+
+/// Sets mtimes of copied files, with GNU `touch -d @secs` or, failing
+/// that, BSD `touch -t`.
+pub fn touch_script(root: &str, files: &[(String, i64)]) -> String {
+    let mut lines = vec![
+        cd_root(root),
+        r#"t() { touch -c -m -d "@$1" "$2" 2>/dev/null || touch -c -m -t "$(date -r "$1" +%Y%m%d%H%M.%S)" "$2"; }"#
+            .to_owned(),
+    ];
+    for (path, mtime) in files {
+        lines.push(format!("t {mtime} {}", sh_quote(&format!("./{path}"))));
+    }
+    lines.push("exit 0".to_owned());
+    lines.join("\n")
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// Hashes `paths` below `root`, the local counterpart of [`RemoteHashes`].
+/// A file that cannot be read fails the whole batch.
+pub fn local_hashes(
+    root: &Path,
+    paths: impl IntoIterator<Item = String>,
+) -> io::Result<BTreeMap<String, String>> {
+    paths
+        .into_iter()
+        .map(|path| match sha256_file(&root.join(&path)) {
+            Ok(sha) => Ok((path, sha)),
+            Err(e) => Err(in_file(&path, e)),
+        })
+        .collect()
+}
+
+fn in_file(path: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{path}: {e}"))
+}
+
+/// Lists a local tree the way [`RemoteManifest`] lists a remote one.
+/// Symlinks are skipped, as `find -type f` does.
+pub fn local_tree(root: &Path, checksums: bool, gitignore: bool) -> io::Result<FileTree> {
+    let mut tree = FileTree::default();
+    let mut pending = vec![String::new()];
+    while let Some(rel) = pending.pop() {
+        let dir = if rel.is_empty() {
+            root.to_path_buf()
+        } else {
+            root.join(&rel)
+        };
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) if rel.is_empty() => return Err(e),
+            Err(_) => continue,
+        };
+        for entry in read_dir.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = if rel.is_empty() {
+                name.clone()
+            } else {
+                format!("{rel}/{name}")
+            };
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                if name != ".git" {
+                    pending.push(path);
+                }
+                continue;
+            }
+            if !meta.is_file() {
+                continue;
+            }
+            if gitignore && name == ".gitignore" {
+                let contents = fs::read_to_string(entry.path()).map_err(|e| in_file(&path, e))?;
+                tree.gitignores.push((rel.clone(), contents));
+            }
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX));
+            let sha256 = if checksums {
+                Some(sha256_file(&entry.path()).map_err(|e| in_file(&path, e))?)
+            } else {
+                None
+            };
+            tree.files.insert(
+                path,
+                FileMeta {
+                    size: meta.len(),
+                    mtime,
+                    sha256,
+                },
+            );
+        }
+    }
+    Ok(tree)
+}
@@ -0,0 +1,537 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_write::sha256_hex;
+use crate::shell::{SHA256_FN, sh_quote};
+
+/// Exit status the scripts use when the file to read does not exist.
+const EXIT_NOT_FOUND: u32 = 2;
+/// Exit status when the `.partial` file no longer holds the verified prefix.
+const EXIT_PARTIAL_MISMATCH: u32 = 3;
+/// Exit status when the remote has no usable SHA-256 tool.
+const EXIT_NO_CHECKSUM: u32 = 4;
+/// Exit status when a chunk does not hash to what was sent.
+const EXIT_CHECKSUM_MISMATCH: u32 = 5;
+/// Exit status when the source file changed since the transfer started.
+const EXIT_SOURCE_CHANGED: u32 = 6;
+
+/// Chunk size when the caller does not ask for one.
+pub const DEFAULT_CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+pub const MIN_CHUNK_BYTES: u64 = 64 * 1024;
+pub const MAX_CHUNK_BYTES: u64 = 64 * 1024 * 1024;
+
+const JOURNAL_VERSION: u32 = 1;
+
+/// Where a resumable transfer keeps its bytes until the last chunk is in.
+pub fn partial_path(path: &str) -> String {
+    format!("{path}.partial")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResumeError {
+    SourceNotFound(String),
+    /// The source's size or mtime differs from the journal; start over.
+    SourceChanged,
+    /// The `.partial` file is missing, short, or differs from the recorded
+    /// chunks; start over.
+    PartialMismatch(String),
+    ChecksumUnavailable,
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    Malformed(String),
+    Failed {
+        exit_status: u32,
+        message: String,
+    },
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SourceNotFound(path) => write!(f, "no such file: {path}"),
+            Self::SourceChanged => f.write_str("source file changed during the transfer"),
+            Self::PartialMismatch(msg) => write!(f, "partial file does not match journal: {msg}"),
+            Self::ChecksumUnavailable => {
+                f.write_str("remote has no sha256sum, shasum or openssl to verify chunks")
+            }
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "chunk checksum mismatch (expected {expected}, got {actual})"
+                )
+            }
+            Self::Malformed(msg) => write!(f, "unexpected remote output: {msg}"),
+            Self::Failed {
+                exit_status,
+                message,
+            } if message.is_empty() => write!(f, "remote command failed (exit={exit_status})"),
+            Self::Failed {
+                exit_status,
+                message,
+            } => write!(f, "remote command failed (exit={exit_status}): {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ResumeError {}
+
+fn script_error(exit_status: u32, stderr: &str) -> ResumeError {
+    let stderr = stderr.trim();
+    match exit_status {
+        EXIT_NOT_FOUND => ResumeError::SourceNotFound(stderr.to_owned()),
+        EXIT_PARTIAL_MISMATCH => ResumeError::PartialMismatch(stderr.to_owned()),
+        EXIT_NO_CHECKSUM => ResumeError::ChecksumUnavailable,
+        EXIT_SOURCE_CHANGED => ResumeError::SourceChanged,
+        exit_status => ResumeError::Failed {
+            exit_status,
+            message: stderr.to_owned(),
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+/// Identifies the version of the source file a journal was written for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceFingerprint {
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: i64,
+}
+
+/// One chunk of a transfer: bytes `offset..offset + len` of the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkSpan {
+    pub index: usize,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl ChunkSpan {
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// Stable journal key for one transfer; asking for the same transfer again
+/// finds the same journal.
+pub fn journal_key(
+    direction: TransferDirection,
+    host: &str,
+    port: u16,
+    username: &str,
+    local_path: &str,
+    remote_path: &str,
+) -> String {
+    let direction = match direction {
+        TransferDirection::Upload => "upload",
+        TransferDirection::Download => "download",
+    };
+    let port = port.to_string();
+    let id = [direction, host, &port, username, local_path, remote_path].join("\0");
+    sha256_hex(id.as_bytes())[..32].to_owned()
+}
+
+/// Progress of a resumable transfer, persisted after every verified chunk.
+///
+/// `chunks` holds the SHA-256 of each chunk already in the `.partial` file,
+/// in order, so the verified prefix is always a whole number of chunks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferJournal {
+    pub version: u32,
+    pub key: String,
+    pub direction: TransferDirection,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub local_path: String,
+    pub remote_path: String,
+    pub chunk_bytes: u64,
+    pub source: SourceFingerprint,
+    pub chunks: Vec<String>,
+    /// Seconds since the Unix epoch of the last change.
+    pub updated_at: u64,
+}
+
+impl TransferJournal {
+    pub fn new(
+        direction: TransferDirection,
+        host: impl Into<String>,
+        port: u16,
+        username: impl Into<String>,
+        local_path: impl Into<String>,
+        remote_path: impl Into<String>,
+    ) -> Self {
+        let host = host.into();
+        let username = username.into();
+        let local_path = local_path.into();
+        let remote_path = remote_path.into();
+        Self {
+            version: JOURNAL_VERSION,
+            key: journal_key(direction, &host, port, &username, &local_path, &remote_path),
+            direction,
+            host,
+            port,
+            username,
+            local_path,
+            remote_path,
+            chunk_bytes: DEFAULT_CHUNK_BYTES,
+            source: SourceFingerprint { size: 0, mtime: 0 },
+            chunks: Vec::new(),
+            updated_at: 0,
+        }
+    }
+
+    /// Only applies before the first chunk; a journal keeps its chunk size.
+    pub fn chunk_bytes(mut self, chunk_bytes: Option<u64>) -> Self {
+        if self.chunks.is_empty() {
+            self.chunk_bytes = chunk_bytes
+                .unwrap_or(DEFAULT_CHUNK_BYTES)
+                .clamp(MIN_CHUNK_BYTES, MAX_CHUNK_BYTES);
+        }
+        self
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self, String> {
+        let journal: Self = serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+        if journal.version != JOURNAL_VERSION {
+            return Err(format!("unsupported journal version {}", journal.version));
+        }
+        if journal.chunk_bytes == 0 {
+            return Err("journal has no chunk size".to_owned());
+        }
+        Ok(journal)
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).unwrap_or_default()
+    }
+
+    /// The partial file that is written on the receiving side.
+    pub fn partial_path(&self) -> String {
+        match self.direction {
+            TransferDirection::Upload => partial_path(&self.remote_path),
+            TransferDirection::Download => partial_path(&self.local_path),
+        }
+    }
+
+    /// Removes an upload's partial file on the remote.
+    pub fn remove_partial_script(&self) -> String {
+        format!("rm -f -- {}", sh_quote(&self.partial_path()))
+    }
+
+    /// Bytes known to be in the partial file and to match the source.
+    pub fn verified_offset(&self) -> u64 {
+        (self.chunks.len() as u64)
+            .saturating_mul(self.chunk_bytes)
+            .min(self.source.size)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.verified_offset() == self.source.size
+    }
+
+    fn span(&self, index: usize) -> ChunkSpan {
+        let offset = (index as u64)
+            .saturating_mul(self.chunk_bytes)
+            .min(self.source.size);
+        ChunkSpan {
+            index,
+            offset,
+            len: self.chunk_bytes.min(self.source.size - offset),
+        }
+    }
+
+    pub fn next_chunk(&self) -> Option<ChunkSpan> {
+        (!self.is_complete()).then(|| self.span(self.chunks.len()))
+    }
+
+    /// The newest verified chunk, which a resume re-checks before trusting
+    /// the partial file.
+    pub fn last_chunk(&self) -> Option<(ChunkSpan, &str)> {
+        let sha = self.chunks.last()?;
+        Some((self.span(self.chunks.len() - 1), sha))
+    }
+
+    pub fn record_chunk(&mut self, span: ChunkSpan, sha256: String) {
+        if span.index == self.chunks.len() {
+            self.chunks.push(sha256);
+        }
+    }
+
+    /// Forgets every chunk, e.g. because the source or the partial changed.
+    pub fn restart(&mut self, source: SourceFingerprint) {
+        self.source = source;
+        self.chunks.clear();
+    }
+}
+
+/// Prints `size mtime` for `path`, the fingerprint of a remote source.
+pub fn stat_script(path: &str) -> String {
+    [
+        format!("f={}", sh_quote(path)),
+        format!(
+            r#"[ -f "$f" ] || {{ echo {} >&2; exit {EXIT_NOT_FOUND}; }}"#,
+            sh_quote(path)
+        ),
+        r#"stat -L -c '%s %Y' "$f" 2>/dev/null || stat -L -f '%z %m' "$f""#.to_owned(),
+    ]
+    .join("\n")
+}
+
+pub fn parse_stat(
+    exit_status: u32,
+    stdout: &str,
+    stderr: &str,
+) -> Result<SourceFingerprint, ResumeError> {
+    if exit_status != 0 {
+        return Err(script_error(exit_status, stderr));
+    }
+    let mut fields = stdout.split_whitespace();
+    match (
+        fields.next().and_then(|v| v.parse::<u64>().ok()),
+        fields.next().and_then(|v| v.parse::<i64>().ok()),
+    ) {
+        (Some(size), Some(mtime)) => Ok(SourceFingerprint { size, mtime }),
+        _ => Err(ResumeError::Malformed(stdout.trim().to_owned())),
+    }
+}
+
+/// Checks that a remote partial file still holds `span` with the recorded
+/// hash, before an upload resumes after it.
+#[derive(Clone, Debug)]
+pub struct ChunkCheck {
+    partial: String,
+    span: ChunkSpan,
+    sha256: String,
+}
+
+impl ChunkCheck {
+    pub fn new(partial: impl Into<String>, span: ChunkSpan, sha256: impl Into<String>) -> Self {
+        Self {
+            partial: partial.into(),
+            span,
+            sha256: sha256.into(),
+        }
+    }
+
+    pub fn script(&self) -> String {
+        [
+            format!("p={}", sh_quote(&self.partial)),
+            format!(
+                r#"[ -f "$p" ] || {{ echo "partial file is missing" >&2; exit {EXIT_PARTIAL_MISMATCH}; }}"#
+            ),
+            r#"size=$(wc -c < "$p" | tr -d ' ')"#.to_owned(),
+            format!(
+                r#"[ "$size" -ge {} ] || {{ echo "partial file has only $size bytes" >&2; exit {EXIT_PARTIAL_MISMATCH}; }}"#,
+                self.span.end()
+            ),
+            SHA256_FN.to_owned(),
+            format!(
+                r#"sum=$(tail -c +{} "$p" | head -c {} | sha256_of)"#,
+                self.span.offset + 1,
+                self.span.len
+            ),
+            format!(r#"[ -n "$sum" ] || exit {EXIT_NO_CHECKSUM}"#),
+            format!(
+                r#"[ "$sum" = {} ] || {{ echo "chunk {} changed" >&2; exit {EXIT_PARTIAL_MISMATCH}; }}"#,
+                self.sha256, self.span.index
+            ),
+        ]
+        .join("\n")
+    }
+
+    pub fn parse_result(&self, exit_status: u32, stderr: &str) -> Result<(), ResumeError> {
+        match exit_status {
+            0 => Ok(()),
+            exit_status => Err(script_error(exit_status, stderr)),
+        }
+    }
+}
+
+/// Writes one chunk, read from stdin, into a remote partial file at its
+/// offset. Anything past the offset is cut off first, so a chunk that was
+/// half-written before a disconnect is simply replaced.
+#[derive(Clone, Debug)]
+pub struct ChunkWrite {
+    partial: String,
+    span: ChunkSpan,
+    sha256: String,
+}
+
+impl ChunkWrite {
+    pub fn new(partial: impl Into<String>, span: ChunkSpan, chunk: &[u8]) -> Self {
+        Self {
+            partial: partial.into(),
+            span,
+            sha256: sha256_hex(chunk),
+        }
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn script(&self) -> String {
+        let offset = self.span.offset;
+        [
+            format!("p={}", sh_quote(&self.partial)),
+            format!("expected={}", self.sha256),
+            r#"dir=$(dirname "$p")"#.to_owned(),
+            r#"mkdir -p "$dir" || exit 1"#.to_owned(),
+            r#"tmp=$(mktemp "$dir/.$(basename "$p").XXXXXX") || exit 1"#.to_owned(),
+            r#"trap 'rm -f "$tmp"' EXIT"#.to_owned(),
+            r#"cat > "$tmp" || exit 1"#.to_owned(),
+            SHA256_FN.to_owned(),
+            r#"sum=$(sha256_of "$tmp")"#.to_owned(),
+            format!(r#"[ -n "$sum" ] || exit {EXIT_NO_CHECKSUM}"#),
+            format!(
+                r#"[ "$sum" = "$expected" ] || {{ echo "$sum" >&2; exit {EXIT_CHECKSUM_MISMATCH}; }}"#
+            ),
+            r#"[ -f "$p" ] || : > "$p" || exit 1"#.to_owned(),
+            r#"size=$(wc -c < "$p" | tr -d ' ')"#.to_owned(),
+            format!(
+                r#"[ "$size" -ge {offset} ] || {{ echo "partial file has only $size bytes" >&2; exit {EXIT_PARTIAL_MISMATCH}; }}"#
+            ),
+            format!(
+                r#"if [ "$size" -gt {offset} ]; then dd if=/dev/null of="$p" bs=1 seek={offset} count=0 2>/dev/null || exit 1; fi"#
+            ),
+            r#"cat "$tmp" >> "$p" || exit 1"#.to_owned(),
+        ]
+        .join("\n")
+    }
+
+    pub fn parse_result(&self, exit_status: u32, stderr: &str) -> Result<(), ResumeError> {
+        match exit_status {
+            0 => Ok(()),
+            EXIT_CHECKSUM_MISMATCH => Err(ResumeError::ChecksumMismatch {
+                expected: self.sha256.clone(),
+                actual: stderr.trim().to_owned(),
+            }),
+            exit_status => Err(script_error(exit_status, stderr)),
+        }
+    }
+}
+
+/// Reads one chunk of a remote source for a download. The script prints the
+/// chunk's SHA-256 on the first line and then the raw bytes, and refuses to
+/// run if the source no longer matches the journal's fingerprint.
+#[derive(Clone, Debug)]
+pub struct ChunkRead {
+    path: String,
+    span: ChunkSpan,
+    source: SourceFingerprint,
+}
+
+impl ChunkRead {
+    pub fn new(path: impl Into<String>, span: ChunkSpan, source: SourceFingerprint) -> Self {
+        Self {
+            path: path.into(),
+            span,
+            source,
+        }
+    }
+
+    pub fn script(&self) -> String {
+        let range = format!(
+            r#"tail -c +{} "$f" | head -c {}"#,
+            self.span.offset + 1,
+            self.span.len
+        );
+        [
+            format!("f={}", sh_quote(&self.path)),
+            format!(
+                r#"[ -f "$f" ] || {{ echo {} >&2; exit {EXIT_NOT_FOUND}; }}"#,
+                sh_quote(&self.path)
+            ),
+            r#"meta=$(stat -L -c '%s %Y' "$f" 2>/dev/null || stat -L -f '%z %m' "$f") || exit 1"#
+                .to_owned(),
+            format!(
+                r#"[ "$meta" = "{} {}" ] || exit {EXIT_SOURCE_CHANGED}"#,
+                self.source.size, self.source.mtime
+            ),
+            SHA256_FN.to_owned(),
+            format!("sum=$({range} | sha256_of)"),
+            format!(r#"[ -n "$sum" ] || exit {EXIT_NO_CHECKSUM}"#),
+            r#"echo "$sum""#.to_owned(),
+            range,
+        ]
+        .join("\n")
+    }
+
+    /// Returns the chunk's bytes and SHA-256 once they hash to what the
+    /// remote computed.
+    pub fn parse_output(
+        &self,
+        exit_status: u32,
+        mut stdout: Vec<u8>,
+        stderr: &str,
+    ) -> Result<(Vec<u8>, String), ResumeError> {
+        if exit_status != 0 {
+            return Err(script_error(exit_status, stderr));
+        }
+        let newline = stdout
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| ResumeError::Malformed("missing chunk checksum".to_owned()))?;
+        let expected = String::from_utf8_lossy(&stdout[..newline])
+            .trim()
+            .to_owned();
+        let data = stdout.split_off(newline + 1);
+        let actual = sha256_hex(&data);
+        if actual != expected || data.len() as u64 != self.span.len {
+            return Err(ResumeError::ChecksumMismatch { expected, actual });
+        }
+        Ok((data, actual))
+    }
+}
+
+/// Moves a finished remote partial file into place once it has the
+/// expected size.
+#[derive(Clone, Debug)]
+pub struct FinishUpload {
+    partial: String,
+    remote_path: String,
+    size: u64,
+}
+
+impl FinishUpload {
+    pub fn new(journal: &TransferJournal) -> Self {
+        Self {
+            partial: journal.partial_path(),
+            remote_path: journal.remote_path.clone(),
+            size: journal.source.size,
+        }
+    }
+
+    pub fn script(&self) -> String {
+        [
+            format!("p={}", sh_quote(&self.partial)),
+            format!("dst={}", sh_quote(&self.remote_path)),
+            r#"mkdir -p "$(dirname "$p")" || exit 1"#.to_owned(),
+            r#"[ -f "$p" ] || : > "$p" || exit 1"#.to_owned(),
+            r#"size=$(wc -c < "$p" | tr -d ' ')"#.to_owned(),
+            format!(
+                r#"[ "$size" -eq {} ] || {{ echo "partial file has $size bytes" >&2; exit {EXIT_PARTIAL_MISMATCH}; }}"#,
+                self.size
+            ),
+            r#"mv -f "$p" "$dst" || exit 1"#.to_owned(),
+        ]
+        .join("\n")
+    }
+
+    pub fn parse_result(&self, exit_status: u32, stderr: &str) -> Result<(), ResumeError> {
+        match exit_status {
+            0 => Ok(()),
+            exit_status => Err(script_error(exit_status, stderr)),
+        }
+    }
+}
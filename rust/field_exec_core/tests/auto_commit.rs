@@ -0,0 +1,247 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{TempDir, git, git_init, sh};
+use field_exec_core::auto_commit::{
+    AutoCommit, CommitReport, CommitResult, SkipReason, commit_message_from_status,
+};
+use field_exec_core::job::JobLiveness;
+use field_exec_core::job_status::{JobOutcome, JobStatus};
+use field_exec_core::output_schema::base_schema;
+use field_exec_core::structured_response::check_response;
+
+/// The status of an exited job whose final message was `reply`.
+fn status(outcome: JobOutcome, reply: Option<&str>) -> JobStatus {
+    JobStatus {
+        liveness: JobLiveness::Exited,
+        outcome,
+        heartbeat: None,
+        alarm: None,
+        idle_secs: None,
+        thread_id: None,
+        final_message: reply.map(str::to_owned),
+        response: reply.map(|reply| check_response(reply, &base_schema())),
+    }
+}
+
+fn reply(commit_message: &str) -> String {
+    serde_json::json!({
+        "message": "done",
+        "commit_message": commit_message,
+        "images": [],
+        "extra": true,
+    })
+    .to_string()
+}
+
+/// A repository on `branch` with one commit and an uncommitted change,
+/// plus files under `.field_exec/` that must never be committed.
+fn repo(name: &str, branch: &str) -> TempDir {
+    let dir = TempDir::new(&format!("commit_{name}"));
+    assert!(fs::create_dir_all(dir.join(".field_exec/sessions")).is_ok());
+    assert!(fs::create_dir_all(dir.join("src/.field_exec")).is_ok());
+    git_init(&dir, branch);
+    assert!(fs::write(dir.join("README"), "one\n").is_ok());
+    git("add README", &dir);
+    git("commit -q -m init", &dir);
+    assert!(fs::write(dir.join("README"), "two\n").is_ok());
+    assert!(fs::write(dir.join("src/new file.rs"), "fn main() {}\n").is_ok());
+    assert!(fs::write(dir.join(".field_exec/sessions/tab.log"), "{}\n").is_ok());
+    assert!(fs::write(dir.join("src/.field_exec/x"), "x\n").is_ok());
+    dir
+}
+
+fn run(commit: &AutoCommit, dir: &Path) -> CommitReport {
+    let (code, out, err) = sh(&commit.script(), dir);
+    match commit.parse_output(code, &out, &err) {
+        Ok(report) => report,
+        Err(e) => panic!("{e}: {}", String::from_utf8_lossy(&out)),
+    }
+}
+
+fn files(report: &CommitReport) -> Vec<&str> {
+    let mut files: Vec<&str> = report.files.iter().map(String::as_str).collect();
+    files.sort_unstable();
+    files
+}
+
+#[test]
+fn commit_message_needs_a_completed_job() {
+    let done = status(JobOutcome::Completed, Some(&reply("  Fix\n the\tparser ")));
+    assert_eq!(
+        commit_message_from_status(&done),
+        Ok("Fix the parser".to_owned())
+    );
+
+    let blank = status(JobOutcome::Completed, Some(&reply(" \n ")));
+    assert_eq!(
+        commit_message_from_status(&blank),
+        Err(SkipReason::EmptyMessage)
+    );
+    let plain = status(JobOutcome::Completed, Some("not json"));
+    assert_eq!(
+        commit_message_from_status(&plain),
+        Err(SkipReason::EmptyMessage)
+    );
+
+    // An earlier run's reply never stands in for this one.
+    let stale = reply("old");
+    for outcome in [
+        JobOutcome::Running,
+        JobOutcome::Crashed { message: None },
+        JobOutcome::Failed {
+            message: "boom".to_owned(),
+        },
+        JobOutcome::Cancelled,
+    ] {
+        let status = status(outcome, Some(&stale));
+        assert_eq!(
+            commit_message_from_status(&status),
+            Err(SkipReason::JobNotCompleted)
+        );
+    }
+}
+
+#[test]
+fn commits_changes_without_field_exec_and_reports_the_sha() {
+    let dir = repo("commit", "feature/x");
+    let commit = AutoCommit::new(dir.to_string_lossy(), "Update\n readme")
+        .protected_branches(vec!["master".to_owned(), "release/*".to_owned()]);
+    let report = run(&commit, &dir);
+
+    let head = git("rev-parse HEAD", &dir);
+    assert_eq!(report.result, CommitResult::Committed { sha: head });
+    assert_eq!(report.branch.as_deref(), Some("feature/x"));
+    assert_eq!(files(&report), ["README", "src/new file.rs"]);
+    assert_eq!(git("log -1 --format=%s", &dir), "Update readme");
+    assert_eq!(git("status --porcelain", &dir), "");
+    let exclude = fs::read_to_string(dir.join(".git/info/exclude")).unwrap_or_default();
+    assert_eq!(
+        exclude.lines().filter(|l| *l == "**/.field_exec/").count(),
+        1
+    );
+
+    // The exclusion is added once, and a clean tree has nothing to commit.
+    assert_eq!(
+        run(&commit, &dir).result,
+        CommitResult::Skipped(SkipReason::NoChanges)
+    );
+    let exclude = fs::read_to_string(dir.join(".git/info/exclude")).unwrap_or_default();
+    assert_eq!(
+        exclude.lines().filter(|l| *l == "**/.field_exec/").count(),
+        1
+    );
+}
+
+#[test]
+fn tracked_field_exec_files_stay_out() {
+    let dir = repo("tracked", "work");
+    git("add -f .field_exec/sessions/tab.log", &dir);
+    git("commit -q -m tracked", &dir);
+    assert!(fs::write(dir.join(".field_exec/sessions/tab.log"), "{}\n{}\n").is_ok());
+
+    let report = run(&AutoCommit::new(dir.to_string_lossy(), "Work"), &dir);
+    assert!(matches!(report.result, CommitResult::Committed { .. }));
+    assert_eq!(files(&report), ["README", "src/new file.rs"]);
+}
+
+#[test]
+fn protected_branches_are_refused() {
+    let dir = repo("protected", "release/1.2");
+    let before = git("rev-parse HEAD", &dir);
+    for pattern in ["release/*", "release/1.?"] {
+        let commit = AutoCommit::new(dir.to_string_lossy(), "Nope")
+            .protected_branches(vec!["master".to_owned(), pattern.to_owned()]);
+        let report = run(&commit, &dir);
+        assert_eq!(
+            report.result,
+            CommitResult::Protected {
+                pattern: pattern.to_owned()
+            }
+        );
+        assert_eq!(report.branch.as_deref(), Some("release/1.2"));
+    }
+    assert_eq!(git("rev-parse HEAD", &dir), before);
+
+    // Only `*` and `?` are wildcards.
+    let commit = AutoCommit::new(dir.to_string_lossy(), "Yes").protected_branches(vec![
+        "release/[0-9].2".to_owned(),
+        "$(touch pwned)".to_owned(),
+    ]);
+    assert!(matches!(
+        run(&commit, &dir).result,
+        CommitResult::Committed { .. }
+    ));
+    assert!(!dir.join("pwned").exists());
+}
+
+#[test]
+fn dry_run_lists_staged_files_and_leaves_the_index_alone() {
+    let dir = repo("dry", "work");
+    git("add README", &dir);
+    let status = git("status --porcelain", &dir);
+
+    let commit = AutoCommit::new(dir.to_string_lossy(), "Dry").dry_run(true);
+    let report = run(&commit, &dir);
+    assert_eq!(report.result, CommitResult::DryRun);
+    assert_eq!(files(&report), ["README", "src/new file.rs"]);
+    assert_eq!(git("status --porcelain", &dir), status);
+    assert_eq!(git("rev-list --count HEAD", &dir), "1");
+    let exclude = fs::read_to_string(dir.join(".git/info/exclude")).unwrap_or_default();
+    assert!(!exclude.contains("**/.field_exec/"));
+}
+
+#[test]
+fn unborn_repository_gets_its_first_commit() {
+    let dir = TempDir::new("commit_unborn");
+    assert!(fs::create_dir_all(dir.join(".field_exec")).is_ok());
+    git_init(&dir, "main");
+    assert!(fs::write(dir.join("a.txt"), "a\n").is_ok());
+    assert!(fs::write(dir.join(".field_exec/env.sh"), "\n").is_ok());
+
+    let dry = run(
+        &AutoCommit::new(dir.to_string_lossy(), "First").dry_run(true),
+        &dir,
+    );
+    assert_eq!(dry.result, CommitResult::DryRun);
+    assert_eq!(files(&dry), ["a.txt"]);
+    let report = run(&AutoCommit::new(dir.to_string_lossy(), "First"), &dir);
+    assert!(matches!(report.result, CommitResult::Committed { .. }));
+    assert_eq!(files(&report), ["a.txt"]);
+}
+
+#[test]
+fn skips_and_failures_are_reported() {
+    let dir = repo("skips", "work");
+    let plain = TempDir::new("commit_plain");
+    let report = run(&AutoCommit::new(plain.to_string_lossy(), "x"), &plain);
+    assert_eq!(report, CommitReport::skipped(SkipReason::NotARepository));
+
+    let missing = AutoCommit::new("/nonexistent/field_exec", "x");
+    let (code, out, err) = sh(&missing.script(), &dir);
+    assert!(missing.parse_output(code, &out, &err).is_err());
+
+    git("checkout -q --detach", &dir);
+    let report = run(&AutoCommit::new(dir.to_string_lossy(), "x"), &dir);
+    assert_eq!(
+        report.result,
+        CommitResult::Skipped(SkipReason::DetachedHead)
+    );
+    assert_eq!(report.branch, None);
+
+    git("checkout -q work", &dir);
+    let hook = dir.join(".git/hooks/pre-commit");
+    assert!(fs::write(&hook, "#!/bin/sh\necho 'lint failed' >&2\nexit 1\n").is_ok());
+    git("config core.hooksPath .git/hooks", &dir);
+    sh("chmod +x .git/hooks/pre-commit", &dir);
+    let report = run(&AutoCommit::new(dir.to_string_lossy(), "x"), &dir);
+    assert_eq!(
+        report.result,
+        CommitResult::Failed {
+            message: "lint failed".to_owned()
+        }
+    );
+    assert_eq!(report.branch.as_deref(), Some("work"));
+}
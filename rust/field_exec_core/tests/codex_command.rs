@@ -0,0 +1,222 @@
+use std::process::Command;
+
+use field_exec_core::codex_command::{
+    ApprovalPolicy, CodexCommand, CodexCommandError, SandboxMode, TomlValue, prompt_stdin,
+    shell_join,
+};
+use proptest::prelude::{Strategy, any, prop_assert_eq, prop_oneof, proptest};
+use proptest::{collection, option};
+
+/// Runs `command` through `sh -c` with `printf` in front and returns the
+/// words the shell passed to it.
+fn sh_words(command: &str) -> Vec<String> {
+    let out = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("printf '%s\\0' {command}"))
+        .output()
+        .unwrap_or_else(|e| panic!("spawn sh: {e}"));
+    assert!(out.status.success(), "sh failed: {out:?}");
+    let mut words: Vec<String> = out
+        .stdout
+        .split(|b| *b == 0)
+        .map(|w| String::from_utf8_lossy(w).into_owned())
+        .collect();
+    // The trailing NUL leaves an empty last piece.
+    words.pop();
+    words
+}
+
+/// Any string a process argument can hold: everything but NUL.
+fn arg() -> impl Strategy<Value = String> {
+    any::<String>().prop_map(|s| s.replace('\0', ""))
+}
+
+fn shellish() -> impl Strategy<Value = String> {
+    prop_oneof![arg(), "[ -~\n\t'\"\\\\$`!*?]{0,12}"]
+}
+
+fn toml_value() -> impl Strategy<Value = TomlValue> {
+    let leaf = prop_oneof![
+        any::<String>().prop_map(TomlValue::String),
+        any::<i64>().prop_map(TomlValue::Integer),
+        any::<bool>().prop_map(TomlValue::Boolean),
+        (-1e12f64..1e12).prop_map(TomlValue::Float),
+    ];
+    leaf.prop_recursive(3, 16, 4, |inner| {
+        prop_oneof![
+            collection::vec(inner.clone(), 0..4).prop_map(TomlValue::Array),
+            collection::btree_map(any::<String>(), inner, 0..4)
+                .prop_map(|m| TomlValue::Table(m.into_iter().collect())),
+        ]
+    })
+}
+
+fn to_toml(value: &TomlValue) -> toml::Value {
+    match value {
+        TomlValue::String(s) => toml::Value::String(s.clone()),
+        TomlValue::Integer(i) => toml::Value::Integer(*i),
+        TomlValue::Float(f) => toml::Value::Float(*f),
+        TomlValue::Boolean(b) => toml::Value::Boolean(*b),
+        TomlValue::Array(items) => toml::Value::Array(items.iter().map(to_toml).collect()),
+        TomlValue::Table(entries) => toml::Value::Table(
+            entries
+                .iter()
+                .map(|(k, v)| (k.clone(), to_toml(v)))
+                .collect(),
+        ),
+    }
+}
+
+proptest! {
+    #[test]
+    fn shell_join_round_trips_through_sh(words in collection::vec(shellish(), 1..6)) {
+        prop_assert_eq!(sh_words(&shell_join(&words)), words);
+    }
+
+    #[test]
+    fn shell_string_round_trips_to_argv(
+        cd in option::of(shellish()),
+        schema in option::of(shellish()),
+        model in option::of(shellish()),
+        config in collection::vec(("[a-z_]{1,8}(\\.[a-z_-]{1,8})?", arg()), 0..3),
+        images in collection::vec(arg().prop_map(|s| s.replace(',', "")), 0..3),
+        resume in option::of("[0-9a-f-]{0,12}".prop_map(|s| s.trim_start_matches('-').to_owned())),
+    ) {
+        let mut cmd = CodexCommand::new()
+            .cd(cd)
+            .output_schema(schema)
+            .model(model)
+            .resume(resume)
+            .sandbox(Some(SandboxMode::WorkspaceWrite))
+            .approval(Some(ApprovalPolicy::Never));
+        for (key, value) in config {
+            cmd = cmd.config(key, value);
+        }
+        for image in images {
+            cmd = cmd.image(image);
+        }
+        let argv = cmd.argv().unwrap_or_else(|e| panic!("{e}"));
+        let shell = cmd.shell_string().unwrap_or_else(|e| panic!("{e}"));
+        prop_assert_eq!(sh_words(&shell), argv);
+    }
+
+    #[test]
+    fn toml_values_parse_back(value in toml_value()) {
+        let doc = format!("v = {}", value.render());
+        let parsed: toml::Table = doc
+            .parse()
+            .unwrap_or_else(|e| panic!("{doc}: {e}"));
+        prop_assert_eq!(parsed.get("v"), Some(&to_toml(&value)));
+    }
+}
+
+#[test]
+fn renders_all_options_in_order() {
+    let cmd = CodexCommand::new()
+        .config("model_reasoning_effort", "high")
+        .config("sandbox_workspace_write.network_access", true)
+        .cd(Some("/work/my app".to_owned()))
+        .skip_git_repo_check(true)
+        .output_schema(Some("/work/my app/.field_exec/schema.json".to_owned()))
+        .model(Some("gpt-5-codex".to_owned()))
+        .profile(Some("ci".to_owned()))
+        .sandbox(Some(SandboxMode::WorkspaceWrite))
+        .approval(Some(ApprovalPolicy::OnRequest))
+        .image("/tmp/screen.png")
+        .resume(Some("0199a213-81c0-7800-8aa1-bbab2a035a53".to_owned()));
+    assert_eq!(
+        cmd.args(),
+        Ok(vec![
+            "exec".to_owned(),
+            "-c".to_owned(),
+            "model_reasoning_effort=\"high\"".to_owned(),
+            "-c".to_owned(),
+            "sandbox_workspace_write.network_access=true".to_owned(),
+            "-c".to_owned(),
+            "approval_policy=\"on-request\"".to_owned(),
+            "--cd=/work/my app".to_owned(),
+            "--skip-git-repo-check".to_owned(),
+            "--json".to_owned(),
+            "--output-schema=/work/my app/.field_exec/schema.json".to_owned(),
+            "--model=gpt-5-codex".to_owned(),
+            "--profile=ci".to_owned(),
+            "--sandbox=workspace-write".to_owned(),
+            "--image=/tmp/screen.png".to_owned(),
+            "resume".to_owned(),
+            "0199a213-81c0-7800-8aa1-bbab2a035a53".to_owned(),
+            "-".to_owned(),
+        ])
+    );
+    assert_eq!(
+        CodexCommand::new().json(false).shell_string(),
+        Ok("codex exec -".to_owned())
+    );
+}
+
+#[test]
+fn rejects_arguments_codex_would_misread() {
+    assert_eq!(
+        CodexCommand::new().config("bad key", 1i64).args(),
+        Err(CodexCommandError::InvalidConfigKey("bad key".to_owned()))
+    );
+    assert_eq!(
+        CodexCommand::new().config("a..b", 1i64).args(),
+        Err(CodexCommandError::InvalidConfigKey("a..b".to_owned()))
+    );
+    assert_eq!(
+        CodexCommand::new().image("a,b.png").args(),
+        Err(CodexCommandError::ImagePathHasComma("a,b.png".to_owned()))
+    );
+    assert_eq!(
+        CodexCommand::new().resume(Some("--last".to_owned())).args(),
+        Err(CodexCommandError::InvalidThreadId("--last".to_owned()))
+    );
+}
+
+#[test]
+fn toml_rendering() {
+    assert_eq!(
+        TomlValue::from("a\"b\\c\n\u{1}").render(),
+        r#""a\"b\\c\n\u0001""#
+    );
+    assert_eq!(TomlValue::Float(1.0).render(), "1.0");
+    assert_eq!(TomlValue::Float(f64::NEG_INFINITY).render(), "-inf");
+    assert_eq!(
+        TomlValue::Table(vec![
+            ("plain".to_owned(), TomlValue::Integer(1)),
+            (
+                "needs quotes".to_owned(),
+                TomlValue::Array(vec![true.into()])
+            ),
+        ])
+        .render(),
+        r#"{plain = 1, "needs quotes" = [true]}"#
+    );
+}
+
+#[test]
+fn prompt_is_newline_terminated() {
+    assert_eq!(prompt_stdin("fix it"), "fix it\n");
+    assert_eq!(prompt_stdin("fix it\n"), "fix it\n");
+}
+
+#[test]
+fn modes_deserialize_from_their_flag_values() {
+    for mode in [
+        SandboxMode::ReadOnly,
+        SandboxMode::WorkspaceWrite,
+        SandboxMode::DangerFullAccess,
+    ] {
+        let parsed = serde_json::from_value::<SandboxMode>(mode.as_str().into());
+        assert_eq!(parsed.ok(), Some(mode));
+    }
+    for policy in [
+        ApprovalPolicy::Untrusted,
+        ApprovalPolicy::OnFailure,
+        ApprovalPolicy::OnRequest,
+        ApprovalPolicy::Never,
+    ] {
+        let parsed = serde_json::from_value::<ApprovalPolicy>(policy.as_str().into());
+        assert_eq!(parsed.ok(), Some(policy));
+    }
+}
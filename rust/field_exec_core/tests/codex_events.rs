@@ -0,0 +1,234 @@
+use field_exec_core::codex_events::{
+    ChangeKind, CodexEvent, CodexEventStream, CodexLine, FileUpdate, ItemDetails, ItemStatus,
+    TodoItem, Usage, parse_log,
+};
+
+const COMPLETED: &str = include_str!("fixtures/codex_turn_completed.jsonl");
+const FAILED: &str = include_str!("fixtures/codex_turn_failed.jsonl");
+
+fn events(lines: Vec<CodexLine>) -> Vec<CodexEvent> {
+    lines
+        .into_iter()
+        .filter_map(|line| match line {
+            CodexLine::Event(event) => Some(event),
+            CodexLine::Text(_) => None,
+        })
+        .collect()
+}
+
+#[test]
+fn completed_turn_is_fully_typed() {
+    let events = events(parse_log(COMPLETED.as_bytes()));
+    assert_eq!(events.len(), 16);
+    assert_eq!(
+        events[0],
+        CodexEvent::ThreadStarted {
+            thread_id: "0199a213-81c0-7800-8aa1-bbab2a035a53".to_owned()
+        }
+    );
+    assert_eq!(events[1], CodexEvent::TurnStarted);
+    assert!(
+        !events
+            .iter()
+            .any(|e| matches!(e, CodexEvent::Unknown { .. }))
+    );
+    assert!(
+        !events
+            .iter()
+            .filter_map(CodexEvent::item)
+            .any(|item| matches!(item.details, ItemDetails::Unknown { .. }))
+    );
+
+    let CodexEvent::ItemCompleted(failed_run) = &events[4] else {
+        panic!("expected item.completed, got {:?}", events[4]);
+    };
+    assert_eq!(failed_run.id, "item_1");
+    let ItemDetails::CommandExecution(cmd) = &failed_run.details else {
+        panic!("expected command_execution");
+    };
+    assert_eq!(cmd.command, "bash -lc 'cargo test -p api'");
+    assert_eq!(cmd.exit_code, Some(101));
+    assert_eq!(cmd.status, ItemStatus::Failed);
+    assert!(cmd.aggregated_output.contains("parse::dates ... FAILED"));
+
+    let CodexEvent::ItemCompleted(patch) = &events[6] else {
+        panic!("expected item.completed, got {:?}", events[6]);
+    };
+    let ItemDetails::FileChange(change) = &patch.details else {
+        panic!("expected file_change");
+    };
+    assert_eq!(change.status, ItemStatus::Completed);
+    assert_eq!(
+        change.changes,
+        vec![
+            FileUpdate {
+                path: "/work/api/src/parse.rs".to_owned(),
+                kind: ChangeKind::Update,
+            },
+            FileUpdate {
+                path: "/work/api/tests/dates.rs".to_owned(),
+                kind: ChangeKind::Add,
+            },
+        ]
+    );
+
+    let CodexEvent::ItemUpdated(todo) = &events[7] else {
+        panic!("expected item.updated, got {:?}", events[7]);
+    };
+    assert_eq!(
+        todo.details,
+        ItemDetails::TodoList {
+            items: vec![
+                TodoItem {
+                    text: "Fix date parsing".to_owned(),
+                    completed: true,
+                },
+                TodoItem {
+                    text: "Re-run api tests".to_owned(),
+                    completed: false,
+                },
+            ]
+        }
+    );
+
+    let CodexEvent::ItemCompleted(mcp) = &events[9] else {
+        panic!("expected item.completed, got {:?}", events[9]);
+    };
+    let ItemDetails::McpToolCall(call) = &mcp.details else {
+        panic!("expected mcp_tool_call");
+    };
+    assert_eq!(
+        (call.server.as_str(), call.tool.as_str()),
+        ("docs", "search")
+    );
+    assert_eq!(call.arguments["query"], "chrono NaiveDate parse_from_str");
+    assert_eq!(call.status, ItemStatus::Completed);
+    assert!(call.result.is_some());
+    assert_eq!(call.error_message(), None);
+
+    assert_eq!(
+        events[10].item().map(|item| &item.details),
+        Some(&ItemDetails::WebSearch {
+            query: "chrono %-d format specifier".to_owned()
+        })
+    );
+
+    let reply = events[14].item().and_then(|item| item.agent_text());
+    let reply: serde_json::Value = serde_json::from_str(reply.unwrap_or_default())
+        .unwrap_or_else(|e| panic!("agent_message is not JSON: {e}"));
+    assert_eq!(reply["commit_message"], "Fix parsing of single-digit days");
+
+    assert_eq!(
+        events[15],
+        CodexEvent::TurnCompleted {
+            usage: Some(Usage {
+                input_tokens: 24763,
+                cached_input_tokens: 24448,
+                output_tokens: 1122,
+            })
+        }
+    );
+}
+
+#[test]
+fn failed_turn_keeps_unknowns_and_text() {
+    let lines = parse_log(FAILED.as_bytes());
+    assert_eq!(
+        lines[0],
+        CodexLine::Text("Reading prompt from stdin...".to_owned())
+    );
+    assert_eq!(
+        lines.last(),
+        Some(&CodexLine::Event(CodexEvent::TurnFailed {
+            message: "stream disconnected before completion: Transport error".to_owned()
+        }))
+    );
+    // Blank lines are dropped; JSON without a type is just text.
+    assert_eq!(lines.len(), 12);
+    assert_eq!(
+        lines[10],
+        CodexLine::Text(r#"{"not":"an event"}"#.to_owned())
+    );
+
+    let events = events(lines);
+    let CodexEvent::Unknown { event_type, raw } = &events[2] else {
+        panic!("expected unknown event, got {:?}", events[2]);
+    };
+    assert_eq!(event_type, "session.configured");
+    assert_eq!(raw["model"], "gpt-5-codex");
+
+    // Legacy field and type names still map to an agent message.
+    assert_eq!(
+        events[3].item().and_then(|i| i.agent_text()),
+        Some("Looking into it.")
+    );
+
+    let Some(ItemDetails::Unknown { item_type, raw }) = events[4].item().map(|i| &i.details) else {
+        panic!("expected unknown item, got {:?}", events[4]);
+    };
+    assert_eq!(item_type, "image_generation");
+    assert_eq!(raw["prompt"], "architecture diagram");
+
+    // Unknown fields are ignored on known items.
+    let Some(ItemDetails::CommandExecution(cmd)) = events[5].item().map(|i| &i.details) else {
+        panic!("expected command_execution, got {:?}", events[5]);
+    };
+    assert_eq!(cmd.status, ItemStatus::Declined);
+    assert_eq!(cmd.exit_code, None);
+
+    assert_eq!(
+        events[6].item().map(|i| &i.details),
+        Some(&ItemDetails::Error {
+            message: "command timed out after 600s".to_owned()
+        })
+    );
+    assert_eq!(
+        events[7],
+        CodexEvent::Error {
+            message: "Reconnecting... 1/5".to_owned()
+        }
+    );
+}
+
+#[test]
+fn unknown_status_and_kind_do_not_fail_the_item() {
+    let line = r#"{"type":"item.completed","item":{"id":"item_9","type":"file_change","changes":[{"path":"a","kind":"rename"}],"status":"partially_applied"}}"#;
+    let Some(CodexLine::Event(event)) = CodexLine::parse(line) else {
+        panic!("expected an event");
+    };
+    let Some(ItemDetails::FileChange(change)) = event.item().map(|i| &i.details) else {
+        panic!("expected file_change, got {event:?}");
+    };
+    assert_eq!(change.status, ItemStatus::Unknown);
+    assert_eq!(change.changes[0].kind, ChangeKind::Unknown);
+}
+
+#[test]
+fn stream_matches_whole_log_for_any_chunking() {
+    let whole = parse_log(COMPLETED.as_bytes());
+    // Put a multi-byte character in the middle of a line so some splits cut
+    // through it.
+    let text = COMPLETED.replace("single-digit", "single\u{2011}digit");
+    let whole_text = parse_log(text.as_bytes());
+    assert_eq!(whole.len(), whole_text.len());
+    for chunk_size in [1, 2, 3, 7, 64, 4096] {
+        let mut stream = CodexEventStream::new();
+        let mut lines = Vec::new();
+        for chunk in text.as_bytes().chunks(chunk_size) {
+            lines.extend(stream.push(chunk));
+        }
+        lines.extend(stream.finish());
+        assert_eq!(lines, whole_text, "chunk size {chunk_size}");
+    }
+}
+
+#[test]
+fn last_line_without_newline_is_parsed_on_finish() {
+    let mut stream = CodexEventStream::new();
+    assert!(stream.push(br#"{"type":"turn.started"}"#).is_empty());
+    assert_eq!(
+        stream.finish(),
+        Some(CodexLine::Event(CodexEvent::TurnStarted))
+    );
+    assert_eq!(stream.finish(), None);
+}
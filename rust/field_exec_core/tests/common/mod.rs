@@ -0,0 +1,130 @@
+//! Helpers for the tests that run the generated scripts in scratch
+//! directories under the system temp dir.
+
+// Each test crate uses only some of these.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use field_exec_core::job::{JobLiveness, RemoteJobId, parse_status, status_script};
+
+/// Runs `script` with `/bin/sh -c` in `dir`.
+pub fn sh(script: &str, dir: &Path) -> (u32, Vec<u8>, String) {
+    sh_input(script, dir, b"")
+}
+
+/// Runs `script` with `/bin/sh -c` in `dir`, feeding it `input` on stdin.
+pub fn sh_input(script: &str, dir: &Path, input: &[u8]) -> (u32, Vec<u8>, String) {
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(script)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => return (u32::MAX, Vec::new(), e.to_string()),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // A script that exits early leaves the rest unread.
+        let input = input.to_vec();
+        std::thread::spawn(move || stdin.write_all(&input));
+    }
+    match child.wait_with_output() {
+        Ok(out) => (
+            out.status.code().unwrap_or(-1) as u32,
+            out.stdout,
+            String::from_utf8_lossy(&out.stderr).into_owned(),
+        ),
+        Err(e) => (u32::MAX, Vec::new(), e.to_string()),
+    }
+}
+
+/// A fresh directory that is removed again when dropped, even when the
+/// test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("field_exec_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(fs::create_dir_all(&dir).is_ok());
+        Self(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A project whose `.field_exec/env.sh` points `CODEX_BIN` at `codex`.
+pub fn project(name: &str, codex: &str) -> TempDir {
+    let dir = TempDir::new(name);
+    assert!(fs::create_dir_all(dir.join(".field_exec")).is_ok());
+    let bin = dir.join(".field_exec/fake-codex");
+    assert!(fs::write(&bin, codex).is_ok());
+    let env_sh = format!("CODEX_BIN={}\n", bin.display());
+    assert!(fs::write(dir.join(".field_exec/env.sh"), env_sh).is_ok());
+    sh("chmod +x .field_exec/fake-codex", &dir);
+    dir
+}
+
+/// Runs `git` in `dir` and returns its trimmed stdout.
+pub fn git(args: &str, dir: &Path) -> String {
+    let (code, out, err) = sh(&format!("git {args}"), dir);
+    assert_eq!(code, 0, "git {args}: {err}");
+    String::from_utf8_lossy(&out).trim().to_owned()
+}
+
+/// Makes `dir` a repository on `branch` that can commit without a global
+/// git identity.
+pub fn git_init(dir: &Path, branch: &str) {
+    git(&format!("init -q -b {branch}"), dir);
+    git("config user.name t", dir);
+    git("config user.email t@example.com", dir);
+}
+
+/// Polls `check` until it returns something, for up to ten seconds.
+pub fn wait_for<T>(mut check: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(value) = check() {
+            return Some(value);
+        }
+        sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+pub fn liveness(job: &RemoteJobId, dir: &Path) -> Option<JobLiveness> {
+    let (code, out, err) = sh(&status_script(job), dir);
+    parse_status(code, &String::from_utf8_lossy(&out), &err).ok()
+}
+
+pub fn wait_exited(job: &RemoteJobId, dir: &Path) -> bool {
+    wait_for(|| (liveness(job, dir) == Some(JobLiveness::Exited)).then_some(())).is_some()
+}
+
+/// An `item.completed` log event carrying the agent's message.
+pub fn agent(text: &str) -> String {
+    let item = serde_json::json!({"id": "i1", "type": "agent_message", "text": text});
+    serde_json::json!({"type": "item.completed", "item": item}).to_string()
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::{TempDir, sh};
+use field_exec_core::compress::{gunzip_output, gzip_command};
+
+const COMMAND: &str = "printf 'héllo\\n'; seq 1 2000; echo oops >&2; exit 3";
+
+#[test]
+fn gzip_command_round_trips_through_sh() {
+    let dir = TempDir::new("gzip_round_trip");
+    let (code, out, err) = sh(&gzip_command(COMMAND), &dir);
+    assert_eq!((code, err.as_str()), (3, "oops\n"));
+    let Ok((raw, stats)) = gunzip_output(&out) else {
+        panic!("compressed output");
+    };
+    let (_, plain, _) = sh(COMMAND, &dir);
+    assert_eq!(raw, plain);
+    assert!(stats.compressed);
+    assert_eq!(stats.raw_bytes, plain.len() as u64);
+    assert!(stats.wire_bytes < stats.raw_bytes);
+}
+
+#[test]
+fn gzip_command_falls_back_to_raw_output_without_gzip() {
+    let dir = TempDir::new("gzip_fallback");
+    // Only shell builtins are left to run.
+    let script = format!("PATH=/nonexistent\n{}", gzip_command("echo plain; exit 5"));
+    let (code, out, _) = sh(&script, &dir);
+    assert_eq!(code, 5);
+    let Ok((raw, stats)) = gunzip_output(&out) else {
+        panic!("raw output");
+    };
+    assert_eq!(raw, b"plain\n");
+    assert!(!stats.compressed);
+    assert_eq!((stats.wire_bytes, stats.raw_bytes), (6, 6));
+}
+
+#[test]
+fn output_without_a_marker_is_rejected() {
+    assert!(gunzip_output(b"plain\n").is_err());
+}
@@ -0,0 +1,289 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{TempDir, git_init, liveness, wait_exited, wait_for};
+use field_exec_core::codex_command::{CodexCommand, DEFAULT_DEVELOPER_INSTRUCTIONS};
+use field_exec_core::job::{
+    JobError, JobLaunch, JobLiveness, RemoteJobId, SessionFiles, stop_script, tmux_window_name,
+};
+use field_exec_core::shell::sh_quote;
+
+/// Stands in for `codex`: records its arguments and stdin, then prints two
+/// events, the last without a trailing newline.
+const FAKE_CODEX: &str = r#"#!/bin/sh
+printf '%s\0' "$@" > .field_exec/tmp/args
+cat > .field_exec/tmp/stdin
+if [ -n "${FAKE_SLEEP:-}" ]; then sleep "$FAKE_SLEEP"; fi
+echo '{"type":"thread.started","thread_id":"t1"}'
+printf '%s' '{"type":"turn.completed"}'
+"#;
+
+fn project(name: &str) -> TempDir {
+    let dir = common::project(&format!("job_{name}"), FAKE_CODEX);
+    let env_sh = dir.join(".field_exec/env.sh");
+    let env = fs::read_to_string(&env_sh).unwrap_or_default();
+    assert!(fs::write(&env_sh, format!("{env}export FROM_ENV_SH=1\n")).is_ok());
+    dir
+}
+
+/// Runs `script` in `dir`, with stdout as text.
+fn sh(script: &str, dir: &Path) -> (u32, String, String) {
+    let (code, out, err) = common::sh(script, dir);
+    (code, String::from_utf8_lossy(&out).into_owned(), err)
+}
+
+/// Runs the launch script in `dir` with the prompt on stdin.
+fn launch_in(launch: &JobLaunch, dir: &Path) -> (u32, String, String) {
+    let script = launch.script().unwrap_or_default();
+    let (code, out, err) = common::sh_input(&script, dir, launch.input().as_bytes());
+    (code, String::from_utf8_lossy(&out).into_owned(), err)
+}
+
+#[test]
+fn job_ids_round_trip() {
+    for id in ["tmux:__field_exec__:cr_13ab51_0b7c41d29f", "pid:4242"] {
+        assert_eq!(
+            RemoteJobId::parse(id).map(|j| j.to_string()),
+            Some(id.to_owned())
+        );
+    }
+    assert_eq!(RemoteJobId::parse(" pid:77\n"), Some(RemoteJobId::Pid(77)));
+    for bad in [
+        "",
+        "tmux:",
+        "tmux:s",
+        "tmux::w",
+        "tmux:s:w:x",
+        "pid:",
+        "pid:1",
+        "pid:x",
+        "77",
+    ] {
+        assert_eq!(RemoteJobId::parse(bad), None, "{bad:?}");
+    }
+}
+
+#[test]
+fn window_names_match_the_app() {
+    assert_eq!(
+        tmux_window_name("me@host:22", "/srv/app", "0b7c-41d2-9f00-aa"),
+        "cr_13ab51_0b7c41d29f"
+    );
+    assert_eq!(
+        tmux_window_name("me@host:22", "/srv/app", "ab"),
+        "cr_13ab51_ab"
+    );
+}
+
+#[test]
+fn session_files_live_under_field_exec() {
+    let files = SessionFiles::new("/srv/app/", "t1");
+    assert_eq!(files.log, "/srv/app/.field_exec/sessions/t1.log");
+    assert_eq!(
+        files.stderr_log,
+        "/srv/app/.field_exec/sessions/t1.stderr.log"
+    );
+    assert_eq!(files.job, "/srv/app/.field_exec/sessions/t1.job");
+    assert_eq!(files.prompt, "/srv/app/.field_exec/sessions/t1.prompt");
+    assert_eq!(files.run, "/srv/app/.field_exec/sessions/t1.sh");
+    assert_eq!(files.heartbeat, "/srv/app/.field_exec/sessions/t1.hb");
+}
+
+#[test]
+fn rejects_tab_ids_that_are_not_file_names() {
+    for tab in ["", "../x", "a b", "a/b"] {
+        assert_eq!(
+            JobLaunch::new("/srv/app", tab, "hi").script(),
+            Err(JobError::InvalidTabId(tab.to_owned()))
+        );
+    }
+}
+
+#[test]
+fn missing_project_is_reported() {
+    let launch = JobLaunch::new("/nonexistent/field_exec", "t1", "hi").use_tmux(false);
+    let script = launch.script().unwrap_or_default();
+    let (code, out, err) = sh(&script, &std::env::temp_dir());
+    assert!(launch.parse_output(code, &out, &err).is_err());
+    assert_eq!(code, 2);
+}
+
+#[test]
+fn nohup_job_runs_codex_with_merged_instructions() {
+    let dir = project("run");
+    let dir_str = dir.to_string_lossy().into_owned();
+    assert!(
+        fs::write(
+            dir.join(".field_exec/developer_instructions.txt"),
+            "Use tabs.\n'''x"
+        )
+        .is_ok()
+    );
+    git_init(&dir, "main");
+    sh("git commit -q --allow-empty -m init", &dir);
+
+    let schema = r#"{"type":"object"}"#;
+    let launch = JobLaunch::new(dir_str.clone(), "tab-1", "Fix 'it' $HOME")
+        .command(CodexCommand::new().resume(Some("thread-9".to_owned())))
+        .output_schema(Some(schema.to_owned()))
+        .log_preamble(Some(r#"{"type":"client.user_message"}"#.to_owned()))
+        .use_tmux(false);
+    let (code, out, err) = launch_in(&launch, &dir);
+    let job = launch.parse_output(code, &out, &err);
+    let Ok(job @ RemoteJobId::Pid(_)) = job else {
+        panic!("expected a pid job, got {job:?} ({err})");
+    };
+    let files = launch.files();
+    assert_eq!(
+        fs::read_to_string(&files.job).ok(),
+        Some(format!("{job}\n"))
+    );
+    assert!(wait_exited(&job, &dir));
+
+    let tmp = dir.join(".field_exec/tmp");
+    let stdin = fs::read_to_string(tmp.join("stdin")).unwrap_or_default();
+    assert_eq!(stdin, "Fix 'it' $HOME\n");
+    let args = fs::read_to_string(tmp.join("args")).unwrap_or_default();
+    let args: Vec<&str> = args.trim_end_matches('\0').split('\0').collect();
+    assert_eq!(args[0], "exec");
+    assert_eq!(args[1], "-c");
+    let instructions = args[2]
+        .strip_prefix("developer_instructions='''\n")
+        .unwrap_or_default();
+    assert!(instructions.starts_with(DEFAULT_DEVELOPER_INSTRUCTIONS.trim_end()));
+    assert!(
+        instructions.ends_with("Use tabs.\n''\u{2019}x\n'''"),
+        "{instructions:?}"
+    );
+    assert_eq!(
+        &args[3..],
+        [
+            format!("--cd={dir_str}").as_str(),
+            "--json",
+            format!("--output-schema={dir_str}/.field_exec/output-schema.json").as_str(),
+            "resume",
+            "thread-9",
+            "-",
+        ]
+    );
+    let written = fs::read_to_string(dir.join(".field_exec/output-schema.json"));
+    assert_eq!(written.ok().as_deref(), Some(schema));
+
+    let log = fs::read_to_string(&files.log).unwrap_or_default();
+    assert_eq!(
+        log,
+        concat!(
+            "{\"type\":\"client.user_message\"}\n",
+            "{\"type\":\"server.job_started\"}\n",
+            "{\"type\":\"server.gitignore_bootstrap\",\"status\":\"committed\"}\n",
+            "{\"type\":\"thread.started\",\"thread_id\":\"t1\"}\n",
+            "{\"type\":\"turn.completed\"}\n",
+        )
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join(".gitignore")).ok().as_deref(),
+        Some("**/.field_exec/\n")
+    );
+    let (_, status, _) = sh("git status --porcelain", &dir);
+    assert_eq!(status, "");
+
+    // A second run finds .gitignore already set up.
+    let (code, out, err) = launch_in(&launch, &dir);
+    let job = launch.parse_output(code, &out, &err);
+    assert!(
+        job.as_ref().is_ok_and(|job| wait_exited(job, &dir)),
+        "{job:?}"
+    );
+    let log = fs::read_to_string(&files.log).unwrap_or_default();
+    assert_eq!(log.matches("gitignore_bootstrap").count(), 1);
+}
+
+#[test]
+fn prompts_beyond_the_argument_limit_go_through_stdin() {
+    let dir = project("big_prompt");
+    // Linux caps a single argument at 128 KiB.
+    let prompt = "Fix it. ".repeat(32 * 1024);
+    let launch = JobLaunch::new(dir.to_string_lossy(), "big", prompt.clone()).use_tmux(false);
+    let Ok(script) = launch.script() else {
+        panic!("valid launch");
+    };
+    assert!(!script.contains("Fix it."));
+    // The run script is written once and started from its file.
+    let Ok(run) = launch.run_script() else {
+        panic!("valid run script");
+    };
+    assert_eq!(script.matches(&sh_quote(&run)).count(), 1);
+
+    let (code, out, err) = launch_in(&launch, &dir);
+    let job = launch.parse_output(code, &out, &err);
+    assert!(
+        job.as_ref().is_ok_and(|job| wait_exited(job, &dir)),
+        "{job:?} ({err})"
+    );
+    let files = launch.files();
+    assert_eq!(
+        fs::read_to_string(&files.run).ok(),
+        Some(format!("{run}\n"))
+    );
+    let stdin = fs::read_to_string(dir.join(".field_exec/tmp/stdin")).unwrap_or_default();
+    assert_eq!(stdin.len(), prompt.len() + 1);
+    assert!(stdin == format!("{prompt}\n"));
+}
+
+#[test]
+fn stopping_a_nohup_job_ends_its_children() {
+    let dir = project("stop");
+    let env_sh = dir.join(".field_exec/env.sh");
+    let env = fs::read_to_string(&env_sh).unwrap_or_default();
+    assert!(fs::write(&env_sh, format!("{env}export FAKE_SLEEP=30\n")).is_ok());
+
+    let launch = JobLaunch::new(dir.to_string_lossy(), "t2", "wait").use_tmux(false);
+    let (code, out, err) = launch_in(&launch, &dir);
+    let job = launch.parse_output(code, &out, &err);
+    let Ok(job) = job else {
+        panic!("launch failed: {job:?} ({err})");
+    };
+    assert_eq!(liveness(&job, &dir), Some(JobLiveness::Running));
+
+    wait_for(|| dir.join(".field_exec/tmp/stdin").exists().then_some(()));
+    let (code, _, _) = sh(&stop_script(&job, None), &dir);
+    assert_eq!(code, 0);
+    assert!(wait_exited(&job, &dir));
+    let RemoteJobId::Pid(pid) = job else {
+        panic!("expected a pid job, got {job}");
+    };
+    // The job leads its own process group; nothing in it may survive.
+    let group = format!("ps -eo pgid=,args= | awk '$1 == {pid}' | grep -c 'sleep 30' || true");
+    assert_eq!(sh(&group, &dir).1.trim(), "0");
+    // Stopping again is harmless.
+    assert_eq!(sh(&stop_script(&job, None), &dir).0, 0);
+}
+
+#[test]
+fn tmux_jobs_use_the_tab_window() {
+    if sh("command -v tmux", &std::env::temp_dir()).0 != 0 {
+        return;
+    }
+    let dir = project("tmux");
+    let launch = JobLaunch::new(dir.to_string_lossy(), "tab-tmux", "hi").target_key("test");
+    let (code, out, err) = launch_in(&launch, &dir);
+    let job = launch.parse_output(code, &out, &err);
+    let window = tmux_window_name("test", &dir.to_string_lossy(), "tab-tmux");
+    assert_eq!(
+        job,
+        Ok(RemoteJobId::Tmux {
+            session: "__field_exec__".to_owned(),
+            window,
+        }),
+        "{err}"
+    );
+    let Ok(job) = job else {
+        return;
+    };
+    assert!(wait_exited(&job, &dir));
+    let log = fs::read_to_string(launch.files().log).unwrap_or_default();
+    assert!(log.ends_with("{\"type\":\"turn.completed\"}\n"), "{log:?}");
+    assert_eq!(sh(&stop_script(&job, None), &dir).0, 0);
+}
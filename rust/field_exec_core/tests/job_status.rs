@@ -0,0 +1,374 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use common::{TempDir, agent, sh, sh_input, wait_for};
+use field_exec_core::codex_events::parse_log;
+use field_exec_core::job::{
+    Heartbeat, JobLaunch, JobLiveness, RemoteJobId, SessionFiles, stop_script,
+};
+use field_exec_core::job_status::{
+    HeartbeatAlarm, JobOutcome, JobStatus, JobStatusCheck, LOG_TAIL_BYTES, TurnEnd, TurnLog,
+};
+
+const THREAD: &str = r#"{"type":"thread.started","thread_id":"th-1"}"#;
+const TURN: &str = r#"{"type":"turn.started"}"#;
+const DONE: &str = r#"{"type":"turn.completed","usage":{"input_tokens":1}}"#;
+const USER: &str = r#"{"type":"client.user_message","text":"again"}"#;
+const CANCELLED: &str = r#"{"type":"server.job_cancelled"}"#;
+const STARTED: &str = r#"{"type":"server.job_started"}"#;
+
+fn turn_log(lines: &[&str]) -> TurnLog {
+    TurnLog::from_lines(&parse_log(lines.join("\n").as_bytes()))
+}
+
+#[test]
+fn completed_turn_keeps_thread_and_final_message() {
+    let reply = agent("first");
+    let log = turn_log(&[THREAD, TURN, &agent("draft"), &reply, DONE]);
+    assert_eq!(
+        log,
+        TurnLog {
+            thread_id: Some("th-1".to_owned()),
+            end: Some(TurnEnd::Completed),
+            final_message: Some("first".to_owned()),
+            last_error: None,
+        }
+    );
+    assert_eq!(log.outcome(JobLiveness::Exited, ""), JobOutcome::Completed);
+    assert_eq!(log.outcome(JobLiveness::Unknown, ""), JobOutcome::Completed);
+    assert_eq!(log.outcome(JobLiveness::Running, ""), JobOutcome::Running);
+}
+
+#[test]
+fn failures_cancellations_and_crashes_are_told_apart() {
+    let failed = r#"{"type":"turn.failed","error":{"message":"quota exceeded"}}"#;
+    let log = turn_log(&[THREAD, TURN, failed]);
+    assert_eq!(
+        log.outcome(JobLiveness::Exited, ""),
+        JobOutcome::Failed {
+            message: "quota exceeded".to_owned()
+        }
+    );
+
+    let log = turn_log(&[THREAD, TURN, "partial", CANCELLED]);
+    assert_eq!(log.outcome(JobLiveness::Exited, ""), JobOutcome::Cancelled);
+    // A turn that finished before the stop landed stays completed.
+    let log = turn_log(&[THREAD, TURN, CANCELLED, DONE, CANCELLED]);
+    assert_eq!(log.outcome(JobLiveness::Exited, ""), JobOutcome::Completed);
+
+    let error = r#"{"type":"error","message":"stream disconnected"}"#;
+    let log = turn_log(&[THREAD, TURN, error]);
+    assert_eq!(
+        log.outcome(JobLiveness::Exited, "panic\n"),
+        JobOutcome::Crashed {
+            message: Some("stream disconnected".to_owned())
+        }
+    );
+    let log = turn_log(&[]);
+    assert_eq!(
+        log.outcome(JobLiveness::Exited, "warning\ncodex: not found\n\n"),
+        JobOutcome::Crashed {
+            message: Some("codex: not found".to_owned())
+        }
+    );
+    assert_eq!(log.outcome(JobLiveness::Unknown, ""), JobOutcome::Unknown);
+}
+
+#[test]
+fn a_new_run_forgets_the_previous_ending() {
+    let log = turn_log(&[THREAD, TURN, &agent("old"), DONE, USER]);
+    assert_eq!(log.thread_id.as_deref(), Some("th-1"));
+    assert_eq!(log.end, None);
+    assert_eq!(log.final_message, None);
+    assert_eq!(
+        log.outcome(JobLiveness::Exited, ""),
+        JobOutcome::Crashed { message: None }
+    );
+
+    let log = turn_log(&[THREAD, TURN, &agent("old"), DONE, STARTED]);
+    assert_eq!(log.end, None);
+    assert_eq!(log.final_message, None);
+}
+
+#[test]
+fn heartbeat_alarms() {
+    let text = "pid=4242\nstarted=100\nboot_id=b1\noutput=150\nbeat=160\n";
+    let heartbeat = Heartbeat::parse(text).unwrap_or_else(|| panic!("unparsed"));
+    assert_eq!(
+        heartbeat,
+        Heartbeat {
+            pid: 4242,
+            started_at: 100,
+            boot_id: "b1".to_owned(),
+            last_output_at: 150,
+            beat_at: 160,
+        }
+    );
+    assert_eq!(Heartbeat::parse("pid=1\nstarted=x\noutput=1\nbeat=1"), None);
+
+    let job = RemoteJobId::Pid(4242);
+    let running = JobLiveness::Running;
+    let check = |job: &RemoteJobId, liveness, boot: &str, now| {
+        HeartbeatAlarm::check(&heartbeat, job, liveness, boot, now, 60)
+    };
+    assert_eq!(check(&job, running, "b1", 170), None);
+    assert_eq!(check(&job, running, "", 170), None);
+    assert_eq!(
+        check(&job, running, "b2", 170),
+        Some(HeartbeatAlarm::Rebooted)
+    );
+    assert_eq!(
+        check(&job, JobLiveness::Exited, "b2", 170),
+        Some(HeartbeatAlarm::Rebooted)
+    );
+    assert_eq!(
+        check(&RemoteJobId::Pid(7), running, "b1", 170),
+        Some(HeartbeatAlarm::PidReused)
+    );
+    assert_eq!(
+        check(&job, running, "b1", 210),
+        Some(HeartbeatAlarm::Hung { idle_secs: 60 })
+    );
+    assert_eq!(check(&job, JobLiveness::Exited, "b1", 900), None);
+    let tmux = RemoteJobId::Tmux {
+        session: "s".to_owned(),
+        window: "w".to_owned(),
+    };
+    assert_eq!(check(&tmux, running, "b1", 170), None);
+}
+
+/// A project whose `codex` runs `body` after reading the prompt.
+fn project(name: &str, body: &str) -> TempDir {
+    common::project(
+        &format!("status_{name}"),
+        &format!("#!/bin/sh\ncat > /dev/null\n{body}\n"),
+    )
+}
+
+fn launch(dir: &Path, schema: Option<&str>) -> (JobLaunch, RemoteJobId) {
+    let launch = JobLaunch::new(dir.to_string_lossy(), "tab", "go")
+        .output_schema(schema.map(str::to_owned))
+        .bootstrap_gitignore(false)
+        .use_tmux(false);
+    let script = launch.script().unwrap_or_default();
+    let (code, out, err) = sh_input(&script, dir, launch.input().as_bytes());
+    let job = launch.parse_output(code, &String::from_utf8_lossy(&out), &err);
+    let Ok(job) = job else {
+        panic!("launch failed: {job:?} ({err})");
+    };
+    (launch, job)
+}
+
+fn status(job: &RemoteJobId, dir: &Path) -> Option<JobStatus> {
+    let check = JobStatusCheck::new(job.clone(), &dir.to_string_lossy(), "tab");
+    let (code, out, err) = sh(&check.script().ok()?, dir);
+    check.parse_output(code, &out, &err).ok()
+}
+
+fn exited_status(job: &RemoteJobId, dir: &Path) -> Option<JobStatus> {
+    wait_for(|| status(job, dir).filter(|s| s.liveness == JobLiveness::Exited))
+}
+
+#[test]
+fn completed_job_reports_its_structured_response() {
+    let reply =
+        r#"{"message":"Done","commit_message":"Fix","images":[],"actions":[],"risk":"low"}"#;
+    let event = agent(reply);
+    let dir = project(
+        "done",
+        &format!("echo '{THREAD}'\necho '{TURN}'\necho '{event}'\nprintf '%s' '{DONE}'"),
+    );
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "message": {"type": "string"},
+            "commit_message": {"type": "string"},
+            "images": {"type": "array"},
+            "actions": {"type": "array"},
+            "risk": {"type": "string"}
+        },
+        "required": ["message", "commit_message", "images", "actions", "risk"],
+        "additionalProperties": false
+    });
+    let (_, job) = launch(&dir, Some(&schema.to_string()));
+    let status = exited_status(&job, &dir).unwrap_or_else(|| panic!("no status for {job}"));
+    assert_eq!(status.outcome, JobOutcome::Completed);
+    assert_eq!(status.thread_id.as_deref(), Some("th-1"));
+    assert_eq!(status.final_message.as_deref(), Some(reply));
+    let check = status
+        .response
+        .unwrap_or_else(|| panic!("no response check"));
+    assert!(check.is_valid(), "{:?}", check.violations);
+    let response = check.response.unwrap_or_default();
+    assert_eq!(response.message, "Done");
+    assert!(response.extensions.contains_key("risk"));
+}
+
+#[test]
+fn thread_id_survives_a_turn_longer_than_the_log_tail() {
+    let step = agent(&"working ".repeat(16));
+    let steps = LOG_TAIL_BYTES as usize / step.len() + 100;
+    let dir = project(
+        "long",
+        &format!(
+            "echo '{THREAD}'\necho '{TURN}'\nyes '{step}' | head -n {steps}\necho '{}'\necho '{DONE}'",
+            agent("done")
+        ),
+    );
+    let (launch, job) = launch(&dir, None);
+    let status = exited_status(&job, &dir).unwrap_or_else(|| panic!("no status for {job}"));
+    let log_len = fs::metadata(launch.files().log).map_or(0, |m| m.len());
+    assert!(log_len > LOG_TAIL_BYTES, "{log_len}");
+    assert_eq!(status.outcome, JobOutcome::Completed);
+    assert_eq!(status.final_message.as_deref(), Some("done"));
+    assert_eq!(status.thread_id.as_deref(), Some("th-1"));
+}
+
+#[test]
+fn stopped_job_reads_as_cancelled() {
+    let dir = project("stop", &format!("echo '{THREAD}'\necho '{TURN}'\nsleep 30"));
+    let (launch, job) = launch(&dir, None);
+    let files = launch.files();
+    let log = &files.log;
+    wait_for(|| {
+        fs::read_to_string(log)
+            .is_ok_and(|l| l.contains("turn.started"))
+            .then_some(())
+    });
+    let running = status(&job, &dir);
+    assert_eq!(running.map(|s| s.outcome), Some(JobOutcome::Running));
+
+    assert_eq!(sh(&stop_script(&job, Some(&files)), &dir).0, 0);
+    let status = exited_status(&job, &dir);
+    assert_eq!(status.map(|s| s.outcome), Some(JobOutcome::Cancelled));
+    // Stopping a job that already ended adds nothing to its log.
+    assert_eq!(sh(&stop_script(&job, Some(&files)), &dir).0, 0);
+    let log = fs::read_to_string(log).unwrap_or_default();
+    assert_eq!(log.matches("server.job_cancelled").count(), 1);
+}
+
+#[test]
+fn job_that_dies_mid_turn_reads_as_crashed() {
+    let dir = project(
+        "crash",
+        &format!("echo '{THREAD}'\necho '{TURN}'\necho 'thread panicked' >&2\nexit 101"),
+    );
+    let (_, job) = launch(&dir, None);
+    let status = exited_status(&job, &dir).unwrap_or_else(|| panic!("no status for {job}"));
+    assert_eq!(
+        status.outcome,
+        JobOutcome::Crashed {
+            message: Some("thread panicked".to_owned())
+        }
+    );
+    assert_eq!(status.thread_id.as_deref(), Some("th-1"));
+    assert_eq!(status.response, None);
+}
+
+#[test]
+fn relaunch_without_a_preamble_starts_a_new_turn() {
+    let dir = project(
+        "relaunch",
+        &format!("echo '{THREAD}'\necho '{TURN}'\necho '{DONE}'"),
+    );
+    let (_, job) = launch(&dir, None);
+    let status = exited_status(&job, &dir).map(|s| s.outcome);
+    assert_eq!(status, Some(JobOutcome::Completed));
+
+    // The second run dies before Codex logs anything.
+    assert!(fs::write(dir.join(".field_exec/fake-codex"), "#!/bin/sh\nexit 1\n").is_ok());
+    let (_, job) = launch(&dir, None);
+    let status = exited_status(&job, &dir).map(|s| s.outcome);
+    assert_eq!(status, Some(JobOutcome::Crashed { message: None }));
+}
+
+/// Leaves a heartbeat claiming the tab's job is `pid` on boot `boot_id`.
+fn write_heartbeat(dir: &Path, pid: u32, boot_id: &str) -> SessionFiles {
+    let files = SessionFiles::new(&dir.to_string_lossy(), "tab");
+    assert!(fs::create_dir_all(dir.join(".field_exec/sessions")).is_ok());
+    let text = format!("pid={pid}\nstarted=1\nboot_id={boot_id}\noutput=1\nbeat=1\n");
+    assert!(fs::write(&files.heartbeat, text).is_ok());
+    files
+}
+
+#[test]
+fn running_job_keeps_a_heartbeat() {
+    let dir = project("beat", &format!("echo '{THREAD}'\nsleep 30"));
+    let (launch, job) = launch(&dir, None);
+    let files = launch.files();
+    wait_for(|| Path::new(&files.heartbeat).exists().then_some(()));
+    let status = status(&job, &dir).unwrap_or_else(|| panic!("no status for {job}"));
+    let heartbeat = status.heartbeat.unwrap_or_else(|| panic!("no heartbeat"));
+    assert_eq!(RemoteJobId::Pid(heartbeat.pid), job);
+    assert!(heartbeat.started_at > 0 && heartbeat.beat_at >= heartbeat.started_at);
+    let boot_id = fs::read_to_string("/proc/sys/kernel/random/boot_id").unwrap_or_default();
+    assert_eq!(heartbeat.boot_id, boot_id.trim());
+    assert_eq!(status.alarm, None);
+    assert_eq!(status.outcome, JobOutcome::Running);
+
+    // With no patience at all the silent job reads as hung.
+    let check = JobStatusCheck::new(job.clone(), &dir.to_string_lossy(), "tab").hang_after_secs(0);
+    let (code, out, err) = sh(&check.script().unwrap_or_default(), &dir);
+    let status = check.parse_output(code, &out, &err);
+    assert!(
+        matches!(
+            status.as_ref().map(|s| &s.outcome),
+            Ok(JobOutcome::Hung { .. })
+        ),
+        "{status:?}"
+    );
+
+    assert_eq!(sh(&stop_script(&job, Some(&files)), &dir).0, 0);
+    assert!(exited_status(&job, &dir).is_some());
+}
+
+#[test]
+fn reused_pid_is_neither_running_nor_stopped() {
+    let dir = project("reused", "true");
+    let Ok(mut other) = Command::new("sleep").arg("30").spawn() else {
+        panic!("cannot spawn sleep");
+    };
+    let job = RemoteJobId::Pid(other.id());
+    let files = write_heartbeat(&dir, other.id() + 1, "");
+
+    let status = status(&job, &dir).unwrap_or_else(|| panic!("no status for {job}"));
+    assert_eq!(status.alarm, Some(HeartbeatAlarm::PidReused));
+    assert_eq!(status.liveness, JobLiveness::Exited);
+    assert!(matches!(status.outcome, JobOutcome::Crashed { .. }));
+
+    assert_eq!(sh(&stop_script(&job, Some(&files)), &dir).0, 0);
+    assert!(
+        matches!(other.try_wait(), Ok(None)),
+        "the unrelated process was stopped"
+    );
+    let log = fs::read_to_string(&files.log).unwrap_or_default();
+    assert!(!log.contains("server.job_cancelled"));
+    let _ = other.kill();
+    let _ = other.wait();
+}
+
+#[test]
+fn heartbeat_from_an_earlier_boot_means_the_job_is_gone() {
+    if !Path::new("/proc/sys/kernel/random/boot_id").exists() {
+        return;
+    }
+    let dir = project("reboot", "true");
+    let job = RemoteJobId::Pid(std::process::id());
+    write_heartbeat(
+        &dir,
+        std::process::id(),
+        "00000000-0000-0000-0000-000000000000",
+    );
+    let status = status(&job, &dir).unwrap_or_else(|| panic!("no status for {job}"));
+    assert_eq!(status.alarm, Some(HeartbeatAlarm::Rebooted));
+    assert_eq!(status.liveness, JobLiveness::Exited);
+    assert_eq!(
+        status.outcome,
+        JobOutcome::Crashed {
+            message: Some("the host rebooted while the job was running".to_owned())
+        }
+    );
+}
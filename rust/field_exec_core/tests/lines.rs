@@ -0,0 +1,58 @@
+use field_exec_core::lines::{LineBuffer, LineChunk, LinePart};
+
+fn whole(text: &str) -> LineChunk {
+    LineChunk {
+        text: text.to_owned(),
+        part: None,
+    }
+}
+
+fn part(text: &str, index: u32, is_final: bool) -> LineChunk {
+    LineChunk {
+        text: text.to_owned(),
+        part: Some(LinePart { index, is_final }),
+    }
+}
+
+#[test]
+fn characters_split_across_reads_decode_intact() {
+    let mut buf = LineBuffer::new(None);
+    // "é" is 0xC3 0xA9; "€" is 0xE2 0x82 0xAC.
+    assert_eq!(buf.push(b"caf\xC3"), []);
+    assert_eq!(buf.push(b"\xA9\r\n\xE2\x82"), [whole("café")]);
+    assert_eq!(buf.push(b"\xAC 5\n"), [whole("€ 5")]);
+    assert_eq!(buf.push(b"  tail \r"), []);
+    assert_eq!(buf.finish(), Some(whole("tail")));
+    assert_eq!(buf.finish(), None);
+}
+
+#[test]
+fn long_lines_split_into_parts_on_character_boundaries() {
+    let mut buf = LineBuffer::new(Some(4));
+    // The cut at four bytes would land inside "é", so it moves back to 3.
+    assert_eq!(buf.push("abcé".as_bytes()), [part("abc", 0, false)]);
+    assert_eq!(
+        buf.push("défgh\nok\n".as_bytes()),
+        [
+            part("éd", 1, false),
+            part("éfg", 2, false),
+            part("h", 3, true),
+            whole("ok"),
+        ]
+    );
+    assert_eq!(buf.push(b"abcd\n"), [whole("abcd")]);
+}
+
+#[test]
+fn a_split_line_ends_with_a_final_part() {
+    let mut buf = LineBuffer::new(Some(2));
+    assert_eq!(
+        buf.push(b"abcde"),
+        [part("ab", 0, false), part("cd", 1, false)]
+    );
+    assert_eq!(buf.finish(), Some(part("e", 2, true)));
+
+    let mut buf = LineBuffer::new(Some(2));
+    assert_eq!(buf.push(b"abcd"), [part("ab", 0, false)]);
+    assert_eq!(buf.finish(), Some(part("cd", 1, true)));
+}
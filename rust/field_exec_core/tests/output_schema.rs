@@ -0,0 +1,314 @@
+use std::fs;
+use std::process::Command;
+
+use field_exec_core::output_schema::{
+    ExtensionLoad, OutputSchema, SCHEMA_DIR, SchemaError, SchemaLoadError, base_schema, schema_path,
+};
+use field_exec_core::structured_response::{ExtensionValue, check_response};
+use serde::Deserialize;
+use serde_json::json;
+
+fn file(name: &str, contents: serde_json::Value) -> (String, String) {
+    (name.to_owned(), contents.to_string())
+}
+
+fn risk_and_tests() -> Vec<(String, String)> {
+    vec![
+        file(
+            "20-tests.json",
+            json!({"properties": {"test_results": {
+                "type": "object",
+                "properties": {
+                    "passed": {"type": "integer"},
+                    "failed": {"type": "integer"}
+                },
+                "required": ["passed", "failed"],
+                "additionalProperties": false
+            }}}),
+        ),
+        file(
+            "10-risk.json",
+            json!({"properties": {"risk_level": {"type": "string", "enum": ["low", "high"]}}}),
+        ),
+    ]
+}
+
+#[test]
+fn base_schema_has_no_extensions() {
+    let schema = OutputSchema::base();
+    assert!(schema.extensions().is_empty());
+    assert_eq!(schema.to_value(), base_schema());
+    assert_eq!(OutputSchema::compose(&[]), Ok(OutputSchema::base()));
+}
+
+#[test]
+fn extensions_are_merged_in_file_order_and_required() {
+    let schema = OutputSchema::compose(&risk_and_tests()).unwrap_or_default();
+    let names: Vec<(&str, &str)> = schema
+        .extensions()
+        .iter()
+        .map(|f| (f.name.as_str(), f.source.as_str()))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            ("risk_level", "10-risk.json"),
+            ("test_results", "20-tests.json")
+        ]
+    );
+    let value = schema.to_value();
+    assert_eq!(
+        value["required"],
+        json!([
+            "message",
+            "commit_message",
+            "images",
+            "actions",
+            "risk_level",
+            "test_results"
+        ])
+    );
+    assert_eq!(
+        value["properties"]["risk_level"]["enum"],
+        json!(["low", "high"])
+    );
+    assert_eq!(value["additionalProperties"], json!(false));
+    let written: serde_json::Value = serde_json::from_str(&schema.to_json()).unwrap_or_default();
+    assert_eq!(written, value);
+}
+
+#[test]
+fn conflicts_and_reserved_fields_are_all_reported() {
+    let mut files = risk_and_tests();
+    files.push(file(
+        "30-risk.json",
+        json!({"properties": {"risk_level": {"type": "integer"}}}),
+    ));
+    // An identical redefinition is not a conflict.
+    files.push(file(
+        "40-risk.json",
+        json!({"properties": {"risk_level": {"type": "string", "enum": ["low", "high"]}}}),
+    ));
+    files.push(file(
+        "50-message.json",
+        json!({"properties": {"message": {"type": "string"}}}),
+    ));
+    files.push(("60-broken.json".to_owned(), "{".to_owned()));
+    files.push(file("70-shape.json", json!({"risk": "low"})));
+
+    let errors = OutputSchema::compose(&files).err().unwrap_or_default();
+    assert_eq!(errors.len(), 4, "{errors:?}");
+    assert_eq!(
+        errors[0],
+        SchemaError::ReservedField {
+            source: "50-message.json".to_owned(),
+            field: "message".to_owned(),
+        }
+    );
+    assert!(matches!(
+        &errors[1],
+        SchemaError::InvalidJson { source, .. } if source == "60-broken.json"
+    ));
+    assert!(matches!(
+        &errors[2],
+        SchemaError::InvalidExtension { source, .. } if source == "70-shape.json"
+    ));
+    assert_eq!(
+        errors[3],
+        SchemaError::Conflict {
+            field: "risk_level".to_owned(),
+            sources: vec![
+                "10-risk.json".to_owned(),
+                "30-risk.json".to_owned(),
+                "40-risk.json".to_owned(),
+            ],
+        }
+    );
+}
+
+#[test]
+fn nested_objects_must_follow_strict_mode() {
+    let open =
+        json!({"type": "object", "properties": {"a": {"type": "string"}}, "required": ["a"]});
+    let optional = json!({
+        "type": ["object", "null"],
+        "properties": {"a": {"type": "string"}, "b": {"type": "string"}},
+        "required": ["a"],
+        "additionalProperties": false
+    });
+    let deep = json!({"type": "array", "items": {"anyOf": [{"type": "null"}, open.clone()]}});
+    for (schema, expected) in [
+        (open, r#""f" must set "additionalProperties": false"#),
+        (
+            optional,
+            r#""f" must list "b" in "required"; allow null instead"#,
+        ),
+        (deep, r#""f[]" must set "additionalProperties": false"#),
+    ] {
+        let files = [file("10-f.json", json!({"properties": {"f": schema}}))];
+        assert_eq!(
+            OutputSchema::compose(&files),
+            Err(vec![SchemaError::InvalidExtension {
+                source: "10-f.json".to_owned(),
+                message: expected.to_owned(),
+            }])
+        );
+    }
+}
+
+#[test]
+fn definitions_and_refs_are_rejected() {
+    let level = json!({"type": "string", "enum": ["low", "high"]});
+    let cases = [
+        (
+            json!({"$defs": {"level": level}, "properties": {"f": {"type": "string"}}}),
+            r#""$defs" is not supported; inline the schema"#,
+        ),
+        (
+            json!({"properties": {"f": {"type": "array", "items": {"$ref": "#/$defs/level"}}}}),
+            r#""f[]" uses "$ref", which is not supported; inline the schema"#,
+        ),
+        (
+            json!({"properties": {"f": {"definitions": {"level": level}, "type": "string"}}}),
+            r#""f" uses "definitions", which is not supported; inline the schema"#,
+        ),
+    ];
+    for (extension, expected) in cases {
+        let files = [file("10-f.json", extension)];
+        assert_eq!(
+            OutputSchema::compose(&files),
+            Err(vec![SchemaError::InvalidExtension {
+                source: "10-f.json".to_owned(),
+                message: expected.to_owned(),
+            }])
+        );
+    }
+}
+
+#[test]
+fn extension_values_are_typed_in_the_response() {
+    let schema = OutputSchema::compose(&risk_and_tests())
+        .unwrap_or_default()
+        .to_value();
+    let text = r#"{
+        "message": "ok",
+        "commit_message": "Fix parser",
+        "images": [],
+        "actions": [],
+        "risk_level": "low",
+        "test_results": {"passed": 12, "failed": 0}
+    }"#;
+    let check = check_response(text, &schema);
+    assert!(check.is_valid(), "{:?}", check.violations);
+    let response = check.response.unwrap_or_default();
+    assert_eq!(
+        response.extensions.get("risk_level"),
+        Some(&ExtensionValue::String("low".to_owned()))
+    );
+    let Some(ExtensionValue::Object(results)) = response.extensions.get("test_results") else {
+        panic!("expected an object, got {:?}", response.extensions);
+    };
+    assert_eq!(
+        results.get("passed").and_then(ExtensionValue::as_i64),
+        Some(12)
+    );
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct TestResults {
+        passed: u32,
+        failed: u32,
+    }
+    let typed = response.extension::<TestResults>("test_results");
+    assert_eq!(
+        typed.and_then(Result::ok),
+        Some(TestResults {
+            passed: 12,
+            failed: 0
+        })
+    );
+    assert!(response.extension::<String>("follow_up_tasks").is_none());
+
+    // A missing extension field is a remaining violation, not defaulted.
+    let check = check_response(
+        r#"{"message": "", "commit_message": "x", "images": [], "actions": []}"#,
+        &schema,
+    );
+    assert_eq!(check.remaining.len(), 2);
+    assert!(check.remaining.iter().all(|v| v.keyword == "required"));
+    assert!(check.response.is_some_and(|r| r.extensions.is_empty()));
+}
+
+#[test]
+fn load_script_reads_extension_files() {
+    let project = std::env::temp_dir().join(format!("field_exec_schema_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&project);
+    let project_dir = project.to_string_lossy().into_owned();
+    let load = ExtensionLoad::new(project_dir.clone());
+    let run = |load: &ExtensionLoad| {
+        let out = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(load.script())
+            .output()
+            .ok()?;
+        Some(load.parse_output(
+            out.status.code().unwrap_or(-1) as u32,
+            &out.stdout,
+            &String::from_utf8_lossy(&out.stderr),
+        ))
+    };
+
+    assert!(
+        matches!(run(&load), Some(Err(_))),
+        "missing project must fail"
+    );
+    assert_eq!(fs::create_dir_all(&project).ok(), Some(()));
+    assert_eq!(run(&load), Some(Ok(Vec::new())));
+
+    let dir = project.join(SCHEMA_DIR);
+    assert_eq!(fs::create_dir_all(&dir).ok(), Some(()));
+    let tests = "{\"properties\": {\"tests\": {\"type\": \"string\"}}}\n";
+    let odd = "{\"properties\": {}}\n@file 3 fake.json\n";
+    assert!(fs::write(dir.join("tests.json"), tests).is_ok());
+    assert!(fs::write(dir.join("odd name.json"), odd).is_ok());
+    assert!(fs::write(dir.join("notes.txt"), "ignored").is_ok());
+    let mut files = run(&load).and_then(Result::ok).unwrap_or_default();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            ("odd name.json".to_owned(), odd.to_owned()),
+            ("tests.json".to_owned(), tests.to_owned()),
+        ]
+    );
+
+    assert_eq!(
+        schema_path(&format!("{project_dir}/")),
+        format!("{project_dir}/.field_exec/output-schema.json")
+    );
+    let _ = fs::remove_dir_all(&project);
+}
+
+#[test]
+fn load_composes_the_schema_or_reports_every_problem() {
+    let load = ExtensionLoad::new("/srv/app");
+    let tests = r#"{"properties": {"tests": {"type": "string"}}}"#;
+    let out = format!("@file {} tests.json\n{tests}", tests.len());
+    let schema = load.compose(0, out.as_bytes(), "");
+    assert_eq!(schema.ok().map(|s| s.extensions().len()), Some(1));
+
+    let out = "@file 2 a.json\n{}@file 1 b.json\n[";
+    let Err(SchemaLoadError::Invalid(errors)) = load.compose(0, out.as_bytes(), "") else {
+        panic!("invalid extensions must fail");
+    };
+    assert_eq!(errors.len(), 2);
+    let message = SchemaLoadError::Invalid(errors).to_string();
+    assert!(
+        message.starts_with("a.json: ") && message.contains("; b.json: "),
+        "{message}"
+    );
+
+    assert!(matches!(
+        load.compose(2, b"", "/srv/app"),
+        Err(SchemaLoadError::Remote(_))
+    ));
+}
@@ -0,0 +1,113 @@
+use field_exec_core::ports::{PortProbe, ProbeVia};
+
+const SS_LINUX: &str = include_str!("fixtures/ports_ss_linux.txt");
+const LSOF_LINUX: &str = include_str!("fixtures/ports_lsof_linux.txt");
+const NETSTAT_LINUX: &str = include_str!("fixtures/ports_netstat_linux.txt");
+const LSOF_MACOS: &str = include_str!("fixtures/ports_lsof_macos.txt");
+const NETSTAT_MACOS: &str = include_str!("fixtures/ports_netstat_macos.txt");
+
+type Row = (String, u16, Option<u32>, Option<String>);
+
+/// Parses a captured probe output as the script would print it.
+fn ports(via: &str, out: &str) -> (ProbeVia, Vec<Row>) {
+    let stdout = format!("@via {via}\n{out}@cwd\n");
+    let Ok(listing) = PortProbe::new().parse_output(0, &stdout, "") else {
+        panic!("valid {via} output");
+    };
+    let rows = listing
+        .ports
+        .into_iter()
+        .map(|p| (p.address, p.port, p.pid, p.process))
+        .collect();
+    (listing.via, rows)
+}
+
+fn row(address: &str, port: u16, owner: Option<(u32, &str)>) -> Row {
+    (
+        address.to_owned(),
+        port,
+        owner.map(|(pid, _)| pid),
+        owner.map(|(_, name)| name.to_owned()),
+    )
+}
+
+#[test]
+fn ss_on_linux() {
+    assert_eq!(
+        ports("ss", SS_LINUX),
+        (
+            ProbeVia::Ss,
+            vec![
+                row("0.0.0.0", 22, None),
+                row("::", 22, None),
+                row("127.0.0.53", 53, Some((611, "systemd-resolve"))),
+                row("127.0.0.1", 3000, Some((4242, "node"))),
+                row("::1", 5173, Some((4243, "node"))),
+            ]
+        )
+    );
+}
+
+#[test]
+fn lsof_on_linux() {
+    assert_eq!(
+        ports("lsof", LSOF_LINUX),
+        (
+            ProbeVia::Lsof,
+            vec![
+                row("127.0.0.1", 3000, Some((4242, "node"))),
+                row("::1", 5173, Some((4243, "node"))),
+                row("*", 8000, Some((4250, "python3"))),
+            ]
+        )
+    );
+}
+
+#[test]
+fn netstat_on_linux() {
+    assert_eq!(
+        ports("netstat", NETSTAT_LINUX),
+        (
+            ProbeVia::Netstat,
+            vec![
+                row("0.0.0.0", 22, None),
+                row("::", 22, None),
+                row("127.0.0.53", 53, None),
+                row("127.0.0.1", 3000, Some((4242, "node"))),
+                row("::1", 5173, Some((4243, "node"))),
+            ]
+        )
+    );
+}
+
+#[test]
+fn lsof_on_macos() {
+    // Both address families of rapportd's listener collapse into one row.
+    assert_eq!(
+        ports("lsof", LSOF_MACOS),
+        (
+            ProbeVia::Lsof,
+            vec![
+                row("127.0.0.1", 3000, Some((3310, "Code H"))),
+                row("::1", 5173, Some((4243, "node"))),
+                row("*", 49152, Some((512, "rapportd"))),
+            ]
+        )
+    );
+}
+
+#[test]
+fn netstat_on_macos() {
+    assert_eq!(
+        ports("netstat", NETSTAT_MACOS),
+        (
+            ProbeVia::Netstat,
+            vec![
+                row("127.0.0.1", 3000, None),
+                row("::1", 5173, None),
+                row("fe80::1", 8080, None),
+                row("*", 49152, None),
+            ]
+        )
+    );
+}
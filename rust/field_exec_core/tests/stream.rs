@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use field_exec_core::stream::{StreamEnd, StreamSignal, StreamTermination, StreamWatchdog};
+
+#[test]
+fn watchdog_escalates_term_then_kill_then_gives_up() {
+    let mut watchdog = StreamWatchdog::new(Some(Duration::ZERO), Some(Duration::from_secs(60)));
+    assert_eq!(watchdog.timeout_reason(), None);
+    assert_eq!(watchdog.expire(), Some(StreamSignal::Term));
+    assert_eq!(watchdog.timeout_reason(), Some("max duration exceeded"));
+    assert_eq!(watchdog.expire(), Some(StreamSignal::Kill));
+    assert_eq!(watchdog.expire(), None);
+}
+
+#[test]
+fn idle_timeout_is_reported_when_max_duration_has_not_passed() {
+    let mut watchdog = StreamWatchdog::new(Some(Duration::from_secs(60)), Some(Duration::ZERO));
+    assert_eq!(watchdog.expire(), Some(StreamSignal::Term));
+    assert_eq!(watchdog.timeout_reason(), Some("idle output timeout"));
+}
+
+#[test]
+fn a_timed_out_stream_reads_as_timed_out_however_it_ended() {
+    let idle = StreamWatchdog::new(None, None);
+    assert_eq!(StreamEnd::exited(3).watched_by(&idle), StreamEnd::exited(3));
+
+    let mut expired = StreamWatchdog::new(Some(Duration::ZERO), None);
+    let _ = expired.expire();
+    let end = StreamEnd::signaled("TERM".to_owned(), false, None).watched_by(&expired);
+    assert_eq!(end.termination, StreamTermination::TimedOut);
+    assert_eq!(end.error.as_deref(), Some("max duration exceeded"));
+    assert_eq!(end.exit_signal.as_deref(), Some("TERM"));
+}
@@ -0,0 +1,240 @@
+use field_exec_core::json_schema::{Violation, validate};
+use field_exec_core::output_schema::base_schema;
+use field_exec_core::structured_response::{
+    Action, Fallback, ImageRef, StructuredResponse, check_response,
+};
+use serde_json::json;
+
+fn keywords(violations: &[Violation]) -> Vec<(&str, &str)> {
+    violations
+        .iter()
+        .map(|v| (v.path.as_str(), v.keyword))
+        .collect()
+}
+
+#[test]
+fn conforming_reply_needs_no_fallbacks() {
+    let text = r#"{
+        "message": "Done.",
+        "commit_message": "Add login form",
+        "images": [{"path": "/work/shot.png", "caption": "Login"}],
+        "actions": [{"id": "yes", "label": "Yes", "value": "yes"}]
+    }"#;
+    let check = check_response(text, &base_schema());
+    assert!(check.is_valid(), "{:?}", check.violations);
+    assert!(check.remaining.is_empty());
+    assert!(check.fallbacks.is_empty());
+    assert_eq!(
+        check.response,
+        Some(StructuredResponse {
+            message: "Done.".to_owned(),
+            commit_message: "Add login form".to_owned(),
+            images: vec![ImageRef {
+                path: "/work/shot.png".to_owned(),
+                caption: "Login".to_owned(),
+            }],
+            actions: vec![Action {
+                id: "yes".to_owned(),
+                label: "Yes".to_owned(),
+                value: "yes".to_owned(),
+            }],
+            ..StructuredResponse::default()
+        })
+    );
+}
+
+#[test]
+fn invalid_json_is_a_root_violation() {
+    let check = check_response("Sure! Here is the answer.", &base_schema());
+    assert_eq!(check.response, None);
+    assert_eq!(keywords(&check.violations), [("", "json")]);
+    assert_eq!(check.violations, check.remaining);
+}
+
+#[test]
+fn non_object_reply_has_no_response() {
+    let check = check_response("[1, 2]", &base_schema());
+    assert_eq!(check.response, None);
+    assert_eq!(keywords(&check.violations), [("", "type")]);
+}
+
+#[test]
+fn images_map_aliases_and_wrappers_are_normalized() {
+    let text = r#"{
+        "message": "Screens",
+        "commit_message": "Add screens",
+        "images": {
+            "a": {"file_path": "/work/a.png", "title": "A"},
+            "b": {"image": {"path": "/work/b.png", "caption": "B"}},
+            "c": {"path": "/work/c.png"}
+        },
+        "actions": []
+    }"#;
+    let check = check_response(text, &base_schema());
+    assert!(!check.is_valid());
+    assert_eq!(keywords(&check.violations), [("/images", "type")]);
+    assert!(check.remaining.is_empty(), "{:?}", check.remaining);
+
+    assert_eq!(
+        check.fallbacks,
+        [
+            Fallback::ImagesFromMap,
+            Fallback::ImageKeyAlias {
+                index: 0,
+                from: "file_path".to_owned(),
+                to: "path".to_owned(),
+            },
+            Fallback::ImageKeyAlias {
+                index: 0,
+                from: "title".to_owned(),
+                to: "caption".to_owned(),
+            },
+            Fallback::ImageUnwrapped {
+                index: 1,
+                wrapper: "image".to_owned(),
+            },
+            Fallback::ImageCaptionDefaulted { index: 2 },
+        ]
+    );
+    let images = check.response.map(|r| r.images).unwrap_or_default();
+    let paths: Vec<&str> = images.iter().map(|i| i.path.as_str()).collect();
+    assert_eq!(paths, ["/work/a.png", "/work/b.png", "/work/c.png"]);
+    assert_eq!(images[0].caption, "A");
+    assert_eq!(images[2].caption, "");
+}
+
+#[test]
+fn string_and_pair_entries_become_images() {
+    let text = r#"{
+        "message": "",
+        "commit_message": "x",
+        "images": ["/work/a.png", ["/work/b.png", "B"], {"caption": "no path"}, 7],
+        "actions": []
+    }"#;
+    let check = check_response(text, &base_schema());
+    assert_eq!(
+        keywords(&check.violations),
+        [
+            ("/images/0", "type"),
+            ("/images/1", "type"),
+            ("/images/2", "required"),
+            ("/images/3", "type"),
+        ]
+    );
+    assert!(check.remaining.is_empty(), "{:?}", check.remaining);
+    assert_eq!(
+        check.fallbacks,
+        [
+            Fallback::ImageFromString { index: 0 },
+            Fallback::ImageFromPair { index: 1 },
+            Fallback::ImageDropped { index: 2 },
+            Fallback::ImageDropped { index: 3 },
+        ]
+    );
+    let images = check.response.map(|r| r.images).unwrap_or_default();
+    assert_eq!(
+        images,
+        [
+            ImageRef {
+                path: "/work/a.png".to_owned(),
+                caption: String::new(),
+            },
+            ImageRef {
+                path: "/work/b.png".to_owned(),
+                caption: "B".to_owned(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn missing_fields_and_unknown_properties_are_reported() {
+    let text = r#"{"message": "hi", "extra": true, "actions": [{"id": "a", "label": ""}]}"#;
+    let check = check_response(text, &base_schema());
+    let mut found = keywords(&check.violations);
+    found.sort();
+    assert_eq!(
+        found,
+        [
+            ("", "required"),
+            ("", "required"),
+            ("/actions/0", "required"),
+            ("/actions/0/label", "minLength"),
+            ("/extra", "additionalProperties"),
+        ]
+    );
+    assert_eq!(
+        check.fallbacks,
+        [
+            Fallback::PropertyIgnored {
+                field: "extra".to_owned(),
+            },
+            Fallback::FieldDefaulted {
+                field: "commit_message".to_owned(),
+            },
+            Fallback::ImagesDefaulted,
+            Fallback::ActionDropped { index: 0 },
+        ]
+    );
+    // An empty commit message is still below `minLength`.
+    assert_eq!(
+        keywords(&check.remaining),
+        [("/commit_message", "minLength")]
+    );
+    assert_eq!(check.response.map(|r| r.message), Some("hi".to_owned()));
+}
+
+#[test]
+fn empty_image_path_is_a_remaining_violation() {
+    let text = r#"{
+        "message": "",
+        "commit_message": "x",
+        "images": [{"path": "", "caption": ""}],
+        "actions": []
+    }"#;
+    let check = check_response(text, &base_schema());
+    assert_eq!(
+        keywords(&check.violations),
+        [("/images/0/path", "minLength")]
+    );
+    assert_eq!(check.fallbacks, [Fallback::ImageDropped { index: 0 }]);
+    assert!(check.remaining.is_empty());
+}
+
+#[test]
+fn validator_follows_refs_and_combinators() {
+    let schema = json!({
+        "$defs": {"slug": {"type": "string", "pattern": "^[a-z-]+$"}},
+        "type": "object",
+        "properties": {
+            "name": {"$ref": "#/$defs/slug"},
+            "size": {"anyOf": [{"type": "integer", "minimum": 1}, {"const": "auto"}]},
+            "a/b": {"type": "boolean"}
+        },
+        "additionalProperties": {"type": "number"}
+    });
+    assert!(
+        validate(
+            &schema,
+            &json!({"name": "ok-name", "size": "auto", "z": 1.5})
+        )
+        .is_empty()
+    );
+    let violations = validate(
+        &schema,
+        &json!({"name": "Bad Name", "size": 0, "a/b": "no", "z": "x"}),
+    );
+    let mut found = keywords(&violations);
+    found.sort();
+    assert_eq!(
+        found,
+        [
+            ("/a~1b", "type"),
+            ("/name", "pattern"),
+            ("/size", "anyOf"),
+            ("/z", "type"),
+        ]
+    );
+    let unresolved = validate(&json!({"$ref": "#/$defs/missing"}), &json!(1));
+    assert_eq!(keywords(&unresolved), [("", "$ref")]);
+}
@@ -0,0 +1,233 @@
+mod common;
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+
+use common::TempDir;
+use field_exec_core::remote_fs::RemoteFsError;
+use field_exec_core::sync::{
+    CopyReason, FileMeta, FileTree, RemoteHashes, RemoteManifest, SyncAction, SyncFilter,
+    hash_candidates, local_hashes, local_tree, plan, sha256_file,
+};
+
+const SHA: &str = "ab0c8c4e7a5d5b4c3d2e1f00112233445566778899aabbccddeeff0011223344";
+
+#[test]
+fn manifest_reads_files_hashes_and_gitignores() {
+    let manifest = RemoteManifest::new("/srv/app")
+        .checksums(true)
+        .gitignore(true);
+    let out = format!(
+        "F 3 1700000000 ./src/a b.rs\nH {SHA}  ./src/a b.rs\nI 6 ./src/.gitignore\n*.log\n\n"
+    );
+    let Ok(tree) = manifest.parse_output(0, out.as_bytes(), "") else {
+        panic!("valid manifest");
+    };
+    let meta = tree.files.get("src/a b.rs");
+    assert_eq!(meta.map(|m| (m.size, m.mtime)), Some((3, 1_700_000_000)));
+    assert_eq!(meta.and_then(|m| m.sha256.as_deref()), Some(SHA));
+    assert_eq!(tree.gitignores, [("src".to_owned(), "*.log\n".to_owned())]);
+}
+
+#[test]
+fn manifest_paths_must_stay_below_the_root() {
+    let manifest = RemoteManifest::new("/srv/app").gitignore(true);
+    for out in [
+        "F 1 1 ../etc/passwd\n",
+        "F 1 1 ./a/../../x\n",
+        "F 1 1 /etc/passwd\n",
+        "F 1 1 ./\n",
+        &format!("H {SHA}  ../x\n"),
+        "I 0 ../.gitignore\n\n",
+    ] {
+        let parsed = manifest.parse_output(0, out.as_bytes(), "");
+        assert!(
+            matches!(parsed, Err(RemoteFsError::Malformed(_))),
+            "{out:?}: {parsed:?}"
+        );
+    }
+}
+
+#[test]
+fn remote_hashes_fail_on_any_error() {
+    let hashes = RemoteHashes::new("/srv/app", vec!["a".to_owned(), "gone".to_owned()]);
+    let out = format!("{SHA}  ./a\n");
+    let parsed = hashes.parse_output(0, &out, "");
+    assert_eq!(
+        parsed.ok().and_then(|h| h.get("a").cloned()).as_deref(),
+        Some(SHA)
+    );
+    let parsed = hashes.parse_output(1, &out, "sha256sum: ./gone: No such file or directory");
+    assert!(matches!(
+        parsed,
+        Err(RemoteFsError::Failed { exit_status: 1, .. })
+    ));
+}
+
+#[test]
+fn local_hashes_fail_on_any_error() {
+    let dir = TempDir::new("sync_local_hashes");
+    assert!(fs::write(dir.join("a"), "a").is_ok());
+    let hashes = local_hashes(&dir, ["a".to_owned()]);
+    let expected = sha256_file(&dir.join("a")).ok();
+    assert_eq!(hashes.ok().and_then(|h| h.get("a").cloned()), expected);
+
+    let hashes = local_hashes(&dir, ["a".to_owned(), "gone".to_owned()]);
+    let Err(err) = hashes else {
+        panic!("hashed a missing file: {hashes:?}");
+    };
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(err.to_string().starts_with("gone: "), "{err}");
+}
+
+#[test]
+fn local_tree_fails_on_an_unreadable_file() {
+    let dir = TempDir::new("sync_unreadable");
+    assert!(fs::create_dir_all(dir.join("src")).is_ok());
+    assert!(fs::write(dir.join("src/a.rs"), "a").is_ok());
+    assert!(fs::write(dir.join("src/secret"), "s").is_ok());
+    assert!(local_tree(&dir, true, false).is_ok());
+    let unreadable = fs::Permissions::from_mode(0o000);
+    assert!(fs::set_permissions(dir.join("src/secret"), unreadable).is_ok());
+    if fs::read(dir.join("src/secret")).is_ok() {
+        // Running as root: permissions do not stop reads.
+        return;
+    }
+    // Without checksums the file is only stat'ed.
+    assert!(local_tree(&dir, false, false).is_ok());
+    let tree = local_tree(&dir, true, false);
+    let Err(err) = tree else {
+        panic!("listed an unreadable file: {tree:?}");
+    };
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(err.to_string().starts_with("src/secret: "), "{err}");
+}
+
+fn tree(files: &[(&str, u64, i64, Option<&str>)]) -> FileTree {
+    FileTree {
+        files: files
+            .iter()
+            .map(|(path, size, mtime, sha)| {
+                let meta = FileMeta {
+                    size: *size,
+                    mtime: *mtime,
+                    sha256: sha.map(str::to_owned),
+                };
+                ((*path).to_owned(), meta)
+            })
+            .collect(),
+        gitignores: Vec::new(),
+    }
+}
+
+fn globs(patterns: &[&str]) -> Vec<String> {
+    patterns.iter().map(|p| (*p).to_owned()).collect()
+}
+
+#[test]
+fn filter_globs_match_paths_and_their_parents() {
+    let Ok(filter) = SyncFilter::new(
+        &globs(&["src", "*.md"]),
+        &globs(&["node_modules/", "*.log"]),
+    ) else {
+        panic!("valid globs");
+    };
+    assert!(filter.allows("src/main.rs"));
+    assert!(filter.allows("README.md"));
+    assert!(!filter.allows("Cargo.toml"));
+    assert!(!filter.allows("node_modules/x/index.js"));
+    assert!(!filter.allows("src/debug.log"));
+    assert!(SyncFilter::new(&globs(&["a[b"]), &[]).is_err());
+}
+
+#[test]
+fn nested_gitignores_override_their_parents() {
+    let Ok(filter) = SyncFilter::new(&[], &[]) else {
+        panic!("no globs");
+    };
+    let filter = filter.gitignores(&[
+        (String::new(), "*.tmp\nbuild/\n".to_owned()),
+        ("keep".to_owned(), "!*.tmp\n".to_owned()),
+    ]);
+    assert!(filter.allows("a.rs"));
+    assert!(!filter.allows("a.tmp"));
+    assert!(!filter.allows("build/out.o"));
+    assert!(!filter.allows("sub/build/out.o"));
+    assert!(filter.allows("keep/a.tmp"));
+    assert!(!filter.allows("keeper/a.tmp"));
+}
+
+#[test]
+fn plan_copies_changed_files_and_deletes_only_when_asked() {
+    let source = tree(&[
+        ("new.rs", 4, 10, None),
+        ("size.rs", 5, 10, None),
+        ("hash.rs", 3, 10, Some("aa")),
+        ("same_hash.rs", 3, 10, Some("bb")),
+        ("mtime.rs", 3, 11, None),
+        ("same.rs", 3, 10, None),
+        ("src/deep/x.rs", 1, 10, None),
+        ("skip.log", 1, 10, None),
+    ]);
+    let dest = tree(&[
+        ("size.rs", 4, 10, None),
+        ("hash.rs", 3, 10, Some("ab")),
+        ("same_hash.rs", 3, 99, Some("bb")),
+        ("mtime.rs", 3, 10, None),
+        ("same.rs", 3, 10, None),
+        ("stale.rs", 1, 10, None),
+        ("kept.log", 1, 10, None),
+    ]);
+    let Ok(filter) = SyncFilter::new(&[], &globs(&["*.log"])) else {
+        panic!("valid globs");
+    };
+    let copy = |path: &str, size, mtime, reason| SyncAction::Copy {
+        path: path.to_owned(),
+        size,
+        mtime,
+        reason,
+    };
+    let copies = vec![
+        copy("hash.rs", 3, 10, CopyReason::ContentChanged),
+        copy("mtime.rs", 3, 11, CopyReason::MtimeChanged),
+        copy("new.rs", 4, 10, CopyReason::New),
+        copy("size.rs", 5, 10, CopyReason::SizeChanged),
+        copy("src/deep/x.rs", 1, 10, CopyReason::New),
+    ];
+
+    let kept = plan(&source, &dest, &filter, false);
+    assert_eq!(kept.actions, copies);
+    assert_eq!((kept.unchanged, kept.filtered), (2, 2));
+    assert_eq!(kept.copy_bytes(), 16);
+    assert_eq!(kept.parent_dirs(), ["src/deep"]);
+
+    let deleted = plan(&source, &dest, &filter, true);
+    let mut actions = copies;
+    actions.push(SyncAction::Delete {
+        path: "stale.rs".to_owned(),
+    });
+    assert_eq!(deleted.actions, actions);
+}
+
+#[test]
+fn hash_candidates_are_same_size_files_with_new_mtimes() {
+    let source = tree(&[
+        ("a", 3, 11, None),
+        ("b", 3, 11, Some("aa")),
+        ("c", 3, 10, None),
+        ("d", 4, 11, None),
+        ("e.log", 3, 11, None),
+    ]);
+    let dest = tree(&[
+        ("a", 3, 10, None),
+        ("b", 3, 10, None),
+        ("c", 3, 10, None),
+        ("d", 3, 10, None),
+        ("e.log", 3, 10, None),
+    ]);
+    let Ok(filter) = SyncFilter::new(&[], &globs(&["*.log"])) else {
+        panic!("valid globs");
+    };
+    assert_eq!(hash_candidates(&source, &dest, &filter), ["a", "b"]);
+}
@@ -0,0 +1,111 @@
+use field_exec_core::transfer::{
+    ChunkSpan, MIN_CHUNK_BYTES, SourceFingerprint, TransferDirection, TransferJournal, journal_key,
+};
+
+fn upload() -> TransferJournal {
+    TransferJournal::new(
+        TransferDirection::Upload,
+        "example.com",
+        22,
+        "dev",
+        "/home/dev/a.bin",
+        "/srv/a.bin",
+    )
+    .chunk_bytes(Some(1))
+}
+
+#[test]
+fn journal_round_trips_through_json() {
+    let mut journal = upload();
+    journal.restart(SourceFingerprint {
+        size: 2 * MIN_CHUNK_BYTES + 5,
+        mtime: 1_700_000_000,
+    });
+    let Some(first) = journal.next_chunk() else {
+        panic!("nothing sent yet");
+    };
+    journal.record_chunk(first, "aa".to_owned());
+    journal.updated_at = 1_700_000_100;
+
+    let Ok(read) = TransferJournal::from_json(&journal.to_json()) else {
+        panic!("journal reads back");
+    };
+    assert_eq!(read, journal);
+    assert_eq!(read.chunk_bytes, MIN_CHUNK_BYTES);
+    assert_eq!(read.verified_offset(), MIN_CHUNK_BYTES);
+    assert_eq!(read.partial_path(), "/srv/a.bin.partial");
+    assert_eq!(
+        read.last_chunk(),
+        Some((
+            ChunkSpan {
+                index: 0,
+                offset: 0,
+                len: MIN_CHUNK_BYTES
+            },
+            "aa"
+        ))
+    );
+}
+
+#[test]
+fn journal_key_is_stable_per_transfer() {
+    let journal = upload();
+    assert_eq!(
+        journal.key,
+        journal_key(
+            TransferDirection::Upload,
+            "example.com",
+            22,
+            "dev",
+            "/home/dev/a.bin",
+            "/srv/a.bin"
+        )
+    );
+    assert_eq!(journal.key, upload().key);
+    let download = TransferJournal::new(
+        TransferDirection::Download,
+        "example.com",
+        22,
+        "dev",
+        "/home/dev/a.bin",
+        "/srv/a.bin",
+    );
+    assert_ne!(journal.key, download.key);
+    assert_eq!(download.partial_path(), "/home/dev/a.bin.partial");
+}
+
+#[test]
+fn journal_chunks_cover_the_source_in_order() {
+    let mut journal = upload();
+    journal.restart(SourceFingerprint {
+        size: MIN_CHUNK_BYTES + 5,
+        mtime: 1,
+    });
+    let mut spans = Vec::new();
+    while let Some(span) = journal.next_chunk() {
+        spans.push((span.index, span.offset, span.len));
+        journal.record_chunk(span, span.index.to_string());
+    }
+    assert_eq!(spans, [(0, 0, MIN_CHUNK_BYTES), (1, MIN_CHUNK_BYTES, 5)]);
+    assert!(journal.is_complete());
+
+    // Chunks recorded out of order are ignored.
+    journal.restart(SourceFingerprint { size: 10, mtime: 2 });
+    journal.record_chunk(
+        ChunkSpan {
+            index: 1,
+            offset: 0,
+            len: 10,
+        },
+        "x".to_owned(),
+    );
+    assert!(journal.chunks.is_empty());
+}
+
+#[test]
+fn journals_from_other_versions_are_rejected() {
+    let mut journal = upload();
+    journal.version = 99;
+    assert!(TransferJournal::from_json(&journal.to_json()).is_err());
+    assert!(TransferJournal::from_json(b"{}").is_err());
+}
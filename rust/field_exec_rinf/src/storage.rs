@@ -20,6 +20,12 @@ pub struct StorageClient {
     pending: Arc<Mutex<HashMap<u64, oneshot::Sender<StorageResponse>>>>,
 }
 
+impl Default for StorageClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StorageClient {
     pub fn new() -> Self {
         let client = Self {
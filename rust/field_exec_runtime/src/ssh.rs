@@ -5,14 +5,31 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use async_ssh2_tokio::Error as SshError;
+use field_exec_adapters::fs::{ListVia, list_dir};
+use field_exec_adapters::ssh::{exec_bytes, exec_bytes_with_input};
+use field_exec_adapters::stream::{sleep_until_deadline, stream_end, stream_signal};
 use field_exec_api::signals::{
-    AuthProvide, AuthRequired, SshAuthorizedKeyRequest, SshAuthorizedKeyResponse, SshCancelStream,
-    SshExecRequest, SshExecResponse, SshGenerateKeyRequest, SshGenerateKeyResponse,
-    SshInstallPublicKeyRequest, SshInstallPublicKeyResponse, SshStartCommandRequest,
-    SshStartCommandResponse, SshStreamExit, SshStreamLine, SshResetAllRequest, SshResetAllResponse,
-    SshWriteFileRequest,
-    SshWriteFileResponse,
+    AuthProvide, AuthRequired, CompressionStats, FsEntry, FsEntryKind, FsListRequest,
+    FsListResponse, FsReadRequest, FsReadResponse, JobAlarm, JobApprovalPolicy, JobOutcome,
+    JobSandbox, JobStartRequest, JobStartResponse, JobState, JobStatusRequest, JobStatusResponse,
+    JobStopRequest, JobStopResponse, LogFetchRequest, LogFetchResponse, LogReset,
+    SshAuthorizedKeyRequest, SshAuthorizedKeyResponse, SshCancelStream, SshExecRequest,
+    SshExecResponse, SshGenerateKeyRequest, SshGenerateKeyResponse, SshInstallPublicKeyRequest,
+    SshInstallPublicKeyResponse, SshResetAllRequest, SshResetAllResponse, SshStartCommandRequest,
+    SshStartCommandResponse, SshStreamExit, SshStreamLine, SshStreamLinePart, SshWriteFileRequest,
+    SshWriteFileResponse, StreamTermination,
 };
+use field_exec_core::atomic_write::{AtomicWrite, WritePrecondition};
+use field_exec_core::codex_command::{ApprovalPolicy, CodexCommand, SandboxMode};
+use field_exec_core::compress as remote_compress;
+use field_exec_core::job::{
+    JobLaunch, JobLiveness, RemoteJobId, SessionFiles, check_tab_id, stop_script,
+};
+use field_exec_core::job_status::{self, DEFAULT_HANG_AFTER_SECS, HeartbeatAlarm, JobStatusCheck};
+use field_exec_core::lines::{LineBuffer, LineChunk};
+use field_exec_core::output_schema::ExtensionLoad;
+use field_exec_core::remote_fs::{self, DirList, EntryKind, FileRead, LogFetch};
+use field_exec_core::stream::{StreamEnd, StreamTermination as CoreTermination, StreamWatchdog};
 use field_exec_rinf::storage::StorageClient;
 use rand_core::OsRng;
 use rinf::{DartSignal, RustSignal};
@@ -310,6 +327,12 @@ pub async fn run() {
     let exec_rx = SshExecRequest::get_dart_signal_receiver();
     let start_rx = SshStartCommandRequest::get_dart_signal_receiver();
     let write_rx = SshWriteFileRequest::get_dart_signal_receiver();
+    let read_rx = FsReadRequest::get_dart_signal_receiver();
+    let list_rx = FsListRequest::get_dart_signal_receiver();
+    let log_rx = LogFetchRequest::get_dart_signal_receiver();
+    let job_start_rx = JobStartRequest::get_dart_signal_receiver();
+    let job_stop_rx = JobStopRequest::get_dart_signal_receiver();
+    let job_status_rx = JobStatusRequest::get_dart_signal_receiver();
     let reset_rx = SshResetAllRequest::get_dart_signal_receiver();
     let gen_rx = SshGenerateKeyRequest::get_dart_signal_receiver();
     let authkey_rx = SshAuthorizedKeyRequest::get_dart_signal_receiver();
@@ -350,6 +373,66 @@ pub async fn run() {
                     response.send_signal_to_dart();
                 });
             }
+            Some(pack) = read_rx.recv() => {
+                let req = pack.message;
+                let storage = storage.clone();
+                let auth = auth.clone();
+                let pool = pool.clone();
+                spawn(async move {
+                    let response = handle_fs_read(storage, auth, pool, req).await;
+                    response.send_signal_to_dart();
+                });
+            }
+            Some(pack) = list_rx.recv() => {
+                let req = pack.message;
+                let storage = storage.clone();
+                let auth = auth.clone();
+                let pool = pool.clone();
+                spawn(async move {
+                    let response = handle_fs_list(storage, auth, pool, req).await;
+                    response.send_signal_to_dart();
+                });
+            }
+            Some(pack) = log_rx.recv() => {
+                let req = pack.message;
+                let storage = storage.clone();
+                let auth = auth.clone();
+                let pool = pool.clone();
+                spawn(async move {
+                    let response = handle_log_fetch(storage, auth, pool, req).await;
+                    response.send_signal_to_dart();
+                });
+            }
+            Some(pack) = job_start_rx.recv() => {
+                let req = pack.message;
+                let storage = storage.clone();
+                let auth = auth.clone();
+                let pool = pool.clone();
+                spawn(async move {
+                    let response = handle_job_start(storage, auth, pool, req).await;
+                    response.send_signal_to_dart();
+                });
+            }
+            Some(pack) = job_stop_rx.recv() => {
+                let req = pack.message;
+                let storage = storage.clone();
+                let auth = auth.clone();
+                let pool = pool.clone();
+                spawn(async move {
+                    let response = handle_job_stop(storage, auth, pool, req).await;
+                    response.send_signal_to_dart();
+                });
+            }
+            Some(pack) = job_status_rx.recv() => {
+                let req = pack.message;
+                let storage = storage.clone();
+                let auth = auth.clone();
+                let pool = pool.clone();
+                spawn(async move {
+                    let response = handle_job_status(storage, auth, pool, req).await;
+                    response.send_signal_to_dart();
+                });
+            }
             Some(pack) = reset_rx.recv() => {
                 let req = pack.message;
                 let pool = pool.clone();
@@ -401,6 +484,9 @@ async fn handle_exec(
     req: SshExecRequest,
 ) -> SshExecResponse {
     let request_id = req.request_id;
+    if req.compress {
+        return handle_exec_compressed(storage, auth, pool, req).await;
+    }
 
     let port: u16 = match u16::try_from(req.port) {
         Ok(p) => p,
@@ -411,6 +497,7 @@ async fn handle_exec(
                 stdout: String::new(),
                 stderr: String::new(),
                 exit_status: -1,
+                compression: None,
                 error: Some("Invalid port".to_owned()),
             };
         }
@@ -423,7 +510,7 @@ async fn handle_exec(
         .private_key_pem
         .clone()
         .filter(|s| !s.trim().is_empty())
-        .unwrap_or_else(String::new);
+        .unwrap_or_default();
 
     let private_key_pem = if private_key_pem.trim().is_empty() {
         storage
@@ -467,6 +554,7 @@ async fn handle_exec(
                     stdout: r.stdout,
                     stderr: r.stderr,
                     exit_status: i32::try_from(r.exit_status).unwrap_or(-1),
+                    compression: None,
                     error: None,
                 };
             }
@@ -478,6 +566,7 @@ async fn handle_exec(
                     stdout: String::new(),
                     stderr: String::new(),
                     exit_status: -1,
+                    compression: None,
                     error: Some("SSH private key is invalid or passphrase is wrong".to_owned()),
                 };
             }
@@ -488,6 +577,7 @@ async fn handle_exec(
                     stdout: String::new(),
                     stderr: String::new(),
                     exit_status: -1,
+                    compression: None,
                     error: Some(e.to_string()),
                 };
             }
@@ -511,6 +601,7 @@ async fn handle_exec(
                 stdout: String::new(),
                 stderr: String::new(),
                 exit_status: -1,
+                compression: None,
                 error: Some(e),
             };
         }
@@ -535,6 +626,7 @@ async fn handle_exec(
             stdout: r.stdout,
             stderr: r.stderr,
             exit_status: i32::try_from(r.exit_status).unwrap_or(-1),
+            compression: None,
             error: None,
         },
         Err(e) => SshExecResponse {
@@ -543,6 +635,7 @@ async fn handle_exec(
             stdout: String::new(),
             stderr: String::new(),
             exit_status: -1,
+            compression: None,
             error: Some(e.to_string()),
         },
     }
@@ -569,12 +662,7 @@ impl StreamRegistry {
                 let handle = { tasks.lock().await.remove(&stream_id) };
                 if let Some(handle) = handle {
                     handle.abort();
-                    SshStreamExit {
-                        stream_id,
-                        exit_status: -1,
-                        error: Some("cancelled".to_owned()),
-                    }
-                    .send_signal_to_dart();
+                    cancelled_stream_exit(stream_id, "cancelled").send_signal_to_dart();
                 }
             }
         });
@@ -614,6 +702,14 @@ impl StreamRegistry {
         .await;
 
         let connect_timeout = Duration::from_millis(req.connect_timeout_ms.max(1) as u64);
+        let max_line_bytes = req
+            .max_line_bytes
+            .and_then(|v| usize::try_from(v).ok())
+            .filter(|v| *v > 0);
+        let mut watchdog = StreamWatchdog::new(
+            optional_timeout_ms(req.max_duration_ms),
+            optional_timeout_ms(req.idle_output_timeout_ms),
+        );
 
         let command = req.command.clone();
         let host = req.host.clone();
@@ -649,22 +745,42 @@ impl StreamRegistry {
             tokio::task::yield_now().await;
             let (stdout_tx, mut stdout_rx) = mpsc::channel::<Vec<u8>>(16);
             let (stderr_tx, mut stderr_rx) = mpsc::channel::<Vec<u8>>(16);
-
-            let exec_future =
-                client.execute_io(&command, stdout_tx, Some(stderr_tx), None, false, None);
-
-            let mut out_pending = String::new();
-            let mut err_pending = String::new();
-
-            tokio::pin!(exec_future);
-            let exit_status = loop {
-                tokio::select! {
-                    result = &mut exec_future => break result,
-                    Some(bytes) = stdout_rx.recv() => {
-                        push_lines(stream_id, false, &mut out_pending, &bytes);
-                    }
-                    Some(bytes) = stderr_rx.recv() => {
-                        push_lines(stream_id, true, &mut err_pending, &bytes);
+            let (signal_tx, signal_rx) = mpsc::channel::<russh::Sig>(2);
+
+            let mut out_pending = LineBuffer::new(max_line_bytes);
+            let mut err_pending = LineBuffer::new(max_line_bytes);
+
+            // Scoped so an abandoned command drops its channel senders before
+            // the drain loops below.
+            let exit = {
+                let exec_future = client.execute_io_with_exit(
+                    &command,
+                    stdout_tx,
+                    Some(stderr_tx),
+                    None,
+                    false,
+                    Some(signal_rx),
+                );
+                tokio::pin!(exec_future);
+                loop {
+                    tokio::select! {
+                        result = &mut exec_future => break Some(result),
+                        Some(bytes) = stdout_rx.recv() => {
+                            watchdog.on_output();
+                            push_lines(stream_id, false, &mut out_pending, &bytes);
+                        }
+                        Some(bytes) = stderr_rx.recv() => {
+                            watchdog.on_output();
+                            push_lines(stream_id, true, &mut err_pending, &bytes);
+                        }
+                        _ = sleep_until_deadline(watchdog.deadline()) => {
+                            match watchdog.expire() {
+                                Some(sig) => {
+                                    let _ = signal_tx.send(stream_signal(sig)).await;
+                                }
+                                None => break None,
+                            }
+                        }
                     }
                 }
             };
@@ -679,24 +795,12 @@ impl StreamRegistry {
             flush_pending(stream_id, false, &mut out_pending);
             flush_pending(stream_id, true, &mut err_pending);
 
-            match exit_status {
-                Ok(code) => {
-                    SshStreamExit {
-                        stream_id,
-                        exit_status: i32::try_from(code).unwrap_or(-1),
-                        error: None,
-                    }
-                    .send_signal_to_dart();
-                }
-                Err(e) => {
-                    SshStreamExit {
-                        stream_id,
-                        exit_status: -1,
-                        error: Some(e.to_string()),
-                    }
-                    .send_signal_to_dart();
-                }
+            let end = match &exit {
+                Some(result) => stream_end(result),
+                None => StreamEnd::failed(CoreTermination::TimedOut, "timed out"),
             }
+            .watched_by(&watchdog);
+            stream_exit(stream_id, end).send_signal_to_dart();
         });
 
         self.tasks.lock().await.insert(stream_id, handle);
@@ -714,45 +818,71 @@ impl StreamRegistry {
         let n = g.len();
         for (stream_id, handle) in g.drain() {
             handle.abort();
-            SshStreamExit {
-                stream_id,
-                exit_status: -1,
-                error: Some(reason.to_owned()),
-            }
-            .send_signal_to_dart();
+            cancelled_stream_exit(stream_id, reason).send_signal_to_dart();
         }
         n
     }
 }
 
-fn push_lines(stream_id: u64, is_stderr: bool, pending: &mut String, bytes: &[u8]) {
-    let chunk = String::from_utf8_lossy(bytes);
-    pending.push_str(&chunk);
-    while let Some(idx) = pending.find('\n') {
-        let line = pending[..idx].trim_end_matches('\r').to_owned();
-        pending.drain(..idx + 1);
-        SshStreamLine {
-            stream_id,
-            is_stderr,
-            line,
-        }
-        .send_signal_to_dart();
-    }
+fn optional_timeout_ms(ms: Option<i32>) -> Option<Duration> {
+    ms.filter(|v| *v > 0)
+        .map(|v| Duration::from_millis(v as u64))
 }
 
-fn flush_pending(stream_id: u64, is_stderr: bool, pending: &mut String) {
-    let line = pending.trim().to_owned();
-    if line.is_empty() {
-        pending.clear();
-        return;
+fn stream_exit(stream_id: u64, end: StreamEnd) -> SshStreamExit {
+    SshStreamExit {
+        stream_id,
+        exit_status: end.exit_status,
+        exit_signal: end.exit_signal,
+        core_dumped: end.core_dumped,
+        signal_message: end.signal_message,
+        termination: match end.termination {
+            CoreTermination::Exited => StreamTermination::Exited,
+            CoreTermination::Signaled => StreamTermination::Signaled,
+            CoreTermination::TransportError => StreamTermination::TransportError,
+            CoreTermination::Cancelled => StreamTermination::Cancelled,
+            CoreTermination::TimedOut => StreamTermination::TimedOut,
+        },
+        error: end.error,
     }
-    pending.clear();
-    SshStreamLine {
+}
+
+fn cancelled_stream_exit(stream_id: u64, reason: &str) -> SshStreamExit {
+    stream_exit(
         stream_id,
-        is_stderr,
-        line,
+        StreamEnd::failed(CoreTermination::Cancelled, reason),
+    )
+}
+
+fn push_lines(stream_id: u64, is_stderr: bool, pending: &mut LineBuffer, bytes: &[u8]) {
+    for chunk in pending.push(bytes) {
+        send_chunk(stream_id, is_stderr, chunk);
+    }
+}
+
+fn flush_pending(stream_id: u64, is_stderr: bool, pending: &mut LineBuffer) {
+    if let Some(chunk) = pending.finish() {
+        send_chunk(stream_id, is_stderr, chunk);
+    }
+}
+
+fn send_chunk(stream_id: u64, is_stderr: bool, chunk: LineChunk) {
+    match chunk.part {
+        Some(part) => SshStreamLinePart {
+            stream_id,
+            is_stderr,
+            index: part.index,
+            is_final: part.is_final,
+            chunk: chunk.text,
+        }
+        .send_signal_to_dart(),
+        None => SshStreamLine {
+            stream_id,
+            is_stderr,
+            line: chunk.text,
+        }
+        .send_signal_to_dart(),
     }
-    .send_signal_to_dart();
 }
 
 async fn resolve_key_pem(
@@ -771,6 +901,7 @@ async fn resolve_key_pem(
         .unwrap_or_default()
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn connect_with_optional_password(
     auth: &AuthBroker,
     request_id: u64,
@@ -816,6 +947,593 @@ async fn connect_with_optional_password(
         .map_err(|e| e.to_string())
 }
 
+/// Connection details carried by every request that runs something on a host.
+struct HostRequest<'a> {
+    request_id: u64,
+    host: &'a str,
+    port: i32,
+    username: &'a str,
+    private_key_pem: Option<String>,
+    private_key_passphrase: Option<String>,
+    connect_timeout_ms: i32,
+}
+
+async fn connect_for_request(
+    storage: &StorageClient,
+    auth: &AuthBroker,
+    pool: &SshConnectionPool,
+    target: HostRequest<'_>,
+) -> Result<async_ssh2_tokio::Client, String> {
+    let port = u16::try_from(target.port).map_err(|_| "Invalid port".to_owned())?;
+    let private_key_pem = resolve_key_pem(
+        storage,
+        target.private_key_pem,
+        KEYCHAIN_KEY_SSH_PRIVATE_KEY_PEM,
+    )
+    .await;
+    let connect_timeout = Duration::from_millis(target.connect_timeout_ms.max(1) as u64);
+    connect_with_optional_password(
+        auth,
+        target.request_id,
+        target.host,
+        port,
+        target.username,
+        private_key_pem,
+        target.private_key_passphrase,
+        connect_timeout,
+        pool,
+    )
+    .await
+    .map(|(client, _)| client)
+}
+
+fn compression_stats(stats: remote_compress::CompressionStats) -> CompressionStats {
+    CompressionStats {
+        compressed: stats.compressed,
+        wire_bytes: stats.wire_bytes,
+        raw_bytes: stats.raw_bytes,
+    }
+}
+
+async fn handle_exec_compressed(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: SshExecRequest,
+) -> SshExecResponse {
+    let request_id = req.request_id;
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+    let result = async {
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        exec_bytes(&client, &req.command, command_timeout, true)
+            .await
+            .map_err(|e| e.to_string())
+    }
+    .await;
+
+    match result {
+        Ok(output) => SshExecResponse {
+            request_id,
+            ok: true,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            exit_status: i32::try_from(output.exit_status).unwrap_or(-1),
+            compression: output.compression.map(compression_stats),
+            error: None,
+        },
+        Err(e) => SshExecResponse {
+            request_id,
+            ok: false,
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_status: -1,
+            compression: None,
+            error: Some(e),
+        },
+    }
+}
+
+async fn handle_fs_read(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: FsReadRequest,
+) -> FsReadResponse {
+    let request_id = req.request_id;
+    let read = FileRead::new(req.root.clone(), req.path.clone())
+        .offset(req.offset)
+        .length(req.length)
+        .max_bytes(req.max_bytes);
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+
+    let result = async {
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        let output = exec_bytes(&client, &read.script(), command_timeout, req.compress)
+            .await
+            .map_err(|e| e.to_string())?;
+        let compression = output.compression.map(compression_stats);
+        read.parse_output(
+            output.exit_status,
+            output.stdout,
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map(|contents| (contents, compression))
+        .map_err(|e| e.to_string())
+    }
+    .await;
+
+    match result {
+        Ok((contents, compression)) => FsReadResponse {
+            request_id,
+            ok: true,
+            data: contents.data,
+            offset: contents.offset,
+            size: contents.size,
+            mtime: contents.mtime,
+            mode: contents.mode,
+            truncated: contents.truncated,
+            compression,
+            error: None,
+        },
+        Err(e) => FsReadResponse {
+            request_id,
+            ok: false,
+            data: Vec::new(),
+            offset: req.offset,
+            size: 0,
+            mtime: 0,
+            mode: 0,
+            truncated: false,
+            compression: None,
+            error: Some(e),
+        },
+    }
+}
+
+async fn handle_fs_list(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: FsListRequest,
+) -> FsListResponse {
+    let request_id = req.request_id;
+    let list = DirList::new(req.path.clone())
+        .show_hidden(req.show_hidden)
+        .depth(req.depth);
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+
+    let result = async {
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        list_dir(&client, &list, command_timeout).await
+    }
+    .await;
+
+    match result {
+        Ok((listing, via)) => FsListResponse {
+            request_id,
+            ok: true,
+            path: listing.path,
+            entries: listing
+                .entries
+                .into_iter()
+                .map(|e| FsEntry {
+                    path: e.path,
+                    kind: match e.kind {
+                        EntryKind::File => FsEntryKind::File,
+                        EntryKind::Dir => FsEntryKind::Dir,
+                        EntryKind::Symlink => FsEntryKind::Symlink,
+                        EntryKind::Other => FsEntryKind::Other,
+                    },
+                    size: e.size,
+                    mtime: e.mtime,
+                    is_git_repo: e.is_git_repo,
+                    has_field_exec: e.has_field_exec,
+                })
+                .collect(),
+            truncated: listing.truncated,
+            via_sftp: via == ListVia::Sftp,
+            error: None,
+        },
+        Err(e) => FsListResponse {
+            request_id,
+            ok: false,
+            path: req.path,
+            entries: Vec::new(),
+            truncated: false,
+            via_sftp: false,
+            error: Some(e),
+        },
+    }
+}
+
+async fn handle_log_fetch(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: LogFetchRequest,
+) -> LogFetchResponse {
+    let request_id = req.request_id;
+    let fetch = LogFetch::new(req.path.clone())
+        .from_offset(req.from_offset)
+        .inode(req.inode)
+        .max_bytes(req.max_bytes);
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+
+    let result = async {
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        let output = exec_bytes(&client, &fetch.script(), command_timeout, req.compress)
+            .await
+            .map_err(|e| e.to_string())?;
+        let compression = output.compression.map(compression_stats);
+        fetch
+            .parse_output(
+                output.exit_status,
+                output.stdout,
+                &String::from_utf8_lossy(&output.stderr),
+            )
+            .map(|chunk| (chunk, compression))
+            .map_err(|e| e.to_string())
+    }
+    .await;
+
+    match result {
+        Ok((chunk, compression)) => LogFetchResponse {
+            request_id,
+            ok: true,
+            has_more: chunk.has_more(),
+            data: chunk.data,
+            offset: chunk.offset,
+            next_offset: chunk.next_offset,
+            inode: chunk.inode,
+            size: chunk.size,
+            reset: chunk.reset.map(|r| match r {
+                remote_fs::LogReset::Truncated => LogReset::Truncated,
+                remote_fs::LogReset::Rotated => LogReset::Rotated,
+            }),
+            compression,
+            error: None,
+        },
+        Err(e) => LogFetchResponse {
+            request_id,
+            ok: false,
+            data: Vec::new(),
+            offset: req.from_offset,
+            next_offset: req.from_offset,
+            inode: req.inode.unwrap_or(0),
+            size: 0,
+            reset: None,
+            has_more: false,
+            compression: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn sandbox_mode(sandbox: JobSandbox) -> SandboxMode {
+    match sandbox {
+        JobSandbox::ReadOnly => SandboxMode::ReadOnly,
+        JobSandbox::WorkspaceWrite => SandboxMode::WorkspaceWrite,
+        JobSandbox::DangerFullAccess => SandboxMode::DangerFullAccess,
+    }
+}
+
+fn approval_policy(policy: JobApprovalPolicy) -> ApprovalPolicy {
+    match policy {
+        JobApprovalPolicy::Untrusted => ApprovalPolicy::Untrusted,
+        JobApprovalPolicy::OnFailure => ApprovalPolicy::OnFailure,
+        JobApprovalPolicy::OnRequest => ApprovalPolicy::OnRequest,
+        JobApprovalPolicy::Never => ApprovalPolicy::Never,
+    }
+}
+
+async fn handle_job_start(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: JobStartRequest,
+) -> JobStartResponse {
+    let request_id = req.request_id;
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+    let load = ExtensionLoad::new(req.project_dir.clone());
+    let mut command = CodexCommand::new()
+        .model(req.model.clone())
+        .profile(req.profile.clone())
+        .sandbox(req.sandbox.map(sandbox_mode))
+        .approval(req.approval_policy.map(approval_policy))
+        .resume(req.resume_thread_id.clone());
+    for image in &req.images {
+        command = command.image(image.clone());
+    }
+    let launch = JobLaunch::new(
+        req.project_dir.clone(),
+        req.tab_id.clone(),
+        req.prompt.clone(),
+    )
+    .target_key(&req.target_key)
+    .command(command)
+    .use_tmux(req.use_tmux)
+    .log_preamble(req.log_preamble.clone());
+    let files = launch.files();
+
+    let result = async {
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        let output = exec_bytes(&client, &load.script(), command_timeout, false)
+            .await
+            .map_err(|e| e.to_string())?;
+        let schema = load
+            .compose(
+                output.exit_status,
+                &output.stdout,
+                &String::from_utf8_lossy(&output.stderr),
+            )
+            .map_err(|e| e.to_string())?;
+        let launch = launch.output_schema(Some(schema.to_json()));
+        let script = launch.script().map_err(|e| e.to_string())?;
+        let output =
+            exec_bytes_with_input(&client, &script, launch.input().as_bytes(), command_timeout)
+                .await
+                .map_err(|e| e.to_string())?;
+        launch
+            .parse_output(
+                output.exit_status,
+                &String::from_utf8_lossy(&output.stdout),
+                &String::from_utf8_lossy(&output.stderr),
+            )
+            .map_err(|e| e.to_string())
+    }
+    .await;
+
+    match result {
+        Ok(job) => JobStartResponse {
+            request_id,
+            ok: true,
+            job_id: job.to_string(),
+            log_path: files.log,
+            stderr_path: files.stderr_log,
+            error: None,
+        },
+        Err(e) => JobStartResponse {
+            request_id,
+            ok: false,
+            job_id: String::new(),
+            log_path: files.log,
+            stderr_path: files.stderr_log,
+            error: Some(e),
+        },
+    }
+}
+
+async fn handle_job_stop(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: JobStopRequest,
+) -> JobStopResponse {
+    let request_id = req.request_id;
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+
+    let result = async {
+        let job = RemoteJobId::parse(&req.job_id)
+            .ok_or_else(|| format!("invalid job id: {:?}", req.job_id))?;
+        check_tab_id(&req.tab_id).map_err(|e| e.to_string())?;
+        let files = SessionFiles::new(&req.project_dir, &req.tab_id);
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        let output = exec_bytes(
+            &client,
+            &stop_script(&job, Some(&files)),
+            command_timeout,
+            false,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        if output.exit_status != 0 {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!(
+                "job.stop exited with {}: {}",
+                output.exit_status,
+                stderr.trim()
+            ));
+        }
+        Ok(())
+    }
+    .await;
+
+    JobStopResponse {
+        request_id,
+        ok: result.is_ok(),
+        error: result.err(),
+    }
+}
+
+async fn handle_job_status(
+    storage: StorageClient,
+    auth: AuthBroker,
+    pool: SshConnectionPool,
+    req: JobStatusRequest,
+) -> JobStatusResponse {
+    let request_id = req.request_id;
+    let command_timeout = Duration::from_millis(req.command_timeout_ms.max(1) as u64);
+
+    let result = async {
+        let job = RemoteJobId::parse(&req.job_id)
+            .ok_or_else(|| format!("invalid job id: {:?}", req.job_id))?;
+        let check = JobStatusCheck::new(job, &req.project_dir, &req.tab_id)
+            .hang_after_secs(req.hang_after_secs.unwrap_or(DEFAULT_HANG_AFTER_SECS));
+        let script = check.script().map_err(|e| e.to_string())?;
+        let client = connect_for_request(
+            &storage,
+            &auth,
+            &pool,
+            HostRequest {
+                request_id,
+                host: &req.host,
+                port: req.port,
+                username: &req.username,
+                private_key_pem: req.private_key_pem.clone(),
+                private_key_passphrase: req.private_key_passphrase.clone(),
+                connect_timeout_ms: req.connect_timeout_ms,
+            },
+        )
+        .await?;
+        let output = exec_bytes(&client, &script, command_timeout, false)
+            .await
+            .map_err(|e| e.to_string())?;
+        check
+            .parse_output(
+                output.exit_status,
+                &output.stdout,
+                &String::from_utf8_lossy(&output.stderr),
+            )
+            .map_err(|e| e.to_string())
+    }
+    .await;
+
+    match result {
+        Ok(status) => {
+            let (outcome, outcome_message) = match status.outcome {
+                job_status::JobOutcome::Running => (JobOutcome::Running, None),
+                job_status::JobOutcome::Completed => (JobOutcome::Completed, None),
+                job_status::JobOutcome::Failed { message } => (JobOutcome::Failed, Some(message)),
+                job_status::JobOutcome::Hung { .. } => (JobOutcome::Hung, None),
+                job_status::JobOutcome::Cancelled => (JobOutcome::Cancelled, None),
+                job_status::JobOutcome::Crashed { message } => (JobOutcome::Crashed, message),
+                job_status::JobOutcome::Unknown => (JobOutcome::Unknown, None),
+            };
+            let response = status.response;
+            JobStatusResponse {
+                request_id,
+                ok: true,
+                state: Some(match status.liveness {
+                    JobLiveness::Running => JobState::Running,
+                    JobLiveness::Exited => JobState::Exited,
+                    JobLiveness::Unknown => JobState::Unknown,
+                }),
+                outcome: Some(outcome),
+                outcome_message,
+                alarm: status.alarm.map(|alarm| match alarm {
+                    HeartbeatAlarm::Rebooted => JobAlarm::Rebooted,
+                    HeartbeatAlarm::PidReused => JobAlarm::PidReused,
+                    HeartbeatAlarm::Hung { .. } => JobAlarm::Hung,
+                }),
+                idle_secs: status.idle_secs,
+                thread_id: status.thread_id,
+                final_message: status.final_message,
+                response_json: response
+                    .as_ref()
+                    .and_then(|check| check.normalized.as_ref())
+                    .and_then(|object| serde_json::to_string(object).ok()),
+                response_violations: response
+                    .iter()
+                    .flat_map(|check| &check.remaining)
+                    .map(|v| format!("{}: {}", v.path, v.message))
+                    .collect(),
+                error: None,
+            }
+        }
+        Err(e) => JobStatusResponse {
+            request_id,
+            ok: false,
+            state: None,
+            outcome: None,
+            outcome_message: None,
+            alarm: None,
+            idle_secs: None,
+            thread_id: None,
+            final_message: None,
+            response_json: None,
+            response_violations: Vec::new(),
+            error: Some(e),
+        },
+    }
+}
+
+/// Size of the stdin pieces a file write is streamed in.
+const WRITE_CHUNK_BYTES: usize = 32 * 1024;
+
 async fn handle_write_file(
     storage: StorageClient,
     auth: AuthBroker,
@@ -829,11 +1547,28 @@ async fn handle_write_file(
             return SshWriteFileResponse {
                 request_id,
                 ok: false,
+                sha256: None,
                 error: Some("Invalid port".to_owned()),
             };
         }
     };
 
+    let precondition =
+        match WritePrecondition::from_flags(req.create_only, req.if_matches_sha256.clone()) {
+            Ok(precondition) => precondition,
+            Err(e) => {
+                return SshWriteFileResponse {
+                    request_id,
+                    ok: false,
+                    sha256: None,
+                    error: Some(e.to_string()),
+                };
+            }
+        };
+    let write = AtomicWrite::new(&req.remote_path, &req.contents)
+        .mode(req.mode)
+        .precondition(precondition);
+
     let private_key_pem = resolve_key_pem(
         &storage,
         req.private_key_pem.clone(),
@@ -862,20 +1597,13 @@ async fn handle_write_file(
             return SshWriteFileResponse {
                 request_id,
                 ok: false,
+                sha256: None,
                 error: Some(e),
             };
         }
     };
 
-    let remote_dir = match req.remote_path.rfind('/') {
-        Some(idx) => &req.remote_path[..idx],
-        None => ".",
-    };
-    let cmd = format!(
-        "mkdir -p {} && cat > {}",
-        sh_quote(remote_dir),
-        sh_quote(&req.remote_path)
-    );
+    let cmd = write.script();
 
     let (stdout_tx, mut stdout_rx) = mpsc::channel::<Vec<u8>>(8);
     let (stderr_tx, mut stderr_rx) = mpsc::channel::<Vec<u8>>(8);
@@ -892,7 +1620,11 @@ async fn handle_write_file(
     tokio::pin!(exec_future);
 
     let send_stdin = async move {
-        let _ = stdin_tx.send(req.contents.into_bytes()).await;
+        for chunk in req.contents.chunks(WRITE_CHUNK_BYTES) {
+            if stdin_tx.send(chunk.to_vec()).await.is_err() {
+                return;
+            }
+        }
         let _ = stdin_tx.send(Vec::new()).await;
     };
     spawn(send_stdin);
@@ -912,11 +1644,12 @@ async fn handle_write_file(
     .await;
 
     let status = match status {
-        Ok(Ok(code)) => i32::try_from(code).unwrap_or(-1),
+        Ok(Ok(code)) => code,
         Ok(Err(e)) => {
             return SshWriteFileResponse {
                 request_id,
                 ok: false,
+                sha256: None,
                 error: Some(e.to_string()),
             };
         }
@@ -924,44 +1657,36 @@ async fn handle_write_file(
             return SshWriteFileResponse {
                 request_id,
                 ok: false,
+                sha256: None,
                 error: Some("SSH command timeout".to_owned()),
             };
         }
     };
 
-    if status == 0 {
-        return SshWriteFileResponse {
+    // The script reports the checksum last; pick up output that was still
+    // buffered when the command finished.
+    while let Ok(bytes) = stdout_rx.try_recv() {
+        out.extend_from_slice(&bytes);
+    }
+    while let Ok(bytes) = stderr_rx.try_recv() {
+        err.extend_from_slice(&bytes);
+    }
+    let stdout = String::from_utf8_lossy(&out);
+    let stderr = String::from_utf8_lossy(&err);
+    match write.parse_result(status, &stdout, &stderr) {
+        Ok(sha256) => SshWriteFileResponse {
             request_id,
             ok: true,
+            sha256: Some(sha256),
             error: None,
-        };
-    }
-
-    let stderr = String::from_utf8_lossy(&err).to_string();
-    let stdout = String::from_utf8_lossy(&out).to_string();
-    let msg = if stderr.trim().is_empty() {
-        stdout
-    } else {
-        stderr
-    };
-
-    SshWriteFileResponse {
-        request_id,
-        ok: false,
-        error: Some(format!("write failed (exit={status}): {}", msg.trim())),
-    }
-}
-
-fn sh_quote(s: &str) -> String {
-    if s.is_empty() {
-        return "''".to_owned();
-    }
-    if s.chars()
-        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '/' | ':' | '=' | '@' | '-'))
-    {
-        return s.to_owned();
+        },
+        Err(e) => SshWriteFileResponse {
+            request_id,
+            ok: false,
+            sha256: None,
+            error: Some(e.to_string()),
+        },
     }
-    format!("'{}'", s.replace('\'', "'\\''"))
 }
 
 fn handle_generate_key(req: SshGenerateKeyRequest) -> SshGenerateKeyResponse {
@@ -1,23 +1,56 @@
-use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs;
 use std::hash::{Hash, Hasher};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_ssh2_tokio::Error as SshError;
+use base64ct::{Base64, Encoding};
+use field_exec_adapters::fs::{ListVia, list_dir};
+use field_exec_adapters::ssh::{ExecError, ExecOutput, exec_bytes, exec_bytes_with_input};
+use field_exec_adapters::stream::{sleep_until_deadline, stream_end, stream_signal};
+use field_exec_core::atomic_write::{AtomicWrite, WritePrecondition, sha256_hex};
+use field_exec_core::auto_commit::{
+    AutoCommit, CommitReport, CommitResult, SkipReason, commit_message_from_status,
+};
+use field_exec_core::codex_command::{ApprovalPolicy, CodexCommand, SandboxMode};
+use field_exec_core::compress::CompressionStats;
+use field_exec_core::job::{
+    JobLaunch, JobLiveness, RemoteJobId, SessionFiles, check_tab_id, stop_script,
+};
+use field_exec_core::job_status::{
+    DEFAULT_HANG_AFTER_SECS, HeartbeatAlarm, JobOutcome, JobStatusCheck,
+};
+use field_exec_core::lines::{LineBuffer, LineChunk, LinePart};
+use field_exec_core::output_schema::{ExtensionLoad, OutputSchema, schema_path};
+use field_exec_core::ports::{ListeningPort, PortProbe, ProbeVia};
+use field_exec_core::remote_fs::{DirEntry, DirList, FileRead, LogFetch, LogReset, RemoteFsError};
+use field_exec_core::shell::sh_quote;
+use field_exec_core::stream::{StreamEnd, StreamTermination, StreamWatchdog};
+use field_exec_core::sync::{
+    CopyReason, FileTree, RemoteHashes, RemoteManifest, SyncAction, SyncDirection, SyncFilter,
+    SyncPlan, delete_script, hash_candidates, local_hashes, local_tree, mkdirs_script, plan,
+    touch_script,
+};
+use field_exec_core::transfer::{
+    ChunkCheck, ChunkRead, ChunkSpan, ChunkWrite, FinishUpload, ResumeError, SourceFingerprint,
+    TransferDirection, TransferJournal, parse_stat, stat_script,
+};
 use rand_core::{OsRng, RngCore};
 use russh::keys;
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::OpenFlags;
 use serde::{Deserialize, Serialize};
 use ssh_key::{Algorithm, LineEnding, PrivateKey};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{Mutex, Notify, broadcast, mpsc};
 use tokio::task::JoinHandle;
-use tokio::time::timeout;
+use tokio::time::{Instant, MissedTickBehavior, interval_at, sleep, timeout};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 enum PoolAuthKind {
@@ -220,6 +253,9 @@ struct SshExecParams {
     command: String,
     connect_timeout_ms: u64,
     command_timeout_ms: u64,
+    /// Gzip stdout on the wire when the remote has `gzip`.
+    #[serde(default)]
+    compress: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -227,13 +263,33 @@ struct SshStartParams {
     target: SshTarget,
     command: String,
     connect_timeout_ms: u64,
+    max_duration_ms: Option<u64>,
+    idle_output_timeout_ms: Option<u64>,
+    /// Lines longer than this are delivered as `line_part` chunks.
+    max_line_bytes: Option<usize>,
+    #[serde(flatten)]
+    queue: StreamQueueOptions,
 }
 
+/// Cancels this connection's subscriptions to a stream. The command only
+/// stops once no other connection is subscribed to it.
 #[derive(Debug, Clone, Deserialize)]
 struct SshCancelParams {
     stream_id: u64,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct StreamSubscribeParams {
+    stream_id: u64,
+    #[serde(flatten)]
+    queue: StreamQueueOptions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StreamUnsubscribeParams {
+    subscription_id: u64,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct SshResetAllParams {
     reason: Option<String>,
@@ -243,7 +299,253 @@ struct SshResetAllParams {
 struct SshWriteFileParams {
     target: SshTarget,
     remote_path: String,
-    contents: String,
+    /// UTF-8 text; use `contents_base64` for binary data. Exactly one is set.
+    contents: Option<String>,
+    contents_base64: Option<String>,
+    /// Permission bits for the file, e.g. 420 for 0644.
+    mode: Option<u32>,
+    #[serde(default)]
+    create_only: bool,
+    if_matches_sha256: Option<String>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SftpUploadParams {
+    target: SshTarget,
+    local_path: String,
+    remote_path: String,
+    connect_timeout_ms: u64,
+    /// Send in checksummed chunks that survive disconnects and restarts.
+    #[serde(default)]
+    resumable: bool,
+    chunk_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SftpDownloadParams {
+    target: SshTarget,
+    remote_path: String,
+    local_path: String,
+    connect_timeout_ms: u64,
+    #[serde(default)]
+    resumable: bool,
+    chunk_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SyncParams {
+    target: SshTarget,
+    local_dir: String,
+    remote_dir: String,
+    direction: SyncDirection,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Skip what the source tree's `.gitignore` files ignore.
+    #[serde(default)]
+    gitignore: bool,
+    /// Delete destination files that are missing from the source.
+    #[serde(default)]
+    delete: bool,
+    /// Only report the planned actions.
+    #[serde(default)]
+    dry_run: bool,
+    /// Compare every file by SHA-256, not just same-size files with
+    /// different mtimes.
+    #[serde(default)]
+    checksum: bool,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ForwardLocalParams {
+    target: SshTarget,
+    remote_host: String,
+    remote_port: u16,
+    /// Loopback port to listen on; 0 picks a free one.
+    #[serde(default)]
+    local_port: u16,
+    connect_timeout_ms: u64,
+}
+
+fn default_remote_bind_address() -> String {
+    "localhost".to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ForwardRemoteParams {
+    target: SshTarget,
+    /// Address the server listens on, as in `ssh -R address:port:...`.
+    #[serde(default = "default_remote_bind_address")]
+    remote_bind_address: String,
+    /// Port the server listens on; 0 lets the server pick one.
+    remote_bind_port: u16,
+    local_host: String,
+    local_port: u16,
+    connect_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ForwardSocksParams {
+    target: SshTarget,
+    /// Loopback port to listen on; 0 picks a free one.
+    #[serde(default)]
+    local_port: u16,
+    connect_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ForwardCloseParams {
+    forward_id: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TransferCancelParams {
+    transfer_id: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TransferDiscardParams {
+    key: String,
+    /// Host of an upload's journal, where its partial file is removed.
+    target: Option<SshTarget>,
+    #[serde(default = "default_discard_timeout_ms")]
+    connect_timeout_ms: u64,
+    #[serde(default = "default_discard_timeout_ms")]
+    command_timeout_ms: u64,
+}
+
+fn default_discard_timeout_ms() -> u64 {
+    10_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FsReadParams {
+    target: SshTarget,
+    /// Project directory the path must stay inside; relative paths start here.
+    root: String,
+    path: String,
+    #[serde(default)]
+    offset: u64,
+    length: Option<u64>,
+    max_bytes: Option<u64>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+    #[serde(default)]
+    compress: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FsListParams {
+    target: SshTarget,
+    /// Empty lists the login directory.
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    show_hidden: bool,
+    depth: Option<u32>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LogFetchParams {
+    target: SshTarget,
+    path: String,
+    #[serde(default)]
+    from_offset: u64,
+    /// Inode returned by the previous fetch, to detect rotation.
+    inode: Option<u64>,
+    max_bytes: Option<u64>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+    #[serde(default)]
+    compress: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PortsListParams {
+    target: SshTarget,
+    /// Only report ports whose owning process runs inside this directory.
+    project_dir: Option<String>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SchemaPrepareParams {
+    target: SshTarget,
+    project_dir: String,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct JobStartParams {
+    target: SshTarget,
+    project_dir: String,
+    tab_id: String,
+    prompt: String,
+    /// Host identity used to name the tmux window, as the app does.
+    #[serde(default)]
+    target_key: String,
+    resume_thread_id: Option<String>,
+    model: Option<String>,
+    profile: Option<String>,
+    sandbox: Option<SandboxMode>,
+    approval_policy: Option<ApprovalPolicy>,
+    #[serde(default)]
+    images: Vec<String>,
+    #[serde(default = "default_use_tmux")]
+    use_tmux: bool,
+    /// JSON line appended to the tab log before the job starts.
+    log_preamble: Option<String>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+fn default_use_tmux() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct JobRefParams {
+    target: SshTarget,
+    /// `tmux:<session>:<window>` or `pid:<pid>`, as returned by `job.start`.
+    job_id: String,
+    /// Project and tab the job was started for; their log is read for the
+    /// outcome and marked when the job is stopped.
+    project_dir: String,
+    tab_id: String,
+    /// `job.status` only: seconds without output before a running job
+    /// counts as hung.
+    hang_after_secs: Option<u64>,
+    connect_timeout_ms: u64,
+    command_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct GitAutoCommitParams {
+    target: SshTarget,
+    project_dir: String,
+    /// Tab whose latest completed turn supplies the commit message.
+    tab_id: String,
+    /// The tab's job, as returned by `job.start`. Needed unless
+    /// `commit_message` is given: the commit only happens once that job has
+    /// exited after completing its turn.
+    job_id: Option<String>,
+    /// Commit with this message instead of the one from the tab's log.
+    commit_message: Option<String>,
+    /// Branch globs (`*`, `?`) to never commit on, e.g. `master`.
+    #[serde(default)]
+    protected_branches: Vec<String>,
+    /// Only report what would be committed.
+    #[serde(default)]
+    dry_run: bool,
     connect_timeout_ms: u64,
     command_timeout_ms: u64,
 }
@@ -292,375 +594,3870 @@ enum EventEnvelope<'a> {
     #[serde(rename = "stream_line")]
     StreamLine {
         stream_id: u64,
+        subscription_id: u64,
+        is_stderr: bool,
+        line: &'a str,
+    },
+    /// A piece of a line longer than the stream's `max_line_bytes`. Pieces
+    /// arrive in order; the one with `final: true` ends the line.
+    #[serde(rename = "line_part")]
+    LinePart {
+        stream_id: u64,
+        subscription_id: u64,
         is_stderr: bool,
+        index: u32,
+        #[serde(rename = "final")]
+        is_final: bool,
         line: &'a str,
     },
+    #[serde(rename = "stream_lines")]
+    StreamLines {
+        stream_id: u64,
+        subscription_id: u64,
+        lines: Vec<QueuedLine<'a>>,
+    },
+    #[serde(rename = "lines_dropped")]
+    LinesDropped {
+        stream_id: u64,
+        subscription_id: u64,
+        count: u64,
+    },
+    #[serde(rename = "stream_stats")]
+    StreamStats {
+        stream_id: u64,
+        subscription_id: u64,
+        queued: usize,
+        #[serde(flatten)]
+        stats: StreamStats,
+    },
+    #[serde(rename = "transfer_progress")]
+    TransferProgress {
+        transfer_id: u64,
+        bytes: u64,
+        total: Option<u64>,
+    },
+    #[serde(rename = "transfer_done")]
+    TransferDone {
+        transfer_id: u64,
+        ok: bool,
+        cancelled: bool,
+        /// Unknown for cancelled transfers.
+        bytes: Option<u64>,
+        via: Option<TransferVia>,
+        /// Offset a resumable transfer picked up from, when it did.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resumed_from: Option<u64>,
+        error: Option<&'a str>,
+    },
     #[serde(rename = "stream_exit")]
     StreamExit {
         stream_id: u64,
+        subscription_id: u64,
         exit_status: i32,
+        exit_signal: Option<&'a str>,
+        core_dumped: bool,
+        signal_message: Option<&'a str>,
+        termination: StreamTermination,
         error: Option<&'a str>,
     },
 }
 
+#[derive(Debug, Serialize)]
+struct QueuedLine<'a> {
+    is_stderr: bool,
+    line: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part: Option<PartInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct PartInfo {
+    index: u32,
+    #[serde(rename = "final")]
+    is_final: bool,
+}
+
+impl From<LinePart> for PartInfo {
+    fn from(part: LinePart) -> Self {
+        Self {
+            index: part.index,
+            is_final: part.is_final,
+        }
+    }
+}
+
+impl<'a> EventEnvelope<'a> {
+    fn transfer_cancelled(transfer_id: u64, reason: &'a str) -> Self {
+        EventEnvelope::TransferDone {
+            transfer_id,
+            ok: false,
+            cancelled: true,
+            bytes: None,
+            via: None,
+            resumed_from: None,
+            error: Some(reason),
+        }
+    }
+
+    fn stream_failed(
+        stream_id: u64,
+        subscription_id: u64,
+        termination: StreamTermination,
+        error: &'a str,
+    ) -> Self {
+        EventEnvelope::StreamExit {
+            stream_id,
+            subscription_id,
+            exit_status: -1,
+            exit_signal: None,
+            core_dumped: false,
+            signal_message: None,
+            termination,
+            error: Some(error),
+        }
+    }
+
+    fn stream_exit(stream_id: u64, subscription_id: u64, end: &'a StreamEnd) -> Self {
+        EventEnvelope::StreamExit {
+            stream_id,
+            subscription_id,
+            exit_status: end.exit_status,
+            exit_signal: end.exit_signal.as_deref(),
+            core_dumped: end.core_dumped,
+            signal_message: end.signal_message.as_deref(),
+            termination: end.termination,
+            error: end.error.as_deref(),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct DaemonState {
     pool: SshConnectionPool,
+    hub: StreamHub,
     next_stream_id: Arc<std::sync::atomic::AtomicU64>,
+    next_subscription_id: Arc<std::sync::atomic::AtomicU64>,
+    next_transfer_id: Arc<std::sync::atomic::AtomicU64>,
+    next_forward_id: Arc<std::sync::atomic::AtomicU64>,
+    journals: JournalStore,
+    forwards: ForwardRegistry,
 }
 
 impl DaemonState {
-    fn new() -> Self {
+    fn new(journals: JournalStore) -> Self {
         Self {
             pool: SshConnectionPool::new(),
+            hub: StreamHub::default(),
             next_stream_id: Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            next_subscription_id: Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            next_transfer_id: Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            next_forward_id: Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            journals,
+            forwards: ForwardRegistry::default(),
         }
     }
 }
 
-#[derive(Clone, Default)]
-struct ConnectionStreams {
-    tasks: Arc<Mutex<HashMap<u64, JoinHandle<()>>>>,
+/// Capacity of the per-stream broadcast channel. Subscribers further behind
+/// than this skip ahead.
+const STREAM_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone)]
+enum StreamEvent {
+    Line(OutputLine),
+    /// `published` is the stream's total output, as counted by `offset`.
+    Exit {
+        end: Arc<StreamEnd>,
+        published: u64,
+    },
 }
 
 #[derive(Clone)]
-struct Outbox {
-    tx: mpsc::Sender<String>,
+struct OutputLine {
+    is_stderr: bool,
+    line: Arc<str>,
+    part: Option<LinePart>,
+    /// Bytes of output the stream published before this line.
+    offset: u64,
 }
 
-impl Outbox {
-    async fn send_json<T: Serialize>(&self, value: &T) -> Result<(), ()> {
-        let line = match serde_json::to_string(value) {
-            Ok(s) => s,
-            Err(_) => return Err(()),
-        };
-        self.tx.send(line).await.map_err(|_| ())
+/// The publishing side of a stream. Lines carry the running byte count so a
+/// subscriber that falls behind the broadcast buffer can report the bytes it
+/// lost as well as the lines.
+#[derive(Clone)]
+struct StreamOutlet {
+    inner: Arc<std::sync::Mutex<OutletState>>,
+}
+
+struct OutletState {
+    events: broadcast::Sender<StreamEvent>,
+    published: u64,
+}
+
+/// A subscriber's end of a stream and the byte offset it starts at.
+struct StreamFeed {
+    rx: broadcast::Receiver<StreamEvent>,
+    offset: u64,
+}
+
+impl StreamOutlet {
+    fn new() -> Self {
+        let (events, _) = broadcast::channel(STREAM_EVENT_CAPACITY);
+        Self {
+            inner: Arc::new(std::sync::Mutex::new(OutletState {
+                events,
+                published: 0,
+            })),
+        }
     }
 
-    async fn send_response_ok<T: Serialize>(&self, id: u64, result: T) -> Result<(), ()> {
-        self.send_json(&ResponseEnvelope::<T> {
-            id,
-            ok: true,
-            result: Some(result),
-            error: None,
-        })
-        .await
+    fn state(&self) -> std::sync::MutexGuard<'_, OutletState> {
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
     }
 
-    async fn send_response_err(&self, id: u64, error: impl Into<String>) -> Result<(), ()> {
-        self.send_json(&ResponseEnvelope::<serde_json::Value> {
-            id,
-            ok: false,
-            result: None,
-            error: Some(error.into()),
-        })
-        .await
+    fn subscribe(&self) -> StreamFeed {
+        let state = self.state();
+        StreamFeed {
+            rx: state.events.subscribe(),
+            offset: state.published,
+        }
+    }
+
+    fn publish_line(&self, is_stderr: bool, chunk: LineChunk) {
+        let mut state = self.state();
+        let offset = state.published;
+        state.published += chunk.text.len() as u64;
+        let _ = state.events.send(StreamEvent::Line(OutputLine {
+            is_stderr,
+            line: chunk.text.into(),
+            part: chunk.part,
+            offset,
+        }));
+    }
+
+    fn publish_exit(&self, end: StreamEnd) {
+        let state = self.state();
+        let _ = state.events.send(StreamEvent::Exit {
+            end: Arc::new(end),
+            published: state.published,
+        });
     }
 }
 
-#[derive(Serialize)]
-struct HelloResult {
-    protocol: u32,
+struct SharedStream {
+    outlet: StreamOutlet,
+    subscribers: usize,
+    task: JoinHandle<()>,
 }
 
-#[derive(Deserialize)]
-struct HelloParams {
-    token: String,
-    protocol: u32,
+/// Running streams, shared by every client connection so several windows can
+/// watch the same remote command.
+#[derive(Clone, Default)]
+struct StreamHub {
+    streams: Arc<Mutex<HashMap<u64, SharedStream>>>,
 }
 
-#[derive(Serialize)]
-struct SshExecResult {
-    stdout: String,
-    stderr: String,
-    exit_code: i32,
+impl StreamHub {
+    async fn subscribe(&self, stream_id: u64) -> Option<StreamFeed> {
+        let mut g = self.streams.lock().await;
+        let stream = g.get_mut(&stream_id)?;
+        stream.subscribers += 1;
+        Some(stream.outlet.subscribe())
+    }
+
+    /// Drops one subscriber and stops the remote command once none are left.
+    /// Returns the number of subscribers still attached.
+    async fn leave(&self, stream_id: u64) -> usize {
+        let mut g = self.streams.lock().await;
+        let Some(stream) = g.get_mut(&stream_id) else {
+            return 0;
+        };
+        stream.subscribers = stream.subscribers.saturating_sub(1);
+        if stream.subscribers > 0 {
+            return stream.subscribers;
+        }
+        if let Some(stream) = g.remove(&stream_id) {
+            stream.task.abort();
+        }
+        0
+    }
+
+    async fn finish(&self, stream_id: u64) {
+        self.streams.lock().await.remove(&stream_id);
+    }
 }
 
-#[derive(Serialize)]
-struct SshStartResult {
+struct Subscription {
     stream_id: u64,
+    forwarder: JoinHandle<()>,
+    outbox: Outbox,
 }
 
-#[derive(Serialize)]
-struct SshResetAllResult {
-    cleared_connections: usize,
-    cancelled_streams: usize,
+/// Stream subscriptions held by one client connection, keyed by subscription id.
+#[derive(Clone, Default)]
+struct ConnectionStreams {
+    subscriptions: Arc<Mutex<HashMap<u64, Subscription>>>,
 }
 
-#[derive(Serialize)]
-struct SshGenerateKeyResult {
-    private_key_pem: String,
+impl ConnectionStreams {
+    async fn attach(
+        &self,
+        stream_id: u64,
+        subscription_id: u64,
+        feed: StreamFeed,
+        outbox: Outbox,
+        options: &StreamQueueOptions,
+    ) {
+        outbox
+            .open_stream_queue(stream_id, subscription_id, options)
+            .await;
+        let mut g = self.subscriptions.lock().await;
+        let subscriptions = self.subscriptions.clone();
+        let stats_interval = options.stats_interval();
+        let forwarder_outbox = outbox.clone();
+        let forwarder = tokio::spawn(async move {
+            forward_stream_events(subscription_id, feed, forwarder_outbox, stats_interval).await;
+            subscriptions.lock().await.remove(&subscription_id);
+        });
+        g.insert(
+            subscription_id,
+            Subscription {
+                stream_id,
+                forwarder,
+                outbox,
+            },
+        );
+    }
+
+    /// Stops forwarding for every subscription matching `pred(subscription_id,
+    /// stream_id)` and returns those pairs. The caller is responsible for
+    /// releasing them from the [`StreamHub`].
+    async fn detach(&self, pred: impl Fn(u64, u64) -> bool) -> Vec<(u64, u64)> {
+        let mut g = self.subscriptions.lock().await;
+        let ids: Vec<u64> = g
+            .iter()
+            .filter(|(subscription_id, sub)| pred(**subscription_id, sub.stream_id))
+            .map(|(subscription_id, _)| *subscription_id)
+            .collect();
+        let mut detached = Vec::with_capacity(ids.len());
+        for subscription_id in ids {
+            if let Some(sub) = g.remove(&subscription_id) {
+                sub.forwarder.abort();
+                sub.outbox.close_stream_queue(subscription_id).await;
+                detached.push((subscription_id, sub.stream_id));
+            }
+        }
+        detached
+    }
 }
 
-#[derive(Serialize)]
-struct SshAuthorizedKeyLineResult {
+/// Default number of lines a subscription may have queued for the writer.
+const DEFAULT_STREAM_QUEUE_CAPACITY: usize = 256;
+/// Default interval between `stream_stats` events.
+const DEFAULT_STREAM_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a subscription queue does when the client is not reading fast enough.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum QueuePolicy {
+    /// Stop pulling from the stream until the writer catches up. Lines the
+    /// stream produces meanwhile are dropped, and counted as dropped, once the
+    /// broadcast buffer overflows.
+    #[default]
+    Block,
+    /// Discard the oldest queued line, with all of its `line_part` chunks, and
+    /// report it with a `lines_dropped` event. A line whose first chunks were
+    /// already sent is never cut short.
+    DropOldest,
+    /// Like `Block`, but send everything queued as one `stream_lines` event.
+    Coalesce,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct StreamQueueOptions {
+    queue_policy: Option<QueuePolicy>,
+    queue_capacity: Option<usize>,
+    stats_interval_ms: Option<u64>,
+}
+
+impl StreamQueueOptions {
+    fn stats_interval(&self) -> Duration {
+        self.stats_interval_ms
+            .filter(|v| *v > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_STREAM_STATS_INTERVAL)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+struct StreamStats {
+    lines_delivered: u64,
+    bytes_delivered: u64,
+    lines_dropped: u64,
+    bytes_dropped: u64,
+}
+
+/// Lines waiting to be written for one subscription.
+struct StreamQueue {
+    stream_id: u64,
+    policy: QueuePolicy,
+    capacity: usize,
+    lines: VecDeque<OutputLine>,
+    exit: Option<Arc<StreamEnd>>,
+    dropped_unreported: u64,
+    /// Per `is_stderr`: a line was evicted before its final chunk arrived,
+    /// so its remaining chunks are dropped as they come in.
+    discarding: [bool; 2],
+    stats: StreamStats,
+    last_reported_stats: StreamStats,
+    stats_due: bool,
+    scheduled: bool,
+}
+
+impl StreamQueue {
+    fn count_dropped(&mut self, line: &OutputLine) {
+        self.dropped_unreported += 1;
+        self.stats.lines_dropped += 1;
+        self.stats.bytes_dropped += line.line.len() as u64;
+    }
+
+    /// Evicts the oldest queued line that has not started going out, with
+    /// every chunk of it. Returns false when there is no such line.
+    fn drop_oldest_line(&mut self) -> bool {
+        let Some(start) = self
+            .lines
+            .iter()
+            .position(|out| out.part.is_none_or(|part| part.index == 0))
+        else {
+            return false;
+        };
+        let Some(first) = self.lines.remove(start) else {
+            return false;
+        };
+        self.count_dropped(&first);
+        let mut rest_queued = first.part.is_none_or(|part| part.is_final);
+        // The chunks of one line are consecutive within its output stream.
+        while !rest_queued {
+            let next = self
+                .lines
+                .iter()
+                .skip(start)
+                .position(|out| out.is_stderr == first.is_stderr);
+            let Some(out) = next.and_then(|i| self.lines.remove(start + i)) else {
+                break;
+            };
+            self.count_dropped(&out);
+            rest_queued = out.part.is_none_or(|part| part.is_final);
+        }
+        if !rest_queued {
+            self.discarding[usize::from(first.is_stderr)] = true;
+        }
+        true
+    }
+
+    fn stats_message(&mut self, subscription_id: u64) -> Option<String> {
+        self.last_reported_stats = self.stats;
+        serde_json::to_string(&EventEnvelope::StreamStats {
+            stream_id: self.stream_id,
+            subscription_id,
+            queued: self.lines.len(),
+            stats: self.stats,
+        })
+        .ok()
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.lines.is_empty()
+            || self.exit.is_some()
+            || self.dropped_unreported > 0
+            || (self.stats_due && self.stats != self.last_reported_stats)
+    }
+
+    /// Serializes the next event for this subscription. Returns the event and
+    /// whether the subscription is finished.
+    fn next_message(&mut self, subscription_id: u64) -> (Option<String>, bool) {
+        let stream_id = self.stream_id;
+        if self.dropped_unreported > 0 {
+            let count = std::mem::take(&mut self.dropped_unreported);
+            let msg = serde_json::to_string(&EventEnvelope::LinesDropped {
+                stream_id,
+                subscription_id,
+                count,
+            });
+            return (msg.ok(), false);
+        }
+        if std::mem::take(&mut self.stats_due) && self.stats != self.last_reported_stats {
+            return (self.stats_message(subscription_id), false);
+        }
+        if self.policy == QueuePolicy::Coalesce && self.lines.len() > 1 {
+            let batch: Vec<OutputLine> = self.lines.drain(..).collect();
+            for out in &batch {
+                self.stats.lines_delivered += 1;
+                self.stats.bytes_delivered += out.line.len() as u64;
+            }
+            let lines: Vec<QueuedLine<'_>> = batch
+                .iter()
+                .map(|out| QueuedLine {
+                    is_stderr: out.is_stderr,
+                    line: &out.line,
+                    part: out.part.map(PartInfo::from),
+                })
+                .collect();
+            let msg = serde_json::to_string(&EventEnvelope::StreamLines {
+                stream_id,
+                subscription_id,
+                lines,
+            });
+            return (msg.ok(), false);
+        }
+        if let Some(out) = self.lines.pop_front() {
+            self.stats.lines_delivered += 1;
+            self.stats.bytes_delivered += out.line.len() as u64;
+            let msg = match out.part {
+                Some(part) => serde_json::to_string(&EventEnvelope::LinePart {
+                    stream_id,
+                    subscription_id,
+                    is_stderr: out.is_stderr,
+                    index: part.index,
+                    is_final: part.is_final,
+                    line: &out.line,
+                }),
+                None => serde_json::to_string(&EventEnvelope::StreamLine {
+                    stream_id,
+                    subscription_id,
+                    is_stderr: out.is_stderr,
+                    line: &out.line,
+                }),
+            };
+            return (msg.ok(), false);
+        }
+        // Always report final numbers before the exit.
+        if self.exit.is_some() && self.stats != self.last_reported_stats {
+            return (self.stats_message(subscription_id), false);
+        }
+        if let Some(report) = self.exit.take() {
+            let msg = serde_json::to_string(&EventEnvelope::stream_exit(
+                stream_id,
+                subscription_id,
+                &report,
+            ));
+            return (msg.ok(), true);
+        }
+        (None, false)
+    }
+}
+
+#[derive(Default)]
+struct StreamQueues {
+    order: VecDeque<u64>,
+    by_subscription: HashMap<u64, StreamQueue>,
+}
+
+impl StreamQueues {
+    fn schedule(&mut self, subscription_id: u64) {
+        if let Some(q) = self.by_subscription.get_mut(&subscription_id)
+            && !q.scheduled
+            && q.has_pending()
+        {
+            q.scheduled = true;
+            self.order.push_back(subscription_id);
+        }
+    }
+}
+
+/// Everything written to one client connection.
+///
+/// Responses and control events go through `tx` in order. Stream output is
+/// kept in per-subscription queues that the writer drains round-robin, so a
+/// chatty stream cannot starve the others and a slow client never blocks the
+/// SSH channel reads.
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::Sender<String>,
+    queues: Arc<Mutex<StreamQueues>>,
+    ready: Arc<Notify>,
+    space: Arc<Notify>,
+}
+
+impl Outbox {
+    fn new(tx: mpsc::Sender<String>) -> Self {
+        Self {
+            tx,
+            queues: Arc::new(Mutex::new(StreamQueues::default())),
+            ready: Arc::new(Notify::new()),
+            space: Arc::new(Notify::new()),
+        }
+    }
+
+    async fn open_stream_queue(
+        &self,
+        stream_id: u64,
+        subscription_id: u64,
+        options: &StreamQueueOptions,
+    ) {
+        let queue = StreamQueue {
+            stream_id,
+            policy: options.queue_policy.unwrap_or_default(),
+            capacity: options
+                .queue_capacity
+                .filter(|v| *v > 0)
+                .unwrap_or(DEFAULT_STREAM_QUEUE_CAPACITY),
+            lines: VecDeque::new(),
+            exit: None,
+            dropped_unreported: 0,
+            discarding: [false; 2],
+            stats: StreamStats::default(),
+            last_reported_stats: StreamStats::default(),
+            stats_due: false,
+            scheduled: false,
+        };
+        self.queues
+            .lock()
+            .await
+            .by_subscription
+            .insert(subscription_id, queue);
+    }
+
+    async fn close_stream_queue(&self, subscription_id: u64) {
+        self.queues
+            .lock()
+            .await
+            .by_subscription
+            .remove(&subscription_id);
+        self.space.notify_waiters();
+    }
+
+    async fn push_stream_line(&self, subscription_id: u64, line: OutputLine) {
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+            {
+                let mut g = self.queues.lock().await;
+                let Some(q) = g.by_subscription.get_mut(&subscription_id) else {
+                    return;
+                };
+                let stream = usize::from(line.is_stderr);
+                if std::mem::take(&mut q.discarding[stream])
+                    && let Some(part) = line.part
+                    && part.index > 0
+                {
+                    q.count_dropped(&line);
+                    q.discarding[stream] = !part.is_final;
+                    g.schedule(subscription_id);
+                    self.ready.notify_one();
+                    return;
+                }
+                if q.lines.len() >= q.capacity && q.policy == QueuePolicy::DropOldest {
+                    q.drop_oldest_line();
+                }
+                if q.lines.len() < q.capacity {
+                    q.lines.push_back(line);
+                    g.schedule(subscription_id);
+                    self.ready.notify_one();
+                    return;
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Records lines the subscription never saw because it fell behind the
+    /// stream's broadcast buffer.
+    async fn record_dropped(&self, subscription_id: u64, count: u64, bytes: u64) {
+        let mut g = self.queues.lock().await;
+        if let Some(q) = g.by_subscription.get_mut(&subscription_id) {
+            q.dropped_unreported += count;
+            q.stats.lines_dropped += count;
+            q.stats.bytes_dropped += bytes;
+            g.schedule(subscription_id);
+            self.ready.notify_one();
+        }
+    }
+
+    async fn request_stream_stats(&self, subscription_id: u64) {
+        let mut g = self.queues.lock().await;
+        if let Some(q) = g.by_subscription.get_mut(&subscription_id) {
+            q.stats_due = true;
+            g.schedule(subscription_id);
+            self.ready.notify_one();
+        }
+    }
+
+    async fn finish_stream_queue(&self, subscription_id: u64, report: Arc<StreamEnd>) {
+        let mut g = self.queues.lock().await;
+        if let Some(q) = g.by_subscription.get_mut(&subscription_id) {
+            q.exit = Some(report);
+            g.schedule(subscription_id);
+            self.ready.notify_one();
+        }
+    }
+
+    /// Pops the next stream event in round-robin order across subscriptions.
+    async fn next_stream_message(&self) -> Option<String> {
+        let mut g = self.queues.lock().await;
+        let StreamQueues {
+            order,
+            by_subscription,
+        } = &mut *g;
+        while let Some(subscription_id) = order.pop_front() {
+            let Some(q) = by_subscription.get_mut(&subscription_id) else {
+                continue;
+            };
+            q.scheduled = false;
+            let (msg, finished) = q.next_message(subscription_id);
+            if finished {
+                by_subscription.remove(&subscription_id);
+            } else if q.has_pending() {
+                q.scheduled = true;
+                order.push_back(subscription_id);
+            }
+            if msg.is_some() {
+                self.space.notify_waiters();
+                return msg;
+            }
+        }
+        None
+    }
+
+    async fn send_json<T: Serialize>(&self, value: &T) -> Result<(), ()> {
+        let line = match serde_json::to_string(value) {
+            Ok(s) => s,
+            Err(_) => return Err(()),
+        };
+        self.tx.send(line).await.map_err(|_| ())
+    }
+
+    async fn send_response_ok<T: Serialize>(&self, id: u64, result: T) -> Result<(), ()> {
+        self.send_json(&ResponseEnvelope::<T> {
+            id,
+            ok: true,
+            result: Some(result),
+            error: None,
+        })
+        .await
+    }
+
+    async fn send_response_err(&self, id: u64, error: impl Into<String>) -> Result<(), ()> {
+        self.send_json(&ResponseEnvelope::<serde_json::Value> {
+            id,
+            ok: false,
+            result: None,
+            error: Some(error.into()),
+        })
+        .await
+    }
+}
+
+#[derive(Serialize)]
+struct HelloResult {
+    protocol: u32,
+}
+
+#[derive(Deserialize)]
+struct HelloParams {
+    token: String,
+    protocol: u32,
+}
+
+#[derive(Serialize)]
+struct SshExecResult {
+    stdout: String,
+    stderr: String,
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionReport>,
+}
+
+/// Present on results of requests that asked for `compress`.
+#[derive(Serialize)]
+struct CompressionReport {
+    /// False when the remote had no `gzip` and output came back as-is.
+    compressed: bool,
+    wire_bytes: u64,
+    raw_bytes: u64,
+}
+
+impl From<CompressionStats> for CompressionReport {
+    fn from(stats: CompressionStats) -> Self {
+        Self {
+            compressed: stats.compressed,
+            wire_bytes: stats.wire_bytes,
+            raw_bytes: stats.raw_bytes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SshStartResult {
+    stream_id: u64,
+    subscription_id: u64,
+}
+
+#[derive(Serialize)]
+struct StreamSubscribeResult {
+    stream_id: u64,
+    subscription_id: u64,
+}
+
+#[derive(Serialize)]
+struct StreamUnsubscribeResult {
+    remaining_subscribers: usize,
+}
+
+#[derive(Serialize)]
+struct SshResetAllResult {
+    cleared_connections: usize,
+    cancelled_streams: usize,
+}
+
+#[derive(Serialize)]
+struct SshGenerateKeyResult {
+    private_key_pem: String,
+}
+
+#[derive(Serialize)]
+struct SshAuthorizedKeyLineResult {
     authorized_key_line: String,
 }
 
-#[derive(Serialize)]
-struct SshInstallPublicKeyResult {}
-
-#[derive(Serialize)]
-struct SshWriteFileResult {}
-
-#[derive(Clone)]
-struct ServerConfig {
-    token: String,
-    protocol: u32,
+#[derive(Serialize)]
+struct SshInstallPublicKeyResult {}
+
+#[derive(Serialize)]
+struct SshWriteFileResult {
+    /// SHA-256 of the file now in place, as computed by the remote.
+    sha256: String,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct FsReadResult {
+    data_base64: String,
+    offset: u64,
+    size: u64,
+    mtime: i64,
+    mode: u32,
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionReport>,
+}
+
+#[derive(Serialize)]
+struct FsListResult {
+    path: String,
+    entries: Vec<DirEntry>,
+    truncated: bool,
+    via: TransferVia,
+}
+
+#[derive(Serialize)]
+struct LogFetchResult {
+    data_base64: String,
+    offset: u64,
+    next_offset: u64,
+    inode: u64,
+    size: u64,
+    /// Set when the fetch restarted from 0.
+    reset: Option<LogResetKind>,
+    has_more: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionReport>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LogResetKind {
+    Truncated,
+    Rotated,
+}
+
+#[derive(Serialize)]
+struct PortsListResult {
+    via: PortProbeKind,
+    /// Physical project directory the ports were filtered by.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project_dir: Option<String>,
+    ports: Vec<PortEntry>,
+}
+
+#[derive(Serialize)]
+struct SchemaPrepareResult {
+    /// Absolute path to pass as `--output-schema`.
+    schema_path: String,
+    sha256: String,
+    extensions: Vec<SchemaExtensionEntry>,
+}
+
+#[derive(Serialize)]
+struct SchemaExtensionEntry {
+    name: String,
+    /// File under `.field_exec/schema.d` that defined the field.
+    source: String,
+}
+
+#[derive(Serialize)]
+struct JobStartResult {
+    job_id: String,
+    log_path: String,
+    stderr_path: String,
+    schema_path: String,
+    extensions: Vec<SchemaExtensionEntry>,
+}
+
+#[derive(Serialize)]
+struct JobStopResult {
+    job_id: String,
+}
+
+#[derive(Serialize)]
+struct JobStatusResult {
+    job_id: String,
+    state: JobState,
+    outcome: JobOutcomeReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat: Option<JobHeartbeatEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alarm: Option<JobAlarmReport>,
+    /// Seconds since the log last grew, from the heartbeat.
+    idle_secs: Option<u64>,
+    thread_id: Option<String>,
+    /// The agent's final message of a completed turn.
+    final_message: Option<String>,
+    /// `final_message` read under the project's output schema, with the
+    /// app's fallbacks applied.
+    response: Option<serde_json::Map<String, serde_json::Value>>,
+    /// What is still wrong with `response` after the fallbacks.
+    response_violations: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JobOutcomeReport {
+    Running,
+    Completed,
+    Failed { message: String },
+    Hung { idle_secs: u64 },
+    Cancelled,
+    Crashed { message: Option<String> },
+    Unknown,
+}
+
+#[derive(Serialize)]
+struct JobHeartbeatEntry {
+    pid: u32,
+    started_at: u64,
+    boot_id: String,
+    last_output_at: u64,
+    beat_at: u64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JobAlarmReport {
+    Rebooted,
+    PidReused,
+    Hung { idle_secs: u64 },
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Running,
+    Exited,
+    Unknown,
+}
+
+#[derive(Serialize)]
+struct GitAutoCommitResult {
+    #[serde(flatten)]
+    status: GitCommitStatus,
+    branch: Option<String>,
+    /// The message used, on one line.
+    commit_message: Option<String>,
+    /// Paths committed, or staged on a dry run.
+    files: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum GitCommitStatus {
+    Committed { sha: String },
+    DryRun,
+    Protected { pattern: String },
+    Skipped { reason: &'static str },
+    Failed { message: String },
+}
+
+impl GitAutoCommitResult {
+    fn new(report: CommitReport, commit_message: Option<String>) -> Self {
+        Self {
+            status: match report.result {
+                CommitResult::Committed { sha } => GitCommitStatus::Committed { sha },
+                CommitResult::DryRun => GitCommitStatus::DryRun,
+                CommitResult::Protected { pattern } => GitCommitStatus::Protected { pattern },
+                CommitResult::Skipped(reason) => GitCommitStatus::Skipped {
+                    reason: reason.as_str(),
+                },
+                CommitResult::Failed { message } => GitCommitStatus::Failed { message },
+            },
+            branch: report.branch,
+            commit_message,
+            files: report.files,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum PortProbeKind {
+    Ss,
+    Lsof,
+    Netstat,
+}
+
+#[derive(Serialize)]
+struct PortEntry {
+    address: String,
+    port: u16,
+    pid: Option<u32>,
+    process: Option<String>,
+    cwd: Option<String>,
+    /// What to pass as `remote_host` to `forward.local` for this port.
+    connect_host: String,
+}
+
+impl From<ListeningPort> for PortEntry {
+    fn from(port: ListeningPort) -> Self {
+        Self {
+            connect_host: port.connect_host().to_owned(),
+            address: port.address,
+            port: port.port,
+            pid: port.pid,
+            process: port.process,
+            cwd: port.cwd,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SftpTransferResult {
+    transfer_id: u64,
+    /// Journal of a resumable transfer, for `transfer.discard`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    journal_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TransferJournalResult {
+    entries: Vec<TransferJournalEntry>,
+}
+
+#[derive(Serialize)]
+struct TransferJournalEntry {
+    key: String,
+    direction: TransferDirection,
+    host: String,
+    port: u16,
+    username: String,
+    local_path: String,
+    remote_path: String,
+    /// Bytes already verified on the receiving side.
+    bytes: u64,
+    total: u64,
+    updated_at: u64,
+}
+
+impl From<TransferJournal> for TransferJournalEntry {
+    fn from(journal: TransferJournal) -> Self {
+        Self {
+            bytes: journal.verified_offset(),
+            total: journal.source.size,
+            key: journal.key,
+            direction: journal.direction,
+            host: journal.host,
+            port: journal.port,
+            username: journal.username,
+            local_path: journal.local_path,
+            remote_path: journal.remote_path,
+            updated_at: journal.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TransferDiscardResult {
+    discarded: bool,
+}
+
+#[derive(Serialize)]
+struct SyncResult {
+    /// Set when copies or deletions were started; they report through
+    /// `transfer_progress` and `transfer_done` like any transfer.
+    transfer_id: Option<u64>,
+    actions: Vec<SyncActionReport>,
+    unchanged: usize,
+    filtered: usize,
+    copy_bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum SyncActionReport {
+    Copy {
+        path: String,
+        size: u64,
+        reason: CopyReason,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+impl From<&SyncAction> for SyncActionReport {
+    fn from(action: &SyncAction) -> Self {
+        match action {
+            SyncAction::Copy {
+                path, size, reason, ..
+            } => SyncActionReport::Copy {
+                path: path.clone(),
+                size: *size,
+                reason: *reason,
+            },
+            SyncAction::Delete { path } => SyncActionReport::Delete { path: path.clone() },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ForwardLocalResult {
+    forward_id: u64,
+    local_port: u16,
+}
+
+#[derive(Serialize)]
+struct ForwardRemoteResult {
+    forward_id: u64,
+    remote_port: u16,
+}
+
+#[derive(Serialize)]
+struct ForwardSocksResult {
+    forward_id: u64,
+    local_port: u16,
+}
+
+#[derive(Serialize)]
+struct ForwardListResult {
+    forwards: Vec<ForwardInfo>,
+}
+
+#[derive(Serialize)]
+struct ForwardCloseResult {
+    closed: bool,
+}
+
+#[derive(Serialize)]
+struct TransferCancelResult {
+    cancelled: bool,
+}
+
+#[derive(Clone)]
+struct ServerConfig {
+    token: String,
+    protocol: u32,
+}
+
+fn hex_token(bytes_len: usize) -> String {
+    let mut bytes = vec![0_u8; bytes_len];
+    OsRng.fill_bytes(&mut bytes);
+    let mut out = String::with_capacity(bytes_len * 2);
+    for b in bytes {
+        use std::fmt::Write;
+        let _ = write!(&mut out, "{:02x}", b);
+    }
+    out
+}
+
+#[derive(Serialize)]
+struct StateFile {
+    version: u32,
+    pid: u32,
+    port: u16,
+    token: String,
+    protocol: u32,
+}
+
+fn write_state_file(path: &Path, port: u16, token: &str, protocol: u32) -> io::Result<()> {
+    let Some(dir) = path.parent() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing parent dir"));
+    };
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).ok();
+    }
+
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let payload = StateFile {
+        version: 1,
+        pid: std::process::id(),
+        port,
+        token: token.to_owned(),
+        protocol,
+    };
+    let json = serde_json::to_string(&payload).map_err(|e| io::Error::other(e.to_string()))?;
+    fs::write(&tmp_path, json)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600)).ok();
+    }
+    fs::rename(&tmp_path, path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).ok();
+    }
+    Ok(())
+}
+
+fn parse_args() -> (u16, PathBuf) {
+    let mut port: u16 = 0;
+    let mut state_file: Option<PathBuf> = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                if let Some(v) = args.next()
+                    && let Ok(p) = v.parse::<u16>()
+                {
+                    port = p;
+                }
+            }
+            "--state-file" => {
+                if let Some(v) = args.next() {
+                    state_file = Some(PathBuf::from(v));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let state_file = state_file.unwrap_or_else(|| {
+        let home = env::var("HOME").unwrap_or_default();
+        let base = if home.trim().is_empty() {
+            PathBuf::from(".")
+        } else {
+            PathBuf::from(home).join(".config/field_exec")
+        };
+        base.join("field_execd.json")
+    });
+
+    (port, state_file)
+}
+
+fn parse_target(target: SshTarget) -> Result<(String, u16, String, SshAuth), String> {
+    if target.host.trim().is_empty() {
+        return Err("host is empty".to_owned());
+    }
+    if target.username.trim().is_empty() {
+        return Err("username is empty".to_owned());
+    }
+    if target.port == 0 {
+        return Err("invalid port".to_owned());
+    }
+    Ok((target.host, target.port, target.username, target.auth))
+}
+
+async fn ssh_get_client(
+    pool: &SshConnectionPool,
+    target: SshTarget,
+    connect_timeout: Duration,
+) -> Result<(PoolKey, async_ssh2_tokio::Client), String> {
+    let (host, port, username, auth) = parse_target(target)?;
+    match auth {
+        SshAuth::Key {
+            private_key_pem,
+            private_key_passphrase,
+        } => {
+            if private_key_pem.trim().is_empty() {
+                return Err("private_key_pem is empty".to_owned());
+            }
+            pool.get_or_connect_key(
+                &host,
+                port,
+                &username,
+                &private_key_pem,
+                private_key_passphrase.as_deref(),
+                connect_timeout,
+            )
+            .await
+            .map_err(|e| e.to_string())
+        }
+        SshAuth::Password { password } => {
+            if password.trim().is_empty() {
+                return Err("password is empty".to_owned());
+            }
+            pool.get_or_connect_password(&host, port, &username, &password, connect_timeout)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Opens a connection outside the pool, for state that lives and dies with
+/// the connection, such as remote forwards.
+async fn ssh_connect_unpooled(
+    pool: &SshConnectionPool,
+    target: SshTarget,
+    connect_timeout: Duration,
+) -> Result<async_ssh2_tokio::Client, String> {
+    let (host, port, username, auth) = parse_target(target)?;
+    match auth {
+        SshAuth::Key {
+            private_key_pem,
+            private_key_passphrase,
+        } => {
+            if private_key_pem.trim().is_empty() {
+                return Err("private_key_pem is empty".to_owned());
+            }
+            pool.connect_key(
+                &host,
+                port,
+                &username,
+                &private_key_pem,
+                private_key_passphrase.as_deref(),
+                connect_timeout,
+            )
+            .await
+            .map_err(|e| e.to_string())
+        }
+        SshAuth::Password { password } => {
+            if password.trim().is_empty() {
+                return Err("password is empty".to_owned());
+            }
+            pool.connect_password(&host, port, &username, &password, connect_timeout)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+async fn ssh_exec(
+    state: &DaemonState,
+    params: SshExecParams,
+) -> Result<SshExecResult, String> {
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+
+    if params.compress {
+        let output = ssh_exec_bytes(
+            state,
+            params.target,
+            connect_timeout,
+            command_timeout,
+            &params.command,
+            true,
+        )
+        .await?;
+        return Ok(SshExecResult {
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            exit_code: i32::try_from(output.exit_status).unwrap_or(-1),
+            compression: output.compression.map(CompressionReport::from),
+        });
+    }
+
+    let target = params.target.clone();
+    let (pool_key, client) = ssh_get_client(&state.pool, target.clone(), connect_timeout).await?;
+
+    let first = timeout(command_timeout, client.execute(&params.command))
+        .await
+        .map_err(|_| "SSH command timeout".to_owned());
+    match first {
+        Ok(Ok(res)) => Ok(SshExecResult {
+            stdout: res.stdout,
+            stderr: res.stderr,
+            exit_code: i32::try_from(res.exit_status).unwrap_or(-1),
+            compression: None,
+        }),
+        Ok(Err(e)) if SshConnectionPool::should_reconnect(&e) => {
+            state.pool.remove(&pool_key).await;
+            let (_pool_key2, client2) =
+                ssh_get_client(&state.pool, target, connect_timeout).await?;
+            let res = timeout(command_timeout, client2.execute(&params.command))
+                .await
+                .map_err(|_| "SSH command timeout".to_owned())?
+                .map_err(|e| e.to_string())?;
+            Ok(SshExecResult {
+                stdout: res.stdout,
+                stderr: res.stderr,
+                exit_code: i32::try_from(res.exit_status).unwrap_or(-1),
+                compression: None,
+            })
+        }
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs `command` over the pooled connection for `target`, dropping the
+/// connection from the pool when it turned out to be dead.
+async fn ssh_exec_bytes(
+    state: &DaemonState,
+    target: SshTarget,
+    connect_timeout: Duration,
+    command_timeout: Duration,
+    command: &str,
+    compress: bool,
+) -> Result<ExecOutput, String> {
+    let (pool_key, client) = ssh_get_client(&state.pool, target, connect_timeout).await?;
+    match exec_bytes(&client, command, command_timeout, compress).await {
+        Ok(output) => Ok(output),
+        Err(ExecError::Ssh(e)) if SshConnectionPool::should_reconnect(&e) => {
+            state.pool.remove(&pool_key).await;
+            Err(e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Like [`ssh_exec_bytes`], with `input` as the command's stdin.
+async fn ssh_exec_with_input(
+    state: &DaemonState,
+    target: SshTarget,
+    connect_timeout: Duration,
+    command_timeout: Duration,
+    command: &str,
+    input: &[u8],
+) -> Result<ExecOutput, String> {
+    let (pool_key, client) = ssh_get_client(&state.pool, target, connect_timeout).await?;
+    match exec_bytes_with_input(&client, command, input, command_timeout).await {
+        Ok(output) => Ok(output),
+        Err(ExecError::Ssh(e)) if SshConnectionPool::should_reconnect(&e) => {
+            state.pool.remove(&pool_key).await;
+            Err(e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn fs_read(state: &DaemonState, params: FsReadParams) -> Result<FsReadResult, String> {
+    let read = FileRead::new(params.root, params.path)
+        .offset(params.offset)
+        .length(params.length)
+        .max_bytes(params.max_bytes);
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let output = ssh_exec_bytes(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &read.script(),
+        params.compress,
+    )
+    .await?;
+    let contents = read
+        .parse_output(
+            output.exit_status,
+            output.stdout,
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(FsReadResult {
+        data_base64: Base64::encode_string(&contents.data),
+        offset: contents.offset,
+        size: contents.size,
+        mtime: contents.mtime,
+        mode: contents.mode,
+        truncated: contents.truncated,
+        compression: output.compression.map(CompressionReport::from),
+    })
+}
+
+async fn fs_list(state: &DaemonState, params: FsListParams) -> Result<FsListResult, String> {
+    let list = DirList::new(params.path)
+        .show_hidden(params.show_hidden)
+        .depth(params.depth);
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let (pool_key, client) = ssh_get_client(&state.pool, params.target, connect_timeout).await?;
+    let res = list_dir(&client, &list, command_timeout).await;
+    if res.is_err() && client.is_closed() {
+        state.pool.remove(&pool_key).await;
+    }
+    let (listing, via) = res?;
+    Ok(FsListResult {
+        path: listing.path,
+        entries: listing.entries,
+        truncated: listing.truncated,
+        via: match via {
+            ListVia::Sftp => TransferVia::Sftp,
+            ListVia::Shell => TransferVia::Shell,
+        },
+    })
+}
+
+async fn ports_list(
+    state: &DaemonState,
+    params: PortsListParams,
+) -> Result<PortsListResult, String> {
+    let probe = PortProbe::new().project_dir(params.project_dir);
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let output = ssh_exec_bytes(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &probe.script(),
+        false,
+    )
+    .await?;
+    let listing = probe
+        .parse_output(
+            output.exit_status,
+            &String::from_utf8_lossy(&output.stdout),
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(PortsListResult {
+        via: match listing.via {
+            ProbeVia::Ss => PortProbeKind::Ss,
+            ProbeVia::Lsof => PortProbeKind::Lsof,
+            ProbeVia::Netstat => PortProbeKind::Netstat,
+        },
+        project_dir: listing.project_dir,
+        ports: listing.ports.into_iter().map(PortEntry::from).collect(),
+    })
+}
+
+/// Reads the project's `.field_exec/schema.d` extensions and composes them
+/// with the base fields.
+async fn load_output_schema(
+    state: &DaemonState,
+    target: SshTarget,
+    connect_timeout: Duration,
+    command_timeout: Duration,
+    project_dir: &str,
+) -> Result<OutputSchema, String> {
+    let load = ExtensionLoad::new(project_dir);
+    let output = ssh_exec_bytes(
+        state,
+        target,
+        connect_timeout,
+        command_timeout,
+        &load.script(),
+        false,
+    )
+    .await?;
+    load.compose(
+        output.exit_status,
+        &output.stdout,
+        &String::from_utf8_lossy(&output.stderr),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Composes the project's output schema from the base fields and its
+/// `.field_exec/schema.d` extensions, then writes it to the remote.
+async fn schema_prepare(
+    state: &DaemonState,
+    params: SchemaPrepareParams,
+) -> Result<SchemaPrepareResult, String> {
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let schema = load_output_schema(
+        state,
+        params.target.clone(),
+        connect_timeout,
+        command_timeout,
+        &params.project_dir,
+    )
+    .await?;
+    let path = schema_path(&params.project_dir);
+    let written = ssh_write_file(
+        state,
+        SshWriteFileParams {
+            target: params.target,
+            remote_path: path.clone(),
+            contents: Some(schema.to_json()),
+            contents_base64: None,
+            mode: None,
+            create_only: false,
+            if_matches_sha256: None,
+            connect_timeout_ms: params.connect_timeout_ms,
+            command_timeout_ms: params.command_timeout_ms,
+        },
+    )
+    .await?;
+    Ok(SchemaPrepareResult {
+        schema_path: path,
+        sha256: written.sha256,
+        extensions: schema
+            .extensions()
+            .iter()
+            .map(|field| SchemaExtensionEntry {
+                name: field.name.clone(),
+                source: field.source.clone(),
+            })
+            .collect(),
+    })
+}
+
+/// Starts a Codex run for a tab in the project's tmux window, or under
+/// `nohup` when the host has no tmux, with the composed output schema.
+async fn job_start(state: &DaemonState, params: JobStartParams) -> Result<JobStartResult, String> {
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let schema = load_output_schema(
+        state,
+        params.target.clone(),
+        connect_timeout,
+        command_timeout,
+        &params.project_dir,
+    )
+    .await?;
+
+    let mut command = CodexCommand::new()
+        .model(params.model)
+        .profile(params.profile)
+        .sandbox(params.sandbox)
+        .approval(params.approval_policy)
+        .resume(params.resume_thread_id);
+    for image in params.images {
+        command = command.image(image);
+    }
+    let launch = JobLaunch::new(params.project_dir.clone(), params.tab_id, params.prompt)
+        .target_key(&params.target_key)
+        .command(command)
+        .output_schema(Some(schema.to_json()))
+        .use_tmux(params.use_tmux)
+        .log_preamble(params.log_preamble);
+    let script = launch.script().map_err(|e| e.to_string())?;
+    let output = ssh_exec_with_input(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &script,
+        launch.input().as_bytes(),
+    )
+    .await?;
+    let job = launch
+        .parse_output(
+            output.exit_status,
+            &String::from_utf8_lossy(&output.stdout),
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map_err(|e| e.to_string())?;
+    let session = launch.files();
+    Ok(JobStartResult {
+        job_id: job.to_string(),
+        log_path: session.log,
+        stderr_path: session.stderr_log,
+        schema_path: schema_path(&params.project_dir),
+        extensions: schema
+            .extensions()
+            .iter()
+            .map(|field| SchemaExtensionEntry {
+                name: field.name.clone(),
+                source: field.source.clone(),
+            })
+            .collect(),
+    })
+}
+
+fn parse_job_id(job_id: &str) -> Result<RemoteJobId, String> {
+    RemoteJobId::parse(job_id).ok_or_else(|| format!("invalid job id: {job_id:?}"))
+}
+
+async fn job_stop(state: &DaemonState, params: JobRefParams) -> Result<JobStopResult, String> {
+    let job = parse_job_id(&params.job_id)?;
+    check_tab_id(&params.tab_id).map_err(|e| e.to_string())?;
+    let files = SessionFiles::new(&params.project_dir, &params.tab_id);
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let output = ssh_exec_bytes(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &stop_script(&job, Some(&files)),
+        false,
+    )
+    .await?;
+    if output.exit_status != 0 {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "job.stop exited with {}: {}",
+            output.exit_status,
+            stderr.trim()
+        ));
+    }
+    Ok(JobStopResult {
+        job_id: job.to_string(),
+    })
+}
+
+/// Reports whether a job still runs and how its latest turn went.
+async fn job_status(state: &DaemonState, params: JobRefParams) -> Result<JobStatusResult, String> {
+    let job = parse_job_id(&params.job_id)?;
+    let check = JobStatusCheck::new(job.clone(), &params.project_dir, &params.tab_id)
+        .hang_after_secs(params.hang_after_secs.unwrap_or(DEFAULT_HANG_AFTER_SECS));
+    let script = check.script().map_err(|e| e.to_string())?;
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let output = ssh_exec_bytes(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &script,
+        false,
+    )
+    .await?;
+    let status = check
+        .parse_output(
+            output.exit_status,
+            &output.stdout,
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map_err(|e| e.to_string())?;
+    let (response, response_violations) = match status.response {
+        Some(check) => (
+            check.normalized,
+            check
+                .remaining
+                .iter()
+                .map(|v| format!("{}: {}", v.path, v.message))
+                .collect(),
+        ),
+        None => (None, Vec::new()),
+    };
+    Ok(JobStatusResult {
+        job_id: job.to_string(),
+        state: match status.liveness {
+            JobLiveness::Running => JobState::Running,
+            JobLiveness::Exited => JobState::Exited,
+            JobLiveness::Unknown => JobState::Unknown,
+        },
+        outcome: match status.outcome {
+            JobOutcome::Running => JobOutcomeReport::Running,
+            JobOutcome::Completed => JobOutcomeReport::Completed,
+            JobOutcome::Failed { message } => JobOutcomeReport::Failed { message },
+            JobOutcome::Hung { idle_secs } => JobOutcomeReport::Hung { idle_secs },
+            JobOutcome::Cancelled => JobOutcomeReport::Cancelled,
+            JobOutcome::Crashed { message } => JobOutcomeReport::Crashed { message },
+            JobOutcome::Unknown => JobOutcomeReport::Unknown,
+        },
+        idle_secs: status.idle_secs,
+        heartbeat: status.heartbeat.map(|hb| JobHeartbeatEntry {
+            pid: hb.pid,
+            started_at: hb.started_at,
+            boot_id: hb.boot_id,
+            last_output_at: hb.last_output_at,
+            beat_at: hb.beat_at,
+        }),
+        alarm: status.alarm.map(|alarm| match alarm {
+            HeartbeatAlarm::Rebooted => JobAlarmReport::Rebooted,
+            HeartbeatAlarm::PidReused => JobAlarmReport::PidReused,
+            HeartbeatAlarm::Hung { idle_secs } => JobAlarmReport::Hung { idle_secs },
+        }),
+        thread_id: status.thread_id,
+        final_message: status.final_message,
+        response,
+        response_violations,
+    })
+}
+
+/// Commits a tab's work after its turn, with the commit message from the
+/// turn's final response.
+async fn git_auto_commit(
+    state: &DaemonState,
+    params: GitAutoCommitParams,
+) -> Result<GitAutoCommitResult, String> {
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let message = match params.commit_message {
+        Some(message) => Ok(message),
+        None => {
+            let job_id = params
+                .job_id
+                .as_deref()
+                .ok_or("job_id is required without a commit_message")?;
+            let check =
+                JobStatusCheck::new(parse_job_id(job_id)?, &params.project_dir, &params.tab_id);
+            let script = check.script().map_err(|e| e.to_string())?;
+            let output = ssh_exec_bytes(
+                state,
+                params.target.clone(),
+                connect_timeout,
+                command_timeout,
+                &script,
+                false,
+            )
+            .await?;
+            let status = check
+                .parse_output(
+                    output.exit_status,
+                    &output.stdout,
+                    &String::from_utf8_lossy(&output.stderr),
+                )
+                .map_err(|e| e.to_string())?;
+            commit_message_from_status(&status)
+        }
+    };
+    let commit = message
+        .map(|message| AutoCommit::new(params.project_dir.as_str(), &message))
+        .and_then(|commit| {
+            if commit.message().is_empty() {
+                Err(SkipReason::EmptyMessage)
+            } else {
+                Ok(commit)
+            }
+        });
+    let commit = match commit {
+        Ok(commit) => commit,
+        Err(reason) => {
+            return Ok(GitAutoCommitResult::new(
+                CommitReport::skipped(reason),
+                None,
+            ));
+        }
+    };
+    let commit = commit
+        .protected_branches(params.protected_branches)
+        .dry_run(params.dry_run);
+    let output = ssh_exec_bytes(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &commit.script(),
+        false,
+    )
+    .await?;
+    let report = commit
+        .parse_output(
+            output.exit_status,
+            &output.stdout,
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(GitAutoCommitResult::new(
+        report,
+        Some(commit.message().to_owned()),
+    ))
+}
+
+async fn log_fetch(state: &DaemonState, params: LogFetchParams) -> Result<LogFetchResult, String> {
+    let fetch = LogFetch::new(params.path)
+        .from_offset(params.from_offset)
+        .inode(params.inode)
+        .max_bytes(params.max_bytes);
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let output = ssh_exec_bytes(
+        state,
+        params.target,
+        connect_timeout,
+        command_timeout,
+        &fetch.script(),
+        params.compress,
+    )
+    .await?;
+    let chunk = fetch
+        .parse_output(
+            output.exit_status,
+            output.stdout,
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(LogFetchResult {
+        data_base64: Base64::encode_string(&chunk.data),
+        offset: chunk.offset,
+        next_offset: chunk.next_offset,
+        inode: chunk.inode,
+        size: chunk.size,
+        reset: chunk.reset.map(|r| match r {
+            LogReset::Truncated => LogResetKind::Truncated,
+            LogReset::Rotated => LogResetKind::Rotated,
+        }),
+        has_more: chunk.has_more(),
+        compression: output.compression.map(CompressionReport::from),
+    })
+}
+
+fn write_file_request(params: &SshWriteFileParams) -> Result<(Vec<u8>, AtomicWrite), String> {
+    let contents = match (&params.contents, &params.contents_base64) {
+        (Some(text), None) => text.clone().into_bytes(),
+        (None, Some(b64)) => {
+            Base64::decode_vec(b64.trim()).map_err(|e| format!("invalid contents_base64: {e}"))?
+        }
+        _ => return Err("exactly one of contents and contents_base64 must be set".to_owned()),
+    };
+    let precondition =
+        WritePrecondition::from_flags(params.create_only, params.if_matches_sha256.clone())
+            .map_err(|e| e.to_string())?;
+    let write = AtomicWrite::new(&params.remote_path, &contents)
+        .mode(params.mode)
+        .precondition(precondition)
+        .private_dir(true);
+    Ok((contents, write))
+}
+
+async fn ssh_write_file(
+    state: &DaemonState,
+    params: SshWriteFileParams,
+) -> Result<SshWriteFileResult, String> {
+    let (contents, write) = write_file_request(&params)?;
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let (pool_key, client) = ssh_get_client(&state.pool, params.target, connect_timeout).await?;
+
+    // Files written through the daemon are private unless a mode says otherwise.
+    let command = format!("umask 077\n{}", write.script());
+
+    let (stdout_tx, mut stdout_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(8);
+    let (stderr_tx, mut stderr_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(8);
+    let (stdin_tx, stdin_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(2);
+
+    let write_task: JoinHandle<Result<u32, async_ssh2_tokio::Error>> =
+        tokio::spawn(async move {
+            client
+                .execute_io(&command, stdout_tx, Some(stderr_tx), Some(stdin_rx), false, None)
+                .await
+        });
+
+    // Collect stdout/stderr while stdin is still being sent; a full output
+    // channel would otherwise stall the command before it reads all input.
+    let drain = tokio::spawn(async move {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        loop {
+            tokio::select! {
+                Some(bytes) = stdout_rx.recv() => out.extend_from_slice(&bytes),
+                Some(bytes) = stderr_rx.recv() => err.extend_from_slice(&bytes),
+                else => break,
+            }
+        }
+        (out, err)
+    });
+
+    // Send file contents then EOF (empty vec).
+    let bytes = contents.len() as u64;
+    for chunk in contents.chunks(TRANSFER_CHUNK_BYTES) {
+        stdin_tx
+            .send(chunk.to_vec())
+            .await
+            .map_err(|_| "stdin send failed".to_owned())?;
+    }
+    stdin_tx
+        .send(Vec::new())
+        .await
+        .map_err(|_| "stdin send failed".to_owned())?;
+
+    let status = timeout(command_timeout, write_task)
+        .await
+        .map_err(|_| "SSH command timeout".to_owned())?
+        .map_err(|_| "SSH write task join failed".to_owned())?
+        .map_err(|e| e.to_string());
+
+    let (out, err) = drain.await.unwrap_or_default();
+
+    match status {
+        Ok(code) => write
+            .parse_result(
+                code,
+                &String::from_utf8_lossy(&out),
+                &String::from_utf8_lossy(&err),
+            )
+            .map(|sha256| SshWriteFileResult { sha256, bytes })
+            .map_err(|e| e.to_string()),
+        Err(e) if e.contains("Broken pipe") || e.contains("Connection reset") => {
+            state.pool.remove(&pool_key).await;
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Chunk size for file transfers in either direction.
+const TRANSFER_CHUNK_BYTES: usize = 32 * 1024;
+/// Minimum gap between two `transfer_progress` events of one transfer.
+const TRANSFER_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum TransferVia {
+    Sftp,
+    /// The server has no sftp subsystem; a shell command did the work.
+    Shell,
+    /// Checksummed chunks through shell commands, resumable via the journal.
+    Chunked,
+}
+
+/// Transfers started on one client connection, keyed by transfer id. A task
+/// removes itself when done; whoever removes an entry reports `transfer_done`.
+#[derive(Clone, Default)]
+struct ConnectionTransfers {
+    tasks: Arc<Mutex<HashMap<u64, JoinHandle<()>>>>,
+}
+
+impl ConnectionTransfers {
+    async fn finish(&self, transfer_id: u64) -> bool {
+        self.tasks.lock().await.remove(&transfer_id).is_some()
+    }
+
+    async fn cancel(&self, pred: impl Fn(u64) -> bool) -> Vec<u64> {
+        let mut g = self.tasks.lock().await;
+        let ids: Vec<u64> = g.keys().copied().filter(|id| pred(*id)).collect();
+        for id in &ids {
+            if let Some(task) = g.remove(id) {
+                task.abort();
+            }
+        }
+        ids
+    }
+}
+
+struct TransferProgress {
+    transfer_id: u64,
+    outbox: Outbox,
+    total: Option<u64>,
+    bytes: u64,
+    last_sent: Option<Instant>,
+}
+
+impl TransferProgress {
+    fn new(transfer_id: u64, outbox: Outbox) -> Self {
+        Self {
+            transfer_id,
+            outbox,
+            total: None,
+            bytes: 0,
+            last_sent: None,
+        }
+    }
+
+    async fn advance(&mut self, n: usize) {
+        self.bytes += n as u64;
+        let due = self
+            .last_sent
+            .is_none_or(|at| at.elapsed() >= TRANSFER_PROGRESS_INTERVAL);
+        if due {
+            self.send().await;
+        }
+    }
+
+    async fn send(&mut self) {
+        self.last_sent = Some(Instant::now());
+        let _ = self
+            .outbox
+            .send_json(&EventEnvelope::TransferProgress {
+                transfer_id: self.transfer_id,
+                bytes: self.bytes,
+                total: self.total,
+            })
+            .await;
+    }
+}
+
+async fn run_transfer(
+    client: &async_ssh2_tokio::Client,
+    direction: TransferDirection,
+    local_path: &str,
+    remote_path: &str,
+    progress: &mut TransferProgress,
+) -> (TransferVia, Result<(), String>) {
+    // Downloads land in a temp file next to the target, so a failed or
+    // cancelled one leaves whatever was there before untouched.
+    let download = match direction {
+        TransferDirection::Upload => None,
+        TransferDirection::Download => Some(TempDownload::new(local_path, progress.transfer_id)),
+    };
+    let download_path = download.as_ref().map_or(local_path, |d| d.path.as_str());
+    // Only a refused subsystem means "no sftp here"; any other error is the
+    // connection's, and the shell would not fare better.
+    let (via, res) = match client.open_sftp(None).await {
+        Ok(sftp) => {
+            let res = match direction {
+                TransferDirection::Upload => {
+                    sftp_upload(&sftp, local_path, remote_path, progress).await
+                }
+                TransferDirection::Download => {
+                    sftp_download(&sftp, remote_path, download_path, progress).await
+                }
+            };
+            let _ = sftp.close().await;
+            (TransferVia::Sftp, res)
+        }
+        Err(SshError::SubsystemRefused(_)) => {
+            let res = match direction {
+                TransferDirection::Upload => {
+                    shell_upload(client, local_path, remote_path, progress).await
+                }
+                TransferDirection::Download => {
+                    shell_download(client, remote_path, download_path, progress).await
+                }
+            };
+            (TransferVia::Shell, res)
+        }
+        Err(e) => return (TransferVia::Sftp, Err(e.to_string())),
+    };
+    match (res, download) {
+        (Ok(()), Some(download)) => (via, download.persist(local_path).await),
+        (res, _) => (via, res),
+    }
+}
+
+/// The temp file a download is written to. It is removed when dropped unless
+/// [`TempDownload::persist`] moved it into place.
+struct TempDownload {
+    path: String,
+    persisted: bool,
+}
+
+impl TempDownload {
+    fn new(local_path: &str, transfer_id: u64) -> Self {
+        let target = Path::new(local_path);
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let path = target.with_file_name(format!(".{name}.{transfer_id}.download"));
+        Self {
+            path: path.to_string_lossy().into_owned(),
+            persisted: false,
+        }
+    }
+
+    async fn persist(mut self, local_path: &str) -> Result<(), String> {
+        tokio::fs::rename(&self.path, local_path)
+            .await
+            .map_err(|e| format!("rename to {local_path}: {e}"))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempDownload {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+async fn sftp_upload(
+    sftp: &SftpSession,
+    local_path: &str,
+    remote_path: &str,
+    progress: &mut TransferProgress,
+) -> Result<(), String> {
+    let mut local = tokio::fs::File::open(local_path)
+        .await
+        .map_err(|e| format!("open {local_path}: {e}"))?;
+    progress.total = progress
+        .total
+        .or(local.metadata().await.ok().map(|m| m.len()));
+    let mut remote = sftp
+        .open_with_flags(
+            remote_path,
+            OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+        )
+        .await
+        .map_err(|e| format!("open {remote_path}: {e}"))?;
+
+    let mut buf = vec![0u8; TRANSFER_CHUNK_BYTES];
+    loop {
+        let n = local.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        remote
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| e.to_string())?;
+        progress.advance(n).await;
+    }
+    remote.shutdown().await.map_err(|e| e.to_string())
+}
+
+async fn sftp_download(
+    sftp: &SftpSession,
+    remote_path: &str,
+    local_path: &str,
+    progress: &mut TransferProgress,
+) -> Result<(), String> {
+    let mut remote = sftp
+        .open_with_flags(remote_path, OpenFlags::READ)
+        .await
+        .map_err(|e| format!("open {remote_path}: {e}"))?;
+    progress.total = progress
+        .total
+        .or(remote.metadata().await.ok().and_then(|m| m.size));
+    let mut local = tokio::fs::File::create(local_path)
+        .await
+        .map_err(|e| format!("create {local_path}: {e}"))?;
+
+    let mut buf = vec![0u8; TRANSFER_CHUNK_BYTES];
+    loop {
+        let n = remote.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        local
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| e.to_string())?;
+        progress.advance(n).await;
+    }
+    local.flush().await.map_err(|e| e.to_string())
+}
+
+async fn shell_upload(
+    client: &async_ssh2_tokio::Client,
+    local_path: &str,
+    remote_path: &str,
+    progress: &mut TransferProgress,
+) -> Result<(), String> {
+    let mut local = tokio::fs::File::open(local_path)
+        .await
+        .map_err(|e| format!("open {local_path}: {e}"))?;
+    progress.total = progress
+        .total
+        .or(local.metadata().await.ok().map(|m| m.len()));
+
+    let command = format!("cat > {}", sh_quote(remote_path));
+    let (stdout_tx, mut stdout_rx) = mpsc::channel::<Vec<u8>>(1);
+    let (stderr_tx, mut stderr_rx) = mpsc::channel::<Vec<u8>>(8);
+    let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>(2);
+    let exec_client = client.clone();
+    let exec_task = tokio::spawn(async move {
+        exec_client
+            .execute_io(
+                &command,
+                stdout_tx,
+                Some(stderr_tx),
+                Some(stdin_rx),
+                false,
+                None,
+            )
+            .await
+    });
+    let drain = tokio::spawn(async move {
+        let mut stderr = Vec::new();
+        while stdout_rx.recv().await.is_some() {}
+        while let Some(chunk) = stderr_rx.recv().await {
+            stderr.extend_from_slice(&chunk);
+        }
+        stderr
+    });
+
+    let mut buf = vec![0u8; TRANSFER_CHUNK_BYTES];
+    let read = loop {
+        let n = match local.read(&mut buf).await {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) => break Err(format!("read {local_path}: {e}")),
+        };
+        if stdin_tx.send(buf[..n].to_vec()).await.is_err() {
+            // The remote side went away; the exit status below says why.
+            break Ok(());
+        }
+        progress.advance(n).await;
+    };
+    // EOF (an empty chunk) ends `cat` on every path, so the command never
+    // outlives the transfer. A cancelled transfer drops `stdin_tx`, which
+    // ends it the same way.
+    let _ = stdin_tx.send(Vec::new()).await;
+
+    let status = exec_task
+        .await
+        .map_err(|_| "SSH transfer task join failed".to_owned())?
+        .map_err(|e| e.to_string())?;
+    read?;
+    let stderr = drain.await.unwrap_or_default();
+    match status {
+        0 => Ok(()),
+        code => Err(shell_transfer_error(code, &stderr)),
+    }
+}
+
+async fn shell_download(
+    client: &async_ssh2_tokio::Client,
+    remote_path: &str,
+    local_path: &str,
+    progress: &mut TransferProgress,
+) -> Result<(), String> {
+    let remote_path_q = sh_quote(remote_path);
+    let size = client
+        .execute(&format!("wc -c < {remote_path_q}"))
+        .await
+        .map_err(|e| e.to_string())?;
+    if size.exit_status != 0 {
+        return Err(shell_transfer_error(
+            size.exit_status,
+            size.stderr.as_bytes(),
+        ));
+    }
+    progress.total = progress.total.or(size.stdout.trim().parse().ok());
+
+    let mut local = tokio::fs::File::create(local_path)
+        .await
+        .map_err(|e| format!("create {local_path}: {e}"))?;
+    let command = format!("cat {remote_path_q}");
+    let (stdout_tx, mut stdout_rx) = mpsc::channel::<Vec<u8>>(8);
+    let (stderr_tx, mut stderr_rx) = mpsc::channel::<Vec<u8>>(8);
+    let exec_client = client.clone();
+    let exec_task = tokio::spawn(async move {
+        exec_client
+            .execute_io(&command, stdout_tx, Some(stderr_tx), None, false, None)
+            .await
+    });
+    let drain = tokio::spawn(async move {
+        let mut stderr = Vec::new();
+        while let Some(chunk) = stderr_rx.recv().await {
+            stderr.extend_from_slice(&chunk);
+        }
+        stderr
+    });
+
+    while let Some(chunk) = stdout_rx.recv().await {
+        local.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        progress.advance(chunk.len()).await;
+    }
+    local.flush().await.map_err(|e| e.to_string())?;
+
+    let status = exec_task
+        .await
+        .map_err(|_| "SSH transfer task join failed".to_owned())?
+        .map_err(|e| e.to_string())?;
+    let stderr = drain.await.unwrap_or_default();
+    match status {
+        0 => Ok(()),
+        code => Err(shell_transfer_error(code, &stderr)),
+    }
+}
+
+fn shell_transfer_error(code: u32, stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        format!("transfer failed (exit={code})")
+    } else {
+        format!("transfer failed (exit={code}): {stderr}")
+    }
+}
+
+/// Everything `sftp.upload` and `sftp.download` have in common.
+struct TransferRequest {
+    target: SshTarget,
+    connect_timeout_ms: u64,
+    direction: TransferDirection,
+    local_path: String,
+    remote_path: String,
+    resumable: bool,
+    chunk_bytes: Option<u64>,
+}
+
+impl From<SftpUploadParams> for TransferRequest {
+    fn from(params: SftpUploadParams) -> Self {
+        Self {
+            target: params.target,
+            connect_timeout_ms: params.connect_timeout_ms,
+            direction: TransferDirection::Upload,
+            local_path: params.local_path,
+            remote_path: params.remote_path,
+            resumable: params.resumable,
+            chunk_bytes: params.chunk_bytes,
+        }
+    }
+}
+
+impl From<SftpDownloadParams> for TransferRequest {
+    fn from(params: SftpDownloadParams) -> Self {
+        Self {
+            target: params.target,
+            connect_timeout_ms: params.connect_timeout_ms,
+            direction: TransferDirection::Download,
+            local_path: params.local_path,
+            remote_path: params.remote_path,
+            resumable: params.resumable,
+            chunk_bytes: params.chunk_bytes,
+        }
+    }
+}
+
+async fn start_transfer(
+    state: &DaemonState,
+    transfers: &ConnectionTransfers,
+    outbox: Outbox,
+    request: TransferRequest,
+) -> Result<SftpTransferResult, String> {
+    let TransferRequest {
+        target,
+        connect_timeout_ms,
+        direction,
+        local_path,
+        remote_path,
+        resumable,
+        chunk_bytes,
+    } = request;
+    if local_path.trim().is_empty() {
+        return Err("local_path is empty".to_owned());
+    }
+    if remote_path.trim().is_empty() {
+        return Err("remote_path is empty".to_owned());
+    }
+    let connect_timeout = Duration::from_millis(connect_timeout_ms.max(1));
+    let journal = if resumable {
+        let fresh = TransferJournal::new(
+            direction,
+            target.host.clone(),
+            target.port,
+            target.username.clone(),
+            local_path.clone(),
+            remote_path.clone(),
+        );
+        let journal = state.journals.load(&fresh.key).await.unwrap_or(fresh);
+        Some(journal.chunk_bytes(chunk_bytes))
+    } else {
+        None
+    };
+    let journal_key = journal.as_ref().map(|j| j.key.clone());
+    let claim = match &journal_key {
+        Some(key) => Some(
+            state
+                .journals
+                .claim(key)
+                .ok_or_else(|| "another transfer is using this journal".to_owned())?,
+        ),
+        None => None,
+    };
+    let (pool_key, client) = ssh_get_client(&state.pool, target.clone(), connect_timeout).await?;
+    let transfer_id = state
+        .next_transfer_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    let state = state.clone();
+    let registry = transfers.clone();
+    // Hold the registry lock across spawn so the task cannot finish (and
+    // unregister itself) before it has been registered.
+    let mut g = transfers.tasks.lock().await;
+    let task = tokio::spawn(async move {
+        // Held until the task ends or is aborted.
+        let _claim = claim;
+        let mut progress = TransferProgress::new(transfer_id, outbox.clone());
+        let (via, resumed_from, res) = match journal {
+            Some(journal) => {
+                let mut run = ResumableRun {
+                    state: &state,
+                    target,
+                    connect_timeout,
+                    journal,
+                    progress: &mut progress,
+                    resumed_from: None,
+                };
+                let res = run.run((pool_key, client)).await;
+                (TransferVia::Chunked, run.resumed_from, res)
+            }
+            None => {
+                let (via, res) =
+                    run_transfer(&client, direction, &local_path, &remote_path, &mut progress)
+                        .await;
+                if res.is_err() && client.is_closed() {
+                    state.pool.remove(&pool_key).await;
+                }
+                (via, None, res)
+            }
+        };
+        if res.is_ok() {
+            progress.send().await;
+        }
+        if !registry.finish(transfer_id).await {
+            return;
+        }
+        let _ = outbox
+            .send_json(&EventEnvelope::TransferDone {
+                transfer_id,
+                ok: res.is_ok(),
+                cancelled: false,
+                bytes: Some(progress.bytes),
+                via: Some(via),
+                resumed_from,
+                error: res.as_ref().err().map(String::as_str),
+            })
+            .await;
+    });
+    g.insert(transfer_id, task);
+    Ok(SftpTransferResult {
+        transfer_id,
+        journal_key,
+    })
+}
+
+fn remote_join(dir: &str, path: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" if dir.starts_with('/') => format!("/{path}"),
+        "" => path.to_owned(),
+        dir => format!("{dir}/{path}"),
+    }
+}
+
+fn sync_exec_error(output: &ExecOutput) -> String {
+    shell_transfer_error(output.exit_status, &output.stderr)
+}
+
+/// Lists both trees and works out what a sync has to do. Files with the
+/// same size but different mtimes are hashed on both sides, in one remote
+/// command, before they count as changed.
+async fn sync_plan(state: &DaemonState, params: &SyncParams) -> Result<SyncPlan, String> {
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+    let mut filter = SyncFilter::new(&params.include, &params.exclude)?;
+    let local_dir = PathBuf::from(&params.local_dir);
+    let (checksum, gitignore) = (params.checksum, params.gitignore);
+    let local = {
+        let local_dir = local_dir.clone();
+        tokio::task::spawn_blocking(move || local_tree(&local_dir, checksum, gitignore))
+            .await
+            .map_err(|_| "local scan failed".to_owned())?
+    };
+    // A pull may create the local directory; it just starts out empty.
+    let local = match local {
+        Ok(tree) => tree,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound && params.direction == SyncDirection::Pull =>
+        {
+            FileTree::default()
+        }
+        Err(e) => return Err(format!("{}: {e}", params.local_dir)),
+    };
+
+    let manifest = RemoteManifest::new(params.remote_dir.clone())
+        .checksums(checksum)
+        .gitignore(gitignore);
+    let output = ssh_exec_bytes(
+        state,
+        params.target.clone(),
+        connect_timeout,
+        command_timeout,
+        &manifest.script(),
+        true,
+    )
+    .await?;
+    let remote = match manifest.parse_output(
+        output.exit_status,
+        &output.stdout,
+        &String::from_utf8_lossy(&output.stderr),
+    ) {
+        Ok(tree) => tree,
+        Err(RemoteFsError::RootNotFound(_)) if params.direction == SyncDirection::Push => {
+            FileTree::default()
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let (mut source, mut dest) = match params.direction {
+        SyncDirection::Push => (local, remote),
+        SyncDirection::Pull => (remote, local),
+    };
+    if gitignore {
+        filter = filter.gitignores(&source.gitignores);
+    }
+
+    let candidates = hash_candidates(&source, &dest, &filter);
+    if !candidates.is_empty() {
+        let hashes = RemoteHashes::new(params.remote_dir.clone(), candidates.clone());
+        let output = ssh_exec_bytes(
+            state,
+            params.target.clone(),
+            connect_timeout,
+            command_timeout,
+            &hashes.script(),
+            false,
+        )
+        .await?;
+        let remote_hashes = hashes
+            .parse_output(
+                output.exit_status,
+                &String::from_utf8_lossy(&output.stdout),
+                &String::from_utf8_lossy(&output.stderr),
+            )
+            .map_err(|e| e.to_string())?;
+        let local_hashes =
+            tokio::task::spawn_blocking(move || local_hashes(&local_dir, candidates))
+                .await
+                .map_err(|_| "local hashing failed".to_owned())?
+                .map_err(|e| format!("{}: {e}", params.local_dir))?;
+        let (source_hashes, dest_hashes) = match params.direction {
+            SyncDirection::Push => (local_hashes, remote_hashes),
+            SyncDirection::Pull => (remote_hashes, local_hashes),
+        };
+        source.apply_hashes(source_hashes);
+        dest.apply_hashes(dest_hashes);
+    }
+
+    Ok(plan(&source, &dest, &filter, params.delete))
+}
+
+async fn start_sync(
+    state: &DaemonState,
+    transfers: &ConnectionTransfers,
+    outbox: Outbox,
+    params: SyncParams,
+) -> Result<SyncResult, String> {
+    if params.local_dir.trim().is_empty() {
+        return Err("local_dir is empty".to_owned());
+    }
+    if params.remote_dir.trim().is_empty() {
+        return Err("remote_dir is empty".to_owned());
+    }
+    let plan = sync_plan(state, &params).await?;
+    let mut result = SyncResult {
+        transfer_id: None,
+        actions: plan.actions.iter().map(SyncActionReport::from).collect(),
+        unchanged: plan.unchanged,
+        filtered: plan.filtered,
+        copy_bytes: plan.copy_bytes(),
+    };
+    if params.dry_run || plan.actions.is_empty() {
+        return Ok(result);
+    }
+
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    let (pool_key, client) =
+        ssh_get_client(&state.pool, params.target.clone(), connect_timeout).await?;
+    let transfer_id = state
+        .next_transfer_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    result.transfer_id = Some(transfer_id);
+
+    let state = state.clone();
+    let registry = transfers.clone();
+    let mut g = transfers.tasks.lock().await;
+    let task = tokio::spawn(async move {
+        let mut progress = TransferProgress::new(transfer_id, outbox.clone());
+        progress.total = Some(plan.copy_bytes());
+        let (via, res) = run_sync(&state, &client, &params, &plan, &mut progress).await;
+        match &res {
+            Ok(()) => progress.send().await,
+            Err(_) if client.is_closed() => state.pool.remove(&pool_key).await,
+            Err(_) => {}
+        }
+        if !registry.finish(transfer_id).await {
+            return;
+        }
+        let _ = outbox
+            .send_json(&EventEnvelope::TransferDone {
+                transfer_id,
+                ok: res.is_ok(),
+                cancelled: false,
+                bytes: Some(progress.bytes),
+                via: Some(via),
+                resumed_from: None,
+                error: res.as_ref().err().map(String::as_str),
+            })
+            .await;
+    });
+    g.insert(transfer_id, task);
+    Ok(result)
+}
+
+/// Carries out a sync plan: copies over one SFTP session (or shell
+/// commands without sftp), then fixes up mtimes and deletes.
+async fn run_sync(
+    state: &DaemonState,
+    client: &async_ssh2_tokio::Client,
+    params: &SyncParams,
+    plan: &SyncPlan,
+    progress: &mut TransferProgress,
+) -> (TransferVia, Result<(), String>) {
+    let sftp = match client.open_sftp(None).await {
+        Ok(sftp) => Some(sftp),
+        Err(SshError::SubsystemRefused(_)) => None,
+        Err(e) => return (TransferVia::Sftp, Err(e.to_string())),
+    };
+    let via = if sftp.is_some() {
+        TransferVia::Sftp
+    } else {
+        TransferVia::Shell
+    };
+    let res = async {
+        let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+        let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
+        let run_script = |script: String| async move {
+            ssh_exec_bytes(
+                state,
+                params.target.clone(),
+                connect_timeout,
+                command_timeout,
+                &script,
+                false,
+            )
+            .await
+        };
+        let copies: Vec<(&str, i64)> = plan
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                SyncAction::Copy { path, mtime, .. } => Some((path.as_str(), *mtime)),
+                SyncAction::Delete { .. } => None,
+            })
+            .collect();
+        let deletes: Vec<String> = plan
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                SyncAction::Delete { path } => Some(path.clone()),
+                SyncAction::Copy { .. } => None,
+            })
+            .collect();
+        let local_root = Path::new(&params.local_dir);
+
+        if params.direction == SyncDirection::Push {
+            let output = run_script(mkdirs_script(&params.remote_dir, &plan.parent_dirs())).await?;
+            if output.exit_status != 0 {
+                return Err(sync_exec_error(&output));
+            }
+        }
+        for (path, mtime) in &copies {
+            let local_path = local_root.join(path);
+            let local_path = local_path.to_string_lossy();
+            let remote_path = remote_join(&params.remote_dir, path);
+            match params.direction {
+                SyncDirection::Push => match &sftp {
+                    Some(sftp) => sftp_upload(sftp, &local_path, &remote_path, progress).await,
+                    None => shell_upload(client, &local_path, &remote_path, progress).await,
+                }
+                .map_err(|e| format!("{path}: {e}"))?,
+                SyncDirection::Pull => {
+                    if let Some(parent) = Path::new(local_path.as_ref()).parent() {
+                        tokio::fs::create_dir_all(parent)
+                            .await
+                            .map_err(|e| format!("create {}: {e}", parent.display()))?;
+                    }
+                    let download = TempDownload::new(&local_path, progress.transfer_id);
+                    match &sftp {
+                        Some(sftp) => {
+                            sftp_download(sftp, &remote_path, &download.path, progress).await
+                        }
+                        None => {
+                            shell_download(client, &remote_path, &download.path, progress).await
+                        }
+                    }
+                    .map_err(|e| format!("{path}: {e}"))?;
+                    let modified =
+                        UNIX_EPOCH + Duration::from_secs(u64::try_from(*mtime).unwrap_or(0));
+                    if let Ok(file) = fs::File::options().write(true).open(&download.path) {
+                        let _ = file.set_modified(modified);
+                    }
+                    download
+                        .persist(&local_path)
+                        .await
+                        .map_err(|e| format!("{path}: {e}"))?;
+                }
+            }
+        }
+
+        match params.direction {
+            SyncDirection::Push => {
+                let touched: Vec<(String, i64)> =
+                    copies.iter().map(|(p, m)| ((*p).to_owned(), *m)).collect();
+                let output = run_script(touch_script(&params.remote_dir, &touched)).await?;
+                if output.exit_status != 0 {
+                    return Err(sync_exec_error(&output));
+                }
+                if !deletes.is_empty() {
+                    let output = run_script(delete_script(&params.remote_dir, &deletes)).await?;
+                    if output.exit_status != 0 {
+                        return Err(sync_exec_error(&output));
+                    }
+                }
+            }
+            SyncDirection::Pull => {
+                for path in &deletes {
+                    let file = local_root.join(path);
+                    tokio::fs::remove_file(&file)
+                        .await
+                        .map_err(|e| format!("delete {}: {e}", file.display()))?;
+                    // Drop directories the deletion left empty, up to the root.
+                    let mut dir = file.parent();
+                    while let Some(d) = dir {
+                        if d == local_root || tokio::fs::remove_dir(d).await.is_err() {
+                            break;
+                        }
+                        dir = d.parent();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Some(sftp) = sftp {
+        let _ = sftp.close().await;
+    }
+    (via, res)
+}
+
+/// Consecutive failed attempts, without a chunk getting through, after
+/// which a resumable transfer gives up. Its journal stays for a later retry.
+const RESUME_MAX_ATTEMPTS: u32 = 5;
+/// Pause before the first retry; doubled for each one after that.
+const RESUME_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper bound for one chunk command, so a stalled connection is noticed.
+const RESUME_COMMAND_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Journals of resumable transfers, one JSON file per transfer next to the
+/// daemon's state file, so a transfer can pick up after a restart.
+#[derive(Clone)]
+struct JournalStore {
+    dir: PathBuf,
+    /// Keys of journals a running transfer is using.
+    active: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+/// Exclusive use of one journal, released when dropped.
+struct JournalClaim {
+    active: Arc<std::sync::Mutex<HashSet<String>>>,
+    key: String,
+}
+
+impl Drop for JournalClaim {
+    fn drop(&mut self) {
+        self.active
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+
+impl JournalStore {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            active: Arc::default(),
+        }
+    }
+
+    /// Claims the journal `key` for one transfer; `None` while another
+    /// transfer holds it.
+    fn claim(&self, key: &str) -> Option<JournalClaim> {
+        let mut active = self
+            .active
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        active.insert(key.to_owned()).then(|| JournalClaim {
+            active: self.active.clone(),
+            key: key.to_owned(),
+        })
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        // Keys are hex digests; anything else must not become a path.
+        let valid = !key.is_empty() && key.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| self.dir.join(format!("{key}.json")))
+    }
+
+    async fn load(&self, key: &str) -> Option<TransferJournal> {
+        let bytes = tokio::fs::read(self.path(key)?).await.ok()?;
+        TransferJournal::from_json(&bytes).ok()
+    }
+
+    async fn list(&self) -> Vec<TransferJournal> {
+        let mut journals = Vec::new();
+        let Ok(mut dir) = tokio::fs::read_dir(&self.dir).await else {
+            return journals;
+        };
+        while let Ok(Some(entry)) = dir.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            if let Ok(bytes) = tokio::fs::read(&path).await
+                && let Ok(journal) = TransferJournal::from_json(&bytes)
+            {
+                journals.push(journal);
+            }
+        }
+        journals.sort_by_key(|j| std::cmp::Reverse(j.updated_at));
+        journals
+    }
+
+    async fn save(&self, journal: &mut TransferJournal) -> Result<(), String> {
+        let path = self
+            .path(&journal.key)
+            .ok_or_else(|| "invalid journal key".to_owned())?;
+        journal.updated_at = unix_now();
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("create {}: {e}", self.dir.display()))?;
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, journal.to_json())
+            .await
+            .map_err(|e| format!("write journal: {e}"))?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .map_err(|e| format!("write journal: {e}"))
+    }
+
+    async fn remove(&self, key: &str) -> bool {
+        match self.path(key) {
+            Some(path) => tokio::fs::remove_file(path).await.is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Forgets a resumable transfer and removes its partial file, on the remote
+/// for an upload.
+async fn transfer_discard(
+    state: &DaemonState,
+    params: TransferDiscardParams,
+) -> Result<TransferDiscardResult, String> {
+    let _claim = state
+        .journals
+        .claim(&params.key)
+        .ok_or_else(|| "a transfer is using this journal".to_owned())?;
+    let Some(journal) = state.journals.load(&params.key).await else {
+        return Ok(TransferDiscardResult {
+            discarded: state.journals.remove(&params.key).await,
+        });
+    };
+    match journal.direction {
+        TransferDirection::Download => {
+            let _ = tokio::fs::remove_file(journal.partial_path()).await;
+        }
+        TransferDirection::Upload => {
+            let target = params
+                .target
+                .filter(|t| {
+                    t.host == journal.host
+                        && t.port == journal.port
+                        && t.username == journal.username
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "discarding an upload needs the target {}@{}:{} to remove its partial file",
+                        journal.username, journal.host, journal.port
+                    )
+                })?;
+            let output = ssh_exec_bytes(
+                state,
+                target,
+                Duration::from_millis(params.connect_timeout_ms.max(1)),
+                Duration::from_millis(params.command_timeout_ms.max(1)),
+                &journal.remove_partial_script(),
+                false,
+            )
+            .await?;
+            if output.exit_status != 0 {
+                return Err(shell_transfer_error(output.exit_status, &output.stderr));
+            }
+        }
+    }
+    Ok(TransferDiscardResult {
+        discarded: state.journals.remove(&params.key).await,
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Why one attempt of a resumable transfer stopped.
+enum AttemptError {
+    /// Try again on the same connection.
+    Retry(String),
+    /// The connection failed or stalled; try again on a fresh one.
+    Reconnect(String),
+    Fatal(String),
+}
+
+impl From<ResumeError> for AttemptError {
+    fn from(err: ResumeError) -> Self {
+        match err {
+            ResumeError::ChecksumMismatch { .. } | ResumeError::Malformed(_) => {
+                AttemptError::Retry(err.to_string())
+            }
+            err => AttemptError::Fatal(err.to_string()),
+        }
+    }
+}
+
+/// Runs `command` on `client` with `input` as its stdin and returns the exit
+/// status, stdout and stderr.
+async fn exec_with_input(
+    client: &async_ssh2_tokio::Client,
+    command: &str,
+    input: &[u8],
+) -> Result<(u32, Vec<u8>, Vec<u8>), AttemptError> {
+    let pieces = input.chunks(TRANSFER_CHUNK_BYTES);
+    // Room for every piece plus EOF, so all of stdin is queued up front.
+    let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>(pieces.len() + 1);
+    for piece in pieces {
+        let _ = stdin_tx.try_send(piece.to_vec());
+    }
+    let _ = stdin_tx.try_send(Vec::new());
+
+    let (stdout_tx, mut stdout_rx) = mpsc::channel::<Vec<u8>>(8);
+    let (stderr_tx, mut stderr_rx) = mpsc::channel::<Vec<u8>>(8);
+    let exec_future = client.execute_io(
+        command,
+        stdout_tx,
+        Some(stderr_tx),
+        Some(stdin_rx),
+        false,
+        None,
+    );
+    tokio::pin!(exec_future);
+
+    let mut out = Vec::new();
+    let mut err = Vec::new();
+    let status = timeout(RESUME_COMMAND_TIMEOUT, async {
+        loop {
+            tokio::select! {
+                result = &mut exec_future => break result,
+                Some(bytes) = stdout_rx.recv() => out.extend_from_slice(&bytes),
+                Some(bytes) = stderr_rx.recv() => err.extend_from_slice(&bytes),
+            }
+        }
+    })
+    .await
+    .map_err(|_| AttemptError::Reconnect("SSH command timeout".to_owned()))?
+    .map_err(|e| AttemptError::Reconnect(e.to_string()))?;
+    while let Ok(bytes) = stdout_rx.try_recv() {
+        out.extend_from_slice(&bytes);
+    }
+    while let Ok(bytes) = stderr_rx.try_recv() {
+        err.extend_from_slice(&bytes);
+    }
+    Ok((status, out, err))
+}
+
+fn local_fingerprint(meta: &std::fs::Metadata) -> SourceFingerprint {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX));
+    SourceFingerprint {
+        size: meta.len(),
+        mtime,
+    }
 }
 
-fn hex_token(bytes_len: usize) -> String {
-    let mut bytes = vec![0_u8; bytes_len];
-    OsRng.fill_bytes(&mut bytes);
-    let mut out = String::with_capacity(bytes_len * 2);
-    for b in bytes {
-        use std::fmt::Write;
-        let _ = write!(&mut out, "{:02x}", b);
+/// Fails once the local source of an upload no longer has the size and
+/// mtime it started with, so the remote file never mixes two versions.
+async fn ensure_unchanged(path: &str, source: SourceFingerprint) -> Result<(), AttemptError> {
+    let meta = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| AttemptError::Fatal(format!("open {path}: {e}")))?;
+    if local_fingerprint(&meta) == source {
+        Ok(())
+    } else {
+        Err(ResumeError::SourceChanged.into())
     }
-    out
 }
 
-#[derive(Serialize)]
-struct StateFile {
-    version: u32,
-    pid: u32,
-    port: u16,
-    token: String,
-    protocol: u32,
+/// Reads `span` of a local file, failing unless all of it is there.
+async fn read_span(path: &str, span: ChunkSpan) -> io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(io::SeekFrom::Start(span.offset)).await?;
+    let mut buf = vec![0u8; usize::try_from(span.len).unwrap_or(usize::MAX)];
+    file.read_exact(&mut buf).await?;
+    Ok(buf)
 }
 
-fn write_state_file(path: &Path, port: u16, token: &str, protocol: u32) -> io::Result<()> {
-    let Some(dir) = path.parent() else {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "missing parent dir"));
-    };
-    fs::create_dir_all(dir)?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).ok();
+/// A transfer that moves the file in checksummed chunks into a `.partial`
+/// file on the receiving side, saving the journal after each chunk, and
+/// reconnects after failures to carry on from the last verified offset.
+struct ResumableRun<'a> {
+    state: &'a DaemonState,
+    target: SshTarget,
+    connect_timeout: Duration,
+    journal: TransferJournal,
+    progress: &'a mut TransferProgress,
+    resumed_from: Option<u64>,
+}
+
+impl ResumableRun<'_> {
+    async fn run(&mut self, connection: (PoolKey, async_ssh2_tokio::Client)) -> Result<(), String> {
+        let mut connection = Some(connection);
+        let mut attempts = 0;
+        loop {
+            let before = self.journal.verified_offset();
+            let err = match connection.take() {
+                Some((pool_key, client)) => match self.attempt(&client).await {
+                    Ok(()) => {
+                        self.state.journals.remove(&self.journal.key).await;
+                        return Ok(());
+                    }
+                    Err(AttemptError::Fatal(e)) => return Err(e),
+                    Err(AttemptError::Retry(e)) if !client.is_closed() => {
+                        connection = Some((pool_key, client));
+                        e
+                    }
+                    Err(AttemptError::Retry(e) | AttemptError::Reconnect(e)) => {
+                        self.state.pool.remove(&pool_key).await;
+                        e
+                    }
+                },
+                None => match ssh_get_client(
+                    &self.state.pool,
+                    self.target.clone(),
+                    self.connect_timeout,
+                )
+                .await
+                {
+                    Ok(fresh) => {
+                        connection = Some(fresh);
+                        continue;
+                    }
+                    Err(e) => e,
+                },
+            };
+
+            if self.journal.verified_offset() > before {
+                attempts = 0;
+            }
+            attempts += 1;
+            if attempts >= RESUME_MAX_ATTEMPTS {
+                return Err(err);
+            }
+            sleep(RESUME_BACKOFF * 2_u32.pow(attempts - 1)).await;
+        }
     }
 
-    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
-    let payload = StateFile {
-        version: 1,
-        pid: std::process::id(),
-        port,
-        token: token.to_owned(),
-        protocol,
-    };
-    let json = serde_json::to_string(&payload)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    fs::write(&tmp_path, json)?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600)).ok();
+    async fn attempt(&mut self, client: &async_ssh2_tokio::Client) -> Result<(), AttemptError> {
+        match self.journal.direction {
+            TransferDirection::Upload => self.upload(client).await,
+            TransferDirection::Download => self.download(client).await,
+        }
     }
-    fs::rename(&tmp_path, path)?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).ok();
+
+    /// Adopts `source` and, the first time through, reports where the
+    /// transfer resumes from.
+    async fn begin(
+        &mut self,
+        source: SourceFingerprint,
+        verified: bool,
+    ) -> Result<(), AttemptError> {
+        if !verified || self.journal.source != source {
+            self.journal.restart(source);
+            self.save().await?;
+        }
+        let offset = self.journal.verified_offset();
+        if self.resumed_from.is_none() && offset > 0 && self.progress.bytes == 0 {
+            self.resumed_from = Some(offset);
+        }
+        self.progress.total = Some(source.size);
+        self.progress.bytes = offset;
+        self.progress.send().await;
+        Ok(())
     }
-    Ok(())
-}
 
-fn parse_args() -> (u16, PathBuf) {
-    let mut port: u16 = 0;
-    let mut state_file: Option<PathBuf> = None;
-    let mut args = env::args().skip(1);
-    while let Some(arg) = args.next() {
-        match arg.as_str() {
-            "--port" => {
-                if let Some(v) = args.next() {
-                    if let Ok(p) = v.parse::<u16>() {
-                        port = p;
-                    }
+    async fn save(&mut self) -> Result<(), AttemptError> {
+        self.state
+            .journals
+            .save(&mut self.journal)
+            .await
+            .map_err(AttemptError::Fatal)
+    }
+
+    async fn upload(&mut self, client: &async_ssh2_tokio::Client) -> Result<(), AttemptError> {
+        let local_path = self.journal.local_path.clone();
+        let partial = self.journal.partial_path();
+        let meta = tokio::fs::metadata(&local_path)
+            .await
+            .map_err(|e| AttemptError::Fatal(format!("open {local_path}: {e}")))?;
+        let source = local_fingerprint(&meta);
+
+        // Trust the remote partial only if its newest chunk still checks out.
+        let verified = match self.journal.last_chunk() {
+            Some((span, sha)) if self.journal.source == source => {
+                let check = ChunkCheck::new(partial.clone(), span, sha);
+                let (code, _, err) = exec_with_input(client, &check.script(), &[]).await?;
+                match check.parse_result(code, &String::from_utf8_lossy(&err)) {
+                    Ok(()) => true,
+                    Err(ResumeError::PartialMismatch(_)) => false,
+                    Err(e) => return Err(e.into()),
                 }
             }
-            "--state-file" => {
-                if let Some(v) = args.next() {
-                    state_file = Some(PathBuf::from(v));
+            _ => false,
+        };
+        self.begin(source, verified).await?;
+
+        while let Some(span) = self.journal.next_chunk() {
+            let chunk = read_span(&local_path, span)
+                .await
+                .map_err(|e| AttemptError::Fatal(format!("read {local_path}: {e}")))?;
+            // Checked after the read, so the chunk is known to be from the
+            // version the earlier chunks came from.
+            ensure_unchanged(&local_path, source).await?;
+            let write = ChunkWrite::new(partial.clone(), span, &chunk);
+            let (code, _, err) = exec_with_input(client, &write.script(), &chunk).await?;
+            match write.parse_result(code, &String::from_utf8_lossy(&err)) {
+                Ok(()) => {}
+                Err(ResumeError::PartialMismatch(e)) => {
+                    self.journal.restart(source);
+                    self.save().await?;
+                    return Err(AttemptError::Retry(e));
                 }
+                Err(e) => return Err(e.into()),
             }
-            _ => {}
+            self.journal.record_chunk(span, write.sha256().to_owned());
+            self.save().await?;
+            self.progress
+                .advance(usize::try_from(span.len).unwrap_or(usize::MAX))
+                .await;
+        }
+
+        ensure_unchanged(&local_path, source).await?;
+        let finish = FinishUpload::new(&self.journal);
+        let (code, _, err) = exec_with_input(client, &finish.script(), &[]).await?;
+        match finish.parse_result(code, &String::from_utf8_lossy(&err)) {
+            Ok(()) => Ok(()),
+            Err(ResumeError::PartialMismatch(e)) => {
+                self.journal.restart(source);
+                self.save().await?;
+                Err(AttemptError::Retry(e))
+            }
+            Err(e) => Err(e.into()),
         }
     }
 
-    let state_file = state_file.unwrap_or_else(|| {
-        let home = env::var("HOME").unwrap_or_default();
-        let base = if home.trim().is_empty() {
-            PathBuf::from(".")
-        } else {
-            PathBuf::from(home).join(".config/field_exec")
+    async fn download(&mut self, client: &async_ssh2_tokio::Client) -> Result<(), AttemptError> {
+        let remote_path = self.journal.remote_path.clone();
+        let local_path = self.journal.local_path.clone();
+        let partial = self.journal.partial_path();
+        let (code, out, err) = exec_with_input(client, &stat_script(&remote_path), &[]).await?;
+        let source = parse_stat(
+            code,
+            &String::from_utf8_lossy(&out),
+            &String::from_utf8_lossy(&err),
+        )?;
+
+        let verified = match self.journal.last_chunk() {
+            Some((span, sha)) if self.journal.source == source => read_span(&partial, span)
+                .await
+                .is_ok_and(|bytes| sha256_hex(&bytes) == sha),
+            _ => false,
         };
-        base.join("field_execd.json")
-    });
+        self.begin(source, verified).await?;
 
-    (port, state_file)
-}
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&partial)
+            .await
+            .map_err(|e| AttemptError::Fatal(format!("create {partial}: {e}")))?;
+        let local_err = |e: io::Error| AttemptError::Fatal(format!("write {partial}: {e}"));
+        let offset = self.journal.verified_offset();
+        file.set_len(offset).await.map_err(local_err)?;
+        file.seek(io::SeekFrom::Start(offset))
+            .await
+            .map_err(local_err)?;
 
-fn sh_quote(s: &str) -> String {
-    if s.is_empty() {
-        return "''".to_owned();
-    }
-    if s.chars()
-        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '/' | ':' | '=' | '@' | '-'))
-    {
-        return s.to_owned();
+        while let Some(span) = self.journal.next_chunk() {
+            let read = ChunkRead::new(remote_path.clone(), span, source);
+            let (code, out, err) = exec_with_input(client, &read.script(), &[]).await?;
+            let (chunk, sha) = read.parse_output(code, out, &String::from_utf8_lossy(&err))?;
+            file.write_all(&chunk).await.map_err(local_err)?;
+            // The chunk must be on disk before the journal says it is.
+            file.sync_data().await.map_err(local_err)?;
+            self.journal.record_chunk(span, sha);
+            self.save().await?;
+            self.progress.advance(chunk.len()).await;
+        }
+        drop(file);
+
+        tokio::fs::rename(&partial, &local_path)
+            .await
+            .map_err(|e| AttemptError::Fatal(format!("rename {partial} to {local_path}: {e}")))
     }
-    format!("'{}'", s.replace('\'', "'\\''"))
 }
 
-fn parse_target(target: SshTarget) -> Result<(String, u16, String, SshAuth), String> {
-    if target.host.trim().is_empty() {
-        return Err("host is empty".to_owned());
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ForwardKind {
+    /// A loopback port here reaches `remote_host:remote_port` from the server.
+    Local,
+    /// `remote_host:remote_port` on the server reaches `local_host:local_port`
+    /// from here.
+    Remote,
+    /// A SOCKS5 proxy on a loopback port; each CONNECT goes out from the
+    /// server, like `ssh -D`.
+    Socks,
+}
+
+/// Live numbers of one forward. `bytes_in` flows from the server to this
+/// machine, `bytes_out` the other way.
+#[derive(Default)]
+struct ForwardCounters {
+    bytes_in: std::sync::atomic::AtomicU64,
+    bytes_out: std::sync::atomic::AtomicU64,
+    active_connections: std::sync::atomic::AtomicU64,
+    total_connections: std::sync::atomic::AtomicU64,
+}
+
+#[derive(Clone, Serialize)]
+struct ForwardInfo {
+    forward_id: u64,
+    kind: ForwardKind,
+    host: String,
+    port: u16,
+    username: String,
+    local_host: String,
+    local_port: u16,
+    /// Unset for SOCKS forwards, where every connection picks its own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_port: Option<u16>,
+    active_connections: u64,
+    total_connections: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+struct ForwardEntry {
+    info: ForwardInfo,
+    counters: Arc<ForwardCounters>,
+    task: JoinHandle<()>,
+    /// The connection a remote forward owns, so closing it can stop the
+    /// server listening and hang up.
+    remote: Option<async_ssh2_tokio::Client>,
+}
+
+/// Forwards live in the daemon, not in the client connection that opened
+/// them, so any client can list or close them.
+#[derive(Clone, Default)]
+struct ForwardRegistry {
+    entries: Arc<Mutex<HashMap<u64, ForwardEntry>>>,
+}
+
+impl ForwardRegistry {
+    async fn list(&self) -> Vec<ForwardInfo> {
+        use std::sync::atomic::Ordering::Relaxed;
+        let g = self.entries.lock().await;
+        let mut forwards: Vec<ForwardInfo> = g
+            .values()
+            .map(|entry| ForwardInfo {
+                active_connections: entry.counters.active_connections.load(Relaxed),
+                total_connections: entry.counters.total_connections.load(Relaxed),
+                bytes_in: entry.counters.bytes_in.load(Relaxed),
+                bytes_out: entry.counters.bytes_out.load(Relaxed),
+                ..entry.info.clone()
+            })
+            .collect();
+        forwards.sort_by_key(|f| f.forward_id);
+        forwards
     }
-    if target.username.trim().is_empty() {
-        return Err("username is empty".to_owned());
+
+    async fn close(&self, forward_id: u64) -> bool {
+        let Some(entry) = self.entries.lock().await.remove(&forward_id) else {
+            return false;
+        };
+        entry.task.abort();
+        if let (Some(client), Some(address), Some(port)) =
+            (entry.remote, entry.info.remote_host, entry.info.remote_port)
+        {
+            // Best effort: the connection may already be gone, which stops
+            // the listener as well. It belongs to this forward alone.
+            let _ = client.cancel_remote_forward(&address, port.into()).await;
+            let _ = client.disconnect().await;
+        }
+        true
     }
-    if target.port == 0 {
-        return Err("invalid port".to_owned());
+}
+
+/// Copies `reader` into `writer` until EOF, counting bytes as they go, then
+/// passes the EOF on.
+async fn copy_counted<R, W>(
+    mut reader: R,
+    mut writer: W,
+    counter: &std::sync::atomic::AtomicU64,
+) -> io::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; TRANSFER_CHUNK_BYTES];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n]).await?;
+        counter.fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
     }
-    Ok((target.host, target.port, target.username, target.auth))
+    writer.shutdown().await
 }
 
-async fn ssh_get_client(
-    pool: &SshConnectionPool,
-    target: SshTarget,
-    connect_timeout: Duration,
-) -> Result<(PoolKey, async_ssh2_tokio::Client), String> {
-    let (host, port, username, auth) = parse_target(target)?;
-    match auth {
-        SshAuth::Key {
-            private_key_pem,
-            private_key_passphrase,
-        } => {
-            if private_key_pem.trim().is_empty() {
-                return Err("private_key_pem is empty".to_owned());
+/// Pipes a TCP connection through an SSH channel in both directions until
+/// both sides are done.
+async fn pipe_channel(
+    tcp: TcpStream,
+    channel: russh::Channel<russh::client::Msg>,
+    counters: &ForwardCounters,
+) {
+    use std::sync::atomic::Ordering::Relaxed;
+    counters.active_connections.fetch_add(1, Relaxed);
+    counters.total_connections.fetch_add(1, Relaxed);
+    let (tcp_read, tcp_write) = tcp.into_split();
+    let (channel_read, channel_write) = tokio::io::split(channel.into_stream());
+    let _ = tokio::join!(
+        copy_counted(tcp_read, channel_write, &counters.bytes_out),
+        copy_counted(channel_read, tcp_write, &counters.bytes_in),
+    );
+    counters.active_connections.fetch_sub(1, Relaxed);
+}
+
+/// Accepts the next connection on a forward's listener. Accept errors such
+/// as running out of file descriptors last until something else closes, so
+/// each failure in a row waits longer, up to about a second, instead of
+/// spinning.
+async fn accept_backoff(listener: &TcpListener, failures: &mut u32) -> TcpStream {
+    loop {
+        match listener.accept().await {
+            Ok((tcp, _)) => {
+                *failures = 0;
+                return tcp;
             }
-            pool.get_or_connect_key(
-                &host,
-                port,
-                &username,
-                &private_key_pem,
-                private_key_passphrase.as_deref(),
-                connect_timeout,
-            )
-            .await
-            .map_err(|e| e.to_string())
-        }
-        SshAuth::Password { password } => {
-            if password.trim().is_empty() {
-                return Err("password is empty".to_owned());
+            Err(_) => {
+                *failures = failures.saturating_add(1);
+                sleep(Duration::from_millis(10 << (*failures).min(7))).await;
             }
-            pool.get_or_connect_password(&host, port, &username, &password, connect_timeout)
-                .await
-                .map_err(|e| e.to_string())
         }
     }
 }
 
-async fn ssh_exec(
-    state: &DaemonState,
-    params: SshExecParams,
-) -> Result<SshExecResult, String> {
-    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
-    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
-
-    let target = params.target.clone();
-    let (pool_key, client) = ssh_get_client(&state.pool, target.clone(), connect_timeout).await?;
+/// Why a `direct-tcpip` channel could not be opened.
+enum DirectChannelError {
+    /// No SSH connection to the target.
+    Connect,
+    /// The server refused or failed the channel.
+    Open(SshError),
+}
 
-    let first = timeout(command_timeout, client.execute(&params.command))
+/// Opens a `direct-tcpip` channel to `host:port` as seen from the server,
+/// reconnecting once if the pooled connection turns out to be dead. `host` is
+/// resolved by the server.
+async fn open_direct_channel(
+    state: &DaemonState,
+    target: &SshTarget,
+    connect_timeout: Duration,
+    host: &str,
+    port: u16,
+) -> Result<russh::Channel<russh::client::Msg>, DirectChannelError> {
+    let (pool_key, client) = ssh_get_client(&state.pool, target.clone(), connect_timeout)
         .await
-        .map_err(|_| "SSH command timeout".to_owned());
-    match first {
-        Ok(Ok(res)) => Ok(SshExecResult {
-            stdout: res.stdout,
-            stderr: res.stderr,
-            exit_code: i32::try_from(res.exit_status).unwrap_or(-1),
-        }),
-        Ok(Err(e)) if SshConnectionPool::should_reconnect(&e) => {
+        .map_err(|_| DirectChannelError::Connect)?;
+    match client
+        .open_direct_tcpip_channel_to_host(host, port, None)
+        .await
+    {
+        Ok(channel) => Ok(channel),
+        Err(e) if client.is_closed() || SshConnectionPool::should_reconnect(&e) => {
             state.pool.remove(&pool_key).await;
-            let (_pool_key2, client2) =
-                ssh_get_client(&state.pool, target, connect_timeout).await?;
-            let res = timeout(command_timeout, client2.execute(&params.command))
+            let (_, client) = ssh_get_client(&state.pool, target.clone(), connect_timeout)
                 .await
-                .map_err(|_| "SSH command timeout".to_owned())?
-                .map_err(|e| e.to_string())?;
-            Ok(SshExecResult {
-                stdout: res.stdout,
-                stderr: res.stderr,
-                exit_code: i32::try_from(res.exit_status).unwrap_or(-1),
-            })
+                .map_err(|_| DirectChannelError::Connect)?;
+            client
+                .open_direct_tcpip_channel_to_host(host, port, None)
+                .await
+                .map_err(DirectChannelError::Open)
         }
-        Ok(Err(e)) => Err(e.to_string()),
-        Err(e) => Err(e),
+        Err(e) => Err(DirectChannelError::Open(e)),
+    }
+}
+
+async fn forward_local(
+    state: &DaemonState,
+    params: ForwardLocalParams,
+) -> Result<ForwardLocalResult, String> {
+    if params.remote_host.trim().is_empty() {
+        return Err("remote_host is empty".to_owned());
+    }
+    if params.remote_port == 0 {
+        return Err("invalid remote_port".to_owned());
+    }
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    // Connect up front so a bad target fails the request, not the first use.
+    ssh_get_client(&state.pool, params.target.clone(), connect_timeout).await?;
+    let listener = TcpListener::bind(("127.0.0.1", params.local_port))
+        .await
+        .map_err(|e| format!("listen on 127.0.0.1:{}: {e}", params.local_port))?;
+    let local_port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let forward_id = state
+        .next_forward_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    let counters = Arc::new(ForwardCounters::default());
+    let info = ForwardInfo {
+        forward_id,
+        kind: ForwardKind::Local,
+        host: params.target.host.clone(),
+        port: params.target.port,
+        username: params.target.username.clone(),
+        local_host: "127.0.0.1".to_owned(),
+        local_port,
+        remote_host: Some(params.remote_host.clone()),
+        remote_port: Some(params.remote_port),
+        active_connections: 0,
+        total_connections: 0,
+        bytes_in: 0,
+        bytes_out: 0,
+    };
+    let task = {
+        let state = state.clone();
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            // Dropping the set when the forward is closed aborts its
+            // connections too.
+            let mut connections = tokio::task::JoinSet::new();
+            let mut failures = 0;
+            loop {
+                tokio::select! {
+                    tcp = accept_backoff(&listener, &mut failures) => {
+                        let state = state.clone();
+                        let params = params.clone();
+                        let counters = counters.clone();
+                        connections.spawn(async move {
+                            if let Ok(channel) = open_direct_channel(
+                                &state,
+                                &params.target,
+                                connect_timeout,
+                                &params.remote_host,
+                                params.remote_port,
+                            )
+                            .await
+                            {
+                                pipe_channel(tcp, channel, &counters).await;
+                            }
+                        });
+                    }
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+        })
+    };
+    state.forwards.entries.lock().await.insert(
+        forward_id,
+        ForwardEntry {
+            info,
+            counters,
+            task,
+            remote: None,
+        },
+    );
+    Ok(ForwardLocalResult {
+        forward_id,
+        local_port,
+    })
+}
+
+async fn forward_remote(
+    state: &DaemonState,
+    params: ForwardRemoteParams,
+) -> Result<ForwardRemoteResult, String> {
+    if params.local_host.trim().is_empty() {
+        return Err("local_host is empty".to_owned());
     }
+    if params.local_port == 0 {
+        return Err("invalid local_port".to_owned());
+    }
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    // The forward gets a connection of its own: requesting it needs the
+    // session to itself, and it ends when that connection does.
+    let mut client =
+        ssh_connect_unpooled(&state.pool, params.target.clone(), connect_timeout).await?;
+    let mut forward = client
+        .request_remote_forward(&params.remote_bind_address, params.remote_bind_port.into())
+        .await
+        .map_err(|e| e.to_string())?;
+    let remote_port = u16::try_from(forward.port())
+        .map_err(|_| format!("server bound invalid port {}", forward.port()))?;
+    let forward_id = state
+        .next_forward_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    let counters = Arc::new(ForwardCounters::default());
+    let info = ForwardInfo {
+        forward_id,
+        kind: ForwardKind::Remote,
+        host: params.target.host.clone(),
+        port: params.target.port,
+        username: params.target.username.clone(),
+        local_host: params.local_host.clone(),
+        local_port: params.local_port,
+        remote_host: Some(params.remote_bind_address.clone()),
+        remote_port: Some(remote_port),
+        active_connections: 0,
+        total_connections: 0,
+        bytes_in: 0,
+        bytes_out: 0,
+    };
+    // Hold the registry while spawning so the task can't try to remove its
+    // entry before it is there.
+    let mut entries = state.forwards.entries.lock().await;
+    let task = {
+        let registry = state.forwards.clone();
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = forward.accept() => {
+                        let Some(connection) = accepted else {
+                            break;
+                        };
+                        let local_host = params.local_host.clone();
+                        let counters = counters.clone();
+                        connections.spawn(async move {
+                            match TcpStream::connect((local_host.as_str(), params.local_port)).await
+                            {
+                                Ok(tcp) => pipe_channel(tcp, connection.channel, &counters).await,
+                                Err(_) => {
+                                    let _ = connection.channel.close().await;
+                                }
+                            }
+                        });
+                    }
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+            // The SSH connection is gone, and the server stopped listening
+            // with it.
+            registry.entries.lock().await.remove(&forward_id);
+        })
+    };
+    entries.insert(
+        forward_id,
+        ForwardEntry {
+            info,
+            counters,
+            task,
+            remote: Some(client),
+        },
+    );
+    Ok(ForwardRemoteResult {
+        forward_id,
+        remote_port,
+    })
 }
 
-async fn ssh_write_file(state: &DaemonState, params: SshWriteFileParams) -> Result<(), String> {
-    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
-    let command_timeout = Duration::from_millis(params.command_timeout_ms.max(1));
-    let (pool_key, client) = ssh_get_client(&state.pool, params.target, connect_timeout).await?;
-
-    let remote_path_q = sh_quote(&params.remote_path);
-    let command = [
-        "umask 077",
-        &format!("dir=$(dirname {remote_path_q})"),
-        r#"mkdir -p "$dir""#,
-        r#"chmod 700 "$dir" >/dev/null 2>&1 || true"#,
-        &format!("cat > {remote_path_q}"),
-        &format!("chmod 600 {remote_path_q} >/dev/null 2>&1 || true"),
-    ]
-    .join("; ");
-
-    let (stdout_tx, mut stdout_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
-    let (stderr_tx, mut stderr_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
-    let (stdin_tx, stdin_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(2);
+/// How long a SOCKS client gets to say where it wants to go.
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
-    let write_task: JoinHandle<Result<u32, async_ssh2_tokio::Error>> =
-        tokio::spawn(async move {
-            client
-                .execute_io(&command, stdout_tx, Some(stderr_tx), Some(stdin_rx), false, None)
-                .await
-        });
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+const SOCKS_REPLY_SUCCEEDED: u8 = 0;
+const SOCKS_REPLY_GENERAL_FAILURE: u8 = 1;
+const SOCKS_REPLY_NOT_ALLOWED: u8 = 2;
+const SOCKS_REPLY_HOST_UNREACHABLE: u8 = 4;
+const SOCKS_REPLY_CONNECTION_REFUSED: u8 = 5;
+const SOCKS_REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS_REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
 
-    // Send file contents then EOF (empty vec).
-    stdin_tx
-        .send(params.contents.into_bytes())
-        .await
-        .map_err(|_| "stdin send failed".to_owned())?;
-    stdin_tx
-        .send(Vec::new())
-        .await
-        .map_err(|_| "stdin send failed".to_owned())?;
+/// Reads a SOCKS5 greeting and CONNECT request (RFC 1928, no
+/// authentication). Returns the requested host and port, or the reply code
+/// to refuse with.
+async fn socks_read_connect(tcp: &mut TcpStream) -> io::Result<Result<(String, u16), u8>> {
+    let mut header = [0u8; 2];
+    tcp.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a SOCKS5 client",
+        ));
+    }
+    let mut methods = vec![0u8; header[1].into()];
+    tcp.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        tcp.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHOD])
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no acceptable auth method",
+        ));
+    }
+    tcp.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).await?;
 
-    // Drain any stdout/stderr to avoid deadlocks.
-    let drain = tokio::spawn(async move {
-        while stdout_rx.recv().await.is_some() {}
-        while stderr_rx.recv().await.is_some() {}
-    });
+    let mut request = [0u8; 4];
+    tcp.read_exact(&mut request).await?;
+    if request[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad SOCKS5 request",
+        ));
+    }
+    let host = match request[3] {
+        SOCKS_ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            tcp.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            tcp.read_exact(&mut ip).await?;
+            std::net::Ipv6Addr::from(ip).to_string()
+        }
+        SOCKS_ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            tcp.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0].into()];
+            tcp.read_exact(&mut name).await?;
+            match String::from_utf8(name) {
+                Ok(name) => name,
+                Err(_) => return Ok(Err(SOCKS_REPLY_ADDRESS_NOT_SUPPORTED)),
+            }
+        }
+        _ => return Ok(Err(SOCKS_REPLY_ADDRESS_NOT_SUPPORTED)),
+    };
+    let mut port = [0u8; 2];
+    tcp.read_exact(&mut port).await?;
+    if request[1] != SOCKS_CMD_CONNECT {
+        return Ok(Err(SOCKS_REPLY_COMMAND_NOT_SUPPORTED));
+    }
+    Ok(Ok((host, u16::from_be_bytes(port))))
+}
 
-    let status = timeout(command_timeout, write_task)
+/// Sends a SOCKS5 reply. The bound address is always reported as zero; the
+/// server never tells us which one it used.
+async fn socks_reply(tcp: &mut TcpStream, code: u8) -> io::Result<()> {
+    tcp.write_all(&[SOCKS_VERSION, code, 0, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
         .await
-        .map_err(|_| "SSH command timeout".to_owned())?
-        .map_err(|_| "SSH write task join failed".to_owned())?
-        .map_err(|e| e.to_string());
+}
 
-    let _ = drain.await;
+fn socks_reply_for(err: &DirectChannelError) -> u8 {
+    match err {
+        DirectChannelError::Connect => SOCKS_REPLY_GENERAL_FAILURE,
+        DirectChannelError::Open(SshError::SshError(russh::Error::ChannelOpenFailure(reason))) => {
+            match reason {
+                russh::ChannelOpenFailure::AdministrativelyProhibited => SOCKS_REPLY_NOT_ALLOWED,
+                russh::ChannelOpenFailure::ConnectFailed => SOCKS_REPLY_CONNECTION_REFUSED,
+                _ => SOCKS_REPLY_HOST_UNREACHABLE,
+            }
+        }
+        DirectChannelError::Open(_) => SOCKS_REPLY_GENERAL_FAILURE,
+    }
+}
 
-    match status {
-        Ok(code) if code == 0 => Ok(()),
-        Ok(code) => Err(format!("write failed (exit={code})")),
-        Err(e) if e.contains("Broken pipe") || e.contains("Connection reset") => {
-            state.pool.remove(&pool_key).await;
-            Err(e)
+/// Serves one SOCKS client: handshake, then a `direct-tcpip` channel to
+/// wherever it asked for.
+async fn socks_serve(
+    state: DaemonState,
+    target: SshTarget,
+    connect_timeout: Duration,
+    mut tcp: TcpStream,
+    counters: Arc<ForwardCounters>,
+) {
+    let Ok(Ok(request)) = timeout(SOCKS_HANDSHAKE_TIMEOUT, socks_read_connect(&mut tcp)).await
+    else {
+        return;
+    };
+    let (host, port) = match request {
+        Ok(dest) => dest,
+        Err(code) => {
+            let _ = socks_reply(&mut tcp, code).await;
+            return;
+        }
+    };
+    match open_direct_channel(&state, &target, connect_timeout, &host, port).await {
+        Ok(channel) => {
+            if socks_reply(&mut tcp, SOCKS_REPLY_SUCCEEDED).await.is_ok() {
+                pipe_channel(tcp, channel, &counters).await;
+            }
+        }
+        Err(e) => {
+            let _ = socks_reply(&mut tcp, socks_reply_for(&e)).await;
         }
-        Err(e) => Err(e),
     }
 }
 
+async fn forward_socks(
+    state: &DaemonState,
+    params: ForwardSocksParams,
+) -> Result<ForwardSocksResult, String> {
+    let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+    ssh_get_client(&state.pool, params.target.clone(), connect_timeout).await?;
+    let listener = TcpListener::bind(("127.0.0.1", params.local_port))
+        .await
+        .map_err(|e| format!("listen on 127.0.0.1:{}: {e}", params.local_port))?;
+    let local_port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let forward_id = state
+        .next_forward_id
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+    let counters = Arc::new(ForwardCounters::default());
+    let info = ForwardInfo {
+        forward_id,
+        kind: ForwardKind::Socks,
+        host: params.target.host.clone(),
+        port: params.target.port,
+        username: params.target.username.clone(),
+        local_host: "127.0.0.1".to_owned(),
+        local_port,
+        remote_host: None,
+        remote_port: None,
+        active_connections: 0,
+        total_connections: 0,
+        bytes_in: 0,
+        bytes_out: 0,
+    };
+    let task = {
+        let state = state.clone();
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            let mut failures = 0;
+            loop {
+                tokio::select! {
+                    tcp = accept_backoff(&listener, &mut failures) => {
+                        connections.spawn(socks_serve(
+                            state.clone(),
+                            params.target.clone(),
+                            connect_timeout,
+                            tcp,
+                            counters.clone(),
+                        ));
+                    }
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+        })
+    };
+    state.forwards.entries.lock().await.insert(
+        forward_id,
+        ForwardEntry {
+            info,
+            counters,
+            task,
+            remote: None,
+        },
+    );
+    Ok(ForwardSocksResult {
+        forward_id,
+        local_port,
+    })
+}
+
 fn ssh_generate_key(params: SshGenerateKeyParams) -> Result<SshGenerateKeyResult, String> {
     let mut rng = OsRng;
     let mut key = PrivateKey::random(&mut rng, Algorithm::Ed25519).map_err(|e| e.to_string())?;
@@ -745,10 +4542,155 @@ async fn ssh_install_public_key(params: SshInstallPublicKeyParams) -> Result<(),
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+async fn run_stream(
+    client: async_ssh2_tokio::Client,
+    cmd: String,
+    mut watchdog: StreamWatchdog,
+    max_line_bytes: Option<usize>,
+    outlet: StreamOutlet,
+    hub: StreamHub,
+    pool: SshConnectionPool,
+    pool_key: PoolKey,
+    stream_id: u64,
+) {
+    tokio::task::yield_now().await;
+    let (stdout_tx, mut stdout_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(16);
+    let (stderr_tx, mut stderr_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(16);
+    let (signal_tx, signal_rx) = tokio::sync::mpsc::channel::<russh::Sig>(2);
+
+    let mut out_pending = LineBuffer::new(max_line_bytes);
+    let mut err_pending = LineBuffer::new(max_line_bytes);
+
+    // Scoped so an abandoned command drops its channel senders before
+    // the drain loops below.
+    let exit = {
+        let exec_future = client.execute_io_with_exit(
+            &cmd,
+            stdout_tx,
+            Some(stderr_tx),
+            None,
+            false,
+            Some(signal_rx),
+        );
+        tokio::pin!(exec_future);
+        loop {
+            tokio::select! {
+                result = &mut exec_future => break Some(result),
+                Some(bytes) = stdout_rx.recv() => {
+                    watchdog.on_output();
+                    publish_lines(&outlet, false, &mut out_pending, &bytes);
+                }
+                Some(bytes) = stderr_rx.recv() => {
+                    watchdog.on_output();
+                    publish_lines(&outlet, true, &mut err_pending, &bytes);
+                }
+                _ = sleep_until_deadline(watchdog.deadline()) => {
+                    match watchdog.expire() {
+                        Some(sig) => {
+                            let _ = signal_tx.send(stream_signal(sig)).await;
+                        }
+                        None => break None,
+                    }
+                }
+            }
+        }
+    };
+
+    while let Some(bytes) = stdout_rx.recv().await {
+        publish_lines(&outlet, false, &mut out_pending, &bytes);
+    }
+    while let Some(bytes) = stderr_rx.recv().await {
+        publish_lines(&outlet, true, &mut err_pending, &bytes);
+    }
+
+    publish_pending(&outlet, false, &mut out_pending);
+    publish_pending(&outlet, true, &mut err_pending);
+
+    let report = match &exit {
+        Some(result) => stream_end(result),
+        None => StreamEnd::failed(StreamTermination::TimedOut, "timed out"),
+    }
+    .watched_by(&watchdog);
+    if let Some(Err(e)) = &exit
+        && SshConnectionPool::should_reconnect(e)
+    {
+        pool.remove(&pool_key).await;
+    }
+
+    // Unregister before publishing the exit so late `stream.subscribe` calls
+    // get an error instead of a receiver that never hears anything.
+    hub.finish(stream_id).await;
+    outlet.publish_exit(report);
+}
+
+fn publish_lines(outlet: &StreamOutlet, is_stderr: bool, pending: &mut LineBuffer, bytes: &[u8]) {
+    for chunk in pending.push(bytes) {
+        outlet.publish_line(is_stderr, chunk);
+    }
+}
+
+fn publish_pending(outlet: &StreamOutlet, is_stderr: bool, pending: &mut LineBuffer) {
+    if let Some(chunk) = pending.finish() {
+        outlet.publish_line(is_stderr, chunk);
+    }
+}
+
+async fn forward_stream_events(
+    subscription_id: u64,
+    feed: StreamFeed,
+    outbox: Outbox,
+    stats_interval: Duration,
+) {
+    let StreamFeed {
+        mut rx,
+        offset: mut next_offset,
+    } = feed;
+    // Lines skipped since the last received event; their size is known once
+    // the next event shows how far the stream got.
+    let mut lagged = 0;
+    let mut stats_tick = interval_at(Instant::now() + stats_interval, stats_interval);
+    stats_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(StreamEvent::Line(line)) => {
+                    if lagged > 0 {
+                        let bytes = line.offset.saturating_sub(next_offset);
+                        outbox.record_dropped(subscription_id, std::mem::take(&mut lagged), bytes).await;
+                    }
+                    next_offset = line.offset + line.line.len() as u64;
+                    outbox.push_stream_line(subscription_id, line).await;
+                }
+                Ok(StreamEvent::Exit { end, published }) => {
+                    if lagged > 0 {
+                        let bytes = published.saturating_sub(next_offset);
+                        outbox.record_dropped(subscription_id, lagged, bytes).await;
+                    }
+                    outbox.finish_stream_queue(subscription_id, end).await;
+                    break;
+                }
+                // A subscriber that falls this far behind loses the oldest lines
+                // rather than holding up the command or the other subscribers.
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    lagged += count;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    outbox.close_stream_queue(subscription_id).await;
+                    break;
+                }
+            },
+            _ = stats_tick.tick() => outbox.request_stream_stats(subscription_id).await,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_request(
     server_cfg: &ServerConfig,
     state: &DaemonState,
     streams: &ConnectionStreams,
+    transfers: &ConnectionTransfers,
     outbox: Outbox,
     req: RequestEnvelope,
 ) -> Result<(), ()> {
@@ -769,204 +4711,355 @@ async fn handle_request(
                     .await;
                 return Err(());
             }
-            if params.token != server_cfg.token {
-                let _ = outbox.send_response_err(id, "unauthorized").await;
-                return Err(());
+            if params.token != server_cfg.token {
+                let _ = outbox.send_response_err(id, "unauthorized").await;
+                return Err(());
+            }
+            outbox
+                .send_response_ok(id, HelloResult { protocol: server_cfg.protocol })
+                .await?;
+            Ok(())
+        }
+        "ssh.exec" => {
+            let params: SshExecParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match ssh_exec(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "ssh.start" => {
+            let params: SshStartParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
+            let (pool_key, client) =
+                match ssh_get_client(&state.pool, params.target.clone(), connect_timeout).await {
+                    Ok(v) => v,
+                    Err(e) => return outbox.send_response_err(id, e).await,
+                };
+
+            let stream_id = state
+                .next_stream_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let subscription_id = state
+                .next_subscription_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+            let watchdog = StreamWatchdog::new(
+                params
+                    .max_duration_ms
+                    .filter(|v| *v > 0)
+                    .map(Duration::from_millis),
+                params
+                    .idle_output_timeout_ms
+                    .filter(|v| *v > 0)
+                    .map(Duration::from_millis),
+            );
+
+            let outlet = StreamOutlet::new();
+            let feed = outlet.subscribe();
+            {
+                // Hold the hub lock across spawn so the task cannot finish (and
+                // unregister itself) before it has been registered.
+                let mut g = state.hub.streams.lock().await;
+                let task = tokio::spawn(run_stream(
+                    client,
+                    params.command,
+                    watchdog,
+                    params.max_line_bytes,
+                    outlet.clone(),
+                    state.hub.clone(),
+                    state.pool.clone(),
+                    pool_key,
+                    stream_id,
+                ));
+                g.insert(
+                    stream_id,
+                    SharedStream {
+                        outlet,
+                        subscribers: 1,
+                        task,
+                    },
+                );
+            }
+            streams
+                .attach(
+                    stream_id,
+                    subscription_id,
+                    feed,
+                    outbox.clone(),
+                    &params.queue,
+                )
+                .await;
+
+            outbox
+                .send_response_ok(
+                    id,
+                    SshStartResult {
+                        stream_id,
+                        subscription_id,
+                    },
+                )
+                .await
+        }
+        "stream.subscribe" => {
+            let params: StreamSubscribeParams =
+                serde_json::from_value(req.params).map_err(|_| ())?;
+            let Some(feed) = state.hub.subscribe(params.stream_id).await else {
+                return outbox.send_response_err(id, "unknown stream").await;
+            };
+            let subscription_id = state
+                .next_subscription_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            streams
+                .attach(
+                    params.stream_id,
+                    subscription_id,
+                    feed,
+                    outbox.clone(),
+                    &params.queue,
+                )
+                .await;
+            outbox
+                .send_response_ok(
+                    id,
+                    StreamSubscribeResult {
+                        stream_id: params.stream_id,
+                        subscription_id,
+                    },
+                )
+                .await
+        }
+        "stream.unsubscribe" => {
+            let params: StreamUnsubscribeParams =
+                serde_json::from_value(req.params).map_err(|_| ())?;
+            let detached = streams
+                .detach(|subscription_id, _| subscription_id == params.subscription_id)
+                .await;
+            let mut remaining_subscribers = 0;
+            for (_, stream_id) in detached {
+                remaining_subscribers = state.hub.leave(stream_id).await;
+            }
+            outbox
+                .send_response_ok(
+                    id,
+                    StreamUnsubscribeResult {
+                        remaining_subscribers,
+                    },
+                )
+                .await
+        }
+        "ssh.cancel" => {
+            let params: SshCancelParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            let detached = streams
+                .detach(|_, stream_id| stream_id == params.stream_id)
+                .await;
+            for (subscription_id, stream_id) in detached {
+                state.hub.leave(stream_id).await;
+                let _ = outbox
+                    .send_json(&EventEnvelope::stream_failed(
+                        stream_id,
+                        subscription_id,
+                        StreamTermination::Cancelled,
+                        "cancelled",
+                    ))
+                    .await;
+            }
+            outbox
+                .send_response_ok(id, serde_json::json!({"cancelled": true}))
+                .await
+        }
+        "ssh.reset_all" => {
+            let params: SshResetAllParams =
+                serde_json::from_value(req.params).unwrap_or(SshResetAllParams { reason: None });
+            let reason = params.reason.unwrap_or_else(|| "reset".to_owned());
+            let detached = streams.detach(|_, _| true).await;
+            let cancelled_streams = detached.len();
+            for (subscription_id, stream_id) in detached {
+                state.hub.leave(stream_id).await;
+                let _ = outbox
+                    .send_json(&EventEnvelope::stream_failed(
+                        stream_id,
+                        subscription_id,
+                        StreamTermination::Cancelled,
+                        &reason,
+                    ))
+                    .await;
+            }
+            let cleared_connections = state.pool.clear_all().await;
+            outbox
+                .send_response_ok(
+                    id,
+                    SshResetAllResult {
+                        cleared_connections,
+                        cancelled_streams,
+                    },
+                )
+                .await
+        }
+        "ssh.write_file" => {
+            let params: SshWriteFileParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match ssh_write_file(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "fs.read" => {
+            let params: FsReadParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match fs_read(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "fs.list" => {
+            let params: FsListParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match fs_list(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "ports.list" => {
+            let params: PortsListParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match ports_list(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "schema.prepare" => {
+            let params: SchemaPrepareParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match schema_prepare(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "job.start" => {
+            let params: JobStartParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match job_start(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "job.stop" => {
+            let params: JobRefParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match job_stop(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "job.status" => {
+            let params: JobRefParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match job_status(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "git.auto_commit" => {
+            let params: GitAutoCommitParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match git_auto_commit(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "log.fetch" => {
+            let params: LogFetchParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match log_fetch(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "sftp.upload" => {
+            let params: SftpUploadParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match start_transfer(state, transfers, outbox.clone(), params.into()).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "sftp.download" => {
+            let params: SftpDownloadParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match start_transfer(state, transfers, outbox.clone(), params.into()).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "sync.run" => {
+            let params: SyncParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match start_sync(state, transfers, outbox.clone(), params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "forward.local" => {
+            let params: ForwardLocalParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match forward_local(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
             }
-            outbox
-                .send_response_ok(id, HelloResult { protocol: server_cfg.protocol })
-                .await?;
-            Ok(())
         }
-        "ssh.exec" => {
-            let params: SshExecParams = serde_json::from_value(req.params).map_err(|e| {
-                let _ = e;
-                ()
-            })?;
-            match ssh_exec(state, params).await {
+        "forward.remote" => {
+            let params: ForwardRemoteParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match forward_remote(state, params).await {
                 Ok(res) => outbox.send_response_ok(id, res).await,
                 Err(e) => outbox.send_response_err(id, e).await,
             }
         }
-        "ssh.start" => {
-            let params: SshStartParams = serde_json::from_value(req.params).map_err(|_| ())?;
-            let connect_timeout = Duration::from_millis(params.connect_timeout_ms.max(1));
-            let (pool_key, client) =
-                ssh_get_client(&state.pool, params.target.clone(), connect_timeout)
-                    .await
-                    .map_err(|e| {
-                        let _ = outbox.send_response_err(id, e);
-                        ()
-                    })?;
-
-            let stream_id = state
-                .next_stream_id
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-            let cmd = params.command.clone();
-            let outbox2 = outbox.clone();
-            let streams2 = streams.clone();
-            let pool = state.pool.clone();
-            let handle = tokio::spawn(async move {
-                tokio::task::yield_now().await;
-                let (stdout_tx, mut stdout_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(16);
-                let (stderr_tx, mut stderr_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(16);
-                let exec_future = client.execute_io(&cmd, stdout_tx, Some(stderr_tx), None, false, None);
-
-                let mut out_pending = String::new();
-                let mut err_pending = String::new();
-
-                tokio::pin!(exec_future);
-                let exit_status = loop {
-                    tokio::select! {
-                        result = &mut exec_future => break result,
-                        Some(bytes) = stdout_rx.recv() => {
-                            let chunk = String::from_utf8_lossy(&bytes);
-                            out_pending.push_str(&chunk);
-                            while let Some(idx) = out_pending.find('\n') {
-                                let line = out_pending[..idx].trim_end_matches('\r').to_owned();
-                                out_pending.drain(..idx + 1);
-                                let _ = outbox2.send_json(&EventEnvelope::StreamLine {
-                                    stream_id,
-                                    is_stderr: false,
-                                    line: &line,
-                                }).await;
-                            }
-                        }
-                        Some(bytes) = stderr_rx.recv() => {
-                            let chunk = String::from_utf8_lossy(&bytes);
-                            err_pending.push_str(&chunk);
-                            while let Some(idx) = err_pending.find('\n') {
-                                let line = err_pending[..idx].trim_end_matches('\r').to_owned();
-                                err_pending.drain(..idx + 1);
-                                let _ = outbox2.send_json(&EventEnvelope::StreamLine {
-                                    stream_id,
-                                    is_stderr: true,
-                                    line: &line,
-                                }).await;
-                            }
-                        }
-                    }
-                };
-
-                while let Some(bytes) = stdout_rx.recv().await {
-                    let chunk = String::from_utf8_lossy(&bytes);
-                    out_pending.push_str(&chunk);
-                    while let Some(idx) = out_pending.find('\n') {
-                        let line = out_pending[..idx].trim_end_matches('\r').to_owned();
-                        out_pending.drain(..idx + 1);
-                        let _ = outbox2.send_json(&EventEnvelope::StreamLine {
-                            stream_id,
-                            is_stderr: false,
-                            line: &line,
-                        }).await;
-                    }
-                }
-                while let Some(bytes) = stderr_rx.recv().await {
-                    let chunk = String::from_utf8_lossy(&bytes);
-                    err_pending.push_str(&chunk);
-                    while let Some(idx) = err_pending.find('\n') {
-                        let line = err_pending[..idx].trim_end_matches('\r').to_owned();
-                        err_pending.drain(..idx + 1);
-                        let _ = outbox2.send_json(&EventEnvelope::StreamLine {
-                            stream_id,
-                            is_stderr: true,
-                            line: &line,
-                        }).await;
-                    }
-                }
-
-                let pending = out_pending.trim().to_owned();
-                out_pending.clear();
-                if !pending.is_empty() {
-                    let _ = outbox2.send_json(&EventEnvelope::StreamLine {
-                        stream_id,
-                        is_stderr: false,
-                        line: &pending,
-                    }).await;
-                }
-                let pending = err_pending.trim().to_owned();
-                err_pending.clear();
-                if !pending.is_empty() {
-                    let _ = outbox2.send_json(&EventEnvelope::StreamLine {
-                        stream_id,
-                        is_stderr: true,
-                        line: &pending,
-                    }).await;
-                }
-
-                match exit_status {
-                    Ok(code) => {
-                        let _ = outbox2.send_json(&EventEnvelope::StreamExit {
-                            stream_id,
-                            exit_status: i32::try_from(code).unwrap_or(-1),
-                            error: None,
-                        }).await;
-                    }
-                    Err(e) => {
-                        if SshConnectionPool::should_reconnect(&e) {
-                            pool.remove(&pool_key).await;
-                        }
-                        let msg = e.to_string();
-                        let _ = outbox2.send_json(&EventEnvelope::StreamExit {
-                            stream_id,
-                            exit_status: -1,
-                            error: Some(&msg),
-                        }).await;
-                    }
-                }
-
-                streams2.tasks.lock().await.remove(&stream_id);
-            });
-
-            streams.tasks.lock().await.insert(stream_id, handle);
+        "forward.socks" => {
+            let params: ForwardSocksParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            match forward_socks(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
+                Err(e) => outbox.send_response_err(id, e).await,
+            }
+        }
+        "forward.list" => {
+            let forwards = state.forwards.list().await;
             outbox
-                .send_response_ok(id, SshStartResult { stream_id })
+                .send_response_ok(id, ForwardListResult { forwards })
                 .await
         }
-        "ssh.cancel" => {
-            let params: SshCancelParams = serde_json::from_value(req.params).map_err(|_| ())?;
-            if let Some(handle) = streams.tasks.lock().await.remove(&params.stream_id) {
-                handle.abort();
-                let _ = outbox.send_json(&EventEnvelope::StreamExit {
-                    stream_id: params.stream_id,
-                    exit_status: -1,
-                    error: Some("cancelled"),
-                }).await;
+        "forward.close" => {
+            let params: ForwardCloseParams = serde_json::from_value(req.params).map_err(|_| ())?;
+            if !state.forwards.close(params.forward_id).await {
+                return outbox.send_response_err(id, "unknown forward").await;
             }
-            outbox.send_response_ok(id, serde_json::json!({"cancelled": true})).await
+            outbox
+                .send_response_ok(id, ForwardCloseResult { closed: true })
+                .await
         }
-        "ssh.reset_all" => {
-            let params: SshResetAllParams = serde_json::from_value(req.params).unwrap_or(SshResetAllParams { reason: None });
-            let reason = params.reason.unwrap_or_else(|| "reset".to_owned());
-            let mut g = streams.tasks.lock().await;
-            let cancelled_streams = g.len();
-            for (stream_id, handle) in g.drain() {
-                handle.abort();
-                let _ = outbox.send_json(&EventEnvelope::StreamExit {
-                    stream_id,
-                    exit_status: -1,
-                    error: Some(&reason),
-                }).await;
-            }
-            let cleared_connections = state.pool.clear_all().await;
+        "transfer.journal" => {
+            let entries = state
+                .journals
+                .list()
+                .await
+                .into_iter()
+                .map(TransferJournalEntry::from)
+                .collect();
             outbox
-                .send_response_ok(
-                    id,
-                    SshResetAllResult {
-                        cleared_connections,
-                        cancelled_streams,
-                    },
-                )
+                .send_response_ok(id, TransferJournalResult { entries })
                 .await
         }
-        "ssh.write_file" => {
-            let params: SshWriteFileParams = serde_json::from_value(req.params).map_err(|_| ())?;
-            match ssh_write_file(state, params).await {
-                Ok(()) => outbox.send_response_ok(id, SshWriteFileResult {}).await,
+        "transfer.discard" => {
+            let params: TransferDiscardParams =
+                serde_json::from_value(req.params).map_err(|_| ())?;
+            match transfer_discard(state, params).await {
+                Ok(res) => outbox.send_response_ok(id, res).await,
                 Err(e) => outbox.send_response_err(id, e).await,
             }
         }
+        "transfer.cancel" => {
+            let params: TransferCancelParams =
+                serde_json::from_value(req.params).map_err(|_| ())?;
+            let cancelled = transfers
+                .cancel(|transfer_id| transfer_id == params.transfer_id)
+                .await;
+            if cancelled.is_empty() {
+                return outbox.send_response_err(id, "unknown transfer").await;
+            }
+            for transfer_id in cancelled {
+                let _ = outbox
+                    .send_json(&EventEnvelope::transfer_cancelled(transfer_id, "cancelled"))
+                    .await;
+            }
+            outbox
+                .send_response_ok(id, TransferCancelResult { cancelled: true })
+                .await
+        }
         "ssh.generate_key" => {
             let params: SshGenerateKeyParams = serde_json::from_value(req.params).map_err(|_| ())?;
             match ssh_generate_key(params) {
@@ -1002,9 +5095,30 @@ async fn handle_connection(
     let (reader, mut writer) = stream.into_split();
     let mut lines = BufReader::new(reader).lines();
     let (tx, mut rx) = mpsc::channel::<String>(256);
+    let outbox = Outbox::new(tx);
 
+    let writer_outbox = outbox.clone();
     let writer_task: JoinHandle<()> = tokio::spawn(async move {
-        while let Some(line) = rx.recv().await {
+        loop {
+            // Responses and control events first, then one stream event.
+            let line = match rx.try_recv() {
+                Ok(line) => line,
+                Err(mpsc::error::TryRecvError::Disconnected) => break,
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    match writer_outbox.next_stream_message().await {
+                        Some(line) => line,
+                        None => {
+                            tokio::select! {
+                                line = rx.recv() => match line {
+                                    Some(line) => line,
+                                    None => break,
+                                },
+                                _ = writer_outbox.ready.notified() => continue,
+                            }
+                        }
+                    }
+                }
+            };
             if writer.write_all(line.as_bytes()).await.is_err() {
                 break;
             }
@@ -1017,8 +5131,8 @@ async fn handle_connection(
         }
     });
 
-    let outbox = Outbox { tx };
     let streams = ConnectionStreams::default();
+    let transfers = ConnectionTransfers::default();
     let mut authed = false;
 
     while let Some(line) = lines.next_line().await? {
@@ -1039,9 +5153,16 @@ async fn handle_connection(
                 let _ = outbox.send_response_err(req.id, "unauthorized").await;
                 break;
             }
-            if handle_request(&server_cfg, &state, &streams, outbox.clone(), req)
-                .await
-                .is_err()
+            if handle_request(
+                &server_cfg,
+                &state,
+                &streams,
+                &transfers,
+                outbox.clone(),
+                req,
+            )
+            .await
+            .is_err()
             {
                 break;
             }
@@ -1049,18 +5170,34 @@ async fn handle_connection(
             continue;
         }
 
-        let _ = handle_request(&server_cfg, &state, &streams, outbox.clone(), req).await;
+        let _ = handle_request(
+            &server_cfg,
+            &state,
+            &streams,
+            &transfers,
+            outbox.clone(),
+            req,
+        )
+        .await;
     }
 
-    let mut g = streams.tasks.lock().await;
-    for (stream_id, handle) in g.drain() {
-        handle.abort();
+    for (subscription_id, stream_id) in streams.detach(|_, _| true).await {
+        state.hub.leave(stream_id).await;
         let _ = outbox
-            .send_json(&EventEnvelope::StreamExit {
+            .send_json(&EventEnvelope::stream_failed(
                 stream_id,
-                exit_status: -1,
-                error: Some("connection closed"),
-            })
+                subscription_id,
+                StreamTermination::Cancelled,
+                "connection closed",
+            ))
+            .await;
+    }
+    for transfer_id in transfers.cancel(|_| true).await {
+        let _ = outbox
+            .send_json(&EventEnvelope::transfer_cancelled(
+                transfer_id,
+                "connection closed",
+            ))
             .await;
     }
 
@@ -1081,7 +5218,10 @@ async fn main() -> io::Result<()> {
     write_state_file(&state_file, actual_port, &token, protocol)?;
 
     let server_cfg = ServerConfig { token, protocol };
-    let state = DaemonState::new();
+    let journal_dir = state_file
+        .parent()
+        .map_or_else(|| PathBuf::from("transfers"), |dir| dir.join("transfers"));
+    let state = DaemonState::new(JournalStore::new(journal_dir));
 
     loop {
         let (stream, _) = listener.accept().await?;
@@ -1092,3 +5232,738 @@ async fn main() -> io::Result<()> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use rand_core::OsRng;
+    use russh::server::{Auth, Session};
+    use tokio::net::TcpListener;
+    use tokio::time::{Instant, sleep};
+
+    use field_exec_core::lines::{LineChunk, LinePart};
+    use tokio::sync::{mpsc, oneshot};
+
+    use field_exec_core::stream::StreamEnd;
+    use field_exec_core::transfer::{
+        MIN_CHUNK_BYTES, ResumeError, TransferDirection, TransferJournal,
+    };
+    use russh::server::Msg;
+    use russh::{Channel, ChannelId, CryptoVec};
+
+    use super::{
+        ConnectionStreams, ConnectionTransfers, DaemonState, EventEnvelope, ForwardRemoteParams,
+        JournalStore, Outbox, OutputLine, QueuePolicy, RequestEnvelope, ResumableRun,
+        STREAM_EVENT_CAPACITY, ServerConfig, SharedStream, SshTarget, SshWriteFileParams,
+        StreamHub, StreamOutlet, StreamQueueOptions, TransferProgress, forward_remote,
+        forward_stream_events, handle_request, ssh_get_client, ssh_write_file,
+    };
+
+    /// Runs each exec request with the local `/bin/sh` once its stdin has
+    /// ended, after telling `on_exec` the command.
+    struct ExecServer {
+        on_exec: Arc<dyn Fn(&str) + Send + Sync>,
+        pending: HashMap<ChannelId, (String, Vec<u8>)>,
+    }
+
+    impl russh::server::Handler for ExecServer {
+        type Error = russh::Error;
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<Auth, Self::Error> {
+            Ok(Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _: Channel<Msg>,
+            _: &mut Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        async fn exec_request(
+            &mut self,
+            channel: ChannelId,
+            command: &[u8],
+            session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            let command = String::from_utf8_lossy(command).into_owned();
+            self.pending.insert(channel, (command, Vec::new()));
+            session.channel_success(channel)
+        }
+
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            _: &mut Session,
+        ) -> Result<(), Self::Error> {
+            if let Some((_, input)) = self.pending.get_mut(&channel) {
+                input.extend_from_slice(data);
+            }
+            Ok(())
+        }
+
+        async fn channel_eof(
+            &mut self,
+            channel: ChannelId,
+            session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            let Some((command, input)) = self.pending.remove(&channel) else {
+                return Ok(());
+            };
+            (self.on_exec)(&command);
+            let handle = session.handle();
+            tokio::spawn(async move {
+                let output = tokio::task::spawn_blocking(move || run_sh(&command, &input)).await;
+                let (code, stdout, stderr) = output.unwrap_or((u32::MAX, Vec::new(), Vec::new()));
+                let _ = handle.data(channel, CryptoVec::from_slice(&stdout)).await;
+                let _ = handle
+                    .extended_data(channel, 1, CryptoVec::from_slice(&stderr))
+                    .await;
+                let _ = handle.exit_status_request(channel, code).await;
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+            });
+            Ok(())
+        }
+    }
+
+    fn run_sh(command: &str, input: &[u8]) -> (u32, Vec<u8>, Vec<u8>) {
+        let child = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn();
+        let Ok(mut child) = child else {
+            return (u32::MAX, Vec::new(), Vec::new());
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            let input = input.to_vec();
+            std::thread::spawn(move || stdin.write_all(&input));
+        }
+        match child.wait_with_output() {
+            Ok(out) => (
+                out.status.code().map_or(u32::MAX, |c| c as u32),
+                out.stdout,
+                out.stderr,
+            ),
+            Err(_) => (u32::MAX, Vec::new(), Vec::new()),
+        }
+    }
+
+    /// Serves one connection with `handler` and returns a target for it.
+    async fn serve<H>(handler: H) -> SshTarget
+    where
+        H: russh::server::Handler + Send + 'static,
+    {
+        let Ok(listener) = TcpListener::bind(("127.0.0.1", 0)).await else {
+            panic!("bind test server");
+        };
+        let Ok(addr) = listener.local_addr() else {
+            panic!("test server address");
+        };
+        let Ok(key) = russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)
+        else {
+            panic!("host key");
+        };
+        let config = Arc::new(russh::server::Config {
+            keys: vec![key],
+            ..Default::default()
+        });
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.ok()?;
+            let session = russh::server::run_stream(config, tcp, handler).await.ok()?;
+            session.await.ok()
+        });
+        let target = serde_json::json!({
+            "host": "127.0.0.1",
+            "port": addr.port(),
+            "username": "u",
+            "auth": {"kind": "password", "password": "x"},
+        });
+        let Ok(target) = serde_json::from_value(target) else {
+            panic!("target");
+        };
+        target
+    }
+
+    /// Accepts any password and every `tcpip-forward` request.
+    struct ForwardServer;
+
+    impl russh::server::Handler for ForwardServer {
+        type Error = russh::Error;
+
+        async fn auth_password(&mut self, _: &str, _: &str) -> Result<Auth, Self::Error> {
+            Ok(Auth::Accept)
+        }
+
+        async fn tcpip_forward(
+            &mut self,
+            _: &str,
+            port: &mut u32,
+            _: &mut Session,
+        ) -> Result<bool, Self::Error> {
+            if *port == 0 {
+                *port = 40_000;
+            }
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn remote_forward_goes_away_with_its_connection() {
+        let Ok(listener) = TcpListener::bind(("127.0.0.1", 0)).await else {
+            panic!("bind test server");
+        };
+        let Ok(addr) = listener.local_addr() else {
+            panic!("test server address");
+        };
+        let Ok(key) = russh::keys::PrivateKey::random(&mut OsRng, russh::keys::Algorithm::Ed25519)
+        else {
+            panic!("host key");
+        };
+        let config = Arc::new(russh::server::Config {
+            keys: vec![key],
+            ..Default::default()
+        });
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.ok()?;
+            russh::server::run_stream(config, tcp, ForwardServer)
+                .await
+                .ok()
+        });
+
+        let journals =
+            std::env::temp_dir().join(format!("field_execd_test_{}", std::process::id()));
+        let state = DaemonState::new(JournalStore::new(journals));
+        let params = serde_json::json!({
+            "target": {
+                "host": "127.0.0.1",
+                "port": addr.port(),
+                "username": "u",
+                "auth": {"kind": "password", "password": "x"},
+            },
+            "remote_bind_port": 0,
+            "local_host": "127.0.0.1",
+            "local_port": 9,
+            "connect_timeout_ms": 5000,
+        });
+        let Ok(params) = serde_json::from_value::<ForwardRemoteParams>(params) else {
+            panic!("params");
+        };
+        let result = forward_remote(&state, params).await;
+        assert_eq!(result.map(|r| r.remote_port), Ok(40_000));
+        assert_eq!(state.forwards.list().await.len(), 1);
+
+        let Ok(Some(session)) = server.await else {
+            panic!("test server session");
+        };
+        let bye = session
+            .handle()
+            .disconnect(
+                russh::Disconnect::ByApplication,
+                "bye".to_owned(),
+                String::new(),
+            )
+            .await;
+        assert!(bye.is_ok());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !state.forwards.list().await.is_empty() {
+            assert!(Instant::now() < deadline, "forward outlived its connection");
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    fn line(is_stderr: bool, text: &str, part: Option<(u32, bool)>) -> OutputLine {
+        OutputLine {
+            is_stderr,
+            line: text.into(),
+            part: part.map(|(index, is_final)| LinePart { index, is_final }),
+            offset: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_whole_lines() {
+        let (tx, _rx) = mpsc::channel(1);
+        let outbox = Outbox::new(tx);
+        let options = StreamQueueOptions {
+            queue_policy: Some(QueuePolicy::DropOldest),
+            queue_capacity: Some(3),
+            stats_interval_ms: None,
+        };
+        outbox.open_stream_queue(1, 1, &options).await;
+        for out in [
+            line(false, "aa", Some((0, false))),
+            line(true, "err", None),
+            line(false, "aa", Some((1, false))),
+            // Full: evicts both chunks of the first line, whose last chunk
+            // is then dropped on arrival.
+            line(false, "c", None),
+            line(false, "a", Some((2, true))),
+            line(false, "d", None),
+        ] {
+            outbox.push_stream_line(1, out).await;
+        }
+        let g = outbox.queues.lock().await;
+        let Some(q) = g.by_subscription.get(&1) else {
+            panic!("queue closed");
+        };
+        let queued: Vec<&str> = q.lines.iter().map(|out| &*out.line).collect();
+        assert_eq!(queued, ["err", "c", "d"]);
+        assert_eq!((q.stats.lines_dropped, q.stats.bytes_dropped), (3, 5));
+        assert_eq!(q.dropped_unreported, 3);
+    }
+
+    #[test]
+    fn journal_claim_is_exclusive_until_dropped() {
+        let journals = JournalStore::new(std::env::temp_dir());
+        let Some(claim) = journals.claim("k") else {
+            panic!("first claim refused");
+        };
+        assert!(journals.claim("k").is_none());
+        assert!(journals.claim("other").is_some());
+        drop(claim);
+        assert!(journals.claim("k").is_some());
+    }
+
+    #[test]
+    fn signaled_stream_exits_report_the_signal_and_core_dump() {
+        let end = StreamEnd::signaled("SEGV".to_owned(), true, Some("segfault".to_owned()));
+        let event = serde_json::to_value(EventEnvelope::stream_exit(7, 9, &end)).ok();
+        assert_eq!(
+            event,
+            Some(serde_json::json!({
+                "type": "stream_exit",
+                "stream_id": 7,
+                "subscription_id": 9,
+                "exit_status": -1,
+                "exit_signal": "SEGV",
+                "core_dumped": true,
+                "signal_message": "segfault",
+                "termination": "signaled",
+                "error": "killed by signal SEGV",
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_fails_when_the_source_changes_between_chunks() {
+        // Edits the source when the first or the last chunk goes out; the
+        // upload stops before sending the next chunk or finishing.
+        for edit_at in [0, 2] {
+            let dir = std::env::temp_dir().join(format!(
+                "field_execd_upload_{edit_at}_{}",
+                std::process::id()
+            ));
+            assert!(std::fs::create_dir_all(&dir).is_ok());
+            let source = dir.join("source.bin");
+            let dest = dir.join("dest.bin");
+            let chunk = usize::try_from(MIN_CHUNK_BYTES).unwrap_or(usize::MAX);
+            assert!(std::fs::write(&source, vec![7u8; 2 * chunk + 10]).is_ok());
+
+            let execs = Arc::new(AtomicUsize::new(0));
+            let edited = source.clone();
+            let on_exec = move |_: &str| {
+                if execs.fetch_add(1, Ordering::SeqCst) == edit_at {
+                    let _ = std::fs::write(&edited, vec![8u8; 2 * chunk + 11]);
+                }
+            };
+            let target = serve(ExecServer {
+                on_exec: Arc::new(on_exec),
+                pending: HashMap::new(),
+            })
+            .await;
+
+            let state = DaemonState::new(JournalStore::new(dir.join("journals")));
+            let connect_timeout = Duration::from_secs(5);
+            let Ok(connection) = ssh_get_client(&state.pool, target.clone(), connect_timeout).await
+            else {
+                panic!("connect to test server");
+            };
+            let (tx, _rx) = mpsc::channel(64);
+            let mut progress = TransferProgress::new(1, Outbox::new(tx));
+            let journal = TransferJournal::new(
+                TransferDirection::Upload,
+                &target.host,
+                target.port,
+                &target.username,
+                source.to_string_lossy(),
+                dest.to_string_lossy(),
+            )
+            .chunk_bytes(Some(MIN_CHUNK_BYTES));
+            let mut run = ResumableRun {
+                state: &state,
+                target,
+                connect_timeout,
+                journal,
+                progress: &mut progress,
+                resumed_from: None,
+            };
+            let result = run.run(connection).await;
+            assert_eq!(
+                result,
+                Err(ResumeError::SourceChanged.to_string()),
+                "edit at {edit_at}"
+            );
+            assert!(!dest.exists(), "edit at {edit_at}");
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_file_makes_its_directory_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("field_execd_write_{}", std::process::id()));
+        let dest = dir.join("conf/app.env");
+        assert!(std::fs::create_dir_all(dir.join("conf")).is_ok());
+        let shared = std::fs::Permissions::from_mode(0o755);
+        assert!(std::fs::set_permissions(dir.join("conf"), shared).is_ok());
+        let target = serve(ExecServer {
+            on_exec: Arc::new(|_: &str| {}),
+            pending: HashMap::new(),
+        })
+        .await;
+        let state = DaemonState::new(JournalStore::new(dir.join("journals")));
+        let contents = "x".repeat(300 * 1024);
+        let params = SshWriteFileParams {
+            target,
+            remote_path: dest.to_string_lossy().into_owned(),
+            contents: Some(contents.clone()),
+            contents_base64: None,
+            mode: None,
+            create_only: false,
+            if_matches_sha256: None,
+            connect_timeout_ms: 5_000,
+            command_timeout_ms: 10_000,
+        };
+        let result = ssh_write_file(&state, params).await;
+        assert_eq!(result.map(|r| r.bytes), Ok(300 * 1024));
+        assert_eq!(std::fs::read_to_string(&dest).ok(), Some(contents));
+        let modes = [dest.parent(), Some(dest.as_path())].map(|p| {
+            p.and_then(|p| std::fs::metadata(p).ok())
+                .map(|m| m.permissions().mode() & 0o777)
+        });
+        assert_eq!(modes, [Some(0o700), Some(0o600)]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Registers a stream whose task does nothing; `alive` closes once the
+    /// hub aborts it.
+    async fn fake_stream(hub: &StreamHub, stream_id: u64) -> (StreamOutlet, oneshot::Receiver<()>) {
+        let (alive_tx, alive) = oneshot::channel::<()>();
+        let outlet = StreamOutlet::new();
+        let task = tokio::spawn(async move {
+            let _alive = alive_tx;
+            std::future::pending::<()>().await;
+        });
+        hub.streams.lock().await.insert(
+            stream_id,
+            SharedStream {
+                outlet: outlet.clone(),
+                subscribers: 0,
+                task,
+            },
+        );
+        (outlet, alive)
+    }
+
+    fn chunk(text: &str) -> LineChunk {
+        LineChunk {
+            text: text.to_owned(),
+            part: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_last_subscriber_stops_the_stream() {
+        let hub = StreamHub::default();
+        let (_outlet, mut alive) = fake_stream(&hub, 1).await;
+        assert!(hub.subscribe(1).await.is_some());
+        assert!(hub.subscribe(1).await.is_some());
+
+        assert_eq!(hub.leave(1).await, 1);
+        tokio::task::yield_now().await;
+        assert_eq!(alive.try_recv(), Err(oneshot::error::TryRecvError::Empty));
+
+        assert_eq!(hub.leave(1).await, 0);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), alive)
+                .await
+                .is_ok()
+        );
+        assert!(hub.subscribe(1).await.is_none());
+        assert_eq!(hub.leave(1).await, 0);
+    }
+
+    #[tokio::test]
+    async fn ssh_cancel_detaches_only_this_connections_subscriptions() {
+        let state = DaemonState::new(JournalStore::new(std::env::temp_dir()));
+        let (outlet, mut alive) = fake_stream(&state.hub, 1).await;
+        let options = StreamQueueOptions::default();
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, _rx_b) = mpsc::channel(8);
+        let (outbox_a, outbox_b) = (Outbox::new(tx_a), Outbox::new(tx_b));
+        let (streams_a, streams_b) = (ConnectionStreams::default(), ConnectionStreams::default());
+        for (subscription_id, streams, outbox) in
+            [(1, &streams_a, &outbox_a), (2, &streams_b, &outbox_b)]
+        {
+            let Some(feed) = state.hub.subscribe(1).await else {
+                panic!("stream registered");
+            };
+            streams
+                .attach(1, subscription_id, feed, outbox.clone(), &options)
+                .await;
+        }
+
+        let server_cfg = ServerConfig {
+            token: String::new(),
+            protocol: 1,
+        };
+        let cancel = RequestEnvelope {
+            id: 5,
+            method: "ssh.cancel".to_owned(),
+            params: serde_json::json!({"stream_id": 1}),
+        };
+        let transfers = ConnectionTransfers::default();
+        let handled = handle_request(
+            &server_cfg,
+            &state,
+            &streams_a,
+            &transfers,
+            outbox_a.clone(),
+            cancel,
+        )
+        .await;
+        assert!(handled.is_ok());
+        let sent: Vec<serde_json::Value> = [rx_a.recv().await, rx_a.recv().await]
+            .into_iter()
+            .flatten()
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            (&sent[0]["type"], &sent[0]["termination"]),
+            (
+                &serde_json::json!("stream_exit"),
+                &serde_json::json!("cancelled")
+            )
+        );
+        assert_eq!(sent[1]["id"], 5);
+
+        // The other connection keeps watching the still-running command.
+        assert_eq!(alive.try_recv(), Err(oneshot::error::TryRecvError::Empty));
+        outlet.publish_line(false, chunk("still here"));
+        let delivered = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(msg) = outbox_b.next_stream_message().await {
+                    return msg;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(delivered.is_ok_and(|msg| msg.contains("still here")));
+        assert!(outbox_a.next_stream_message().await.is_none());
+
+        assert_eq!(state.hub.leave(1).await, 0);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), alive)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_count_the_lines_and_bytes_they_skip() {
+        let outlet = StreamOutlet::new();
+        let feed = outlet.subscribe();
+        // Overflow the broadcast buffer before the subscriber reads anything.
+        let lines = STREAM_EVENT_CAPACITY + 10;
+        for i in 0..lines {
+            outlet.publish_line(i % 2 == 1, chunk(&format!("line {i:04}")));
+        }
+        outlet.publish_exit(StreamEnd::exited(0));
+
+        let (tx, _rx) = mpsc::channel(1);
+        let outbox = Outbox::new(tx);
+        let options = StreamQueueOptions {
+            queue_policy: None,
+            queue_capacity: Some(2 * STREAM_EVENT_CAPACITY),
+            stats_interval_ms: None,
+        };
+        outbox.open_stream_queue(1, 1, &options).await;
+        forward_stream_events(1, feed, outbox.clone(), Duration::from_secs(60)).await;
+
+        let g = outbox.queues.lock().await;
+        let Some(q) = g.by_subscription.get(&1) else {
+            panic!("queue closed");
+        };
+        let queued = q.lines.len() as u64;
+        let queued_bytes: u64 = q.lines.iter().map(|out| out.line.len() as u64).sum();
+        assert!(q.stats.lines_dropped > 0);
+        assert_eq!(q.stats.lines_dropped + queued, lines as u64);
+        assert_eq!(q.stats.bytes_dropped + queued_bytes, 9 * lines as u64);
+        assert_eq!(q.dropped_unreported, q.stats.lines_dropped);
+        assert!(q.exit.is_some());
+    }
+
+    async fn open_queue(policy: QueuePolicy, capacity: usize) -> Outbox {
+        let (tx, _rx) = mpsc::channel(1);
+        let outbox = Outbox::new(tx);
+        let options = StreamQueueOptions {
+            queue_policy: Some(policy),
+            queue_capacity: Some(capacity),
+            stats_interval_ms: None,
+        };
+        outbox.open_stream_queue(1, 1, &options).await;
+        outbox
+    }
+
+    /// Every event the writer would send now, as `(type, payload)`.
+    async fn drain(outbox: &Outbox) -> Vec<(String, serde_json::Value)> {
+        let mut events = Vec::new();
+        while let Some(msg) = outbox.next_stream_message().await {
+            let Ok(mut event) = serde_json::from_str::<serde_json::Value>(&msg) else {
+                panic!("event is JSON: {msg}");
+            };
+            let kind = event["type"].as_str().unwrap_or_default().to_owned();
+            if let Some(event) = event.as_object_mut() {
+                for key in ["type", "stream_id", "subscription_id"] {
+                    event.remove(key);
+                }
+            }
+            events.push((kind, event));
+        }
+        events
+    }
+
+    fn event(kind: &str, payload: serde_json::Value) -> (String, serde_json::Value) {
+        (kind.to_owned(), payload)
+    }
+
+    #[tokio::test]
+    async fn block_holds_the_stream_until_the_writer_catches_up() {
+        let outbox = open_queue(QueuePolicy::Block, 2).await;
+        outbox.push_stream_line(1, line(false, "a", None)).await;
+        outbox.push_stream_line(1, line(true, "bb", None)).await;
+        let pusher = outbox.clone();
+        let mut third =
+            tokio::spawn(async move { pusher.push_stream_line(1, line(false, "c", None)).await });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut third)
+                .await
+                .is_err()
+        );
+
+        let first = outbox.next_stream_message().await;
+        assert!(first.is_some_and(|msg| msg.contains(r#""line":"a""#)));
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), third)
+                .await
+                .is_ok()
+        );
+        outbox
+            .finish_stream_queue(1, Arc::new(StreamEnd::exited(0)))
+            .await;
+        let stats = serde_json::json!({
+            "queued": 0,
+            "lines_delivered": 3,
+            "bytes_delivered": 4,
+            "lines_dropped": 0,
+            "bytes_dropped": 0,
+        });
+        let events = drain(&outbox).await;
+        let kinds: Vec<&str> = events.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(
+            kinds,
+            ["stream_line", "stream_line", "stream_stats", "stream_exit"]
+        );
+        assert_eq!(events[0].1["line"], "bb");
+        assert_eq!(events[0].1["is_stderr"], true);
+        assert_eq!(events[1].1["line"], "c");
+        assert_eq!(events[2], event("stream_stats", stats));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_reports_what_it_dropped() {
+        let outbox = open_queue(QueuePolicy::DropOldest, 2).await;
+        for text in ["a", "bb", "ccc", "dddd"] {
+            outbox.push_stream_line(1, line(false, text, None)).await;
+        }
+        outbox.request_stream_stats(1).await;
+        let events = drain(&outbox).await;
+        let stats = serde_json::json!({
+            "queued": 2,
+            "lines_delivered": 0,
+            "bytes_delivered": 0,
+            "lines_dropped": 2,
+            "bytes_dropped": 3,
+        });
+        assert_eq!(
+            events[0],
+            event("lines_dropped", serde_json::json!({"count": 2}))
+        );
+        assert_eq!(events[1], event("stream_stats", stats));
+        let lines: Vec<&serde_json::Value> = events[2..].iter().map(|(_, e)| &e["line"]).collect();
+        assert_eq!(lines, ["ccc", "dddd"]);
+        assert_eq!(events.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn coalesce_sends_the_queued_lines_as_one_event() {
+        let outbox = open_queue(QueuePolicy::Coalesce, 3).await;
+        outbox.push_stream_line(1, line(false, "a", None)).await;
+        outbox.push_stream_line(1, line(true, "b", None)).await;
+        outbox
+            .push_stream_line(1, line(false, "cc", Some((0, false))))
+            .await;
+        let pusher = outbox.clone();
+        let mut fourth = tokio::spawn(async move {
+            pusher
+                .push_stream_line(1, line(false, "d", Some((1, true))))
+                .await;
+        });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut fourth)
+                .await
+                .is_err()
+        );
+
+        let batch = serde_json::json!({"lines": [
+            {"is_stderr": false, "line": "a"},
+            {"is_stderr": true, "line": "b"},
+            {"is_stderr": false, "line": "cc", "part": {"index": 0, "final": false}},
+        ]});
+        let Some(msg) = outbox.next_stream_message().await else {
+            panic!("a batch is queued");
+        };
+        let first: serde_json::Value = serde_json::from_str(&msg).unwrap_or_default();
+        assert_eq!(first["type"], "stream_lines");
+        assert_eq!(first["lines"], batch["lines"]);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), fourth)
+                .await
+                .is_ok()
+        );
+
+        // A single queued line goes out as it is.
+        let events = drain(&outbox).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "line_part");
+        assert_eq!(events[0].1["line"], "d");
+        outbox.request_stream_stats(1).await;
+        let events = drain(&outbox).await;
+        assert_eq!(events[0].1["lines_delivered"], 4);
+        assert_eq!(events[0].1["bytes_delivered"], 5);
+    }
+}
@@ -4,8 +4,9 @@ use russh::{
     client::{Config, Handle, Handler, Msg},
 };
 use russh_sftp::{client::SftpSession, protocol::OpenFlags};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use std::{fmt::Debug, path::Path};
 use std::{io, path::PathBuf};
@@ -228,6 +229,7 @@ impl ServerCheckMethod {
 #[derive(Clone)]
 pub struct Client {
     connection_handle: Arc<Handle<ClientHandler>>,
+    remote_forwards: RemoteForwardTable,
     username: String,
     address: SocketAddr,
 }
@@ -272,11 +274,13 @@ impl Client {
             io::ErrorKind::InvalidInput,
             "could not resolve to any addresses",
         )));
+        let remote_forwards = RemoteForwardTable::default();
         for socket_addr in socket_addrs {
             let handler = ClientHandler {
                 hostname: addr.hostname(),
                 host: socket_addr,
                 server_check: server_check.clone(),
+                remote_forwards: remote_forwards.clone(),
             };
             match russh::client::connect(config.clone(), socket_addr, handler).await {
                 Ok(h) => {
@@ -293,6 +297,7 @@ impl Client {
 
         Ok(Self {
             connection_handle: Arc::new(handle),
+            remote_forwards,
             username,
             address,
         })
@@ -505,6 +510,88 @@ impl Client {
         Err(connect_err)
     }
 
+    /// Open a TCP/IP forwarding channel to `host:port` without resolving
+    /// `host` locally.
+    ///
+    /// The server resolves the name, so hosts only known inside its network
+    /// are reachable, as with `ssh -D`.
+    pub async fn open_direct_tcpip_channel_to_host<S: Into<Option<SocketAddr>>>(
+        &self,
+        host: &str,
+        port: u16,
+        src: S,
+    ) -> Result<Channel<Msg>, crate::Error> {
+        let src = src
+            .into()
+            .map(|src| (src.ip().to_string(), src.port().into()))
+            .unwrap_or_else(|| ("127.0.0.1".to_string(), 22));
+        self.connection_handle
+            .channel_open_direct_tcpip(host, port.into(), src.0, src.1)
+            .await
+            .map_err(crate::Error::SshError)
+    }
+
+    /// Ask the server to listen on `address:port` and forward connections
+    /// back to us (`ssh -R`).
+    ///
+    /// `port` 0 lets the server pick one; the port actually bound is in
+    /// [`RemoteForward::port`]. Connections arrive as `forwarded-tcpip`
+    /// channels on [`RemoteForward::accept`]. Call
+    /// [`Client::cancel_remote_forward`] to stop listening.
+    ///
+    /// russh needs the session handle to itself until the server replies, so
+    /// this only works on a client that has not been cloned yet, and fails
+    /// with [`Error::ConnectionShared`](crate::Error::ConnectionShared)
+    /// otherwise. Nothing else ever waits on the reply.
+    pub async fn request_remote_forward(
+        &mut self,
+        address: &str,
+        port: u32,
+    ) -> Result<RemoteForward, crate::Error> {
+        let handle =
+            Arc::get_mut(&mut self.connection_handle).ok_or(crate::Error::ConnectionShared)?;
+        let bound = handle.tcpip_forward(address, port).await?;
+        // Servers only send the port back when we asked for 0.
+        let port = if port == 0 { bound } else { port };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.remote_forwards.insert(port, sender);
+        Ok(RemoteForward { port, receiver })
+    }
+
+    /// Stop a forward set up with [`Client::request_remote_forward`].
+    ///
+    /// Connections already accepted stay open.
+    pub async fn cancel_remote_forward(&self, address: &str, port: u32) -> Result<(), crate::Error> {
+        self.remote_forwards.remove(port);
+        self.connection_handle
+            .cancel_tcpip_forward(address, port)
+            .await
+            .map_err(crate::Error::SshError)
+    }
+
+    /// Start an sftp session on a new channel.
+    ///
+    /// Fails with [`crate::Error::SubsystemRefused`] if the server has no `sftp`
+    /// subsystem configured, which callers can use to fall back to shell
+    /// commands; any other error means the connection itself is in trouble.
+    /// 'timeout_seconds' is passed on to the sftp session, see [`Client::upload_file`].
+    pub async fn open_sftp(&self, timeout_seconds: Option<u64>) -> Result<SftpSession, crate::Error> {
+        let mut channel = self.get_channel().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        loop {
+            match channel.wait().await {
+                Some(russh::ChannelMsg::Success) => break,
+                // Some servers close the channel instead of answering.
+                Some(russh::ChannelMsg::Failure) | None => {
+                    return Err(crate::Error::SubsystemRefused("sftp".to_owned()));
+                }
+                Some(_) => {}
+            }
+        }
+        let sftp = SftpSession::new_opts(channel.into_stream(), timeout_seconds).await?;
+        Ok(sftp)
+    }
+
     /// Upload a file with sftp to the remote server.
     ///
     /// `src_file_path` is the path to the file on the local machine.
@@ -531,9 +618,7 @@ impl Client {
         U: Into<String>,
     {
         // start sftp session
-        let channel = self.get_channel().await?;
-        channel.request_subsystem(true, "sftp").await?;
-        let sftp = SftpSession::new_opts(channel.into_stream(), timeout_seconds).await?;
+        let sftp = self.open_sftp(timeout_seconds).await?;
 
         let file_size = tokio::fs::metadata(&src_file_path).await?.len();
         // read file contents locally
@@ -613,9 +698,7 @@ impl Client {
         local_file_path: T,
     ) -> Result<(), crate::Error> {
         // start sftp session
-        let channel = self.get_channel().await?;
-        channel.request_subsystem(true, "sftp").await?;
-        let sftp = SftpSession::new(channel.into_stream()).await?;
+        let sftp = self.open_sftp(None).await?;
 
         // open remote file for reading
         let mut remote_file = sftp
@@ -813,13 +896,56 @@ impl Client {
         command: &str,
         stdout_channel: mpsc::Sender<Vec<u8>>,
         stderr_channel: Option<mpsc::Sender<Vec<u8>>>,
-        mut stdin_channel: Option<mpsc::Receiver<Vec<u8>>>,
+        stdin_channel: Option<mpsc::Receiver<Vec<u8>>>,
         request_pty: bool,
         default_exit_code: Option<u32>,
     ) -> Result<u32, crate::Error> {
+        let exit = self
+            .execute_io_with_exit(
+                command,
+                stdout_channel,
+                stderr_channel,
+                stdin_channel,
+                request_pty,
+                None,
+            )
+            .await?;
+
+        // If we received an exit code, report it back
+        if let Some(result) = exit.exit_status {
+            Ok(result)
+        // If we have an default exit code, report it back
+        } else if let Some(default_exit_code) = default_exit_code {
+            Ok(default_exit_code)
+        // Otherwise, report an error
+        } else {
+            Err(crate::Error::CommandDidntExit)
+        }
+    }
+
+    /// Same as `execute_io`, but reports everything the server said about how the
+    /// command ended instead of only the exit code.
+    ///
+    /// A command killed by a signal usually has no exit status; in that case
+    /// [`CommandExit::exit_signal`] carries the signal name. If the channel closes
+    /// without either, both are `None` and it is up to the caller to decide what
+    /// that means.
+    ///
+    /// Signals received on `signal_channel` are forwarded to the remote process
+    /// (RFC 4254 section 6.9). Servers are free to ignore them, so callers that
+    /// need the command gone should be prepared to drop the future as well.
+    pub async fn execute_io_with_exit(
+        &self,
+        command: &str,
+        stdout_channel: mpsc::Sender<Vec<u8>>,
+        stderr_channel: Option<mpsc::Sender<Vec<u8>>>,
+        mut stdin_channel: Option<mpsc::Receiver<Vec<u8>>>,
+        request_pty: bool,
+        mut signal_channel: Option<mpsc::Receiver<russh::Sig>>,
+    ) -> Result<CommandExit, crate::Error> {
         let mut channel = self.connection_handle.channel_open_session().await?;
 
-        let mut result: Option<u32> = None;
+        let mut exit = CommandExit::default();
         if request_pty {
             channel
                 .request_pty(false, "xterm", 80_u32, 24_u32, 0, 0, &[])
@@ -837,16 +963,31 @@ impl Client {
                     None
                 }
             };
+            let recv_signal = async {
+                match signal_channel.as_mut() {
+                    Some(ch) => ch.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
             tokio::select! {
                 Some(input) = recv_stdin => {
-                    if let Some(input) = input {
-                        if input.is_empty() {
-                            channel.eof().await? ;
-                        } else {
+                    match input {
+                        Some(input) if !input.is_empty() => {
                             channel.data(&input as &[u8]).await?;
                         }
+                        // An empty chunk is EOF. When all senders are gone no
+                        // more input can come either, so end it then too, or
+                        // the remote command waits forever. Either way stdin
+                        // is done: polling it again would only spin.
+                        _ => {
+                            stdin_channel = None;
+                            channel.eof().await?;
+                        }
                     }
                 },
+                Some(sig) = recv_signal => {
+                    channel.signal(sig).await?;
+                },
                 msg = channel.wait() => {
                     //dbg!(&msg);
                     match msg {
@@ -879,7 +1020,23 @@ impl Client {
                         // If we get an exit code report, store it, but crucially don't
                         // assume this message means end of communications. The data might
                         // not be finished yet!
-                        Some (russh::ChannelMsg::ExitStatus { exit_status }) => result = Some(exit_status),
+                        Some (russh::ChannelMsg::ExitStatus { exit_status }) => {
+                            exit.exit_status = Some(exit_status);
+                        }
+
+                        // Same for a signal report: the process is gone, but buffered
+                        // output may still follow.
+                        Some (russh::ChannelMsg::ExitSignal {
+                            signal_name,
+                            core_dumped,
+                            error_message,
+                            ..
+                        }) => {
+                            exit.exit_signal = Some(signal_display_name(&signal_name));
+                            exit.core_dumped = core_dumped;
+                            exit.error_message =
+                                Some(error_message).filter(|m| !m.trim().is_empty());
+                        }
 
                         // We SHOULD get this EOF messagge, but 4254 sec 5.3 also permits
                         // the channel to close without it being sent. And sometimes this
@@ -892,16 +1049,7 @@ impl Client {
             }
         }
 
-        // If we received an exit code, report it back
-        if let Some(result) = result {
-            Ok(result)
-        // If we have an default exit code, report it back
-        } else if let Some(default_exit_code) = default_exit_code {
-            Ok(default_exit_code)
-        // Otherwise, report an error
-        } else {
-            Err(crate::Error::CommandDidntExit)
-        }
+        Ok(exit)
     }
 
     /// A debugging function to get the username this client is connected as.
@@ -946,11 +1094,130 @@ pub struct CommandExecutedResult {
     pub exit_status: u32,
 }
 
-#[derive(Debug, Clone)]
+/// How a remote command ended, as reported by [`Client::execute_io_with_exit`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct CommandExit {
+    /// The unix exit status (`$?` in bash), if the server sent one.
+    pub exit_status: Option<u32>,
+    /// The name of the signal that terminated the command, without the `SIG`
+    /// prefix (e.g. `TERM`, `KILL`).
+    pub exit_signal: Option<String>,
+    /// Whether the server reported a core dump along with the signal.
+    pub core_dumped: bool,
+    /// The error message the server attached to the signal report, if any.
+    pub error_message: Option<String>,
+}
+
+fn signal_display_name(sig: &russh::Sig) -> String {
+    match sig {
+        russh::Sig::ABRT => "ABRT".to_string(),
+        russh::Sig::ALRM => "ALRM".to_string(),
+        russh::Sig::FPE => "FPE".to_string(),
+        russh::Sig::HUP => "HUP".to_string(),
+        russh::Sig::ILL => "ILL".to_string(),
+        russh::Sig::INT => "INT".to_string(),
+        russh::Sig::KILL => "KILL".to_string(),
+        russh::Sig::PIPE => "PIPE".to_string(),
+        russh::Sig::QUIT => "QUIT".to_string(),
+        russh::Sig::SEGV => "SEGV".to_string(),
+        russh::Sig::TERM => "TERM".to_string(),
+        russh::Sig::USR1 => "USR1".to_string(),
+        russh::Sig::Custom(name) => name.clone(),
+    }
+}
+
+/// A connection the server accepted on a remote forward.
+#[derive(Debug)]
+pub struct ForwardedConnection {
+    pub channel: Channel<Msg>,
+    /// The address and port the server is listening on.
+    pub connected_address: String,
+    pub connected_port: u32,
+    /// Where the connection came from, as seen by the server.
+    pub originator_address: String,
+    pub originator_port: u32,
+}
+
+/// A listening remote forward, see [`Client::request_remote_forward`].
+#[derive(Debug)]
+pub struct RemoteForward {
+    port: u32,
+    receiver: mpsc::UnboundedReceiver<ForwardedConnection>,
+}
+
+impl RemoteForward {
+    /// The port the server is listening on.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Wait for the next forwarded connection.
+    ///
+    /// Returns `None` once the forward is cancelled or the connection to the
+    /// server is gone.
+    pub async fn accept(&mut self) -> Option<ForwardedConnection> {
+        self.receiver.recv().await
+    }
+}
+
+/// Where `forwarded-tcpip` channels go, keyed by the listening port.
+#[derive(Debug, Clone, Default)]
+struct RemoteForwardTable {
+    senders: Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<ForwardedConnection>>>>,
+}
+
+impl RemoteForwardTable {
+    fn insert(&self, port: u32, sender: mpsc::UnboundedSender<ForwardedConnection>) {
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.insert(port, sender);
+        }
+    }
+
+    fn remove(&self, port: u32) {
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.remove(&port);
+        }
+    }
+
+    /// Drops every sender, so all [`RemoteForward::accept`] calls end.
+    fn clear(&self) {
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.clear();
+        }
+    }
+
+    /// Hands `connection` to whoever listens on its port. Gives it back if
+    /// nobody does.
+    fn dispatch(&self, connection: ForwardedConnection) -> Result<(), ForwardedConnection> {
+        let Ok(mut senders) = self.senders.lock() else {
+            return Err(connection);
+        };
+        let port = connection.connected_port;
+        match senders.get(&port) {
+            Some(sender) => sender.send(connection).map_err(|e| {
+                senders.remove(&port);
+                e.0
+            }),
+            None => Err(connection),
+        }
+    }
+}
+
+#[derive(Debug)]
 struct ClientHandler {
     hostname: String,
     host: SocketAddr,
     server_check: ServerCheckMethod,
+    remote_forwards: RemoteForwardTable,
+}
+
+// russh drops the handler when the session ends. The `Client` keeps its own
+// copy of the table, so without this its senders would outlive the
+// connection and remote forwards would wait forever.
+impl Drop for ClientHandler {
+    fn drop(&mut self) {
+        self.remote_forwards.clear();
+    }
 }
 
 impl Handler for ClientHandler {
@@ -997,6 +1264,30 @@ impl Handler for ClientHandler {
             }
         }
     }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        let connection = ForwardedConnection {
+            channel,
+            connected_address: connected_address.to_string(),
+            connected_port,
+            originator_address: originator_address.to_string(),
+            originator_port,
+        };
+        // russh has already confirmed the channel, so one nobody asked for is
+        // closed right away.
+        if let Err(connection) = self.remote_forwards.dispatch(connection) {
+            let _ = connection.channel.close().await;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1210,6 +1501,35 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn execute_io_with_exit_reports_signal() {
+        let client = establish_test_host_connection().await;
+        let (stdout_tx, mut stdout_rx) = tokio::sync::mpsc::channel(10);
+        let cmd = "echo before; kill -TERM $$";
+        let exec_future = client.execute_io_with_exit(cmd, stdout_tx, None, None, false, None);
+        tokio::pin!(exec_future);
+        let mut exit: Option<CommandExit> = None;
+        let mut stdout_output = vec![];
+        loop {
+            tokio::select! {
+                exit_inner = &mut exec_future => {
+                    exit = Some(exit_inner.unwrap());
+                },
+                Some(stdout) = stdout_rx.recv() => {
+                    stdout_output.push(stdout);
+                },
+            };
+            if exit.is_some() {
+                break;
+            }
+        }
+        let exit = exit.unwrap();
+        assert_eq!(None, exit.exit_status);
+        assert_eq!(Some("TERM".to_string()), exit.exit_signal);
+        assert!(!exit.core_dumped);
+        assert_eq!(b"before\n".to_vec(), stdout_output.concat());
+    }
+
     #[tokio::test]
     async fn execute_multiple_commands() {
         let client = establish_test_host_connection().await;
@@ -43,6 +43,10 @@ pub enum Error {
     SftpError(#[from] russh_sftp::client::error::Error),
     #[error("I/O error")]
     IoError(#[from] io::Error),
+    #[error("The server refused the {0} subsystem")]
+    SubsystemRefused(String),
+    #[error("The connection is shared with other clients")]
+    ConnectionShared,
     #[error("Channel send error")]
     ChannelSendError(#[from] mpsc::error::SendError<Vec<u8>>),
 }